@@ -0,0 +1,58 @@
+// Build time of a BarcodeLookupMap over 10M barcodes.  The "before" case
+// is the build as it was before the prefix table was capped: the input is
+// sorted and the table has a bucket for every prefix of half the barcode
+// length.  The "after" cases cap the table at DEFAULT_MAX_PREFIX_BUCKETS,
+// and skip the sort for presorted input.
+//
+// Run with `cargo bench --bench barcode_map`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use libradicl::{BarcodeLookupMap, DEFAULT_MAX_PREFIX_BUCKETS};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const NUM_BARCODES: usize = 10_000_000;
+
+fn random_barcodes(bclen: u32) -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(1299);
+    let mask = (1u64 << (2 * bclen)) - 1;
+    (0..NUM_BARCODES).map(|_| rng.gen::<u64>() & mask).collect()
+}
+
+fn bench_build(c: &mut Criterion) {
+    // at 16 bases the uncapped table has 4^8 buckets, under the cap; at
+    // 26 it would have 4^13, and the cap shortens the prefix to 12 bases
+    for &bclen in &[16u32, 26] {
+        let bcs = random_barcodes(bclen);
+        let mut sorted = bcs.clone();
+        sorted.sort_unstable();
+
+        let mut group = c.benchmark_group(format!("build_10M_{}bp", bclen));
+        group.sample_size(10);
+        group.bench_function("before", |b| {
+            b.iter_batched(
+                || bcs.clone(),
+                |kv| BarcodeLookupMap::with_params(kv, bclen, usize::MAX, false),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("after", |b| {
+            b.iter_batched(
+                || bcs.clone(),
+                |kv| BarcodeLookupMap::with_params(kv, bclen, DEFAULT_MAX_PREFIX_BUCKETS, false),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function("after_presorted", |b| {
+            b.iter_batched(
+                || sorted.clone(),
+                |kv| BarcodeLookupMap::from_sorted(kv, bclen),
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_build);
+criterion_main!(benches);
@@ -0,0 +1,57 @@
+// Exact lookup of 10M queries in a BarcodeLookupMap of 1M, and of 10M,
+// barcodes.  The "find_exact" case is a naive loop calling `find_exact`
+// once per query; the "find_exact_many" case looks them all up at once,
+// visiting them in sorted order.  Half of the queries are barcodes of the
+// map and half are random, and both cases find the same results.  The
+// sort only pays for itself once the map is too large to stay in cache:
+// it is slower than the naive loop for the smaller map, and faster for the
+// larger one.
+//
+// Run with `cargo bench --bench find_exact_many`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libradicl::BarcodeLookupMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const NUM_QUERIES: usize = 10_000_000;
+const BCLEN: u32 = 16;
+
+fn bench_find_exact(c: &mut Criterion) {
+    for &nbcs in &[1_000_000usize, 10_000_000] {
+        let mut rng = StdRng::seed_from_u64(1272);
+        let mask = (1u64 << (2 * BCLEN)) - 1;
+        let bcs: Vec<u64> = (0..nbcs).map(|_| rng.gen::<u64>() & mask).collect();
+        let queries: Vec<u64> = (0..NUM_QUERIES)
+            .map(|i| {
+                if i % 2 == 0 {
+                    bcs[rng.gen_range(0..nbcs)]
+                } else {
+                    rng.gen::<u64>() & mask
+                }
+            })
+            .collect();
+        let map = BarcodeLookupMap::new(bcs, BCLEN);
+
+        let naive: Vec<Option<usize>> = queries.iter().map(|q| map.find_exact(*q)).collect();
+        let mut out = Vec::new();
+        map.find_exact_many(&queries, &mut out);
+        assert_eq!(naive, out);
+
+        let mut group = c.benchmark_group(format!("find_exact_10M_in_{}M", nbcs / 1_000_000));
+        group.sample_size(10);
+        group.bench_function("find_exact", |b| {
+            b.iter(|| {
+                out.clear();
+                out.extend(queries.iter().map(|q| map.find_exact(*q)));
+            })
+        });
+        group.bench_function("find_exact_many", |b| {
+            b.iter(|| map.find_exact_many(&queries, &mut out))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_find_exact);
+criterion_main!(benches);
@@ -0,0 +1,113 @@
+// Parsing 50M synthetic records, as a chunk of 100k records read 500
+// times.  The "from_bytes" case allocates the `dirs` and `refs` of every
+// record; the "for_each_record" case reuses one `ReadRecord` (see
+// `ReadRecord::read_into`) across each chunk.  The number of allocations
+// made by each case is counted by the global allocator and printed before
+// the timings.
+//
+// Run with `cargo bench --bench record_alloc`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libradicl::{Chunk, RadIntId};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const RECORDS_PER_CHUNK: u32 = 100_000;
+const NUM_CHUNKS: usize = 500;
+
+// one chunk of records with u32 barcodes and umis, and 1 to 4 alignments
+fn synthetic_chunk() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(1296);
+    let mut body = Vec::new();
+    for _ in 0..RECORDS_PER_CHUNK {
+        let na = rng.gen_range(1..5u32);
+        body.extend_from_slice(&na.to_le_bytes());
+        body.extend_from_slice(&rng.gen::<u32>().to_le_bytes());
+        body.extend_from_slice(&rng.gen::<u32>().to_le_bytes());
+        for _ in 0..na {
+            body.extend_from_slice(&rng.gen::<u32>().to_le_bytes());
+        }
+    }
+    let mut chunk = Vec::with_capacity(body.len() + 8);
+    chunk.extend_from_slice(&((body.len() + 8) as u32).to_le_bytes());
+    chunk.extend_from_slice(&RECORDS_PER_CHUNK.to_le_bytes());
+    chunk.extend_from_slice(&body);
+    chunk
+}
+
+fn from_bytes(chunk: &[u8]) -> usize {
+    let mut nalns = 0;
+    for _ in 0..NUM_CHUNKS {
+        let c: Chunk = Chunk::from_bytes(&mut Cursor::new(chunk), &RadIntId::U32, &RadIntId::U32);
+        nalns += c.reads.iter().map(|r| r.refs.len()).sum::<usize>();
+    }
+    nalns
+}
+
+fn for_each_record(chunk: &[u8]) -> usize {
+    let mut nalns = 0;
+    for _ in 0..NUM_CHUNKS {
+        Chunk::for_each_record(
+            &mut Cursor::new(chunk),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            |r| nalns += r.refs.len(),
+        )
+        .unwrap();
+    }
+    nalns
+}
+
+fn count_allocations(name: &str, f: fn(&[u8]) -> usize, chunk: &[u8]) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let nalns = f(chunk);
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{}: {} allocations over {} records ({} alignments)",
+        name,
+        allocs,
+        RECORDS_PER_CHUNK as usize * NUM_CHUNKS,
+        nalns
+    );
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let chunk = synthetic_chunk();
+    count_allocations("from_bytes", from_bytes, &chunk);
+    count_allocations("for_each_record", for_each_record, &chunk);
+
+    let mut group = c.benchmark_group("parse_50M_records");
+    group.sample_size(10);
+    group.bench_function("from_bytes", |b| b.iter(|| from_bytes(&chunk)));
+    group.bench_function("for_each_record", |b| b.iter(|| for_each_record(&chunk)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -0,0 +1,78 @@
+#![no_main]
+
+// Feed arbitrary bytes through the RAD parsers; run with
+// `cargo fuzz run rad_reader` from the `libradicl` directory.
+// Malformed input must produce an error, never a panic or an
+// allocation sized by an untrusted count.
+
+use libfuzzer_sys::fuzz_target;
+use libradicl::{
+    chunk_offsets, compute_max_ambiguity, Chunk, RadIntId, RadReader, ReadRecord, RecordViews,
+};
+use std::collections::HashSet;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    // the plain (single-end, reference id only) records
+    if let Ok(rr) = RadReader::new(Cursor::new(data)) {
+        for chunk in rr {
+            if chunk.is_err() {
+                break;
+            }
+        }
+    }
+
+    // records with the optional alignment-level fields
+    if let Ok(mut rr) = RadReader::new(Cursor::new(data)) {
+        if rr.aln_layout().is_ok() {
+            while let Some(Ok(_)) = rr.next_ext() {}
+        }
+    }
+
+    // walking the chunk headers, and scanning the records, of a file
+    if let Ok(rr) = RadReader::new(Cursor::new(data)) {
+        let (bct, umit) = (rr.bc_type, rr.umi_type);
+        let mut reader = Cursor::new(data);
+        reader.set_position(rr.get_ref().position());
+        let _ = compute_max_ambiguity(&mut reader, &rr.header, &bct, &umit);
+        let _ = chunk_offsets(&mut reader, rr.header.num_chunks);
+    }
+
+    // generic parsing according to the tag descriptions
+    if let Ok(rr) = RadReader::new(Cursor::new(data)) {
+        if let Ok(schema) = rr.record_schema() {
+            let mut reader = rr.into_inner();
+            while schema.read_chunk(&mut reader).is_ok() {}
+        }
+    }
+
+    // the data as the bytes of a single chunk, read in place
+    for t in [
+        RadIntId::U8,
+        RadIntId::U16,
+        RadIntId::U32,
+        RadIntId::U64,
+        RadIntId::U128,
+    ]
+    .iter()
+    {
+        if let Ok(views) = RecordViews::new(data, t, t) {
+            for r in views {
+                if r.is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = Chunk::from_slice(data, t, t);
+
+        // and through the readers that don't materialize whole records
+        let keep: HashSet<u32> = (0..4).collect();
+        let _ = Chunk::from_bytes_filtered(&mut Cursor::new(data), t, t, &keep);
+        let _ = Chunk::for_each_record(&mut Cursor::new(data), t, t, |_| {});
+        let mut reader = Cursor::new(data);
+        while ReadRecord::from_bytes_split_ori(&mut reader, t, t).is_ok() {}
+        let mut reader = Cursor::new(data);
+        let mut rec = ReadRecord::<u128>::default();
+        while rec.read_into(&mut reader, t, t).is_ok() {}
+    }
+});
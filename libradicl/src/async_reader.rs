@@ -0,0 +1,444 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Async mirrors of the basic RAD parsers, for reading from any
+// `tokio::io::AsyncRead` (e.g. a stream out of object storage).
+// The byte layout is identical to that read by the synchronous
+// `from_bytes` functions.  `AsyncRadReader` gathers the bytes of the
+// file prelude and of each chunk asynchronously, and then hands them to
+// the synchronous parsers, so that it needn't know their layout.
+
+extern crate tokio;
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::utils::{MASK_LOWER_31_U32, MASK_TOP_BIT_U32};
+use self::libradicl::{
+    check_limit_io, prealloc, Chunk, FileTags, RadHeader, RadIntId, RadReader, RadVersion,
+    ReadRecord, TagSection, MAX_ALIGNMENTS_PER_RECORD, MAX_RECORDS_PER_CHUNK, MAX_REF_NAME_LEN,
+    RAD_MAGIC, VERSION_FIELDS_LEN,
+};
+use scroll::Pread;
+use std::convert::TryFrom;
+use std::io::{self, Cursor};
+use tokio::io::{AsyncRead, AsyncReadExt, Chain};
+
+// the smallest number of bytes read at a time while looking for the end
+// of the tag values preceding the first chunk
+const PRELUDE_BLOCK_SIZE: usize = 64 * 1024;
+
+async fn read_into_u64_async<T: AsyncRead + Unpin>(
+    reader: &mut T,
+    rt: &RadIntId,
+) -> io::Result<u64> {
+    let v = match rt {
+        RadIntId::U8 => reader.read_u8().await? as u64,
+        RadIntId::U16 => reader.read_u16_le().await? as u64,
+        RadIntId::U32 => reader.read_u32_le().await? as u64,
+        RadIntId::U64 => reader.read_u64_le().await?,
+        RadIntId::U128 => {
+            let v = reader.read_u128_le().await?;
+            u64::try_from(v).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("u128 value {:#x} does not fit in a u64", v),
+                )
+            })?
+        }
+    };
+    Ok(v)
+}
+
+impl RadHeader {
+    /// The async counterpart of `RadHeader::from_bytes_checked`, accepting
+    /// both legacy files and those with a version header.
+    pub async fn from_bytes_async<T: AsyncRead + Unpin>(reader: &mut T) -> io::Result<RadHeader> {
+        let first = reader.read_u8().await?;
+        let (version, is_paired) = match first {
+            0 | 1 => (RadVersion::Legacy, first),
+            _ => {
+                let mut buf = [0u8; VERSION_FIELDS_LEN];
+                let mut magic = vec![first; RAD_MAGIC.len()];
+                reader.read_exact(&mut magic[1..]).await?;
+                if magic[..] != RAD_MAGIC[..] {
+                    return Err(invalid_data(RadError::InvalidMagic(magic)));
+                }
+                reader.read_exact(&mut buf).await?;
+                RadVersion::from_fields(&buf).map_err(invalid_data)?
+            }
+        };
+        let ref_count = reader.read_u64_le().await?;
+
+        // we know how many names we will read in (but don't trust the
+        // count to size the allocation).
+        let mut ref_names = Vec::with_capacity(prealloc(ref_count));
+        let mut buf = vec![0u8; 65536];
+        for _ in 0..ref_count {
+            let l = reader.read_u16_le().await? as usize;
+            check_limit_io("reference name length", l as u64, MAX_REF_NAME_LEN as u64)?;
+            reader.read_exact(&mut buf[0..l]).await?;
+            let name = std::str::from_utf8(&buf[0..l])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            ref_names.push(name.to_string());
+        }
+
+        let num_chunks = reader.read_u64_le().await?;
+        let mut rh = RadHeader {
+            is_paired,
+            ref_count,
+            ref_names,
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks,
+        };
+        if version.has_ref_info() {
+            // gather the block, and then parse it as the sync reader does
+            let flags = reader.read_u8().await?;
+            let mut info = vec![flags];
+            let n = RadHeader::ref_info_body_len(flags, ref_count);
+            let read = reader.take(n).read_to_end(&mut info).await?;
+            if read as u64 != n {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the input ended within the reference lengths",
+                ));
+            }
+            rh.read_ref_info(&mut Cursor::new(info))
+                .map_err(invalid_data)?;
+        }
+        Ok(rh)
+    }
+}
+
+// Carry a `RadError` inside an `io::Error`, as `check_limit_io` does, so
+// that converting back with `RadError::from` recovers it.
+fn invalid_data(e: RadError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl ReadRecord {
+    pub async fn from_bytes_async<T: AsyncRead + Unpin>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> io::Result<Self> {
+        let na = reader.read_u32_le().await?;
+        check_limit_io(
+            "alignment count",
+            na as u64,
+            MAX_ALIGNMENTS_PER_RECORD as u64,
+        )?;
+        let bc = read_into_u64_async(reader, bct).await?;
+        let umi = read_into_u64_async(reader, umit).await?;
+
+        let mut rec = Self {
+            bc,
+            umi,
+            dirs: Vec::with_capacity(na as usize),
+            mate_dirs: Vec::new(),
+            refs: Vec::with_capacity(na as usize),
+            tags: None,
+        };
+
+        for _ in 0..(na as usize) {
+            let v = reader.read_u32_le().await?;
+            rec.dirs.push((v & MASK_LOWER_31_U32) != 0);
+            rec.refs.push(v & MASK_TOP_BIT_U32);
+        }
+        Ok(rec)
+    }
+}
+
+impl Chunk {
+    pub async fn from_bytes_async<T: AsyncRead + Unpin>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> io::Result<Self> {
+        let nbytes = reader.read_u32_le().await?;
+        let nrec = reader.read_u32_le().await?;
+        check_limit_io("record count", nrec as u64, MAX_RECORDS_PER_CHUNK as u64)?;
+        let mut c = Self {
+            nbytes,
+            nrec,
+            reads: Vec::with_capacity(prealloc(nrec as u64)),
+        };
+
+        for _ in 0..(nrec as usize) {
+            c.reads
+                .push(ReadRecord::from_bytes_async(reader, bct, umit).await?);
+        }
+        Ok(c)
+    }
+}
+
+/// The async counterpart of `RadReader`: the header, tag descriptions and
+/// file-level tag values are parsed by `new`, after which `next_chunk`
+/// yields the `header.num_chunks` chunks of the file in turn.
+pub struct AsyncRadReader<R> {
+    // the bytes read past the file-level tag values while parsing them,
+    // followed by the rest of the input
+    reader: Chain<Cursor<Vec<u8>>, R>,
+    pub header: RadHeader,
+    pub file_tags: TagSection,
+    pub read_tags: TagSection,
+    pub aln_tags: TagSection,
+    pub file_tag_vals: FileTags,
+    pub bc_type: RadIntId,
+    pub umi_type: RadIntId,
+    remaining: u64,
+    // holds the bytes of one chunk at a time
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRadReader<R> {
+    /// Parse everything preceding the first chunk.  Rather than mirroring
+    /// the layout of the header and tag sections, the input is read in
+    /// blocks of growing size until `RadReader::new` can parse it, and any
+    /// bytes read beyond the file-level tag values are kept for the chunks.
+    pub async fn new(mut reader: R) -> Result<AsyncRadReader<R>, RadError> {
+        let mut prelude = Vec::new();
+        let mut at_eof = false;
+        loop {
+            match RadReader::new(Cursor::new(&prelude[..])) {
+                Ok(rr) => {
+                    let start = rr.get_ref().position();
+                    let RadReader {
+                        header,
+                        file_tags,
+                        read_tags,
+                        aln_tags,
+                        file_tag_vals,
+                        bc_type,
+                        umi_type,
+                        ..
+                    } = rr;
+                    let mut rest = Cursor::new(prelude);
+                    rest.set_position(start);
+                    return Ok(AsyncRadReader {
+                        reader: rest.chain(reader),
+                        remaining: header.num_chunks,
+                        header,
+                        file_tags,
+                        read_tags,
+                        aln_tags,
+                        file_tag_vals,
+                        bc_type,
+                        umi_type,
+                        buf: Vec::new(),
+                    });
+                }
+                Err(RadError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof && !at_eof => {
+                    let n = prelude.len().max(PRELUDE_BLOCK_SIZE) as u64;
+                    at_eof = (&mut reader).take(n).read_to_end(&mut prelude).await? == 0;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read and parse the next chunk, or return `None` once every chunk
+    /// has been read.  Reading stops after the first error.
+    pub async fn next_chunk(&mut self) -> Option<Result<Chunk, RadError>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let res = self.read_chunk().await;
+        self.remaining = if res.is_ok() { self.remaining - 1 } else { 0 };
+        Some(res)
+    }
+
+    async fn read_chunk(&mut self) -> Result<Chunk, RadError> {
+        self.buf.resize(8, 0);
+        self.reader.read_exact(&mut self.buf[..8]).await?;
+        let nbytes = self.buf.pread::<u32>(0).unwrap();
+        if nbytes < 8 {
+            return Err(RadError::InvalidChunkSize(nbytes));
+        }
+        // grow the buffer only as the bytes arrive, rather than trusting
+        // `nbytes` up front
+        let n = nbytes as u64 - 8;
+        let read = (&mut self.reader)
+            .take(n)
+            .read_to_end(&mut self.buf)
+            .await?;
+        if read as u64 != n {
+            return Err(RadError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the input ended within a chunk",
+            )));
+        }
+        let mut c = libradicl::read_chunk(
+            &mut Cursor::new(&self.buf),
+            self.file_tag_vals.zstd_chunks,
+            &self.bc_type,
+            &self.umi_type,
+        )?;
+        if self.header.is_paired != 0 {
+            c.split_mate_ori();
+        }
+        Ok(c)
+    }
+
+    /// Consumes this `AsyncRadReader`, returning the rest of its input:
+    /// any bytes that were read ahead while parsing the header, followed
+    /// by the underlying reader.
+    pub fn into_inner(self) -> Chain<Cursor<Vec<u8>>, R> {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::libradicl::async_reader::AsyncRadReader;
+    use self::libradicl::error::RadError;
+    use self::libradicl::{Chunk, FileTags, RadHeader, RadIntId, RadReader, RadWriter, ReadRecord};
+    use crate as libradicl;
+    use futures::executor::block_on;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_async_parsers_match_sync() {
+        let mut bytes = vec![0u8];
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        for name in &["tx0", "tx1"] {
+            bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        let header_len = bytes.len();
+
+        // a chunk of two records, with u16 barcodes and u32 umis
+        let mut body = Vec::new();
+        for (bc, umi, refs) in &[(7u16, 70u32, vec![0u32, 0x80000001]), (8, 80, vec![1])] {
+            body.extend_from_slice(&(refs.len() as u32).to_le_bytes());
+            body.extend_from_slice(&bc.to_le_bytes());
+            body.extend_from_slice(&umi.to_le_bytes());
+            for r in refs {
+                body.extend_from_slice(&r.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&((body.len() + 8) as u32).to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&body);
+
+        let mut reader = &bytes[..];
+        let hdr = block_on(RadHeader::from_bytes_async(&mut reader)).unwrap();
+        assert_eq!(hdr.ref_names, vec!["tx0", "tx1"]);
+        assert_eq!((hdr.ref_count, hdr.num_chunks), (2, 1));
+        assert_eq!(reader.len(), bytes.len() - header_len);
+
+        let (bct, umit) = (RadIntId::U16, RadIntId::U32);
+        let c = block_on(Chunk::from_bytes_async(&mut reader, &bct, &umit)).unwrap();
+        assert!(reader.is_empty());
+        let expected = Chunk::from_bytes(&mut Cursor::new(&bytes[header_len..]), &bct, &umit);
+        assert_eq!((c.nbytes, c.nrec), (expected.nbytes, expected.nrec));
+        for (r, e) in c.reads.iter().zip(expected.reads.iter()) {
+            assert_eq!(
+                (r.bc, r.umi, &r.refs, &r.dirs),
+                (e.bc, e.umi, &e.refs, &e.dirs)
+            );
+        }
+        assert_eq!(c.reads[0].refs, vec![0, 1]);
+        assert_eq!(c.reads[0].dirs, vec![false, true]);
+
+        // a record cut short
+        let mut reader = &bytes[header_len + 8..bytes.len() - 2];
+        block_on(ReadRecord::from_bytes_async(&mut reader, &bct, &umit)).unwrap();
+        assert!(block_on(ReadRecord::from_bytes_async(&mut reader, &bct, &umit)).is_err());
+    }
+
+    #[test]
+    fn test_async_rad_reader_round_trip() {
+        // enough references that the prelude spans several reads
+        let ref_names: Vec<String> = (0..5000).map(|i| format!("transcript_{:06}", i)).collect();
+        let mut hdr = RadHeader {
+            is_paired: 0,
+            ref_count: ref_names.len() as u64,
+            ref_lengths: (0..5000).map(|i| 100 + i).collect(),
+            ref_names,
+            ref_digest: None,
+            num_chunks: 3,
+        };
+        hdr.ref_digest = hdr.compute_ref_digest();
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.enable_version_header().unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bc in 0..3u64 {
+            rw.begin_chunk().unwrap();
+            for umi in 0..=bc {
+                rw.push_record(&ReadRecord {
+                    bc,
+                    umi,
+                    dirs: vec![true, false],
+                    mate_dirs: Vec::new(),
+                    refs: vec![umi as u32, 4999],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let bytes = rw.finalize_with_chunk_count().unwrap().into_inner();
+
+        let expected: Vec<Chunk> = RadReader::new(Cursor::new(&bytes))
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        let (reader, chunks) = block_on(async {
+            let mut reader = AsyncRadReader::new(&bytes[..]).await.unwrap();
+            let mut chunks = Vec::new();
+            while let Some(c) = reader.next_chunk().await {
+                chunks.push(c.unwrap());
+            }
+            (reader, chunks)
+        });
+        assert_eq!(reader.header.ref_names, hdr.ref_names);
+        assert_eq!(reader.header.ref_lengths, hdr.ref_lengths);
+        assert_eq!(reader.header.ref_digest, hdr.ref_digest);
+        assert_eq!(
+            (reader.file_tag_vals.bclen, reader.file_tag_vals.umilen),
+            (16, 12)
+        );
+        assert_eq!(chunks.len(), 3);
+        for (c, e) in chunks.iter().zip(expected.iter()) {
+            assert_eq!((c.nbytes, c.nrec), (e.nbytes, e.nrec));
+            for (r, s) in c.reads.iter().zip(e.reads.iter()) {
+                assert_eq!(
+                    (r.bc, r.umi, &r.refs, &r.dirs),
+                    (s.bc, s.umi, &s.refs, &s.dirs)
+                );
+            }
+        }
+
+        // the versioned header alone, through the async header parser
+        let mut rest = &bytes[..];
+        let h = block_on(RadHeader::from_bytes_async(&mut rest)).unwrap();
+        assert_eq!(h.ref_names, hdr.ref_names);
+        assert_eq!(h.ref_lengths, hdr.ref_lengths);
+        assert_eq!(h.ref_digest, hdr.ref_digest);
+        let mut sync_rest = Cursor::new(&bytes);
+        RadHeader::from_bytes_checked(&mut sync_rest).unwrap();
+        assert_eq!(bytes.len() - rest.len(), sync_rest.position() as usize);
+
+        // input that ends within the header
+        let res = block_on(AsyncRadReader::new(&bytes[..1000]));
+        assert!(matches!(res, Err(RadError::Io(_))));
+        assert!(block_on(RadHeader::from_bytes_async(&mut &bytes[..1000])).is_err());
+    }
+}
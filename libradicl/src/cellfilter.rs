@@ -38,6 +38,10 @@ pub enum CellFilterMethod {
     // cut off at this cell in
     // the frequency sorted list
     ForceCells(usize),
+    // keep exactly this many of the most
+    // frequent barcodes, breaking ties in
+    // frequency by barcode
+    ForceTopN(usize),
     // use this cell as a hint in
     // the frequency sorted list
     ExpectCells(usize),
@@ -140,14 +144,26 @@ fn get_max_distance_index(sorted_frequencies: &[u64], is_cumulative: bool) -> us
     max_ind
 }
 
+/// The barcode frequency cutoff chosen by `get_knee`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KneeResult {
+    /// the frequency at the knee; barcodes at least this frequent are retained
+    pub threshold: u64,
+    /// the index of the knee within the reverse-sorted frequencies
+    pub rank: usize,
+    /// the number of barcodes with a frequency of at least `threshold`
+    pub num_retained: usize,
+}
+
 /// Get the knee of the cure using the `distance` method as described
 /// in the [UMI-tools documentation](https://github.com/CGATOxford/UMI-tools).
 /// This method takes a reverse-sorted (sorted in descending order) llist of
 /// frequencies, and a maximum number of iterations to run the algorithm.  It
 /// returns the point on the CDF of the reverse-sorted frequency vector that is
-/// farthest from the line defined by the end-points.  The algorithm is taken from
+/// farthest from the line defined by the end-points, along with the frequency
+/// there and the number of barcodes it retains.  The algorithm is taken from
 /// [here](https://github.com/CGATOxford/UMI-tools/blob/master/umi_tools/whitelist_methods.py#L248).
-fn get_knee(freq: &[u64], max_iterations: usize, log: &slog::Logger) -> usize {
+pub fn get_knee(freq: &[u64], max_iterations: usize, log: &slog::Logger) -> KneeResult {
     // get the cumulative frequency from the frequency
     let cfreq: Vec<u64> = freq
         .iter()
@@ -182,7 +198,12 @@ fn get_knee(freq: &[u64], max_iterations: usize, log: &slog::Logger) -> usize {
         assert_ne!(max_idx, 0,
               "get_knee determined a knee index of 0. This probably should not happen with valid input data.");
     }
-    max_idx
+    let threshold = freq[max_idx];
+    KneeResult {
+        threshold,
+        rank: max_idx,
+        num_retained: freq.iter().take_while(|&&f| f >= threshold).count(),
+    }
 }
 
 fn populate_unfiltered_barcode_map<T: Read>(
@@ -208,10 +229,8 @@ fn populate_unfiltered_barcode_map<T: Read>(
                     l.len()
                 );
             }
-            if let Some((_, km, _)) =
-                needletail::bitkmer::BitNuclKmer::new(&l[..], l.len() as u8, false).next()
-            {
-                hm.insert(km.0, 0);
+            if let Ok(km) = libradicl::utils::pack_barcode(&l[..], l.len() as u16) {
+                hm.insert(km, 0);
             }
         }
     }
@@ -452,6 +471,7 @@ fn process_filtered(
     log: &slog::Logger,
 ) -> u64 {
     let valid_bc: Vec<u64>;
+    let mut knee = None;
     let mut freq: Vec<u64> = hm.values().cloned().collect();
     freq.sort_unstable();
     freq.reverse();
@@ -459,17 +479,19 @@ fn process_filtered(
     // select from among supported filter methods
     match filter_meth {
         CellFilterMethod::KneeFinding => {
-            let num_bc = get_knee(&freq[..], 100, &log);
-            let min_freq = freq[num_bc];
+            let k = get_knee(&freq[..], 100, &log);
 
             // collect all of the barcodes that have a frequency
             // >= to min_thresh.
-            valid_bc = libradicl::permit_list_from_threshold(&hm, min_freq);
+            valid_bc = libradicl::permit_list_from_threshold(&hm, k.threshold);
             info!(
                 log,
-                "knee distance method resulted in the selection of {} permitted barcodes.",
+                "knee distance method found a threshold of {} reads (at rank {}), resulting in the selection of {} permitted barcodes.",
+                k.threshold,
+                k.rank,
                 valid_bc.len()
             );
+            knee = Some(k);
         }
         CellFilterMethod::ForceCells(top_k) => {
             let num_bc = if freq.len() < *top_k {
@@ -484,8 +506,24 @@ fn process_filtered(
             // >= to min_thresh.
             valid_bc = libradicl::permit_list_from_threshold(&hm, min_freq);
         }
+        CellFilterMethod::ForceTopN(top_n) => {
+            let (bcs, cutoff_freq) = libradicl::permit_list_top_n(&hm, *top_n);
+            info!(
+                log,
+                "kept the top {} barcodes; the least frequent of them has {} reads.",
+                bcs.len(),
+                cutoff_freq
+            );
+            valid_bc = bcs;
+        }
         CellFilterMethod::ExplicitList(valid_bc_file) => {
-            valid_bc = libradicl::permit_list_from_file(valid_bc_file.clone(), ft_vals.bclen);
+            valid_bc = match libradicl::permit_list_from_file_checked(valid_bc_file, ft_vals) {
+                Ok(v) => v,
+                Err(e) => {
+                    crit!(log, "{}", e);
+                    std::process::exit(exit_codes::EXIT_INVALID_PERMIT_LIST);
+                }
+            };
         }
         CellFilterMethod::ExpectCells(expected_num_cells) => {
             let robust_quantile = 0.99f64;
@@ -563,7 +601,12 @@ fn process_filtered(
         "version_str" : version,
         "max-ambig-record" : max_ambiguity_read,
         "cmd" : cmdline,
-        "permit-list-type" : "filtered"
+        "permit-list-type" : "filtered",
+        "knee" : knee.map(|k| json!({
+            "threshold" : k.threshold,
+            "rank" : k.rank,
+            "num_retained" : k.num_retained
+        }))
     });
 
     let m_path = parent.join("generate_permit_list.json");
@@ -589,6 +632,10 @@ fn process_filtered(
 /// (i.e. "permitted") barcode values, as well as
 /// a map from each correctable barcode to the
 /// permitted barcode to which it maps.
+#[allow(clippy::too_many_arguments)]
+// The number of chunks handed to a decoding thread at a time.
+const DECODE_BATCH_CHUNKS: usize = 16;
+
 #[allow(clippy::too_many_arguments)]
 pub fn generate_permit_list(
     rad_dir: String,
@@ -598,6 +645,7 @@ pub fn generate_permit_list(
     version: &str,
     velo_mode: bool,
     cmdline: &str,
+    num_threads: usize,
     //top_k: Option<usize>,
     //valid_bc_file: Option<String>,
     //use_knee_distance: bool,
@@ -629,7 +677,7 @@ pub fn generate_permit_list(
 
     let i_file = File::open(i_dir.join("map.rad")).expect("could not open input rad file");
     let mut br = BufReader::new(i_file);
-    let hdr = libradicl::RadHeader::from_bytes(&mut br);
+    let hdr = libradicl::RadHeader::from_bytes_checked(&mut br)?;
     info!(
         log,
         "paired : {:?}, ref_count : {}, num_chunks : {}",
@@ -638,13 +686,13 @@ pub fn generate_permit_list(
         hdr.num_chunks.to_formatted_string(&Locale::en)
     );
     // file-level
-    let fl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let fl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} file-level tags", fl_tags.tags.len());
     // read-level
-    let rl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let rl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
 
-    // right now, we only handle BC and UMI types of U8—U64, so validate that
+    // we handle BC and UMI types of U8—U64 and U128, so validate that
     const BNAME: &str = "b";
     const UNAME: &str = "u";
 
@@ -657,7 +705,7 @@ pub fn generate_permit_list(
             if libradicl::decode_int_type_tag(rt.typeid).is_none() {
                 crit!(
                     log,
-                    "currently only RAD types 1--4 are supported for 'b' and 'u' tags."
+                    "currently only RAD types 1--4 and 9 are supported for 'b' and 'u' tags."
                 );
                 std::process::exit(exit_codes::EXIT_UNSUPPORTED_TAG_TYPE);
             }
@@ -672,10 +720,31 @@ pub fn generate_permit_list(
     }
 
     // alignment-level
-    let al_tags = libradicl::TagSection::from_bytes(&mut br);
+    let al_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
+    // the records are parsed assuming each alignment is just a reference id
+    if let Err(e) = libradicl::AlnLayout::from_tags(hdr.is_paired != 0, &al_tags)
+        .and_then(|layout| layout.require_plain("generate-permit-list"))
+    {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_UNSUPPORTED_LAYOUT);
+    }
 
-    let ft_vals = libradicl::FileTags::from_bytes(&mut br);
+    let ft_vals = match libradicl::FileTags::from_bytes_with_section(&mut br, &fl_tags) {
+        Ok(v) => v,
+        Err(e) => {
+            crit!(log, "could not read the file-level tag values : {}", e);
+            std::process::exit(exit_codes::EXIT_INVALID_FILE_TAGS);
+        }
+    };
+    if let Err(e) = ft_vals.require_uncompressed("generate-permit-list") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_UNSUPPORTED_COMPRESSION);
+    }
+    if let Err(e) = ft_vals.require_u64_barcodes("generate-permit-list") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_INVALID_TAG_WIDTH);
+    }
     info!(log, "File-level tag values {:?}", ft_vals);
 
     let mut num_reads: usize = 0;
@@ -684,6 +753,10 @@ pub fn generate_permit_list(
         .expect("unknown barcode type id.");
     let umi_type = libradicl::decode_int_type_tag(umit.expect("no umi tag description present"))
         .expect("unknown barcode type id.");
+    if let Err(e) = ft_vals.validate(&bc_type, &umi_type) {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_INVALID_TAG_WIDTH);
+    }
 
     // if dealing with filtered type
     let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
@@ -694,6 +767,9 @@ pub fn generate_permit_list(
     let mut unmatched_bc: Vec<u64>;
     let mut num_orientation_compat_reads = 0usize;
     let mut max_ambiguity_read = 0usize;
+    let mut hist_stats = libradicl::HistStats::default();
+    // holds the bytes of one chunk at a time
+    let mut chunk_buf = Vec::new();
 
     match filter_meth {
         CellFilterMethod::UnfilteredExternalList(_, _min_reads) => {
@@ -701,15 +777,18 @@ pub fn generate_permit_list(
             // the unfiltered_bc_count map must be valid in this branch
             if let Some(mut hmu) = unfiltered_bc_counts {
                 for _ in 0..(hdr.num_chunks as usize) {
-                    let c = libradicl::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
-                    num_orientation_compat_reads += libradicl::update_barcode_hist_unfiltered(
+                    libradicl::Chunk::read_raw(&mut br, &mut chunk_buf)?;
+                    let records = libradicl::RecordViews::new(&chunk_buf, &bc_type, &umi_type)?;
+                    num_reads += records.nrec as usize;
+                    let chunk_stats = libradicl::update_barcode_hist_unfiltered_views(
                         &mut hmu,
                         &mut unmatched_bc,
                         &mut max_ambiguity_read,
-                        &c,
+                        records,
                         &expected_ori,
-                    );
-                    num_reads += c.reads.len();
+                    )?;
+                    num_orientation_compat_reads += chunk_stats.num_strand_compat_reads;
+                    hist_stats.merge(&chunk_stats);
                 }
                 info!(
                     log,
@@ -719,6 +798,16 @@ pub fn generate_permit_list(
                     hdr.num_chunks.to_formatted_string(&Locale::en),
                     max_ambiguity_read.to_formatted_string(&Locale::en)
                 );
+                info!(
+                    log,
+                    "alignment orientations : {} forward, {} reverse",
+                    hist_stats
+                        .num_orientation_forward
+                        .to_formatted_string(&Locale::en),
+                    hist_stats
+                        .num_orientation_reverse
+                        .to_formatted_string(&Locale::en)
+                );
                 Ok(process_unfiltered(
                     hmu,
                     unmatched_bc,
@@ -737,10 +826,19 @@ pub fn generate_permit_list(
             }
         }
         _ => {
-            for _ in 0..(hdr.num_chunks as usize) {
-                let c = libradicl::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
-                libradicl::update_barcode_hist(&mut hm, &mut max_ambiguity_read, &c, &expected_ori);
-                num_reads += c.reads.len();
+            // the chunks are decoded on `num_threads` threads, and counted
+            // here in file order
+            let rr = libradicl::RadReader::new(BufReader::new(File::open(i_dir.join("map.rad"))?))?;
+            for chunk in rr.par_chunks(num_threads, DECODE_BATCH_CHUNKS) {
+                let chunk = chunk?;
+                num_reads += chunk.nrec as usize;
+                let chunk_stats = libradicl::update_barcode_hist(
+                    &mut hm,
+                    &mut max_ambiguity_read,
+                    &chunk,
+                    &expected_ori,
+                );
+                hist_stats.merge(&chunk_stats);
             }
             info!(
                 log,
@@ -749,6 +847,16 @@ pub fn generate_permit_list(
                 hdr.num_chunks.to_formatted_string(&Locale::en),
                 max_ambiguity_read.to_formatted_string(&Locale::en)
             );
+            info!(
+                log,
+                "alignment orientations : {} forward, {} reverse",
+                hist_stats
+                    .num_orientation_forward
+                    .to_formatted_string(&Locale::en),
+                hist_stats
+                    .num_orientation_reverse
+                    .to_formatted_string(&Locale::en)
+            );
             Ok(process_filtered(
                 &hm,
                 &ft_vals,
@@ -11,6 +11,7 @@ extern crate bincode;
 extern crate indicatif;
 extern crate slog;
 use crate as libradicl;
+use libradicl::exit_codes;
 
 use self::indicatif::{ProgressBar, ProgressStyle};
 use self::slog::{crit, info};
@@ -21,13 +22,13 @@ use crossbeam_queue::ArrayQueue;
 // use dashmap::DashMap;
 use self::libradicl::schema::TempCellInfo;
 use num_format::{Locale, ToFormattedString};
-use scroll::{Pread, Pwrite};
+use scroll::Pread;
 use serde_json::json;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -38,12 +39,96 @@ pub fn collate(
     num_threads: u32,
     max_records: u32,
     compress_out: bool,
+    min_score: Option<u32>,
     cmdline: &str,
     version_str: &str,
     //expected_ori: Strand,
     log: &slog::Logger,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let parent = std::path::Path::new(&input_dir);
+    let (tsv_map, total_to_collate) = read_permit_freqs(&input_dir, version_str)?;
+
+    /*
+    let est_num_rounds = (total_to_collate as f64 / max_records as f64).ceil() as u64;
+    info!(
+        log,
+        "estimated that collation would require {} passes over input.", est_num_rounds
+    );
+    // if est_num_rounds > 2 {
+    info!(log, "executing temporary file scatter-gather strategy.");
+    */
+
+    collate_with_temp(
+        input_dir,
+        rad_dir,
+        num_threads,
+        max_records,
+        tsv_map,
+        total_to_collate,
+        compress_out,
+        min_score,
+        cmdline,
+        log,
+    )
+
+    /*} else {
+        info!(log, "executing multi-pass strategy.");
+        collate_in_memory_multipass(
+            input_dir,
+            rad_dir,
+            num_threads,
+            max_records,
+            tsv_map,
+            total_to_collate,
+            log,
+        )
+    }*/
+}
+
+/// Like `collate`, but reads the RAD file to be collated from `input`, in
+/// a single pass and without seeking, rather than from the `map.rad` file
+/// in `rad_dir`.  This allows the output of the mapper to be piped straight
+/// into collation.  `rad_dir` must still hold the unmapped barcode counts.
+#[allow(clippy::too_many_arguments)]
+pub fn collate_from_reader<R: Read>(
+    input: R,
+    input_dir: String,
+    rad_dir: String,
+    num_threads: u32,
+    max_records: u32,
+    compress_out: bool,
+    min_score: Option<u32>,
+    cmdline: &str,
+    version_str: &str,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (tsv_map, total_to_collate) = read_permit_freqs(&input_dir, version_str)?;
+    collate_reader_with_temp(
+        input,
+        None,
+        input_dir,
+        rad_dir,
+        num_threads,
+        max_records,
+        tsv_map,
+        total_to_collate,
+        compress_out,
+        min_score,
+        cmdline,
+        log,
+    )
+}
+
+// a permitted barcode and its number of records
+type TsvRec = (u64, u64);
+
+// Check that the permit list in `input_dir` was made by a version of
+// alevin-fry compatible with `version_str`, and read the number of
+// records of each of its barcodes, largest first, and their total.
+fn read_permit_freqs(
+    input_dir: &str,
+    version_str: &str,
+) -> Result<(Vec<TsvRec>, u64), Box<dyn std::error::Error>> {
+    let parent = std::path::Path::new(input_dir);
 
     // open the metadata file and read the json
     let gpl_path = parent.join("generate_permit_list.json");
@@ -71,7 +156,6 @@ pub fn collate(
         return Err(es.into());
     }
 
-    type TsvRec = (u64, u64);
     let mut tsv_map = Vec::<TsvRec>::new(); //HashMap::<u64, u64>::new();
 
     let freq_file =
@@ -90,41 +174,7 @@ pub fn collate(
     // sort this so that we deal with largest cells (by # of reads) first
     // sort in _descending_ order by count.
     quickersort::sort_by_key(&mut tsv_map[..], |&a: &(u64, u64)| std::cmp::Reverse(a.1));
-
-    /*
-    let est_num_rounds = (total_to_collate as f64 / max_records as f64).ceil() as u64;
-    info!(
-        log,
-        "estimated that collation would require {} passes over input.", est_num_rounds
-    );
-    // if est_num_rounds > 2 {
-    info!(log, "executing temporary file scatter-gather strategy.");
-    */
-
-    collate_with_temp(
-        input_dir,
-        rad_dir,
-        num_threads,
-        max_records,
-        tsv_map,
-        total_to_collate,
-        compress_out,
-        cmdline,
-        log,
-    )
-
-    /*} else {
-        info!(log, "executing multi-pass strategy.");
-        collate_in_memory_multipass(
-            input_dir,
-            rad_dir,
-            num_threads,
-            max_records,
-            tsv_map,
-            total_to_collate,
-            log,
-        )
-    }*/
+    Ok((tsv_map, total_to_collate))
 }
 
 fn get_orientation(mdata: &serde_json::Value) -> Result<Strand, StrandError> {
@@ -163,19 +213,15 @@ fn get_filter_type(mdata: &serde_json::Value, log: &slog::Logger) -> FilterType
     }
 }
 
-fn get_most_ambiguous_record(mdata: &serde_json::Value, log: &slog::Logger) -> usize {
-    if let Some(mar) = mdata.get("max-ambig-record") {
-        match mar.as_u64() {
-            Some(mv) => mv as usize,
-            _ => 2500_usize,
-        }
-    } else {
+fn get_most_ambiguous_record(mdata: &serde_json::Value, log: &slog::Logger) -> Option<usize> {
+    let mar = mdata.get("max-ambig-record").and_then(|v| v.as_u64());
+    if mar.is_none() {
         info!(
             log,
-            "max-ambig-record key not present in JSON file; using default of 2,500. Please consider upgrading alevin-fry."
+            "max-ambig-record key not present in JSON file. Please consider upgrading alevin-fry."
         );
-        2500_usize
     }
+    mar.map(|mv| mv as usize)
 }
 
 fn correct_unmapped_counts(
@@ -225,6 +271,69 @@ pub fn collate_with_temp(
     tsv_map: Vec<(u64, u64)>,
     total_to_collate: u64,
     compress_out: bool,
+    min_score: Option<u32>,
+    cmdline: &str,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let i_dir = std::path::Path::new(&rad_dir);
+
+    if !i_dir.exists() {
+        crit!(log, "the input RAD path {} does not exist", rad_dir);
+        return Err("invalid input".into());
+    }
+
+    let input_rad_path = i_dir.join("map.rad");
+    let i_file = File::open(&input_rad_path).unwrap();
+    collate_reader_with_temp(
+        i_file,
+        Some(&input_rad_path),
+        input_dir,
+        rad_dir,
+        num_threads,
+        max_records,
+        tsv_map,
+        total_to_collate,
+        compress_out,
+        min_score,
+        cmdline,
+        log,
+    )
+}
+
+// Keeps a copy of the bytes read through it while `copy` is set, so that
+// the header of the input can be copied to the output without going back
+// to it.
+struct CopyingReader<R> {
+    inner: R,
+    copy: Option<Vec<u8>>,
+}
+
+impl<R: Read> Read for CopyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(c) = self.copy.as_mut() {
+            c.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+// The body of `collate_with_temp` and `collate_from_reader`, reading the
+// RAD file from `input` in a single pass.  `rad_path` is the path of the
+// input if it is a file, in which case it may be scanned separately for
+// the largest number of alignments of a record.
+#[allow(clippy::too_many_arguments)]
+fn collate_reader_with_temp<R: Read>(
+    input: R,
+    rad_path: Option<&std::path::Path>,
+    input_dir: String,
+    rad_dir: String,
+    num_threads: u32,
+    max_records: u32,
+    tsv_map: Vec<(u64, u64)>,
+    total_to_collate: u64,
+    compress_out: bool,
+    min_score: Option<u32>,
     cmdline: &str,
     log: &slog::Logger,
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -314,16 +423,17 @@ pub fn collate_with_temp(
         return Err("invalid input".into());
     }
 
-    let input_rad_path = i_dir.join("map.rad");
-    let i_file = File::open(&input_rad_path).unwrap();
-    let mut br = BufReader::new(i_file);
+    // everything up to the chunks is kept, to be copied to the output
+    let mut br = CopyingReader {
+        inner: BufReader::new(input),
+        copy: Some(Vec::new()),
+    };
 
-    let hdr = libradicl::RadHeader::from_bytes(&mut br);
+    let hdr = libradicl::RadHeader::from_bytes_checked(&mut br)?;
 
     // the exact position at the end of the header,
     // precisely sizeof(u64) bytes beyond the num_chunks field.
-    let end_header_pos =
-        br.get_ref().seek(SeekFrom::Current(0)).unwrap() - (br.buffer().len() as u64);
+    let end_header_pos = br.copy.as_ref().unwrap().len() as u64;
 
     info!(
         log,
@@ -335,23 +445,79 @@ pub fn collate_with_temp(
     );
 
     // file-level
-    let fl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let fl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} file-level tags", fl_tags.tags.len());
     // read-level
-    let rl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let rl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
     // alignment-level
-    let al_tags = libradicl::TagSection::from_bytes(&mut br);
+    let al_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
+    // alignments carrying more than a reference id (see `AlnLayout`) are
+    // passed through collation unchanged
+    let aln_layout = match libradicl::AlnLayout::from_tags(hdr.is_paired != 0, &al_tags) {
+        Ok(layout) => layout,
+        Err(e) => {
+            crit!(log, "{}", e);
+            return Err("unsupported input".into());
+        }
+    };
+    let aln_bytes = aln_layout.aln_bytes();
+    if min_score.is_some() && !aln_layout.score {
+        crit!(
+            log,
+            "a minimum score was given, but the alignments carry no {} tag",
+            libradicl::SCORE_TAG
+        );
+        return Err("unsupported input".into());
+    }
 
-    let ft_vals = libradicl::FileTags::from_bytes(&mut br);
+    let ft_vals = match libradicl::FileTags::from_bytes_with_section(&mut br, &fl_tags) {
+        Ok(v) => v,
+        Err(e) => {
+            crit!(log, "could not read the file-level tag values : {}", e);
+            std::process::exit(exit_codes::EXIT_INVALID_FILE_TAGS);
+        }
+    };
+    if let Err(e) = ft_vals.require_uncompressed("collate") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_UNSUPPORTED_COMPRESSION);
+    }
+    if let Err(e) = ft_vals.require_u64_barcodes("collate") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_INVALID_TAG_WIDTH);
+    }
     info!(log, "File-level tag values {:?}", ft_vals);
 
     let bct = rl_tags.tags[0].typeid;
     let umit = rl_tags.tags[1].typeid;
 
-    // the exact position at the end of the header + file tags
-    let pos = br.get_ref().seek(SeekFrom::Current(0)).unwrap() - (br.buffer().len() as u64);
+    // the header + file tags, up to the first chunk
+    let hdr_bytes = br.copy.take().unwrap();
+    let pos = hdr_bytes.len() as u64;
+
+    // prefer the value recorded in the file, then the one found while
+    // generating the permit list, and only scan the chunks if neither is
+    // present (and the input is a file of a known number of chunks, whose
+    // records can be read as plain reference ids)
+    let most_ambig_record = match (ft_vals.max_ambiguity, most_ambig_record, rad_path) {
+        (Some(m), _, _) => m as usize,
+        (None, Some(mar), _) => mar,
+        (None, None, Some(path))
+            if aln_bytes == std::mem::size_of::<u32>() && hdr.num_chunks > 0 =>
+        {
+            let bc_type = libradicl::decode_int_type_tag(bct).expect("unknown barcode type id.");
+            let umi_type = libradicl::decode_int_type_tag(umit).expect("unknown umi type id.");
+            let mut rfile = BufReader::new(File::open(path)?);
+            rfile.seek(SeekFrom::Start(pos))?;
+            libradicl::max_ambiguity_or_scan(&ft_vals, &mut rfile, &hdr, &bc_type, &umi_type)?
+                as usize
+        }
+        _ => {
+            info!(log, "using a default max-ambig-record of 2,500.");
+            2500_usize
+        }
+    };
 
     // copy the header
     {
@@ -361,14 +527,8 @@ pub fn collate_with_temp(
         let chunk_bytes = std::mem::size_of::<u64>() as u64;
         let take_pos = end_header_pos - chunk_bytes;
 
-        // This temporary file pointer and buffer will be dropped
-        // at the end of this block (scope).
-        let mut rfile = File::open(&input_rad_path).unwrap();
-        let mut hdr_buf = Cursor::new(vec![0u8; pos as usize]);
-
-        rfile
-            .read_exact(&mut hdr_buf.get_mut())
-            .expect("couldn't read input file header");
+        // This buffer will be dropped at the end of this block (scope).
+        let mut hdr_buf = Cursor::new(hdr_bytes);
         hdr_buf.set_position(take_pos);
         hdr_buf
             .write_all(&expected_output_chunks.to_le_bytes())
@@ -421,10 +581,19 @@ pub fn collate_with_temp(
     // max_records is the max size of each intermediate file
     let mut total_allocated_records = 0;
     let mut allocated_records = 0;
+    let mut collate_params = libradicl::CollateParams {
+        aln_layout,
+        min_score,
+        ..libradicl::CollateParams::default()
+    };
     let mut temp_buckets = vec![(
         0,
         0,
-        Arc::new(libradicl::TempBucket::from_id_and_parent(0, parent)),
+        Arc::new(libradicl::TempBucket::from_id_and_parent(
+            0,
+            parent,
+            &collate_params,
+        )),
     )];
 
     let max_records_per_thread = (max_records / n_workers as u32) + 1;
@@ -447,7 +616,11 @@ pub fn collate_with_temp(
                 temp_buckets.push((
                     0,
                     0,
-                    Arc::new(libradicl::TempBucket::from_id_and_parent(tn, parent)),
+                    Arc::new(libradicl::TempBucket::from_id_and_parent(
+                        tn,
+                        parent,
+                        &collate_params,
+                    )),
                 ));
                 total_allocated_records += allocated_records;
                 allocated_records = 0;
@@ -475,8 +648,8 @@ pub fn collate_with_temp(
     // create a thread-safe queue based on the number of worker threads
     let q = Arc::new(ArrayQueue::<(usize, Vec<u8>)>::new(4 * n_workers));
 
-    // the number of cells left to process
-    let chunks_to_process = Arc::new(AtomicUsize::new(cc.num_chunks as usize));
+    // set once every chunk has been put on the queue
+    let reading_done = Arc::new(AtomicBool::new(false));
 
     let mut thread_handles: Vec<thread::JoinHandle<u64>> = Vec::with_capacity(n_workers);
 
@@ -484,9 +657,10 @@ pub fn collate_with_temp(
     let max_rec = max_records as usize;
     let num_buckets = temp_buckets.len();
     let num_threads = n_workers as usize;
-    let loc_buffer_size = (min_rec_len + (most_ambig_record * 4_usize) - 4_usize).max(
+    let loc_buffer_size = (min_rec_len + (most_ambig_record * aln_bytes) - aln_bytes).max(
         (1000_usize.max((min_rec_len * max_rec) / (num_buckets * num_threads))).min(262_144_usize),
     ); //131072_usize);
+    collate_params.flush_limit = loc_buffer_size;
 
     // for each worker, spawn off a thread
     for _worker in 0..n_workers {
@@ -495,14 +669,16 @@ pub fn collate_with_temp(
         // the output cache and correction map
         let oc = output_cache.clone();
         let correct_map = correct_map.clone();
-        // the number of chunks remaining to be processed
-        let chunks_remaining = chunks_to_process.clone();
+        // whether all chunks have been queued
+        let reading_done = reading_done.clone();
         // and knowledge of the UMI and BC types
         let bc_type = libradicl::decode_int_type_tag(cc.bc_type).expect("unknown barcode type id.");
         let umi_type =
             libradicl::decode_int_type_tag(cc.umi_type).expect("unknown barcode type id.");
         let nbuckets = temp_buckets.len();
         let loc_temp_buckets = temp_buckets.clone();
+        // and the logger
+        let log = log.clone();
         //let owrite = owriter.clone();
         // now, make the worker thread
         let handle = std::thread::spawn(move || {
@@ -535,11 +711,10 @@ pub fn collate_with_temp(
 
             // pop from the work queue until everything is
             // processed
-            while chunks_remaining.load(Ordering::SeqCst) > 0 {
+            while !(reading_done.load(Ordering::SeqCst) && in_q.is_empty()) {
                 if let Some((_chunk_num, buf)) = in_q.pop() {
-                    chunks_remaining.fetch_sub(1, Ordering::SeqCst);
                     let mut nbr = BufReader::new(&buf[..]);
-                    libradicl::dump_corrected_cb_chunk_to_temp_file(
+                    if let Err(e) = libradicl::dump_corrected_cb_chunk_to_temp_file(
                         &mut nbr,
                         &bc_type,
                         &umi_type,
@@ -547,8 +722,12 @@ pub fn collate_with_temp(
                         &expected_ori,
                         &oc,
                         &mut local_buffers,
-                        loc_buffer_size,
-                    );
+                        &collate_params,
+                        None,
+                    ) {
+                        crit!(log, "could not read input chunk : {}", e);
+                        std::process::exit(exit_codes::EXIT_MALFORMED_INPUT);
+                    }
                 }
             }
 
@@ -575,14 +754,28 @@ pub fn collate_with_temp(
     ));
 
     // read chunks from the input file and pass them to the
-    // worker threads.
-    let mut buf = vec![0u8; 65536];
-    for cell_num in 0..(cc.num_chunks as usize) {
-        let (nbytes_chunk, nrec_chunk) = libradicl::Chunk::read_header(&mut br);
-        buf.resize(nbytes_chunk as usize, 0);
-        buf.pwrite::<u32>(nbytes_chunk, 0)?;
-        buf.pwrite::<u32>(nrec_chunk, 4)?;
-        br.read_exact(&mut buf[8..]).unwrap();
+    // worker threads.  If the number of chunks wasn't recorded (e.g. the
+    // input is piped from the mapper), they are read to the end of the input.
+    let mut buf = Vec::with_capacity(65536);
+    let mut stream = if cc.num_chunks == 0 {
+        Some(libradicl::StreamChunks::default())
+    } else {
+        None
+    };
+    for cell_num in 0.. {
+        match stream.as_mut() {
+            Some(s) => {
+                if !s.read_raw(&mut br, &mut buf)? {
+                    break;
+                }
+            }
+            None => {
+                if cell_num as u64 == cc.num_chunks {
+                    break;
+                }
+                libradicl::Chunk::read_raw(&mut br, &mut buf)?;
+            }
+        }
 
         let mut bclone = (cell_num, buf.clone());
         // keep trying until we can push this payload
@@ -593,6 +786,7 @@ pub fn collate_with_temp(
         }
         pbar_inner.inc(1);
     }
+    reading_done.store(true, Ordering::SeqCst);
     pbar_inner.finish();
 
     // wait for the worker threads to finish
@@ -698,6 +892,9 @@ pub fn collate_with_temp(
                         &owriter,
                         compress_out,
                         &mut cmap,
+                        None,
+                        &aln_layout,
+                        None,
                     ) as u64;
 
                     // we don't need the file or reader anymore
@@ -766,11 +963,10 @@ pub fn collate_with_temp(
     );
 
     owriter.lock().unwrap().flush()?;
-    info!(
-        log,
-        "finished collating input rad file {:?}.",
-        i_dir.join("map.rad")
-    );
+    match rad_path {
+        Some(path) => info!(log, "finished collating input rad file {:?}.", path),
+        None => info!(log, "finished collating the input rad stream."),
+    }
     Ok(())
 }
 
@@ -785,6 +981,7 @@ pub fn collate_in_memory_multipass(
     log: &slog::Logger,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let parent = std::path::Path::new(&input_dir);
+    let collate_params = libradicl::CollateParams::default();
 
     // open the metadata file and read the json
     let meta_data_file = File::open(parent.join("generate_permit_list.json"))
@@ -830,7 +1027,7 @@ pub fn collate_in_memory_multipass(
     let i_file = File::open(i_dir.join("map.rad")).unwrap();
     let mut br = BufReader::new(i_file);
 
-    let hdr = libradicl::RadHeader::from_bytes(&mut br);
+    let hdr = libradicl::RadHeader::from_bytes_checked(&mut br)?;
 
     let end_header_pos =
         br.get_ref().seek(SeekFrom::Current(0)).unwrap() - (br.buffer().len() as u64);
@@ -844,16 +1041,42 @@ pub fn collate_in_memory_multipass(
         expected_ori
     );
     // file-level
-    let fl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let fl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} file-level tags", fl_tags.tags.len());
     // read-level
-    let rl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let rl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
     // alignment-level
-    let al_tags = libradicl::TagSection::from_bytes(&mut br);
+    let al_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
+    // this path assumes each alignment is a single reference id (see
+    // `AlnLayout`), so other layouts must be collated via temporary buckets
+    let aln_layout = match libradicl::AlnLayout::from_tags(hdr.is_paired != 0, &al_tags) {
+        Ok(layout) => layout,
+        Err(e) => {
+            crit!(log, "{}", e);
+            return Err("unsupported input".into());
+        }
+    };
+    if aln_layout.aln_bytes() != std::mem::size_of::<u32>() {
+        crit!(
+            log,
+            "collating in memory records with alignment fields beyond the reference id is not supported"
+        );
+        return Err("unsupported input".into());
+    }
 
-    let ft_vals = libradicl::FileTags::from_bytes(&mut br);
+    let ft_vals = match libradicl::FileTags::from_bytes_with_section(&mut br, &fl_tags) {
+        Ok(v) => v,
+        Err(e) => {
+            crit!(log, "could not read the file-level tag values : {}", e);
+            std::process::exit(exit_codes::EXIT_INVALID_FILE_TAGS);
+        }
+    };
+    if let Err(e) = ft_vals.require_uncompressed("collate") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_UNSUPPORTED_COMPRESSION);
+    }
     info!(log, "File-level tag values {:?}", ft_vals);
 
     let bct = rl_tags.tags[0].typeid;
@@ -938,7 +1161,11 @@ pub fn collate_in_memory_multipass(
         for (i, rec) in tsv_map[init_offset..].iter().enumerate() {
             output_cache.insert(
                 rec.0,
-                libradicl::CorrectedCbChunk::from_label_and_counter(rec.0, rec.1 as u32),
+                libradicl::CorrectedCbChunk::from_label_and_counter(
+                    rec.0,
+                    rec.1 as u32,
+                    &collate_params,
+                ),
             );
             allocated_records += rec.1;
             last_idx = i + 1;
@@ -967,6 +1194,8 @@ pub fn collate_in_memory_multipass(
             let umi_type =
                 libradicl::decode_int_type_tag(cc.umi_type).expect("unknown barcode type id.");
             let owrite = owriter.clone();
+            // and the logger
+            let log = log.clone();
             // now, make the worker thread
             let handle = std::thread::spawn(move || {
                 // pop from the work queue until everything is
@@ -975,15 +1204,19 @@ pub fn collate_in_memory_multipass(
                     if let Some((_chunk_num, buf)) = in_q.pop() {
                         chunks_remaining.fetch_sub(1, Ordering::SeqCst);
                         let mut nbr = BufReader::new(&buf[..]);
-                        libradicl::process_corrected_cb_chunk(
+                        if let Err(e) = libradicl::process_corrected_cb_chunk(
                             &mut nbr,
                             &bc_type,
                             &umi_type,
                             &correct_map,
+                            &aln_layout,
                             &expected_ori,
                             &oc,
                             &owrite,
-                        );
+                        ) {
+                            crit!(log, "could not read input chunk : {}", e);
+                            std::process::exit(exit_codes::EXIT_MALFORMED_INPUT);
+                        }
                     }
                 }
             });
@@ -999,11 +1232,11 @@ pub fn collate_in_memory_multipass(
         ));
         let mut buf = vec![0u8; 65536];
         for cell_num in 0..(cc.num_chunks as usize) {
-            let (nbytes_chunk, nrec_chunk) = libradicl::Chunk::read_header(&mut br);
+            let (nbytes_chunk, nrec_chunk) = libradicl::Chunk::read_header_checked(&mut br)?;
             buf.resize(nbytes_chunk as usize, 0);
             buf.pwrite::<u32>(nbytes_chunk, 0)?;
             buf.pwrite::<u32>(nrec_chunk, 4)?;
-            br.read_exact(&mut buf[8..]).unwrap();
+            br.read_exact(&mut buf[8..])?;
             loop {
                 if !q.is_full() {
                     let r = q.push((cell_num, buf.clone()));
@@ -1104,6 +1337,8 @@ let handle = std::thread::spawn(move || {
                 temp_bucket.0,
                 temp_bucket.1,
                 &mut cmap,
+                None,
+                None,
             );
 
             // we don't need the file or reader anymore
@@ -1123,3 +1358,265 @@ let handle = std::thread::spawn(move || {
     local_chunks
 });
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cellfilter::{generate_permit_list, CellFilterMethod};
+    use crate::{FileTags, RadHeader, RadIntId, RadReader, RadWriter, ReadRecord};
+
+    type Cell = (u64, Vec<(u64, Vec<u32>)>);
+
+    // the cells of the collated file in `dir`, and their records, in order
+    fn collated_cells(dir: &std::path::Path) -> Vec<Cell> {
+        let f = BufReader::new(File::open(dir.join("map.collated.rad")).unwrap());
+        let mut cells: Vec<Cell> = RadReader::new(f)
+            .unwrap()
+            .map(|c| {
+                let c = c.unwrap();
+                let mut recs: Vec<_> = c.reads.iter().map(|r| (r.umi, r.refs.clone())).collect();
+                recs.sort();
+                (c.reads[0].bc, recs)
+            })
+            .collect();
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn test_collate_from_reader_matches_collate() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dir = std::env::temp_dir().join(format!("collate_stream_{}", std::process::id()));
+        let rad_dir = dir.join("rad");
+        let out_dir = dir.join("out");
+        std::fs::create_dir_all(&rad_dir).unwrap();
+        File::create(rad_dir.join("unmapped_bc_count.bin")).unwrap();
+        let rad_dir_str = rad_dir.to_str().unwrap().to_string();
+        let out_dir_str = out_dir.to_str().unwrap().to_string();
+
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        // 6 chunks of 5 records, the last of which has a barcode that is
+        // corrected to the first of the 3 others
+        let bcs = [0x0u64, 0xf0f0, 0x0f0f_0f00, 0x1];
+        let write = |nchunks: u64| {
+            let hdr = RadHeader {
+                is_paired: 0,
+                ref_count: 3,
+                ref_names: (0..3).map(|i| format!("t{}", i)).collect(),
+                ref_lengths: vec![],
+                ref_digest: None,
+                num_chunks: nchunks,
+            };
+            let mut rw = RadWriter::new(Vec::new());
+            rw.enable_chunk_index().unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            for i in 0..nchunks {
+                rw.begin_chunk().unwrap();
+                for j in 0..5u64 {
+                    rw.push_record(&ReadRecord {
+                        bc: if j == 4 {
+                            bcs[3]
+                        } else {
+                            bcs[((i + j) % 3) as usize]
+                        },
+                        umi: i * 5 + j,
+                        dirs: vec![true; (j % 2 + 1) as usize],
+                        mate_dirs: Vec::new(),
+                        refs: (0..(j % 2 + 1) as u32)
+                            .map(|r| (r + j as u32) % 3)
+                            .collect(),
+                        tags: None,
+                    })
+                    .unwrap();
+                }
+                rw.finish_chunk().unwrap();
+            }
+            rw.finalize().unwrap()
+        };
+        let rad = write(6);
+        std::fs::write(rad_dir.join("map.rad"), &rad).unwrap();
+
+        let version = env!("CARGO_PKG_VERSION");
+        generate_permit_list(
+            rad_dir_str.clone(),
+            out_dir_str.clone(),
+            CellFilterMethod::ForceTopN(3),
+            Strand::Forward,
+            version,
+            false,
+            "",
+            2,
+            &log,
+        )
+        .unwrap();
+
+        collate(
+            out_dir_str.clone(),
+            rad_dir_str.clone(),
+            2,
+            1000,
+            false,
+            None,
+            "",
+            version,
+            &log,
+        )
+        .unwrap();
+        let expected = collated_cells(&out_dir);
+        assert_eq!(expected.len(), 3);
+        assert_eq!(expected.iter().map(|c| c.1.len()).sum::<usize>(), 30);
+
+        // the same file, piped before its number of chunks was known, and
+        // with the chunk index after the chunks
+        let mut stream = write(0);
+        let header_len = stream.len() - 24;
+        stream.truncate(header_len);
+        stream.extend_from_slice(&rad[header_len..]);
+        collate_from_reader(
+            Cursor::new(stream),
+            out_dir_str,
+            rad_dir_str,
+            3,
+            7,
+            false,
+            None,
+            "",
+            version,
+            &log,
+        )
+        .unwrap();
+        assert_eq!(collated_cells(&out_dir), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_paired_collate_and_quant_match_single_end() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dir = std::env::temp_dir().join(format!("collate_paired_{}", std::process::id()));
+        let rad_dir = dir.join("rad");
+        std::fs::create_dir_all(&rad_dir).unwrap();
+        File::create(rad_dir.join("unmapped_bc_count.bin")).unwrap();
+        let tg_map = dir.join("t2g.tsv");
+        std::fs::write(&tg_map, "t0\tg0\nt1\tg1\nt2\tg1\n").unwrap();
+        let rad_dir_str = rad_dir.to_str().unwrap().to_string();
+        let version = env!("CARGO_PKG_VERSION");
+
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        // 6 chunks of 4 records over 3 barcodes, each with a single forward
+        // alignment, whose mate is forward for every other record of a
+        // paired-end file
+        let bcs = [0x0u64, 0xf0f0, 0x0f0f_0f00];
+        let write = |paired: bool| {
+            let hdr = RadHeader {
+                is_paired: paired as u8,
+                ref_count: 3,
+                ref_names: (0..3).map(|i| format!("t{}", i)).collect(),
+                ref_lengths: vec![],
+                ref_digest: None,
+                num_chunks: 6,
+            };
+            let mut rw = RadWriter::new(Vec::new());
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            for i in 0..6u64 {
+                rw.begin_chunk().unwrap();
+                for j in 0..4u64 {
+                    rw.push_record(&ReadRecord {
+                        bc: bcs[((i + j) % 3) as usize],
+                        umi: (i * 4 + j) * 0x111,
+                        dirs: vec![true],
+                        mate_dirs: if paired { vec![j % 2 == 0] } else { Vec::new() },
+                        refs: vec![((i + j) % 3) as u32],
+                        tags: None,
+                    })
+                    .unwrap();
+                }
+                rw.finish_chunk().unwrap();
+            }
+            rw.finalize().unwrap()
+        };
+
+        // the cells of the collated file in `out_dir`, and the quantified
+        // counts of each, for the file written as given by `paired`; the
+        // permit list is generated from the single-end file, as
+        // generate-permit-list doesn't read paired-end files
+        let run = |paired: bool| {
+            let out_dir = dir.join(format!("out_{}", paired));
+            let out_dir_str = out_dir.to_str().unwrap().to_string();
+            std::fs::write(rad_dir.join("map.rad"), write(false)).unwrap();
+            generate_permit_list(
+                rad_dir_str.clone(),
+                out_dir_str.clone(),
+                CellFilterMethod::ForceTopN(3),
+                Strand::Forward,
+                version,
+                false,
+                "",
+                1,
+                &log,
+            )
+            .unwrap();
+            std::fs::write(rad_dir.join("map.rad"), write(paired)).unwrap();
+            collate(
+                out_dir_str.clone(),
+                rad_dir_str.clone(),
+                2,
+                1000,
+                false,
+                None,
+                "",
+                version,
+                &log,
+            )
+            .unwrap();
+            let cells = collated_cells(&out_dir);
+
+            let quant_dir = out_dir.join("quant");
+            crate::quant::quantify(
+                out_dir_str,
+                tg_map.to_str().unwrap().to_string(),
+                quant_dir.to_str().unwrap().to_string(),
+                1,
+                0,
+                false,
+                false,
+                false,
+                true,
+                false,
+                crate::schema::ResolutionStrategy::Trivial,
+                crate::schema::SplicedAmbiguityModel::WinnerTakeAll,
+                10,
+                crate::em::EmParams::default(),
+                1,
+                None,
+                "",
+                &log,
+            )
+            .unwrap();
+            let mtx =
+                std::fs::read_to_string(quant_dir.join("alevin").join("quants_mat.mtx")).unwrap();
+            (cells, mtx)
+        };
+
+        let single = run(false);
+        assert_eq!(single.0.len(), 3);
+        assert_eq!(single.0.iter().map(|c| c.1.len()).sum::<usize>(), 24);
+        assert_eq!(run(true), single);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
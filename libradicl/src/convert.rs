@@ -20,6 +20,7 @@ use std::fs::File;
 use std::io::{stdout, BufReader, BufWriter, Cursor, Seek, SeekFrom, Write};
 // use std::sync::{Arc, Mutex};
 use self::libradicl::utils::MASK_LOWER_31_U32;
+use bio_types::strand::Strand;
 use needletail::bitkmer::*;
 use rand::Rng;
 use rust_htslib::bam::HeaderView;
@@ -98,6 +99,244 @@ pub fn tid_2_contig(h: &HeaderView) -> HashMap<u32, String> {
     dict
 }
 
+/// The smallest integer type able to hold a 2-bit packed sequence of `len` bases.
+fn int_type_for_len(len: u16) -> Option<libradicl::RadIntId> {
+    match len {
+        1..=4 => Some(libradicl::RadIntId::U8),
+        5..=8 => Some(libradicl::RadIntId::U16),
+        9..=16 => Some(libradicl::RadIntId::U32),
+        17..=32 => Some(libradicl::RadIntId::U64),
+        _ => None,
+    }
+}
+
+/// Counts of the BAM records seen by `bam_with_tags_to_rad`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TagConversionStats {
+    pub num_records_written: u64,
+    pub num_unaligned_or_secondary: u64,
+    pub num_strand_incompatible: u64,
+    pub num_missing_tags: u64,
+    pub num_invalid_tags: u64,
+    pub num_chunks: u64,
+}
+
+/// Convert the (e.g. position-sorted) BAM file `bam_path`, whose records carry
+/// their cell barcode and UMI in the aux tags `cb_tag` and `ub_tag`, into the
+/// RAD file `out`.  Each primary alignment compatible with `expected_ori` yields
+/// a RAD record with a single alignment to its target.  Barcodes and UMIs must
+/// be `bclen` and `umilen` bases long, respectively; any suffix following a `-`
+/// (as in Cell Ranger's `CB` tag) is ignored.  Records missing either tag, or
+/// whose tags are of the wrong length or contain an `N`, are counted and skipped.
+#[allow(clippy::too_many_arguments)]
+pub fn bam_with_tags_to_rad(
+    bam_path: &Path,
+    cb_tag: &[u8; 2],
+    ub_tag: &[u8; 2],
+    bclen: u16,
+    umilen: u16,
+    out: &Path,
+    expected_ori: Strand,
+    log: &slog::Logger,
+) -> Result<TagConversionStats, Box<dyn Error>> {
+    let bct = int_type_for_len(bclen)
+        .ok_or_else(|| format!("cannot encode barcode of length {}", bclen))?;
+    let umit = int_type_for_len(umilen)
+        .ok_or_else(|| format!("cannot encode umi of length {}", umilen))?;
+
+    let mut bam = bam::Reader::from_path(bam_path)?;
+    let hdrv = bam.header().to_owned();
+    let hdr = libradicl::RadHeader::from_bam_header(&hdrv);
+    info!(log, "ref count: {:?} ", hdr.ref_count);
+
+    let mut rw = libradicl::RadWriter::new(BufWriter::with_capacity(1048576, File::create(out)?));
+    // every record carries exactly one alignment
+    let file_tags = libradicl::FileTags {
+        bclen,
+        umilen,
+        max_ambiguity: Some(1),
+        variable_umi: false,
+        zstd_chunks: false,
+        extra: Vec::new(),
+    };
+    rw.write_header(&hdr, &file_tags, bct, umit)?;
+
+    // the number of records placed in each chunk
+    let buf_limit = 10000u32;
+    let mut stats = TagConversionStats::default();
+    let mut nrec_in_chunk = 0u32;
+    let mut rec = bam::Record::new();
+    let mut rr = libradicl::ReadRecord::default();
+
+    // pack the value of `tag`, keeping only the part before any '-'
+    let packed_tag = |rec: &bam::Record, tag: &[u8; 2], len: u16| -> Result<u64, bool> {
+        // Err(true) for a missing tag, Err(false) for an invalid one
+        let v = rec.aux(tag).ok_or(true)?.string();
+        let v = v.split(|&c| c == b'-').next().unwrap_or(&[]);
+        if v.len() != len as usize {
+            return Err(false);
+        }
+        match libradicl::utils::pack_kmer_with_ns(v) {
+            Some((k, 0)) => Ok(k),
+            _ => Err(false),
+        }
+    };
+
+    while let Some(r) = bam.read(&mut rec) {
+        r?;
+        if rec.is_unmapped() || rec.is_secondary() || rec.is_supplementary() || rec.tid() < 0 {
+            stats.num_unaligned_or_secondary += 1;
+            continue;
+        }
+        let strand = if rec.is_reverse() {
+            Strand::Reverse
+        } else {
+            Strand::Forward
+        };
+        if !(expected_ori.is_unknown() || expected_ori.same(&strand)) {
+            stats.num_strand_incompatible += 1;
+            continue;
+        }
+
+        let (bc, umi) = match (
+            packed_tag(&rec, cb_tag, bclen),
+            packed_tag(&rec, ub_tag, umilen),
+        ) {
+            (Ok(bc), Ok(umi)) => (bc, umi),
+            (Err(true), _) | (_, Err(true)) => {
+                stats.num_missing_tags += 1;
+                continue;
+            }
+            _ => {
+                stats.num_invalid_tags += 1;
+                continue;
+            }
+        };
+
+        if nrec_in_chunk == 0 {
+            rw.begin_chunk()?;
+        }
+        rr.bc = bc;
+        rr.umi = umi;
+        rr.refs.clear();
+        rr.refs.push(rec.tid() as u32);
+        rr.dirs.clear();
+        rr.dirs.push(strand == Strand::Forward);
+        rw.push_record(&rr)?;
+        stats.num_records_written += 1;
+        nrec_in_chunk += 1;
+
+        if nrec_in_chunk >= buf_limit {
+            rw.finish_chunk()?;
+            nrec_in_chunk = 0;
+        }
+    }
+    if nrec_in_chunk > 0 {
+        rw.finish_chunk()?;
+    }
+
+    stats.num_chunks = rw.chunks_written();
+    rw.finalize_with_chunk_count()?;
+    info!(
+        log,
+        "wrote {} records in {} chunks; skipped {} records missing the {}/{} tags and {} with invalid tags",
+        stats.num_records_written,
+        stats.num_chunks,
+        stats.num_missing_tags,
+        str::from_utf8(cb_tag).unwrap_or("??"),
+        str::from_utf8(ub_tag).unwrap_or("??"),
+        stats.num_invalid_tags
+    );
+    Ok(stats)
+}
+
+/// Convert the RAD file `rad_path` back into the BAM file `out`, e.g. to
+/// inspect in a genome browser which alignments a RAD record represents.
+/// Each alignment of a RAD record becomes a BAM record named after the
+/// index of the RAD record, with the target and orientation of the
+/// alignment, the decoded barcode and UMI in the `CB` and `UB` tags, and
+/// `NH`/`HI` tags giving the number of alignments and the (1-based) index
+/// of this one (alignments after the first are flagged as secondary).  RAD
+/// records carry no sequences, so none is set.  The position of each
+/// alignment is set if the file has the `POS_TAG` alignment field (see
+/// `AlnLayout`), and otherwise the BAM records are flagged as unmapped,
+/// keeping the target of the alignment.  Reference lengths
+/// are taken from `ref_lengths` if given, and otherwise from the header of
+/// the RAD file if it records them (as versioned headers may), and are
+/// written as 1 if neither does.  Returns the number of BAM records
+/// written.
+pub fn rad_to_bam(
+    rad_path: &Path,
+    out: &Path,
+    ref_lengths: Option<&[u64]>,
+    log: &slog::Logger,
+) -> Result<u64, Box<dyn Error>> {
+    let mut rr = libradicl::RadReader::new(BufReader::new(File::open(rad_path)?))?;
+    let (bclen, umilen) = (rr.file_tag_vals.bclen, rr.file_tag_vals.umilen);
+    let layout = rr.aln_layout()?;
+
+    let mut header = bam::Header::new();
+    for (i, name) in rr.header.ref_names.iter().enumerate() {
+        let len = ref_lengths
+            .unwrap_or(&rr.header.ref_lengths)
+            .get(i)
+            .copied()
+            .unwrap_or(1);
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", name)
+                .push_tag(b"LN", &len),
+        );
+    }
+    let mut bam = bam::Writer::from_path(out, &header, bam::Format::Bam)?;
+
+    let mut num_written = 0u64;
+    let mut id = 0u64;
+    while let Some(c) = rr.next_ext() {
+        for read in c?.reads {
+            let qname = id.to_string();
+            let bc = bitmer_to_bytes((read.bc, bclen as u8));
+            let umi = bitmer_to_bytes((read.umi, umilen as u8));
+            let nh = read.refs.len();
+            for (i, &tid) in read.refs.iter().enumerate() {
+                // a fresh record each time, as `set` keeps the aux tags
+                let mut rec = bam::Record::new();
+                rec.set(qname.as_bytes(), None, &[], &[]);
+                rec.set_tid(tid as i32);
+                rec.set_mtid(-1);
+                rec.set_mpos(-1);
+                rec.set_mapq(255);
+                let mut flags = 0u16;
+                if layout.pos {
+                    rec.set_pos(read.positions[i] as i64);
+                } else {
+                    rec.set_pos(-1);
+                    flags |= 0x4;
+                }
+                if !read.dirs.get(i).copied().unwrap_or(true) {
+                    flags |= 0x10;
+                }
+                if i > 0 {
+                    flags |= 0x100;
+                }
+                rec.set_flags(flags);
+                rec.push_aux(b"CB", &bam::record::Aux::String(&bc));
+                rec.push_aux(b"UB", &bam::record::Aux::String(&umi));
+                rec.push_aux(b"NH", &bam::record::Aux::Integer(nh as i64));
+                rec.push_aux(b"HI", &bam::record::Aux::Integer(i as i64 + 1));
+                bam.write(&rec)?;
+                num_written += 1;
+            }
+            id += 1;
+        }
+    }
+    info!(
+        log,
+        "wrote {} BAM records for {} RAD records", num_written, id
+    );
+    Ok(num_written)
+}
+
 pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slog::Logger) {
     let oname = Path::new(&rad_file);
     let parent = oname.parent().unwrap();
@@ -196,12 +435,14 @@ pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slo
         let mut umi_tag_str = "ulen";
 
         // str - type
-        libradicl::write_str_bin(&cb_tag_str, &libradicl::RadIntId::U16, &mut data);
+        libradicl::write_str_bin(&cb_tag_str, &libradicl::RadIntId::U16, &mut data)
+            .expect("coudn't write to output file");
         data.write_all(&typeid.to_le_bytes())
             .expect("coudn't write to output file");
 
         // str - type
-        libradicl::write_str_bin(&umi_tag_str, &libradicl::RadIntId::U16, &mut data);
+        libradicl::write_str_bin(&umi_tag_str, &libradicl::RadIntId::U16, &mut data)
+            .expect("coudn't write to output file");
         data.write_all(&typeid.to_le_bytes())
             .expect("coudn't write to output file");
 
@@ -241,11 +482,13 @@ pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slo
 
         //info!(log, "CB LEN : {}, UMI LEN : {}", bclen, umilen);
 
-        libradicl::write_str_bin(&cb_tag_str, &libradicl::RadIntId::U16, &mut data);
+        libradicl::write_str_bin(&cb_tag_str, &libradicl::RadIntId::U16, &mut data)
+            .expect("coudn't write to output file");
         data.write_all(&bc_typeid.to_le_bytes())
             .expect("coudn't write to output file");
 
-        libradicl::write_str_bin(&umi_tag_str, &libradicl::RadIntId::U16, &mut data);
+        libradicl::write_str_bin(&umi_tag_str, &libradicl::RadIntId::U16, &mut data)
+            .expect("coudn't write to output file");
         data.write_all(&umi_typeid.to_le_bytes())
             .expect("coudn't write to output file");
 
@@ -257,7 +500,8 @@ pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slo
         // reference id
         let refid_str = "compressed_ori_refid";
         typeid = 3u8;
-        libradicl::write_str_bin(&refid_str, &libradicl::RadIntId::U16, &mut data);
+        libradicl::write_str_bin(&refid_str, &libradicl::RadIntId::U16, &mut data)
+            .expect("coudn't write to output file");
         data.write_all(&typeid.to_le_bytes())
             .expect("coudn't write to output file");
 
@@ -474,6 +718,124 @@ pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slo
     info!(log, "finished writing to {:?}.", rad_file);
 }
 
+/// The textual formats in which `dump` can write a RAD file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    /// `#`-prefixed header lines, then one tab-separated line per record.
+    Tsv,
+    /// A JSON object describing the header, then one object per record.
+    JsonLines,
+}
+
+/// Write the header of the RAD file read by `rr`, followed by (up to `head`
+/// of) its records, to `out` in the textual `format`.  Each record gives its
+/// index, barcode, umi, and the ids and orientations (`+`/`-`) of its
+/// alignments; if `decode` is set, the barcode and umi are written as ACGT
+/// strings rather than as packed integers (with the length of each umi of
+/// a file with `variable_umi` taken from the umi itself, see
+/// `utils::untag_umi_length`).  Returns the number of records written.
+pub fn dump<R: std::io::Read, W: Write>(
+    mut rr: libradicl::RadReader<R>,
+    out: &mut W,
+    format: DumpFormat,
+    decode: bool,
+    head: Option<u64>,
+) -> Result<u64, libradicl::error::RadError> {
+    let (bclen, umilen) = (rr.file_tag_vals.bclen, rr.file_tag_vals.umilen);
+    let variable_umi = rr.file_tag_vals.variable_umi;
+    let text = |v: u64, len: u16| -> String {
+        if decode {
+            String::from_utf8_lossy(&bitmer_to_bytes((v, len as u8))).into_owned()
+        } else {
+            v.to_string()
+        }
+    };
+    let json = |v: u64, len: u16| -> serde_json::Value {
+        if decode {
+            text(v, len).into()
+        } else {
+            v.into()
+        }
+    };
+
+    match format {
+        DumpFormat::Tsv => {
+            writeln!(out, "#is_paired\t{}", rr.header.is_paired)?;
+            writeln!(out, "#num_chunks\t{}", rr.header.num_chunks)?;
+            writeln!(out, "#bclen\t{}\n#umilen\t{}", bclen, umilen)?;
+            for (i, n) in rr.header.ref_names.iter().enumerate() {
+                writeln!(out, "#ref\t{}\t{}", i, n)?;
+            }
+            writeln!(out, "id\tbc\tumi\trefs\tdirs")?;
+        }
+        DumpFormat::JsonLines => {
+            let h = serde_json::json!({
+                "is_paired": rr.header.is_paired != 0,
+                "num_chunks": rr.header.num_chunks,
+                "bclen": bclen,
+                "umilen": umilen,
+                "ref_names": rr.header.ref_names,
+            });
+            serde_json::to_writer(&mut *out, &h)?;
+            writeln!(out)?;
+        }
+    }
+
+    let limit = head.unwrap_or(u64::MAX);
+    let mut id = 0u64;
+    while id < limit {
+        let c = match rr.next() {
+            Some(c) => c?,
+            None => break,
+        };
+        for read in c
+            .reads
+            .iter()
+            .take((limit - id).min(c.reads.len() as u64) as usize)
+        {
+            let dirs = read
+                .dirs
+                .iter()
+                .map(|&d| if d { "+" } else { "-" })
+                .collect::<Vec<_>>();
+            let (umi, ulen) = if decode && variable_umi {
+                libradicl::utils::untag_umi_length(read.umi)
+            } else {
+                (read.umi, umilen)
+            };
+            match format {
+                DumpFormat::Tsv => writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}",
+                    id,
+                    text(read.bc, bclen),
+                    text(umi, ulen),
+                    read.refs
+                        .iter()
+                        .map(|r| r.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    dirs.join(",")
+                )?,
+                DumpFormat::JsonLines => {
+                    let r = serde_json::json!({
+                        "id": id,
+                        "bc": json(read.bc, bclen),
+                        "umi": json(umi, ulen),
+                        "refs": read.refs,
+                        "dirs": dirs,
+                    });
+                    serde_json::to_writer(&mut *out, &r)?;
+                    writeln!(out)?;
+                }
+            }
+            id += 1;
+        }
+    }
+    out.flush()?;
+    Ok(id)
+}
+
 pub fn view(rad_file: String, print_header: bool, out_file: String, log: &slog::Logger) {
     let _read_num = view2(rad_file, print_header, out_file, &log).unwrap();
 }
@@ -485,7 +847,7 @@ pub fn view2(
 ) -> Result<u64, Box<dyn std::error::Error>> {
     let i_file = File::open(rad_file).unwrap();
     let mut br = BufReader::new(i_file);
-    let hdr = libradicl::RadHeader::from_bytes(&mut br);
+    let hdr = libradicl::RadHeader::from_bytes_checked(&mut br)?;
     // info!(
     //     log,
     //     "paired : {:?}, ref_count : {}, num_chunks : {}",
@@ -494,10 +856,10 @@ pub fn view2(
     //     hdr.num_chunks.to_formatted_string(&Locale::en)
     // );
     // file-level
-    let _fl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let fl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     // info!(log, "read {:?} file-level tags", fl_tags.tags.len());
     // read-level
-    let rl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let rl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     // info!(log, "read {:?} read-level tags", rl_tags.tags.len());
 
     // right now, we only handle BC and UMI types of U8—U64, so validate that
@@ -528,10 +890,10 @@ pub fn view2(
     }
 
     // alignment-level
-    let _al_tags = libradicl::TagSection::from_bytes(&mut br);
+    let _al_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     // info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
 
-    let ft_vals = libradicl::FileTags::from_bytes(&mut br);
+    let ft_vals = libradicl::FileTags::from_bytes_with_section(&mut br, &fl_tags).unwrap();
     // info!(log, "File-level tag values {:?}", ft_vals);
 
     let mut num_reads: u64 = 0;
@@ -540,6 +902,10 @@ pub fn view2(
         .expect("unknown barcode type id.");
     let umi_type = libradicl::decode_int_type_tag(umit.expect("no umi tag description present"))
         .expect("unknown barcode type id.");
+    if let Err(e) = ft_vals.validate(&bc_type, &umi_type) {
+        crit!(log, "{}", e);
+        std::process::exit(libradicl::exit_codes::EXIT_INVALID_TAG_WIDTH);
+    }
 
     let stdout = stdout(); // get the global stdout entity
     let stdout_l = stdout.lock();
@@ -558,7 +924,7 @@ pub fn view2(
 
     let mut id = 0usize;
     for _ in 0..(hdr.num_chunks as usize) {
-        let c = libradicl::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
+        let c = libradicl::Chunk::from_bytes_checked(&mut br, &bc_type, &umi_type)?;
         for read in c.reads.iter() {
             let bc_mer: BitKmer = (read.bc, ft_vals.bclen as u8);
             let umi_mer: BitKmer = (read.umi, ft_vals.umilen as u8);
@@ -598,3 +964,245 @@ pub fn view2(
 
     Ok(num_reads)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExtReadRecord, FileTags, RadHeader, RadIntId, RadReader, RadWriter, ReadRecord};
+
+    #[test]
+    fn test_rad_to_bam() {
+        let dir = std::env::temp_dir().join(format!("rad_to_bam_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["t0".to_string(), "t1".to_string()],
+            ref_lengths: vec![1500, 800],
+            ref_digest: None,
+            num_chunks: 1,
+        };
+        hdr.ref_digest = hdr.compute_ref_digest();
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: Some(2),
+            variable_umi: false,
+            ..Default::default()
+        };
+        let rec = ExtReadRecord {
+            bc: 5,
+            umi: 9,
+            dirs: vec![true, false],
+            refs: vec![1, 0],
+            positions: vec![40, 700],
+            ..ExtReadRecord::default()
+        };
+
+        for &with_pos in &[false, true] {
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            rw.enable_version_header().unwrap();
+            if with_pos {
+                rw.enable_positions().unwrap();
+            }
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            rw.begin_chunk().unwrap();
+            rw.push_ext_record(&rec).unwrap();
+            rw.finish_chunk().unwrap();
+            let rad = dir.join("map.rad");
+            std::fs::write(&rad, rw.finalize_with_chunk_count().unwrap().into_inner()).unwrap();
+
+            let out = dir.join("map.bam");
+            assert_eq!(rad_to_bam(&rad, &out, None, &log).unwrap(), 2);
+
+            let mut bam = bam::Reader::from_path(&out).unwrap();
+            assert_eq!(bam.header().target_len(0), Some(1500));
+            assert_eq!(bam.header().target_len(1), Some(800));
+            let recs: Vec<bam::Record> = bam.records().map(|r| r.unwrap()).collect();
+            assert_eq!(recs.len(), 2);
+            for (i, r) in recs.iter().enumerate() {
+                assert_eq!(r.qname(), b"0");
+                assert_eq!(r.tid(), rec.refs[i] as i32);
+                assert_eq!(r.is_reverse(), !rec.dirs[i]);
+                assert_eq!(r.is_secondary(), i > 0);
+                assert_eq!(r.aux(b"HI").unwrap().integer(), i as i64 + 1);
+                if with_pos {
+                    assert!(!r.is_unmapped());
+                    assert_eq!(r.pos(), rec.positions[i] as i64);
+                } else {
+                    assert!(r.is_unmapped());
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bam_with_tags_to_rad() {
+        let dir = std::env::temp_dir().join(format!("tags_to_rad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        let mut header = bam::Header::new();
+        for (name, len) in &[("t0", 1500), ("t1", 800)] {
+            header.push_record(
+                bam::header::HeaderRecord::new(b"SQ")
+                    .push_tag(b"SN", name)
+                    .push_tag(b"LN", len),
+            );
+        }
+        let bam_path = dir.join("tagged.bam");
+        let mut bam = bam::Writer::from_path(&bam_path, &header, bam::Format::Bam).unwrap();
+        let recs: [(i32, u16, &[(&[u8; 2], &[u8])]); 6] = [
+            (0, 0, &[(b"CB", b"ACGTACGT-1"), (b"UB", b"AACCGG")]),
+            (1, 0x10, &[(b"CB", b"TTTTACGT-1"), (b"UB", b"GGAACC")]),
+            (1, 0, &[(b"CB", b"TTTTACGT-1")]), // no UMI
+            (0, 0, &[(b"CB", b"ACGNACGT-1"), (b"UB", b"AACCGG")]), // N in the barcode
+            (0, 0x100, &[(b"CB", b"ACGTACGT-1"), (b"UB", b"AACCGG")]), // secondary
+            (-1, 0x4, &[(b"CB", b"ACGTACGT-1"), (b"UB", b"AACCGG")]), // unmapped
+        ];
+        for (i, &(tid, flags, tags)) in recs.iter().enumerate() {
+            let mut rec = bam::Record::new();
+            rec.set(i.to_string().as_bytes(), None, &[], &[]);
+            rec.set_tid(tid);
+            rec.set_pos(if tid < 0 { -1 } else { 100 });
+            rec.set_mtid(-1);
+            rec.set_mpos(-1);
+            rec.set_flags(flags);
+            for &(tag, v) in tags {
+                rec.push_aux(tag, &bam::record::Aux::String(v));
+            }
+            bam.write(&rec).unwrap();
+        }
+        drop(bam);
+
+        let out = dir.join("tagged.rad");
+        let stats =
+            bam_with_tags_to_rad(&bam_path, b"CB", b"UB", 8, 6, &out, Strand::Unknown, &log)
+                .unwrap();
+        assert_eq!(
+            stats,
+            TagConversionStats {
+                num_records_written: 2,
+                num_unaligned_or_secondary: 2,
+                num_strand_incompatible: 0,
+                num_missing_tags: 1,
+                num_invalid_tags: 1,
+                num_chunks: 1,
+            }
+        );
+
+        let mut rr = RadReader::new(BufReader::new(File::open(&out).unwrap())).unwrap();
+        assert_eq!(rr.header.ref_names, vec!["t0", "t1"]);
+        assert_eq!(rr.header.num_chunks, 1);
+        assert_eq!((rr.file_tag_vals.bclen, rr.file_tag_vals.umilen), (8, 6));
+        assert_eq!(rr.file_tag_vals.max_ambiguity, Some(1));
+        let pack = |s: &[u8]| crate::utils::pack_kmer_with_ns(s).unwrap().0;
+        let c = rr.next().unwrap().unwrap();
+        assert_eq!(c.reads.len(), 2);
+        assert_eq!(
+            (c.reads[0].bc, c.reads[0].umi),
+            (pack(b"ACGTACGT"), pack(b"AACCGG"))
+        );
+        assert_eq!(
+            (&c.reads[0].refs, &c.reads[0].dirs),
+            (&vec![0], &vec![true])
+        );
+        assert_eq!(
+            (c.reads[1].bc, c.reads[1].umi),
+            (pack(b"TTTTACGT"), pack(b"GGAACC"))
+        );
+        assert_eq!(
+            (&c.reads[1].refs, &c.reads[1].dirs),
+            (&vec![1], &vec![false])
+        );
+        assert!(rr.next().is_none());
+
+        // only the forward record survives when that orientation is required
+        let stats =
+            bam_with_tags_to_rad(&bam_path, b"CB", b"UB", 8, 6, &out, Strand::Forward, &log)
+                .unwrap();
+        assert_eq!(stats.num_records_written, 1);
+        assert_eq!(stats.num_strand_incompatible, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dump() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 3,
+            ref_names: vec!["t0".to_string(), "t1".to_string(), "t2".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 1,
+        };
+        let ft = FileTags {
+            bclen: 4,
+            umilen: 6,
+            max_ambiguity: None,
+            variable_umi: true,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U64)
+            .unwrap();
+        rw.begin_chunk().unwrap();
+        // TGCA, and the 4 base umi ACGT
+        let umi = crate::utils::tag_umi_length(0b00011011, 4);
+        rw.push_record(&ReadRecord {
+            bc: 0b11100100,
+            umi,
+            dirs: vec![true, false],
+            mate_dirs: Vec::new(),
+            refs: vec![2, 0],
+            tags: None,
+        })
+        .unwrap();
+        rw.push_record(&ReadRecord {
+            bc: 1,
+            umi,
+            dirs: vec![true],
+            mate_dirs: Vec::new(),
+            refs: vec![1],
+            tags: None,
+        })
+        .unwrap();
+        rw.finish_chunk().unwrap();
+        let bytes = rw.finalize().unwrap().into_inner();
+
+        let mut out = Vec::new();
+        let rr = RadReader::new(Cursor::new(&bytes)).unwrap();
+        assert_eq!(
+            dump(rr, &mut out, DumpFormat::Tsv, true, Some(1)).unwrap(),
+            1
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#is_paired\t0\n#num_chunks\t1\n#bclen\t4\n#umilen\t6\n\
+             #ref\t0\tt0\n#ref\t1\tt1\n#ref\t2\tt2\n\
+             id\tbc\tumi\trefs\tdirs\n\
+             0\tTGCA\tACGT\t2,0\t+,-\n"
+        );
+
+        let mut out = Vec::new();
+        let rr = RadReader::new(Cursor::new(&bytes)).unwrap();
+        assert_eq!(
+            dump(rr, &mut out, DumpFormat::JsonLines, false, None).unwrap(),
+            2
+        );
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["ref_names"], serde_json::json!(["t0", "t1", "t2"]));
+        assert_eq!(
+            lines[2],
+            serde_json::json!({"id": 1, "bc": 1, "umi": umi, "refs": [1], "dirs": ["+"]})
+        );
+    }
+}
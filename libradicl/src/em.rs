@@ -35,8 +35,88 @@ const MIN_ITER: u32 = 2;
 const MAX_ITER: u32 = 100;
 const REL_DIFF_TOLERANCE: f32 = 1e-2;
 
+/// Stopping criteria for the EM loop.
+#[derive(Copy, Clone, Debug)]
+pub struct EmParams {
+    /// the maximum number of EM rounds to run
+    pub max_iter: usize,
+    /// convergence threshold on the summed absolute change in
+    /// abundances, relative to the total abundance
+    pub rel_tol: f64,
+    /// convergence threshold on the absolute change of each
+    /// (non-negligible) abundance
+    pub abs_tol: f64,
+}
+
+impl Default for EmParams {
+    /// The historical fixed criteria; the relative check is disabled.
+    fn default() -> Self {
+        EmParams {
+            max_iter: MAX_ITER as usize,
+            rel_tol: f64::INFINITY,
+            abs_tol: REL_DIFF_TOLERANCE as f64,
+        }
+    }
+}
+
+/// A prior weight on each reference, by which its abundance is scaled when
+/// apportioning the reads of a multi-mapping equivalence class.
+pub trait Prior: Send + Sync {
+    fn weight(&self, ref_id: u32) -> f64;
+}
+
+/// Weights every reference equally; this is the behavior without a prior.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UniformPrior;
+
+impl Prior for UniformPrior {
+    #[inline]
+    fn weight(&self, _ref_id: u32) -> f64 {
+        1.0
+    }
+}
+
+/// Weights each reference by the inverse of its length, so that longer
+/// references don't attract reads merely by being long.
+#[derive(Clone, Debug)]
+pub struct LengthPrior {
+    lengths: Vec<u64>,
+}
+
+impl LengthPrior {
+    pub fn new(lengths: Vec<u64>) -> LengthPrior {
+        LengthPrior { lengths }
+    }
+
+    /// Build the prior from the reference lengths of `hdr`, which must
+    /// have been filled in (see `RadHeader::ref_lengths`).
+    pub fn from_header(hdr: &libradicl::RadHeader) -> LengthPrior {
+        assert_eq!(
+            hdr.ref_lengths.len(),
+            hdr.ref_names.len(),
+            "the header has no reference lengths"
+        );
+        LengthPrior::new(hdr.ref_lengths.clone())
+    }
+}
+
+impl Prior for LengthPrior {
+    #[inline]
+    fn weight(&self, ref_id: u32) -> f64 {
+        1.0 / self.lengths[ref_id as usize].max(1) as f64
+    }
+}
+
+/// The estimated abundances along with how the EM loop terminated.
+#[derive(Debug)]
+pub struct EmResult {
+    pub alphas: Vec<f32>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
 #[derive(Copy, Clone)]
-pub(crate) enum EmInitType {
+pub enum EmInitType {
     Informative,
     Uniform,
     Random,
@@ -78,6 +158,7 @@ pub(crate) fn em_update_subset(
     alphas_out: &mut Vec<f32>,
     eqclasses: &IndexedEqList,
     cell_data: &[(u32, u32)], // indices into eqclasses relevant for this cell
+    prior: &dyn Prior,
 ) {
     for (i, count) in cell_data {
         let labels = eqclasses.refs_for_eqc(*i);
@@ -85,14 +166,14 @@ pub(crate) fn em_update_subset(
         if labels.len() > 1 {
             let mut denominator: f32 = 0.0;
             for label in labels {
-                denominator += alphas_in[*label as usize];
+                denominator += alphas_in[*label as usize] * prior.weight(*label) as f32;
             }
 
             if denominator > 0.0 {
                 let inv_denominator = *count as f32 / denominator;
                 for label in labels {
                     let index = *label as usize;
-                    let count = alphas_in[index] * inv_denominator;
+                    let count = alphas_in[index] * prior.weight(*label) as f32 * inv_denominator;
                     alphas_out[index] += count;
                 }
             }
@@ -112,6 +193,38 @@ pub(crate) fn em_optimize_subset(
     init_type: EmInitType,
     num_alphas: usize,
     only_unique: bool,
+    params: &EmParams,
+    log: &slog::Logger,
+) -> Vec<f32> {
+    em_optimize_subset_with_prior(
+        eqclasses,
+        cell_data,
+        unique_evidence,
+        no_ambiguity,
+        init_type,
+        num_alphas,
+        only_unique,
+        &UniformPrior,
+        params,
+        log,
+    )
+}
+
+/// As `em_optimize_subset`, but with the abundances weighted by `prior`
+/// when apportioning multi-mapping reads.  With a `UniformPrior`, the
+/// result is exactly that of `em_optimize_subset`.  The loop stops
+/// according to `params`, as in `em_optimize_with_params`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn em_optimize_subset_with_prior(
+    eqclasses: &IndexedEqList,
+    cell_data: &[(u32, u32)], // indices into eqclasses relevant for this cell
+    unique_evidence: &mut Vec<bool>,
+    no_ambiguity: &mut Vec<bool>,
+    init_type: EmInitType,
+    num_alphas: usize,
+    only_unique: bool,
+    prior: &dyn Prior,
+    params: &EmParams,
     _log: &slog::Logger,
 ) -> Vec<f32> {
     let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
@@ -156,27 +269,32 @@ pub(crate) fn em_optimize_subset(
         }
     }
 
-    let mut it_num: u32 = 0;
+    let mut it_num: usize = 0;
     let mut converged: bool = true;
     // allow one last round of the EM after thresholding
     // very small counts to 0.
     let mut last_round: bool = false;
 
-    while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) || last_round {
+    while it_num < MIN_ITER as usize || (it_num < params.max_iter && !converged) || last_round {
         // perform one round of em update
-        em_update_subset(&alphas_in, &mut alphas_out, eqclasses, cell_data);
+        em_update_subset(&alphas_in, &mut alphas_out, eqclasses, cell_data, prior);
 
         converged = true;
         let mut max_rel_diff = -f32::INFINITY;
+        let mut total_abs_diff = 0.0_f64;
+        let mut total_alpha = 0.0_f64;
 
         for index in 0..num_alphas {
+            total_abs_diff += (alphas_in[index] - alphas_out[index]).abs() as f64;
+            total_alpha += alphas_out[index] as f64;
+
             if alphas_out[index] > ALPHA_CHECK_CUTOFF {
                 let diff = alphas_in[index] - alphas_out[index];
                 let rel_diff = diff.abs();
 
                 max_rel_diff = max_rel_diff.max(rel_diff);
 
-                if rel_diff > REL_DIFF_TOLERANCE {
+                if rel_diff as f64 > params.abs_tol {
                     converged = false;
                 }
             } // end- in>out if
@@ -185,6 +303,10 @@ pub(crate) fn em_optimize_subset(
             alphas_out[index] = 0.0_f32;
         } //end-for
 
+        if total_alpha > 0.0 && total_abs_diff / total_alpha > params.rel_tol {
+            converged = false;
+        }
+
         it_num += 1;
 
         // if this was the last round
@@ -197,7 +319,7 @@ pub(crate) fn em_optimize_subset(
         // of iterations, and if we are converged
         // then do one last round after filtering
         // very small values.
-        if it_num >= MIN_ITER && converged {
+        if it_num >= MIN_ITER as usize && converged {
             alphas_in.iter_mut().for_each(|alpha| {
                 if *alpha < MIN_OUTPUT_ALPHA {
                     *alpha = 0.0_f32;
@@ -247,15 +369,20 @@ pub fn em_update(
     }
 }
 
-pub(crate) fn em_optimize(
+/// Estimate the abundances of the `num_alphas` references from the
+/// counts of `eqclasses`, stopping according to `params`, and report
+/// the number of rounds run and whether the estimates converged.
+#[allow(clippy::too_many_arguments)]
+pub fn em_optimize_with_params(
     eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
     unique_evidence: &mut Vec<bool>,
     no_ambiguity: &mut Vec<bool>,
     init_type: EmInitType,
     num_alphas: usize,
     only_unique: bool,
+    params: &EmParams,
     _log: &slog::Logger,
-) -> Vec<f32> {
+) -> EmResult {
     let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
     let mut alphas_out: Vec<f32> = vec![0.0; num_alphas];
 
@@ -272,7 +399,11 @@ pub(crate) fn em_optimize(
     }
 
     if only_unique {
-        return alphas_in;
+        return EmResult {
+            alphas: alphas_in,
+            iterations: 0,
+            converged: true,
+        };
     }
 
     // fill in the alphas based on the initialization strategy
@@ -295,16 +426,21 @@ pub(crate) fn em_optimize(
     // TODO: is it even necessary?
     //alphas_in.iter_mut().for_each(|alpha| *alpha *= 1e-3);
 
-    let mut it_num: u32 = 0;
+    let mut it_num: usize = 0;
     let mut converged: bool = true;
-    while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) {
+    while it_num < MIN_ITER as usize || (it_num < params.max_iter && !converged) {
         // perform one round of em update
         em_update(&alphas_in, &mut alphas_out, eqclasses);
 
         converged = true;
         let mut max_rel_diff = -f32::INFINITY;
+        let mut total_abs_diff = 0.0_f64;
+        let mut total_alpha = 0.0_f64;
 
         for index in 0..num_alphas {
+            total_abs_diff += (alphas_in[index] - alphas_out[index]).abs() as f64;
+            total_alpha += alphas_out[index] as f64;
+
             if alphas_out[index] > ALPHA_CHECK_CUTOFF {
                 let diff = alphas_in[index] - alphas_out[index];
                 let rel_diff = diff.abs();
@@ -314,7 +450,7 @@ pub(crate) fn em_optimize(
                     false => max_rel_diff,
                 };
 
-                if rel_diff > REL_DIFF_TOLERANCE {
+                if rel_diff as f64 > params.abs_tol {
                     converged = false;
                 }
             } // end- in>out if
@@ -323,6 +459,10 @@ pub(crate) fn em_optimize(
             alphas_out[index] = 0.0_f32;
         } //end-for
 
+        if total_alpha > 0.0 && total_abs_diff / total_alpha > params.rel_tol {
+            converged = false;
+        }
+
         it_num += 1;
     }
 
@@ -340,7 +480,11 @@ pub(crate) fn em_optimize(
         alphas_sum
     );
     */
-    alphas_in
+    EmResult {
+        alphas: alphas_in,
+        iterations: it_num,
+        converged,
+    }
 }
 
 pub(crate) fn run_bootstrap_subset(
@@ -404,6 +548,7 @@ pub(crate) fn run_bootstrap_subset(
             EmInitType::Random,
             num_alphas_us,
             false, // only unique
+            &EmParams::default(),
             &_log,
         );
 
@@ -626,3 +771,237 @@ pub fn run_bootstrap_old(
 
     bootstraps
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eqclasses() -> HashMap<Vec<u32>, u32, ahash::RandomState> {
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut eqc = HashMap::with_hasher(s);
+        eqc.insert(vec![0], 20);
+        eqc.insert(vec![1], 10);
+        eqc.insert(vec![0, 1], 30);
+        eqc.insert(vec![1, 2], 12);
+        eqc
+    }
+
+    fn run(params: &EmParams) -> EmResult {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut unique_evidence = vec![false; 3];
+        let mut no_ambiguity = vec![false; 3];
+        em_optimize_with_params(
+            &eqclasses(),
+            &mut unique_evidence,
+            &mut no_ambiguity,
+            EmInitType::Informative,
+            3,
+            false,
+            params,
+            &log,
+        )
+    }
+
+    #[test]
+    fn test_em_params_stopping_criteria() {
+        let default = run(&EmParams::default());
+        assert!(default.converged);
+        assert!(default.iterations > MIN_ITER as usize);
+        assert!(default.iterations < MAX_ITER as usize);
+
+        // a tolerance that can't be met runs until the cap
+        let capped = run(&EmParams {
+            max_iter: 5,
+            rel_tol: f64::INFINITY,
+            abs_tol: -1.0,
+        });
+        assert_eq!(capped.iterations, 5);
+        assert!(!capped.converged);
+
+        // a loose tolerance stops after the minimum number of rounds
+        let loose = run(&EmParams {
+            max_iter: 100,
+            rel_tol: f64::INFINITY,
+            abs_tol: 1e9,
+        });
+        assert_eq!(loose.iterations, MIN_ITER as usize);
+        assert!(loose.converged);
+
+        // the relative tolerance alone can keep the loop going
+        let rel = run(&EmParams {
+            max_iter: 100,
+            rel_tol: 1e-6,
+            abs_tol: 1e9,
+        });
+        assert!(rel.iterations > MIN_ITER as usize);
+        let total: f32 = rel.alphas.iter().sum();
+        assert!((total - 72.0).abs() < 1e-2);
+    }
+
+    // A copy of `em_update_subset` as it was before priors were added,
+    // kept to check that the current code still gives the same results.
+    fn em_update_subset_before_priors(
+        alphas_in: &[f32],
+        alphas_out: &mut Vec<f32>,
+        eqclasses: &IndexedEqList,
+        cell_data: &[(u32, u32)],
+    ) {
+        for (i, count) in cell_data {
+            let labels = eqclasses.refs_for_eqc(*i);
+
+            if labels.len() > 1 {
+                let mut denominator: f32 = 0.0;
+                for label in labels {
+                    denominator += alphas_in[*label as usize];
+                }
+
+                if denominator > 0.0 {
+                    let inv_denominator = *count as f32 / denominator;
+                    for label in labels {
+                        let index = *label as usize;
+                        let count = alphas_in[index] * inv_denominator;
+                        alphas_out[index] += count;
+                    }
+                }
+            } else {
+                let tidx = labels.get(0).expect("can't extract labels");
+                alphas_out[*tidx as usize] += *count as f32;
+            }
+        }
+    }
+
+    // A copy of `em_optimize_subset` as it was before priors and
+    // `EmParams` were added (with the `Informative` initialization).
+    fn em_optimize_subset_before_priors(
+        eqclasses: &IndexedEqList,
+        cell_data: &[(u32, u32)],
+        num_alphas: usize,
+    ) -> Vec<f32> {
+        let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
+        let mut alphas_out: Vec<f32> = vec![0.0; num_alphas];
+
+        for (i, count) in cell_data {
+            let labels = eqclasses.refs_for_eqc(*i);
+            if labels.len() == 1 {
+                alphas_in[labels[0] as usize] += *count as f32;
+            }
+        }
+        for item in alphas_in.iter_mut() {
+            *item = (*item + 0.5) * 1e-3;
+        }
+
+        let mut it_num: u32 = 0;
+        let mut converged: bool = true;
+        let mut last_round: bool = false;
+
+        while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) || last_round {
+            em_update_subset_before_priors(&alphas_in, &mut alphas_out, eqclasses, cell_data);
+
+            converged = true;
+            for index in 0..num_alphas {
+                if alphas_out[index] > ALPHA_CHECK_CUTOFF {
+                    let rel_diff = (alphas_in[index] - alphas_out[index]).abs();
+                    if rel_diff > REL_DIFF_TOLERANCE {
+                        converged = false;
+                    }
+                }
+                alphas_in[index] = alphas_out[index];
+                alphas_out[index] = 0.0_f32;
+            }
+
+            it_num += 1;
+            if last_round {
+                break;
+            }
+            if it_num >= MIN_ITER && converged {
+                alphas_in.iter_mut().for_each(|alpha| {
+                    if *alpha < MIN_OUTPUT_ALPHA {
+                        *alpha = 0.0_f32;
+                    }
+                });
+                last_round = true;
+            }
+        }
+
+        alphas_in.iter_mut().for_each(|alpha| {
+            if *alpha < MIN_OUTPUT_ALPHA {
+                *alpha = 0.0_f32;
+            }
+        });
+        alphas_in
+    }
+
+    #[test]
+    fn test_em_subset_matches_before_priors() {
+        // a prior that weights every reference equally, but not by 1
+        struct ScaledPrior;
+        impl Prior for ScaledPrior {
+            fn weight(&self, _ref_id: u32) -> f64 {
+                4.0
+            }
+        }
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut eql = IndexedEqList::new();
+        eql.clear();
+        eql.add_single_label(0);
+        eql.add_single_label(1);
+        eql.add_label_vec(&[0, 1]);
+        eql.add_label_vec(&[1, 2]);
+        eql.add_label_vec(&[0, 1, 3]);
+        let cells: [&[(u32, u32)]; 3] = [
+            &[(0, 20), (1, 10), (2, 30), (3, 12)],
+            &[(2, 7), (3, 1), (4, 55)],
+            &[(0, 1), (1, 1000), (2, 3), (4, 9)],
+        ];
+
+        let run = |cell_data: &[(u32, u32)], prior: Option<&dyn Prior>| {
+            let mut unique_evidence = vec![false; 4];
+            let mut no_ambiguity = vec![false; 4];
+            match prior {
+                Some(p) => em_optimize_subset_with_prior(
+                    &eql,
+                    cell_data,
+                    &mut unique_evidence,
+                    &mut no_ambiguity,
+                    EmInitType::Informative,
+                    4,
+                    false,
+                    p,
+                    &EmParams::default(),
+                    &log,
+                ),
+                None => em_optimize_subset(
+                    &eql,
+                    cell_data,
+                    &mut unique_evidence,
+                    &mut no_ambiguity,
+                    EmInitType::Informative,
+                    4,
+                    false,
+                    &EmParams::default(),
+                    &log,
+                ),
+            }
+        };
+        let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<u32>>();
+
+        for cell_data in cells.iter() {
+            let before = em_optimize_subset_before_priors(&eql, cell_data, 4);
+            assert_eq!(bits(&run(cell_data, None)), bits(&before));
+            assert_eq!(bits(&run(cell_data, Some(&UniformPrior))), bits(&before));
+            assert_eq!(bits(&run(cell_data, Some(&ScaledPrior))), bits(&before));
+        }
+
+        // favoring the shorter reference 0 moves the shared reads toward it
+        let before = em_optimize_subset_before_priors(&eql, cells[0], 4);
+        let by_length = run(
+            cells[0],
+            Some(&LengthPrior::new(vec![100, 1000, 1000, 1000])),
+        );
+        assert!(by_length[0] > before[0]);
+        assert!(by_length[1] < before[1]);
+        let total = |v: &[f32]| v.iter().sum::<f32>();
+        assert!((total(&by_length) - total(&before)).abs() < 1e-2);
+    }
+}
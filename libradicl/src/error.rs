@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use crate::RadVersion;
+use std::fmt;
+
+/// Errors that can arise while packing a barcode string into an integer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PackError {
+    // the sequence doesn't have the expected number of bases
+    WrongLength { expected: u16, found: usize },
+    // a byte that isn't one of ACGT (in either case)
+    InvalidBase { byte: u8, pos: usize },
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackError::WrongLength { expected, found } => write!(
+                f,
+                "expected a barcode of length {}, but found one of length {}",
+                expected, found
+            ),
+            PackError::InvalidBase { byte, pos } => write!(
+                f,
+                "invalid base '{}' at position {} of barcode",
+                std::ascii::escape_default(*byte),
+                pos
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+/// Errors that can arise while reading or writing RAD data.
+#[derive(Debug)]
+pub enum RadError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    // a type id that doesn't correspond to any supported RAD type
+    UnsupportedTypeTag(u8),
+    // a type name that doesn't correspond to any supported RAD type
+    UnknownTypeName(String),
+    // a chunk whose declared size can't even hold its own header
+    InvalidChunkSize(u32),
+    // the records of a chunk didn't span the size given in its header
+    ChunkSizeMismatch { declared: u32, consumed: u64 },
+    // a reference or tag name that isn't valid UTF-8
+    InvalidUtf8 { context: String, bytes: Vec<u8> },
+    // the barcodes of a permit list don't have the length given in the file
+    BarcodeLengthMismatch { permit_len: usize, file_len: u16 },
+    // a line of a permit list whose length differs from that of the first
+    InconsistentBarcodeLength { line: u64, len: usize, first: usize },
+    // a barcode string that couldn't be packed
+    InvalidBarcode(PackError),
+    // a required tag description was absent from the file
+    MissingTag(String),
+    // a barcode or umi length too long for the integer type used to store it
+    TagWidthMismatch { tag: String, len: u16, bits: usize },
+    // the input ended after only `read` of the `nrec` records of a chunk
+    TruncatedChunk { nrec: u32, read: u32 },
+    // the input begins with neither a RAD header nor the RAD magic number
+    InvalidMagic(Vec<u8>),
+    // a file whose version can't be read by this library
+    UnsupportedVersion(RadVersion),
+    // random access was requested in a file without a chunk index
+    MissingChunkIndex,
+    // a RadWriter method was called out of order
+    InvalidWriterState(&'static str),
+    // the number of chunks written disagrees with the header
+    ChunkCountMismatch { expected: u64, observed: u64 },
+    // chunks compressed with a codec that isn't known or wasn't built in
+    UnsupportedCompression(String),
+    // files that can't be combined, e.g. by `merge::merge_rad_files`
+    IncompatibleInputs(String),
+    // alignment-level tags that don't describe a supported record layout
+    UnsupportedLayout(String),
+    // headers describing different sets of references
+    ReferenceMismatch(String),
+    // a count or length read from the input exceeds the sanity limit for it
+    LimitExceeded { what: String, value: u64, max: u64 },
+    // an argument outside of the range of values it may take
+    InvalidArgument(String),
+}
+
+impl fmt::Display for RadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RadError::Io(e) => write!(f, "I/O error : {}", e),
+            RadError::Json(e) => write!(f, "JSON error : {}", e),
+            RadError::UnsupportedTypeTag(t) => write!(f, "unsupported type tag {}", t),
+            RadError::UnknownTypeName(n) => write!(f, "unknown RAD type name '{}'", n),
+            RadError::InvalidChunkSize(n) => write!(f, "invalid chunk size of {} bytes", n),
+            RadError::ChunkSizeMismatch { declared, consumed } => write!(
+                f,
+                "chunk declares {} bytes, but its records span {}",
+                declared, consumed
+            ),
+            RadError::InvalidUtf8 { context, bytes } => write!(
+                f,
+                "{} is not valid UTF-8 : {}",
+                context,
+                String::from_utf8_lossy(bytes)
+            ),
+            RadError::BarcodeLengthMismatch {
+                permit_len,
+                file_len,
+            } => write!(
+                f,
+                "the permit list has barcodes of length {}, but the RAD file has barcodes of length {}",
+                permit_len, file_len
+            ),
+            RadError::InconsistentBarcodeLength { line, len, first } => write!(
+                f,
+                "line {} of the permit list has a barcode of length {}, but the first has length {}",
+                line, len, first
+            ),
+            RadError::InvalidBarcode(e) => write!(f, "{}", e),
+            RadError::MissingTag(n) => write!(f, "no description of the '{}' tag present", n),
+            RadError::TagWidthMismatch { tag, len, bits } => write!(
+                f,
+                "a {} of length {} can't be stored in a {} bit integer",
+                tag, len, bits
+            ),
+            RadError::TruncatedChunk { nrec, read } => write!(
+                f,
+                "the input ended after {} of the {} records of a chunk",
+                read, nrec
+            ),
+            RadError::InvalidMagic(b) => write!(
+                f,
+                "the input is not a RAD file (it begins with the bytes {:?})",
+                b
+            ),
+            RadError::UnsupportedVersion(v) => write!(f, "unsupported RAD version {:?}", v),
+            RadError::MissingChunkIndex => write!(f, "the file has no chunk index"),
+            RadError::InvalidWriterState(m) => write!(f, "invalid writer state : {}", m),
+            RadError::ChunkCountMismatch { expected, observed } => write!(
+                f,
+                "header declares {} chunks, but {} were written",
+                expected, observed
+            ),
+            RadError::UnsupportedCompression(c) => {
+                write!(f, "unsupported chunk compression {}", c)
+            }
+            RadError::IncompatibleInputs(m) => write!(f, "incompatible inputs : {}", m),
+            RadError::UnsupportedLayout(m) => write!(f, "unsupported record layout : {}", m),
+            RadError::ReferenceMismatch(m) => write!(f, "mismatched references : {}", m),
+            RadError::LimitExceeded { what, value, max } => write!(
+                f,
+                "{} of {} exceeds the limit of {}; the input is likely malformed",
+                what, value, max
+            ),
+            RadError::InvalidArgument(m) => write!(f, "invalid argument : {}", m),
+        }
+    }
+}
+
+impl std::error::Error for RadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RadError::Io(e) => Some(e),
+            RadError::Json(e) => Some(e),
+            RadError::InvalidBarcode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RadError {
+    fn from(e: std::io::Error) -> Self {
+        // parsers returning `std::io::Result` (e.g. `ReadRecord::from_bytes_checked`)
+        // carry their other errors inside an `InvalidData` error
+        if e.get_ref().map_or(false, |inner| inner.is::<RadError>()) {
+            return *e.into_inner().unwrap().downcast::<RadError>().unwrap();
+        }
+        RadError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RadError {
+    fn from(e: serde_json::Error) -> Self {
+        RadError::Json(e)
+    }
+}
+
+impl From<PackError> for RadError {
+    fn from(e: PackError) -> Self {
+        RadError::InvalidBarcode(e)
+    }
+}
@@ -8,3 +8,9 @@
  */
 
 pub(super) static EXIT_UNSUPPORTED_TAG_TYPE: i32 = 65;
+pub(super) static EXIT_INVALID_TAG_WIDTH: i32 = 66;
+pub(super) static EXIT_INVALID_PERMIT_LIST: i32 = 67;
+pub(super) static EXIT_INVALID_FILE_TAGS: i32 = 68;
+pub(super) static EXIT_UNSUPPORTED_COMPRESSION: i32 = 69;
+pub(super) static EXIT_MALFORMED_INPUT: i32 = 70;
+pub(super) static EXIT_UNSUPPORTED_LAYOUT: i32 = 71;
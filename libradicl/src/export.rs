@@ -0,0 +1,166 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Export of the alignments of a RAD file as Arrow record batches, or to a
+// Parquet file, for inspection in data frame libraries.
+
+extern crate arrow;
+extern crate parquet;
+
+use crate as libradicl;
+
+use self::arrow::array::{ArrayRef, BooleanArray, UInt32Array, UInt64Array};
+use self::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use self::arrow::record_batch::RecordBatch;
+use self::libradicl::error::RadError;
+use self::libradicl::RadReader;
+use self::parquet::arrow::ArrowWriter;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The schema of the batches produced by `RecordBatches`, with one row per
+/// alignment.  The barcode and umi are in their packed (2-bit) form, and
+/// `strand` is true for alignments to the forward strand.
+pub fn alignment_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("barcode", DataType::UInt64, false),
+        Field::new("umi", DataType::UInt64, false),
+        Field::new("ref_id", DataType::UInt32, false),
+        Field::new("strand", DataType::Boolean, false),
+        Field::new("chunk_id", DataType::UInt64, false),
+    ]))
+}
+
+/// An iterator over the chunks of a RAD file, each converted into a
+/// `RecordBatch` of its alignments (see `alignment_schema`).  Only one
+/// chunk is held in memory at a time.
+pub struct RecordBatches<R: Read> {
+    reader: RadReader<R>,
+    schema: SchemaRef,
+    chunk_id: u64,
+}
+
+impl<R: Read> RecordBatches<R> {
+    pub fn new(reader: RadReader<R>) -> RecordBatches<R> {
+        RecordBatches {
+            reader,
+            schema: alignment_schema(),
+            chunk_id: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for RecordBatches<R> {
+    type Item = Result<RecordBatch, RadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let c = match self.reader.next()? {
+            Ok(c) => c,
+            Err(e) => return Some(Err(e)),
+        };
+        let n: usize = c.reads.iter().map(|r| r.refs.len()).sum();
+        let mut bcs = Vec::with_capacity(n);
+        let mut umis = Vec::with_capacity(n);
+        let mut refs = Vec::with_capacity(n);
+        let mut strands = Vec::with_capacity(n);
+        for r in &c.reads {
+            for (i, &t) in r.refs.iter().enumerate() {
+                bcs.push(r.bc);
+                umis.push(r.umi);
+                refs.push(t);
+                strands.push(r.dirs.get(i).copied().unwrap_or(false));
+            }
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(bcs)),
+            Arc::new(UInt64Array::from(umis)),
+            Arc::new(UInt32Array::from(refs)),
+            Arc::new(BooleanArray::from(strands)),
+            Arc::new(UInt64Array::from(vec![self.chunk_id; n])),
+        ];
+        self.chunk_id += 1;
+        // the columns always match the schema
+        Some(Ok(
+            RecordBatch::try_new(self.schema.clone(), columns).unwrap()
+        ))
+    }
+}
+
+/// Write the alignments of the RAD file `input` to the Parquet file
+/// `output`, one row group per chunk (see `alignment_schema`).  Returns the
+/// number of rows written.
+pub fn rad_to_parquet(input: &Path, output: &Path) -> Result<u64, Box<dyn Error>> {
+    let rr = RadReader::new(BufReader::new(File::open(input)?))?;
+    let mut writer = ArrowWriter::try_new(File::create(output)?, alignment_schema(), None)?;
+    let mut num_rows = 0u64;
+    for batch in RecordBatches::new(rr) {
+        let batch = batch?;
+        num_rows += batch.num_rows() as u64;
+        writer.write(&batch)?;
+    }
+    writer.close()?;
+    Ok(num_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileTags, RadHeader, RadIntId, RadWriter, ReadRecord};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_record_batches() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["t0".to_string(), "t1".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 2,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bc in 0..2u64 {
+            rw.begin_chunk().unwrap();
+            rw.push_record(&ReadRecord {
+                bc,
+                umi: 5,
+                dirs: vec![true, false],
+                mate_dirs: Vec::new(),
+                refs: vec![0, 1],
+                tags: None,
+            })
+            .unwrap();
+            rw.finish_chunk().unwrap();
+        }
+        let rr = RadReader::new(Cursor::new(rw.finalize().unwrap())).unwrap();
+        let batches: Vec<RecordBatch> = RecordBatches::new(rr).map(|b| b.unwrap()).collect();
+        assert_eq!(batches.len(), 2);
+        let b = &batches[1];
+        assert_eq!(b.num_rows(), 2);
+        let col = |i: usize| b.column(i).as_any();
+        let refs = col(2).downcast_ref::<UInt32Array>().unwrap();
+        assert_eq!((refs.value(0), refs.value(1)), (0, 1));
+        let strands = col(3).downcast_ref::<BooleanArray>().unwrap();
+        assert_eq!((strands.value(0), strands.value(1)), (true, false));
+        let chunk_ids = col(4).downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!(chunk_ids.value(0), 1);
+    }
+}
@@ -32,8 +32,9 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use self::libradicl::em::{em_optimize_subset, EmInitType};
+use self::libradicl::em::{em_optimize_subset_with_prior, EmInitType, EmParams, Prior};
 
+#[allow(clippy::too_many_arguments)]
 pub fn infer(
     //num_bootstraps,
     //init_uniform,
@@ -44,6 +45,7 @@ pub fn infer(
     num_threads: u32,
     filter_list: Option<&str>,
     output_dir: String,
+    prior: Arc<dyn Prior>,
     log: &slog::Logger,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!(
@@ -177,6 +179,8 @@ pub fn infer(
         let matout = trimat.clone();
         // and the global set of eq class labels
         let global_eq_classes = global_eq_classes.clone();
+        // and the prior used to weight the abundances
+        let prior = prior.clone();
 
         //let unmapped_count = bc_unmapped_map.clone();
         //let mmrate = mmrate.clone();
@@ -204,7 +208,7 @@ pub fn infer(
                     // given the set of equivalence classes and counts for
                     // this cell (coming from the input matrix), perform
                     // inference to obtain gene-level counts.
-                    let counts = em_optimize_subset(
+                    let counts = em_optimize_subset_with_prior(
                         &global_eq_classes,
                         &cell_data,
                         &mut unique_evidence,
@@ -212,6 +216,8 @@ pub fn infer(
                         EmInitType::Informative,
                         num_genes,
                         false,
+                        prior.as_ref(),
+                        &EmParams::default(),
                         &log,
                     );
 
@@ -16,20 +16,24 @@ extern crate sce;
 extern crate scroll;
 use crate as libradicl;
 
+use self::libradicl::error::RadError;
 use self::libradicl::schema::TempCellInfo;
 use self::libradicl::utils::{MASK_LOWER_31_U32, MASK_TOP_BIT_U32};
 #[allow(unused_imports)]
 use ahash::{AHasher, RandomState};
 use bio_types::strand::*;
 use dashmap::DashMap;
-use needletail::bitkmer::*;
 use num::cast::AsPrimitive;
+use rayon::prelude::*;
 use rust_htslib::bam::HeaderView;
 use scroll::Pread;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::{Infallible, TryFrom, TryInto};
 use std::fs::File;
+use std::hash::BuildHasher;
 use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
 use std::io::{BufWriter, Write};
 use std::mem;
@@ -37,15 +41,32 @@ use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
+#[cfg(feature = "async")]
+pub mod async_reader;
 pub mod cellfilter;
 pub mod collate;
 pub mod convert;
 pub mod em;
+pub mod error;
 pub mod exit_codes;
+#[cfg(feature = "export")]
+pub mod export;
 pub mod infer;
+pub mod merge;
+#[cfg(feature = "mmap")]
+pub mod mmap_bcmap;
+#[cfg(feature = "mmap")]
+pub mod mmap_rad;
+pub mod parallel_decode;
 pub mod pugutils;
 pub mod quant;
+#[cfg(feature = "remote")]
+pub mod remote;
 pub mod schema;
+pub mod summary;
+pub mod tags;
+#[cfg(feature = "testutil")]
+pub mod testutil;
 pub mod utils;
 
 // Name of the program, to be used in diagnostic messages.
@@ -59,6 +80,15 @@ pub struct RadHeader {
     pub is_paired: u8,
     pub ref_count: u64,
     pub ref_names: Vec<String>,
+    /// The lengths of the references, parallel to `ref_names`, or empty if
+    /// unknown.  Only files with a version header (see
+    /// `RadVersion::has_ref_info`) record these; otherwise they are known
+    /// only if the header was built from a source that has them (e.g. a BAM
+    /// header).
+    pub ref_lengths: Vec<u64>,
+    /// A digest of the reference set (see `compute_ref_digest`), if known.
+    /// Recorded alongside `ref_lengths`.
+    pub ref_digest: Option<[u8; 32]>,
     pub num_chunks: u64,
 }
 
@@ -74,23 +104,273 @@ pub struct TagSection {
 // The below are currently hard-coded
 // until we decide how to solve this
 // generally
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct FileTags {
     pub bclen: u16,
     pub umilen: u16,
+    /// The largest number of alignments of any record in the file, if
+    /// recorded (as the `MAX_AMBIGUITY_TAG` file-level tag).
+    pub max_ambiguity: Option<u32>,
+    /// Whether UMIs may differ in length between records, in which case
+    /// `umilen` is the largest length, and each UMI carries its own length
+    /// (see `utils::tag_umi_length`).  Recorded as the `VARIABLE_UMI_TAG`.
+    pub variable_umi: bool,
+    /// Whether the records of each chunk are zstd compressed (see
+    /// `RadWriter::enable_chunk_compression`).  Recorded as the
+    /// `CHUNK_COMPRESSION_TAG`.
+    pub zstd_chunks: bool,
+    /// The names and values of the file-level tags other than those above
+    /// (and the `CHECKSUM_TAG`, which is kept by `RadWriter`), in the
+    /// order in which they appear in the file.
+    pub extra: Vec<(String, tags::TagValue)>,
 }
-#[derive(Debug)]
-pub struct ReadRecord {
+
+/// The name of the optional file-level tag holding the largest number
+/// of alignments of any record in the file.
+pub const MAX_AMBIGUITY_TAG: &str = "max_ambiguity";
+
+/// The name of the optional file-level tag holding the CRC-32 of the
+/// bytes of every chunk in the file (see `verify_checksum`).
+pub const CHECKSUM_TAG: &str = "checksum";
+
+/// The name of the optional (bool) file-level tag marking files whose UMIs
+/// vary in length.
+pub const VARIABLE_UMI_TAG: &str = "variable_umi_len";
+
+/// The name of the optional (string) file-level tag naming the codec with
+/// which the records of each chunk are compressed.  Only "zstd" is
+/// supported.
+pub const CHUNK_COMPRESSION_TAG: &str = "chunk_compression";
+
+/// The name of the optional (u32) alignment-level tag holding the fragment
+/// length of each alignment (see `AlnLayout`).
+pub const FRAG_LEN_TAG: &str = "frag_len";
+
+/// The name of the optional (u32) alignment-level tag holding the start
+/// position of each alignment (see `AlnLayout`).
+pub const POS_TAG: &str = "pos";
+
+/// The name of the optional (u32) alignment-level tag holding a score for
+/// each alignment, such as its alignment score or MAPQ (see `AlnLayout`).
+pub const SCORE_TAG: &str = "score";
+
+// the flags of the block written by `RadHeader::write_ref_info`
+pub(crate) const REF_INFO_LENGTHS: u8 = 0x1;
+pub(crate) const REF_INFO_DIGEST: u8 = 0x2;
+
+// the number of bytes following `RAD_MAGIC` in a version header (see
+// `RadVersion::from_fields`)
+pub(crate) const VERSION_FIELDS_LEN: usize = 7;
+
+/// The most records a chunk may declare.  Larger counts are taken to mean
+/// that the input is malformed, rather than trusted to size allocations.
+pub const MAX_RECORDS_PER_CHUNK: u32 = 1 << 26;
+
+/// The most alignments a record may declare (see `MAX_RECORDS_PER_CHUNK`).
+pub const MAX_ALIGNMENTS_PER_RECORD: u32 = 1 << 16;
+
+/// The longest reference name accepted in a header.
+pub const MAX_REF_NAME_LEN: usize = 4096;
+
+// the most elements preallocated for a count read from the input; beyond
+// this, vectors grow only as their elements are actually read
+const MAX_PREALLOC: u64 = 1 << 16;
+
+// the capacity to preallocate for `n` elements read from the input
+pub(crate) fn prealloc(n: u64) -> usize {
+    n.min(MAX_PREALLOC) as usize
+}
+
+// an error if the count `value` read from the input exceeds `limit`
+pub(crate) fn check_limit(what: &'static str, value: u64, limit: u64) -> Result<(), RadError> {
+    if value > limit {
+        return Err(RadError::LimitExceeded {
+            what: what.to_string(),
+            value,
+            max: limit,
+        });
+    }
+    Ok(())
+}
+
+// as `check_limit`, for parsers returning `std::io::Result`
+pub(crate) fn check_limit_io(what: &'static str, value: u64, limit: u64) -> std::io::Result<()> {
+    check_limit(what, value, limit)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// The bytes beginning a RAD file that has a version header.  Files without
+/// one begin directly with the `is_paired` byte of the header (0 or 1).
+pub const RAD_MAGIC: [u8; 4] = *b"RADF";
+
+/// The layout version of a RAD file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RadVersion {
+    /// A file without a version header, as written by older tools.
+    Legacy,
+    /// A file whose header begins with `RAD_MAGIC` and this version.
+    Versioned { major: u16, minor: u16, patch: u16 },
+}
+
+/// The version written by `RadWriter::enable_version_header`.  Files of
+/// any version with the same major version, and no greater minor version,
+/// can be read.
+pub const RAD_VERSION: RadVersion = RadVersion::Versioned {
+    major: 1,
+    minor: 1,
+    patch: 0,
+};
+
+impl RadVersion {
+    /// Whether files of this version can be read by this library.
+    pub fn is_supported(&self) -> bool {
+        match (self, RAD_VERSION) {
+            (RadVersion::Legacy, _) => true,
+            (
+                RadVersion::Versioned { major, minor, .. },
+                RadVersion::Versioned {
+                    major: m, minor: n, ..
+                },
+            ) => *major == m && *minor <= n,
+            _ => false,
+        }
+    }
+
+    /// Whether the header of files of this version is followed (after
+    /// `num_chunks`) by the reference lengths and digest, each preceded by
+    /// a flag byte recording which of them are present (since 1.1).
+    pub fn has_ref_info(&self) -> bool {
+        match self {
+            RadVersion::Legacy => false,
+            RadVersion::Versioned { major, minor, .. } => (*major, *minor) >= (1, 1),
+        }
+    }
+
+    // Parse the fields following `RAD_MAGIC` at the start of a file: the
+    // major, minor and patch versions and then the `is_paired` byte, in
+    // `VERSION_FIELDS_LEN` bytes.  Returns the version and `is_paired`.
+    pub(crate) fn from_fields(buf: &[u8]) -> Result<(RadVersion, u8), RadError> {
+        let version = RadVersion::Versioned {
+            major: buf.pread::<u16>(0).unwrap(),
+            minor: buf.pread::<u16>(2).unwrap(),
+            patch: buf.pread::<u16>(4).unwrap(),
+        };
+        if !version.is_supported() {
+            return Err(RadError::UnsupportedVersion(version));
+        }
+        Ok((version, buf[6]))
+    }
+
+    /// Write `RAD_MAGIC` and this version, or nothing for `Legacy`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        if let RadVersion::Versioned {
+            major,
+            minor,
+            patch,
+        } = self
+        {
+            writer.write_all(&RAD_MAGIC)?;
+            for v in &[major, minor, patch] {
+                writer.write_all(&v.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    // the number of bytes written by `write_to`
+    fn size(&self) -> u64 {
+        match self {
+            RadVersion::Legacy => 0,
+            RadVersion::Versioned { .. } => (RAD_MAGIC.len() + 3 * mem::size_of::<u16>()) as u64,
+        }
+    }
+}
+#[derive(Debug, Default)]
+pub struct ReadRecord<B = u64> {
+    pub bc: B,
+    pub umi: u64,
+    pub dirs: Vec<bool>,
+    /// The orientation of the second read of the pair for each alignment,
+    /// parallel to `refs`, for records of paired-end files (see
+    /// `AlnLayout`) read by a layout-aware reader or passed through
+    /// `split_mate_ori`; empty otherwise.
+    pub mate_dirs: Vec<bool>,
+    pub refs: Vec<u32>,
+    /// The values of every read- and alignment-level tag of the record, if
+    /// it was read with a `tags::RecordSchema`; `None` otherwise.
+    pub tags: Option<Box<tags::RecordTags>>,
+}
+
+/// A record whose alignments are separated by orientation, as returned
+/// by `ReadRecord::from_bytes_split_ori`.  Both vectors hold bare
+/// reference ids, in sorted order.
+#[derive(Debug, Default)]
+pub struct SplitOriRecord {
+    pub bc: u64,
+    pub umi: u64,
+    pub fw_refs: Vec<u32>,
+    pub rc_refs: Vec<u32>,
+}
+
+/// The fields of each alignment of the records of a file, beyond the
+/// reference id and orientation of `ReadRecord`, as declared by the
+/// alignment-level tag section (see `AlnLayout::from_tags`).
+///
+/// In a paired-end file (one whose header has `is_paired` set), the first
+/// word of each alignment holds the orientation of the first read of the
+/// pair in its top bit (as for single-end records), the orientation of the
+/// second read in the bit below it, and the reference id in the remaining
+/// 30 bits.  If `pos` is set, it is followed by the (0-based) start
+/// position of the alignment (`POS_TAG`), if `frag_len` is set, then by
+/// its fragment length (`FRAG_LEN_TAG`), and if `score` is set, then by its
+/// score (`SCORE_TAG`), where higher scores indicate better alignments.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AlnLayout {
+    pub paired: bool,
+    pub pos: bool,
+    pub frag_len: bool,
+    pub score: bool,
+}
+
+/// The fields of a single alignment of a record, as passed to the
+/// predicate of `ReadRecord::from_bytes_keep_ori_filtered`.  Fields absent
+/// from the `AlnLayout` of the file are `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlnEntry {
+    pub ref_id: u32,
+    pub strand: Strand,
+    pub pos: Option<u32>,
+    pub frag_len: Option<u32>,
+    pub score: Option<u32>,
+}
+
+/// A record with every field of the `AlnLayout` of its file.  Each of
+/// `mate_dirs`, `positions`, `frag_lens` and `scores` is parallel to `refs`
+/// if the layout has the corresponding field, and is otherwise empty.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExtReadRecord {
     pub bc: u64,
     pub umi: u64,
     pub dirs: Vec<bool>,
+    pub mate_dirs: Vec<bool>,
     pub refs: Vec<u32>,
+    pub positions: Vec<u32>,
+    pub frag_lens: Vec<u32>,
+    pub scores: Vec<u32>,
+}
+
+/// A chunk of `ExtReadRecord`s.
+#[derive(Debug)]
+pub struct ExtChunk {
+    pub nbytes: u32,
+    pub nrec: u32,
+    pub reads: Vec<ExtReadRecord>,
 }
 #[derive(Debug)]
-pub struct Chunk {
+pub struct Chunk<B = u64> {
     pub nbytes: u32,
     pub nrec: u32,
-    pub reads: Vec<ReadRecord>,
+    pub reads: Vec<ReadRecord<B>>,
 }
 
 #[derive(Debug)]
@@ -107,79 +387,162 @@ pub struct CorrectedCbChunk {
 
 #[derive(Serialize, Deserialize, Debug)]
 #[allow(dead_code)]
-pub struct BarcodeLookupMap {
-    pub barcodes: Vec<u64>,
-    //pub counts: Vec<usize>,
+pub struct BarcodeLookupMap<B = u64> {
+    pub barcodes: Vec<B>,
+    // optional; if non-empty, parallel to `barcodes`
+    pub counts: Vec<u64>,
     offsets: Vec<usize>,
     bclen: u32,
     prefix_len: u32,
     suffix_len: u32,
 }
 
-impl BarcodeLookupMap {
-    pub fn new(mut kv: Vec<u64>, bclen: u32) -> BarcodeLookupMap {
-        let prefix_len = ((bclen + 1) / 2) as u64;
-        let suffix_len = bclen - prefix_len as u32;
+/// The default upper bound on the number of prefix buckets in a `BarcodeLookupMap`.
+pub const DEFAULT_MAX_PREFIX_BUCKETS: usize = 1 << 24;
 
-        let prefix_bits = 2 * prefix_len;
-        let suffix_bits = 2 * suffix_len;
-
-        kv.sort_unstable();
+impl<B: BarcodeT> BarcodeLookupMap<B> {
+    pub fn new(kv: Vec<B>, bclen: u32) -> BarcodeLookupMap<B> {
+        BarcodeLookupMap::with_params(kv, bclen, DEFAULT_MAX_PREFIX_BUCKETS, false)
+    }
 
-        let pref_mask = ((4usize.pow(prefix_len as u32) - 1) as u64) << (suffix_bits);
-        let mut offsets = vec![0; 4usize.pow(prefix_len as u32) + 1];
-        let mut prev_ind = 0xFFFF;
+    /// As `new`, but for barcodes `kv` that are already sorted, which
+    /// avoids sorting them again.
+    pub fn from_sorted(kv: Vec<B>, bclen: u32) -> BarcodeLookupMap<B> {
+        BarcodeLookupMap::with_params(kv, bclen, DEFAULT_MAX_PREFIX_BUCKETS, true)
+    }
 
-        for (n, &v) in kv.iter().enumerate() {
-            let ind = ((v & pref_mask) >> (prefix_bits)) as usize;
-            if ind != prev_ind {
-                for item in offsets.iter_mut().take(ind).skip(prev_ind + 1) {
-                    *item = n;
-                }
-                offsets[ind] = n;
-                prev_ind = ind;
+    /// Build a map over the barcodes `kv`, of length `bclen`.  The barcodes
+    /// are bucketed on a prefix of (at most) half their length, but the
+    /// prefix is shortened as necessary so that there are no more than
+    /// `max_buckets` buckets; lookups then binary search within the
+    /// (larger) buckets.  If `is_sorted` is true, `kv` must already be
+    /// sorted, and isn't sorted again.  Only the low `2 * bclen` bits of
+    /// each barcode are kept.
+    pub fn with_params(
+        mut kv: Vec<B>,
+        bclen: u32,
+        max_buckets: usize,
+        mut is_sorted: bool,
+    ) -> BarcodeLookupMap<B> {
+        let mut prefix_len = (bclen + 1) / 2;
+        while prefix_len > 0
+            && 4usize
+                .checked_pow(prefix_len)
+                .map_or(true, |n| n > max_buckets)
+        {
+            prefix_len -= 1;
+        }
+        let suffix_len = bclen - prefix_len;
+
+        // bits above the barcode would put it out of order with respect
+        // to its prefix bucket
+        if bclen < B::MAX_LEN {
+            let bc_mask = (B::one() << (2 * bclen) as usize) - B::one();
+            if kv.iter().any(|&v| v & !bc_mask != B::zero()) {
+                kv.iter_mut().for_each(|v| *v = *v & bc_mask);
+                is_sorted = false;
             }
         }
-        for item in offsets.iter_mut().skip(prev_ind + 1) {
-            *item = kv.len();
+        if is_sorted {
+            debug_assert!(kv.windows(2).all(|w| w[0] <= w[1]));
+        } else {
+            kv.sort_unstable();
+        }
+
+        // count the barcodes having each prefix, and then turn the
+        // counts into the offset at which each prefix's range begins.
+        let mut offsets = vec![0usize; 4usize.pow(prefix_len) + 1];
+        for &v in &kv {
+            offsets[prefix_bucket(v, prefix_len, suffix_len) + 1] += 1;
+        }
+        let mut total = 0usize;
+        for o in offsets.iter_mut() {
+            total += *o;
+            *o = total;
         }
 
-        //let nbc = kv.len();
         BarcodeLookupMap {
             barcodes: kv,
-            //counts: vec![0usize; nbc],
+            counts: Vec::new(),
             offsets,
             bclen,
-            prefix_len: prefix_len as u32,
+            prefix_len,
             suffix_len,
         }
     }
 
+    /// Build a map over the barcodes `kv` along with a companion array
+    /// `counts` giving the frequency of each barcode.  The counts are
+    /// reordered along with the barcodes, so that `self.counts[i]` is the
+    /// count of `self.barcodes[i]`.
+    pub fn new_with_counts(kv: Vec<B>, counts: Vec<u64>, bclen: u32) -> BarcodeLookupMap<B> {
+        assert_eq!(
+            kv.len(),
+            counts.len(),
+            "must have exactly one count per barcode"
+        );
+        let mut kc: Vec<(B, u64)> = kv.into_iter().zip(counts.into_iter()).collect();
+        kc.sort_unstable();
+        let (kv, counts): (Vec<B>, Vec<u64>) = kc.into_iter().unzip();
+        let mut m = BarcodeLookupMap::from_sorted(kv, bclen);
+        m.counts = counts;
+        m
+    }
+
     #[allow(dead_code)]
-    pub fn barcode_for_idx(&self, idx: usize) -> u64 {
+    pub fn barcode_for_idx(&self, idx: usize) -> B {
         self.barcodes[idx]
     }
 
-    pub fn find_exact(&self, query: u64) -> Option<usize> {
-        let mut ret: Option<usize> = None;
-
-        // extract the prefix we will use to search
-        let suffix_bits = 2 * self.suffix_len;
-        let query_pref = query >> suffix_bits;
-
-        // the range of entries having query_pref as their prefix
-        let qrange = std::ops::Range {
-            start: self.offsets[query_pref as usize],
-            end: self.offsets[(query_pref + 1) as usize],
-        };
-
-        let qs = qrange.start as usize;
+    pub fn find_exact(&self, query: B) -> Option<usize> {
+        find_exact_in(
+            &self.barcodes,
+            &self.offsets,
+            self.prefix_len,
+            self.suffix_len,
+            query,
+        )
+    }
 
-        // if we can, then we return the found barcode and that there was 1 best hit
-        if let Ok(res) = self.barcodes[qrange].binary_search(&query) {
-            ret = Some(qs + res);
+    /// Performs `find_exact` for every barcode in `queries`, placing the
+    /// results in `out` (which is cleared first) in the same order as `queries`.
+    /// Internally, the queries are visited in sorted order, so that lookups
+    /// falling in the same prefix bucket are done together and each binary
+    /// search can start where the previous one in that bucket left off.
+    /// This is faster than calling `find_exact` for each query only if the
+    /// map is too large to stay in cache (see `benches/find_exact_many.rs`).
+    pub fn find_exact_many(&self, queries: &[B], out: &mut Vec<Option<usize>>) {
+        out.clear();
+        out.resize(queries.len(), None);
+
+        // the queries are sorted along with their positions, rather than
+        // sorting the positions by query, so that the sort doesn't go back
+        // to `queries` for every comparison
+        let mut order: Vec<(B, usize)> = queries.iter().copied().zip(0..).collect();
+        order.sort_unstable();
+
+        let mut curr_pref = usize::MAX;
+        let mut lo = 0usize;
+        let mut hi = 0usize;
+        for (query, qi) in order {
+            let query_pref = prefix_bucket(query, self.prefix_len, self.suffix_len);
+            if query_pref != curr_pref {
+                curr_pref = query_pref;
+                lo = self.offsets[query_pref];
+                hi = self.offsets[query_pref + 1];
+            }
+            // since queries are sorted, the next query can't
+            // occur before the position of this one.
+            match self.barcodes[lo..hi].binary_search(&query) {
+                Ok(res) => {
+                    lo += res;
+                    out[qi] = Some(lo);
+                }
+                Err(res) => {
+                    lo += res;
+                }
+            }
         }
-        ret
     }
 
     /// The find function searches for the barcode `query` in the
@@ -193,55 +556,65 @@ impl BarcodeLookupMap {
     /// The parameter `try_exact` controls whether a an exact search is performed
     /// or not.  If this parameter is true, an exact search is performed before
     /// a neighbor search.  Otherwise, the exact search is skipped.
-    pub fn find_neighbors(&self, query: u64, try_exact: bool) -> (Option<usize>, usize) {
-        let mut ret: Option<usize> = None;
-
-        // extract the prefix we will use to search
-        let pref_bits = 2 * self.prefix_len;
-        let suffix_bits = 2 * self.suffix_len;
-        let mut query_pref = query >> suffix_bits;
-        let mut num_neighbors = 0usize;
-
-        // the range of entries having query_pref as their prefix
-        let qrange = std::ops::Range {
-            start: self.offsets[query_pref as usize],
-            end: self.offsets[(query_pref + 1) as usize],
-        };
+    pub fn find_neighbors(&self, query: B, try_exact: bool) -> (Option<usize>, usize) {
+        find_neighbors_in(
+            &self.barcodes,
+            &self.offsets,
+            self.prefix_len,
+            self.suffix_len,
+            query,
+            try_exact,
+        )
+    }
 
-        let qs = qrange.start as usize;
+    /// As `find_neighbors`, but for a `query` in which the bases at the
+    /// positions set in `n_mask` (both bits of each, as produced by
+    /// `utils::pack_kmer_with_ns`) were `N`.  Such a base matches any
+    /// nucleotide without counting as a mismatch, and at most one further
+    /// substitution elsewhere is allowed.  As with `find_neighbors`, if
+    /// `try_exact` is false, the barcodes matching without that substitution
+    /// are skipped.  Queries with more than one `N` are considered
+    /// uncorrectable, and yield `(None, 0)`.
+    pub fn find_neighbors_with_ns(
+        &self,
+        query: B,
+        n_mask: B,
+        try_exact: bool,
+    ) -> (Option<usize>, usize) {
+        match n_mask.count_ones() {
+            0 => return self.find_neighbors(query, try_exact),
+            2 => {}
+            _ => return (None, 0),
+        }
+        let shift = n_mask.trailing_zeros();
+        let candidates =
+            (0..4u64).map(|nucl| (query & !n_mask) | (B::from_u64(nucl) << shift as usize));
 
+        // first (if `try_exact`), the candidates that are an exact match
+        // once the N is filled in
+        let mut ret = None;
+        let mut num_neighbors = 0usize;
         if try_exact {
-            // first, we try to find exactly.
-            // if we can, then we return the found barcode and that there was 1 best hit
-            if let Ok(res) = self.barcodes[qrange.clone()].binary_search(&query) {
-                ret = Some(qs + res);
-                num_neighbors += 1;
-                return (ret, num_neighbors);
+            for c in candidates.clone() {
+                if let Some(idx) = self.find_exact(c) {
+                    ret = Some(idx);
+                    num_neighbors += 1;
+                }
+            }
+            if num_neighbors > 0 {
+                return (ret, num_neighbors.min(2));
             }
         }
 
-        // othwerwise, we fall back to the 1 mismatch search
-        // NOTE: We stop here as soon as we find at most 2 neighbors
-        // for the query.  Thus, we only distinguish between the
-        // the cases where the query has 1 neighbor, or 2 or more neighbors.
-
-        // if we match the prefix exactly, we will look for possible matches
-        // that are 1 mismatch off in the suffix.
-        if !(std::ops::Range::<usize>::is_empty(&qrange)) {
-            // the initial offset of suffixes for this prefix
-            let qs = qrange.start as usize;
-
-            // for each position in the suffix
-            for i in (0..suffix_bits).step_by(2) {
-                let bit_mask = 3 << (i);
-
-                // for each nucleotide
+        // otherwise, allow one substitution in addition to the N, at any
+        // other position.  The barcodes found from different candidates
+        // differ at the N, so none is counted twice, and none is a candidate.
+        let bc_bits = 2 * (self.prefix_len + self.suffix_len);
+        for c in candidates {
+            for i in (0..bc_bits).step_by(2).filter(|&i| i != shift) {
                 for nmod in 1..4 {
-                    let nucl = 0x3 & ((query >> i) + nmod);
-                    let nquery = (query & (!bit_mask)) | (nucl << i);
-
-                    if let Ok(res) = self.barcodes[qrange.clone()].binary_search(&nquery) {
-                        ret = Some(qs + res);
+                    if let Some(idx) = self.find_exact(substitute_base(c, i, nmod)) {
+                        ret = Some(idx);
                         num_neighbors += 1;
                         if num_neighbors >= 2 {
                             return (ret, num_neighbors);
@@ -250,49 +623,222 @@ impl BarcodeLookupMap {
                 }
             }
         }
+        (ret, num_neighbors)
+    }
 
-        {
-            // if we get here we've had either 0 or 1 matches holding the prefix fixed
-            // so we will now hold the suffix fixed and consider possible mutations of the prefix.
+    /// As `find_neighbors`, but additionally returns the Hamming distance
+    /// between `query` and the barcode that was found (0 for an exact match,
+    /// 1 for a single substitution).  The distance is 0 if nothing was found.
+    pub fn find_neighbors_with_distance(
+        &self,
+        query: B,
+        try_exact: bool,
+    ) -> (Option<usize>, usize, u8) {
+        let (ret, num_neighbors) = self.find_neighbors(query, try_exact);
+        let dist = ret.map_or(0, |i| query.hamming_distance(self.barcodes[i]) as u8);
+        (ret, num_neighbors, dist)
+    }
 
-            // for each position in the prefix
-            for i in (suffix_bits..(suffix_bits + pref_bits)).step_by(2) {
-                let bit_mask = 3 << i;
+    /// Like `find_neighbors`, but rather than stopping as soon as 2
+    /// neighbors are found, this considers every barcode 1 mismatch away
+    /// from `query` and returns the index of the one with the highest count
+    /// in `counts` (which must be parallel to `self.barcodes`, e.g. `self.counts`).
+    /// The second element of the result is 0 if there were no neighbors,
+    /// 1 if the most abundant neighbor was unique, and 2 if 2 or more neighbors
+    /// are tied at the highest count.  An exact match is always returned
+    /// as a unique hit.  Barcodes beyond the end of `counts` are taken to
+    /// have a count of 0, so with an empty `counts` (e.g. a map built without
+    /// them) every neighbor ties, as with `find_neighbors`.
+    pub fn find_neighbors_with_counts(&self, query: B, counts: &[u64]) -> (Option<usize>, usize) {
+        if let Some(idx) = self.find_exact(query) {
+            return (Some(idx), 1);
+        }
 
-                // for each nucleotide
-                for nmod in 1..4 {
-                    let nucl = 0x3 & ((query >> i) + nmod);
-                    let nquery = (query & (!bit_mask)) | (nucl << i);
+        let mut best: Option<usize> = None;
+        let mut best_count = 0u64;
+        let mut num_best = 0usize;
+
+        // for each position in the barcode
+        let bc_bits = 2 * (self.prefix_len + self.suffix_len);
+        for i in (0..bc_bits).step_by(2) {
+            // for each nucleotide
+            for nmod in 1..4 {
+                let nquery = substitute_base(query, i, nmod);
+
+                if let Some(idx) = self.find_exact(nquery) {
+                    let c = counts.get(idx).copied().unwrap_or(0);
+                    if best.is_none() || c > best_count {
+                        best = Some(idx);
+                        best_count = c;
+                        num_best = 1;
+                    } else if c == best_count {
+                        num_best += 1;
+                    }
+                }
+            }
+        }
+        (best, num_best.min(2))
+    }
+}
 
-                    query_pref = nquery >> suffix_bits;
+/// The index in a prefix offset table of the bucket of `v`, whose prefix
+/// is the `prefix_len` bases above its lowest `suffix_len`.  Any bits of
+/// `v` above the prefix are ignored, so that a query with stray high bits
+/// can't index past the end of the table.
+#[inline]
+fn prefix_bucket<B: BarcodeT>(v: B, prefix_len: u32, suffix_len: u32) -> usize {
+    let pref = v.checked_shr(2 * suffix_len).unwrap_or_else(B::zero);
+    (pref & ((B::one() << (2 * prefix_len) as usize) - B::one())).as_()
+}
 
-                    let qrange = std::ops::Range {
-                        start: self.offsets[query_pref as usize],
-                        end: self.offsets[(query_pref + 1) as usize],
-                    };
-                    let qs = qrange.start as usize;
-                    if let Ok(res) = self.barcodes[qrange].binary_search(&nquery) {
-                        ret = Some(qs + res);
-                        num_neighbors += 1;
-                        if num_neighbors >= 2 {
-                            return (ret, num_neighbors);
-                        }
+/// `query` with the base at bit `i` advanced by `nmod` (modulo 4), i.e.
+/// one of the substitutions visited by the neighbor searches.
+#[inline]
+fn substitute_base<B: BarcodeT>(query: B, i: u32, nmod: u64) -> B {
+    let base_mask = B::from_u64(3);
+    let nucl = base_mask & ((query >> i as usize) + B::from_u64(nmod));
+    (query & !(base_mask << i as usize)) | (nucl << i as usize)
+}
+
+/// Exact lookup of `query` over a sorted barcode list and its prefix
+/// offset table.  This is shared by the in-memory and memory-mapped
+/// barcode maps, which differ only in where these slices live.
+pub(crate) fn find_exact_in<B: BarcodeT, O: AsPrimitive<usize>>(
+    barcodes: &[B],
+    offsets: &[O],
+    prefix_len: u32,
+    suffix_len: u32,
+    query: B,
+) -> Option<usize> {
+    let mut ret: Option<usize> = None;
+
+    // extract the prefix we will use to search
+    let query_pref = prefix_bucket(query, prefix_len, suffix_len);
+
+    // the range of entries having query_pref as their prefix
+    let qrange: std::ops::Range<usize> = std::ops::Range {
+        start: offsets[query_pref].as_(),
+        end: offsets[query_pref + 1].as_(),
+    };
+
+    let qs = qrange.start as usize;
+
+    // if we can, then we return the found barcode and that there was 1 best hit
+    if let Ok(res) = barcodes[qrange].binary_search(&query) {
+        ret = Some(qs + res);
+    }
+    ret
+}
+
+/// Neighbor search over a sorted barcode list and its prefix offset
+/// table; see [`BarcodeLookupMap::find_neighbors`] for the semantics
+/// of the return value.
+pub(crate) fn find_neighbors_in<B: BarcodeT, O: AsPrimitive<usize>>(
+    barcodes: &[B],
+    offsets: &[O],
+    prefix_len: u32,
+    suffix_len: u32,
+    query: B,
+    try_exact: bool,
+) -> (Option<usize>, usize) {
+    let mut ret: Option<usize> = None;
+
+    // extract the prefix we will use to search
+    let pref_bits = 2 * prefix_len;
+    let suffix_bits = 2 * suffix_len;
+    let mut query_pref = prefix_bucket(query, prefix_len, suffix_len);
+    let mut num_neighbors = 0usize;
+
+    // the range of entries having query_pref as their prefix
+    let qrange: std::ops::Range<usize> = std::ops::Range {
+        start: offsets[query_pref].as_(),
+        end: offsets[query_pref + 1].as_(),
+    };
+
+    let qs = qrange.start as usize;
+
+    if try_exact {
+        // first, we try to find exactly.
+        // if we can, then we return the found barcode and that there was 1 best hit
+        if let Ok(res) = barcodes[qrange.clone()].binary_search(&query) {
+            ret = Some(qs + res);
+            num_neighbors += 1;
+            return (ret, num_neighbors);
+        }
+    }
+
+    // othwerwise, we fall back to the 1 mismatch search
+    // NOTE: We stop here as soon as we find at most 2 neighbors
+    // for the query.  Thus, we only distinguish between the
+    // the cases where the query has 1 neighbor, or 2 or more neighbors.
+
+    // if we match the prefix exactly, we will look for possible matches
+    // that are 1 mismatch off in the suffix.
+    if !(std::ops::Range::<usize>::is_empty(&qrange)) {
+        // the initial offset of suffixes for this prefix
+        let qs = qrange.start as usize;
+
+        // for each position in the suffix
+        for i in (0..suffix_bits).step_by(2) {
+            // for each nucleotide
+            for nmod in 1..4 {
+                let nquery = substitute_base(query, i, nmod);
+
+                if let Ok(res) = barcodes[qrange.clone()].binary_search(&nquery) {
+                    ret = Some(qs + res);
+                    num_neighbors += 1;
+                    if num_neighbors >= 2 {
+                        return (ret, num_neighbors);
                     }
                 }
             }
         }
+    }
 
-        (ret, num_neighbors)
+    {
+        // if we get here we've had either 0 or 1 matches holding the prefix fixed
+        // so we will now hold the suffix fixed and consider possible mutations of the prefix.
+
+        // for each position in the prefix
+        for i in (suffix_bits..(suffix_bits + pref_bits)).step_by(2) {
+            // for each nucleotide
+            for nmod in 1..4 {
+                let nquery = substitute_base(query, i, nmod);
+
+                query_pref = prefix_bucket(nquery, prefix_len, suffix_len);
+
+                let qrange: std::ops::Range<usize> = std::ops::Range {
+                    start: offsets[query_pref].as_(),
+                    end: offsets[query_pref + 1].as_(),
+                };
+                let qs = qrange.start as usize;
+                if let Ok(res) = barcodes[qrange].binary_search(&nquery) {
+                    ret = Some(qs + res);
+                    num_neighbors += 1;
+                    if num_neighbors >= 2 {
+                        return (ret, num_neighbors);
+                    }
+                }
+            }
+        }
     }
+
+    (ret, num_neighbors)
 }
 
 impl CorrectedCbChunk {
-    pub fn from_label_and_counter(corrected_bc_in: u64, num_remain: u32) -> CorrectedCbChunk {
+    pub fn from_label_and_counter(
+        corrected_bc_in: u64,
+        num_remain: u32,
+        params: &CollateParams,
+    ) -> CorrectedCbChunk {
         let mut cc = CorrectedCbChunk {
             remaining_records: num_remain,
             corrected_bc: corrected_bc_in,
             nrec: 0u32,
-            data: Cursor::new(Vec::<u8>::with_capacity((num_remain * 24) as usize))
+            data: Cursor::new(Vec::<u8>::with_capacity(
+                num_remain as usize * params.bytes_per_record_hint,
+            )),
             //umis: Vec::<u64>::with_capacity(num_remain as usize),
             //ref_offsets: Vec::<u32>::with_capacity(num_remain as usize),
             //ref_ids: Vec::<u32>::with_capacity(3 * num_remain as usize),
@@ -302,6 +848,37 @@ impl CorrectedCbChunk {
         cc.data.write_all(&dummy.to_le_bytes()).unwrap();
         cc
     }
+
+    /// Iterate over the `(barcode, umi, refs)` of the records written to this
+    /// chunk so far, where `bct` and `umit` are the types with which they were
+    /// written.  The alignment entries in `refs` are returned exactly as they
+    /// were written.  A `U128` barcode or umi that doesn't fit in a u64 is
+    /// yielded as an `InvalidData` error.
+    pub fn records<'a>(
+        &'a self,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> impl Iterator<Item = std::io::Result<(u64, u64, Vec<u32>)>> + 'a {
+        let buf = &self.data.get_ref()[..];
+        let bct = *bct;
+        let umit = *umit;
+        let size_of_u32 = std::mem::size_of::<u32>();
+        // skip the chunk header
+        let mut offset = 2 * size_of_u32;
+        (0..self.nrec).map(move |_| {
+            let na = buf.pread::<u32>(offset).unwrap() as usize;
+            offset += size_of_u32;
+            let bc = pread_into_u64(buf, offset, &bct);
+            offset += bct.bytes_for_type();
+            let umi = pread_into_u64(buf, offset, &umit);
+            offset += umit.bytes_for_type();
+            let refs = (0..na)
+                .map(|i| buf.pread::<u32>(offset + i * size_of_u32).unwrap())
+                .collect();
+            offset += na * size_of_u32;
+            Ok((bc?, umi?, refs))
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -326,12 +903,55 @@ impl GlobalEqCellList {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RadIntId {
     U8,
     U16,
     U32,
     U64,
+    U128,
+}
+
+/// An integer read by `RadIntId::read_exact_width`, tagged with the
+/// width it was stored with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntVal {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+}
+
+impl IntVal {
+    /// The value as a u64; a `U128` is truncated to its low 64 bits.
+    pub fn as_u64(&self) -> u64 {
+        match *self {
+            IntVal::U8(v) => v as u64,
+            IntVal::U16(v) => v as u64,
+            IntVal::U32(v) => v as u64,
+            IntVal::U64(v) => v,
+            IntVal::U128(v) => v as u64,
+        }
+    }
+
+    pub fn as_u128(&self) -> u128 {
+        match *self {
+            IntVal::U128(v) => v,
+            _ => self.as_u64() as u128,
+        }
+    }
+
+    /// The type of this value.
+    pub fn int_type(&self) -> RadIntId {
+        match self {
+            IntVal::U8(_) => RadIntId::U8,
+            IntVal::U16(_) => RadIntId::U16,
+            IntVal::U32(_) => RadIntId::U32,
+            IntVal::U64(_) => RadIntId::U64,
+            IntVal::U128(_) => RadIntId::U128,
+        }
+    }
 }
 
 pub trait PrimitiveInteger:
@@ -339,6 +959,7 @@ pub trait PrimitiveInteger:
     + AsPrimitive<u16>
     + AsPrimitive<u32>
     + AsPrimitive<u64>
+    + AsPrimitive<u128>
     + AsPrimitive<usize>
     + AsPrimitive<i8>
     + AsPrimitive<i16>
@@ -353,6 +974,7 @@ impl<
             + AsPrimitive<u16>
             + AsPrimitive<u32>
             + AsPrimitive<u64>
+            + AsPrimitive<u128>
             + AsPrimitive<usize>
             + AsPrimitive<i8>
             + AsPrimitive<i16>
@@ -363,13 +985,91 @@ impl<
 {
 }
 
+/// An unsigned integer type holding a barcode packed at 2 bits per base,
+/// as stored in `ReadRecord::bc` and looked up in a `BarcodeLookupMap`.
+/// A `u64` holds barcodes of up to 32 bases; a `u128` (stored in a file as
+/// `RadIntId::U128`) holds the longer composite barcodes of combinatorial
+/// indexing chemistries, of up to 64 bases.  Records, chunks, the lookup
+/// map, the bucket collation and `RadWriter` are generic over it; the
+/// permit lists and correction maps of `generate-permit-list`, `collate`
+/// and `quant` still hold u64s, so those tools reject files with longer
+/// barcodes (see `FileTags::require_u64_barcodes`).
+pub trait BarcodeT:
+    num::PrimInt
+    + num::traits::CheckedShr
+    + PrimitiveInteger
+    + std::hash::Hash
+    + std::fmt::Debug
+    + Default
+    + Send
+    + Sync
+    + 'static
+{
+    /// The length, in bases, of the longest barcode this type can hold.
+    const MAX_LEN: u32;
+
+    fn from_u64(v: u64) -> Self;
+
+    /// Read a barcode stored as an integer of type `rt` from `reader`.
+    /// It is an `InvalidData` error if the value doesn't fit in `Self`.
+    fn read_from<R: Read>(reader: &mut R, rt: &RadIntId) -> std::io::Result<Self>;
+
+    /// The number of bases at which `self` and `other` differ.
+    fn hamming_distance(self, other: Self) -> u32;
+}
+
+impl BarcodeT for u64 {
+    const MAX_LEN: u32 = 32;
+
+    fn from_u64(v: u64) -> Self {
+        v
+    }
+
+    fn read_from<R: Read>(reader: &mut R, rt: &RadIntId) -> std::io::Result<Self> {
+        rt.read_from(reader)
+    }
+
+    fn hamming_distance(self, other: Self) -> u32 {
+        utils::hamming_distance_packed(self, other)
+    }
+}
+
+impl BarcodeT for u128 {
+    const MAX_LEN: u32 = 64;
+
+    fn from_u64(v: u64) -> Self {
+        v as u128
+    }
+
+    fn read_from<R: Read>(reader: &mut R, rt: &RadIntId) -> std::io::Result<Self> {
+        rt.read_u128_from(reader)
+    }
+
+    fn hamming_distance(self, other: Self) -> u32 {
+        utils::hamming_distance_packed_u128(self, other)
+    }
+}
+
 impl RadIntId {
+    /// The RAD type id of this integer type (the inverse of
+    /// `decode_int_type_tag`).
+    pub fn type_tag(&self) -> u8 {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 3,
+            Self::U64 => 4,
+            Self::U128 => 9,
+        }
+    }
+
     pub fn bytes_for_type(&self) -> usize {
         match self {
             Self::U8 => std::mem::size_of::<u8>(),
             Self::U16 => std::mem::size_of::<u16>(),
             Self::U32 => std::mem::size_of::<u32>(),
             Self::U64 => std::mem::size_of::<u64>(),
+            Self::U128 => std::mem::size_of::<u128>(),
         }
     }
 
@@ -401,8 +1101,60 @@ impl RadIntId {
                 let vo: u64 = v.as_();
                 owriter.write_all(&vo.to_le_bytes())
             }
+            Self::U128 => {
+                let vo: u128 = v.as_();
+                owriter.write_all(&vo.to_le_bytes())
+            }
         }
     }
+
+    /// Read a value of this type from `reader`, widened to a u64.  This
+    /// is the inverse of `write_to`.  A `U128` value that doesn't fit in
+    /// a u64 is an `InvalidData` error.
+    pub fn read_from<R: Read>(&self, reader: &mut R) -> std::io::Result<u64> {
+        match self.read_exact_width(reader)? {
+            IntVal::U128(v) => u64::try_from(v).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("u128 value {:#x} does not fit in a u64", v),
+                )
+            }),
+            v => Ok(v.as_u64()),
+        }
+    }
+
+    /// As `read_from`, but widened to a u128, so that any value fits.
+    pub fn read_u128_from<R: Read>(&self, reader: &mut R) -> std::io::Result<u128> {
+        Ok(self.read_exact_width(reader)?.as_u128())
+    }
+
+    /// Read a value of this type from `reader`, keeping its width.
+    pub fn read_exact_width<R: Read>(&self, reader: &mut R) -> std::io::Result<IntVal> {
+        let mut rbuf = [0u8; 16];
+        let v = match self {
+            Self::U8 => {
+                reader.read_exact(&mut rbuf[0..1])?;
+                IntVal::U8(rbuf[0])
+            }
+            Self::U16 => {
+                reader.read_exact(&mut rbuf[0..2])?;
+                IntVal::U16(rbuf.pread::<u16>(0).unwrap())
+            }
+            Self::U32 => {
+                reader.read_exact(&mut rbuf[0..4])?;
+                IntVal::U32(rbuf.pread::<u32>(0).unwrap())
+            }
+            Self::U64 => {
+                reader.read_exact(&mut rbuf[0..8])?;
+                IntVal::U64(rbuf.pread::<u64>(0).unwrap())
+            }
+            Self::U128 => {
+                reader.read_exact(&mut rbuf)?;
+                IntVal::U128(u128::from_le_bytes(rbuf))
+            }
+        };
+        Ok(v)
+    }
 }
 
 pub struct ChunkConfig {
@@ -411,15 +1163,35 @@ pub struct ChunkConfig {
     pub umi_type: u8,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RadType {
     Bool,
     U8,
     U16,
     U32,
     U64,
+    /// A 128-bit unsigned integer (type id 9), as used for barcodes of
+    /// more than 32 bases.
+    U128,
     F32,
     F64,
+    /// A UTF-8 string, preceded by its length in bytes as a u16.
+    String,
+}
+
+impl RadType {
+    /// The number of bytes a value of this type takes, or `None` for a
+    /// `String`, whose size is given by its length prefix.
+    pub fn fixed_size(&self) -> Option<usize> {
+        match self {
+            RadType::Bool | RadType::U8 => Some(1),
+            RadType::U16 => Some(2),
+            RadType::U32 | RadType::F32 => Some(4),
+            RadType::U64 | RadType::F64 => Some(8),
+            RadType::U128 => Some(16),
+            RadType::String => None,
+        }
+    }
 }
 
 pub fn encode_type_tag(type_tag: RadType) -> Option<u8> {
@@ -431,20 +1203,107 @@ pub fn encode_type_tag(type_tag: RadType) -> Option<u8> {
         RadType::U64 => Some(4),
         RadType::F32 => Some(5),
         RadType::F64 => Some(6),
+        RadType::String => Some(8),
+        RadType::U128 => Some(9),
         //_ => None,
     }
 }
 
+/// The inverse of `encode_type_tag`; returns the `RadType` for any
+/// valid type id, or `None` if `type_id` is not a known type.
+pub fn decode_type_tag(type_id: u8) -> Option<RadType> {
+    match type_id {
+        0 => Some(RadType::Bool),
+        1 => Some(RadType::U8),
+        2 => Some(RadType::U16),
+        3 => Some(RadType::U32),
+        4 => Some(RadType::U64),
+        5 => Some(RadType::F32),
+        6 => Some(RadType::F64),
+        8 => Some(RadType::String),
+        9 => Some(RadType::U128),
+        _ => None,
+    }
+}
+
+/// Like `decode_type_tag`, but only for the integer types (ids 1--4 and 9);
+/// returns `None` for any other type id, including strings.
 pub fn decode_int_type_tag(type_id: u8) -> Option<RadIntId> {
     match type_id {
         1 => Some(RadIntId::U8),
         2 => Some(RadIntId::U16),
         3 => Some(RadIntId::U32),
         4 => Some(RadIntId::U64),
+        9 => Some(RadIntId::U128),
         _ => None,
     }
 }
 
+impl std::fmt::Display for RadType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            RadType::Bool => "bool",
+            RadType::U8 => "u8",
+            RadType::U16 => "u16",
+            RadType::U32 => "u32",
+            RadType::U64 => "u64",
+            RadType::U128 => "u128",
+            RadType::F32 => "f32",
+            RadType::F64 => "f64",
+            RadType::String => "string",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Parses the (case-insensitive) spelling written by `Display`.
+impl std::str::FromStr for RadType {
+    type Err = RadError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bool" => Ok(RadType::Bool),
+            "u8" => Ok(RadType::U8),
+            "u16" => Ok(RadType::U16),
+            "u32" => Ok(RadType::U32),
+            "u64" => Ok(RadType::U64),
+            "u128" => Ok(RadType::U128),
+            "f32" => Ok(RadType::F32),
+            "f64" => Ok(RadType::F64),
+            "string" => Ok(RadType::String),
+            _ => Err(RadError::UnknownTypeName(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for RadIntId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            RadIntId::U8 => "u8",
+            RadIntId::U16 => "u16",
+            RadIntId::U32 => "u32",
+            RadIntId::U64 => "u64",
+            RadIntId::U128 => "u128",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Parses the (case-insensitive) spelling written by `Display`; only
+/// the integer types are accepted.
+impl std::str::FromStr for RadIntId {
+    type Err = RadError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "u8" => Ok(RadIntId::U8),
+            "u16" => Ok(RadIntId::U16),
+            "u32" => Ok(RadIntId::U32),
+            "u64" => Ok(RadIntId::U64),
+            "u128" => Ok(RadIntId::U128),
+            _ => Err(RadError::UnknownTypeName(s.to_string())),
+        }
+    }
+}
+
 /*
 pub fn collect_records<T: Read>(
     reader: &mut BufReader<T>,
@@ -470,76 +1329,233 @@ pub fn collect_records<T: Read>(
 }
 */
 
-fn read_into_u64<T: Read>(reader: &mut T, rt: &RadIntId) -> u64 {
-    let mut rbuf = [0u8; 8];
-    let v: u64;
+/// Read the integer of type `rt` at `offset` within `buf`, widened to a u128.
+pub(crate) fn pread_into_u128(buf: &[u8], offset: usize, rt: &RadIntId) -> u128 {
     match rt {
-        RadIntId::U8 => {
-            reader.read_exact(&mut rbuf[0..1]).unwrap();
-            v = rbuf.pread::<u8>(0).unwrap() as u64;
-        }
-        RadIntId::U16 => {
-            reader.read_exact(&mut rbuf[0..2]).unwrap();
-            v = rbuf.pread::<u16>(0).unwrap() as u64;
-        }
-        RadIntId::U32 => {
-            reader.read_exact(&mut rbuf[0..4]).unwrap();
-            v = rbuf.pread::<u32>(0).unwrap() as u64;
-        }
-        RadIntId::U64 => {
-            reader.read_exact(&mut rbuf[0..8]).unwrap();
-            v = rbuf.pread::<u64>(0).unwrap();
-        }
+        RadIntId::U8 => buf.pread::<u8>(offset).unwrap() as u128,
+        RadIntId::U16 => buf.pread::<u16>(offset).unwrap() as u128,
+        RadIntId::U32 => buf.pread::<u32>(offset).unwrap() as u128,
+        RadIntId::U64 => buf.pread::<u64>(offset).unwrap() as u128,
+        RadIntId::U128 => u128::from_le_bytes(buf[offset..offset + 16].try_into().unwrap()),
     }
-    v
 }
 
-impl ReadRecord {
+/// Read the integer of type `rt` at `offset` within `buf`, widened to a u64.
+/// A `U128` value that doesn't fit in a u64 is an `InvalidData` error.
+pub(crate) fn pread_into_u64(buf: &[u8], offset: usize, rt: &RadIntId) -> std::io::Result<u64> {
+    let v = pread_into_u128(buf, offset, rt);
+    u64::try_from(v).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("u128 value {:#x} does not fit in a u64", v),
+        )
+    })
+}
+
+impl<B: BarcodeT> ReadRecord<B> {
     pub fn is_empty(&self) -> bool {
         self.refs.is_empty()
     }
 
-    pub fn from_bytes<T: Read>(reader: &mut T, bct: &RadIntId, umit: &RadIntId) -> Self {
-        let mut rbuf = [0u8; 255];
+    /// Iterate over the (reference id, strand) pairs of the alignments of
+    /// this record.  If no orientation information is present (i.e. `dirs`
+    /// is empty) then every strand is reported as `Strand::Unknown`.
+    pub fn alignments(&self) -> impl Iterator<Item = (u32, Strand)> + '_ {
+        self.refs
+            .iter()
+            .enumerate()
+            .map(move |(i, r)| match self.dirs.get(i) {
+                Some(true) => (*r, Strand::Forward),
+                Some(false) => (*r, Strand::Reverse),
+                None => (*r, Strand::Unknown),
+            })
+    }
+
+    /// Given `refs` holding raw alignment entries (reference id plus
+    /// orientation bits) in the layout `layout`, sort them by reference id,
+    /// and then split the orientation out into `dirs` so that `dirs[i]` is
+    /// the orientation of the alignment to `refs[i]`, and, for paired-end
+    /// layouts, that of the mate into `mate_dirs`.
+    #[inline]
+    fn sort_and_split_ori(&mut self, layout: &AlnLayout) {
+        let mask = layout.ref_mask();
+        // make sure these are sorted in this step.
+        self.refs.sort_unstable_by_key(|v| v & mask);
+        self.dirs.clear();
+        self.mate_dirs.clear();
+        for v in self.refs.iter_mut() {
+            self.dirs.push((*v & utils::MASK_LOWER_31_U32) != 0);
+            if layout.paired {
+                self.mate_dirs.push((*v & utils::MASK_MATE_ORI_U32) != 0);
+            }
+            *v &= mask;
+        }
+    }
+
+    /// Move the orientation bit of the mate, which readers unaware of the
+    /// layout of the file leave in `refs`, out into `mate_dirs`, for a
+    /// record of a paired-end file (see `AlnLayout`).
+    pub fn split_mate_ori(&mut self) {
+        self.mate_dirs.clear();
+        for v in self.refs.iter_mut() {
+            self.mate_dirs.push((*v & utils::MASK_MATE_ORI_U32) != 0);
+            *v &= utils::MASK_LOWER_30_U32;
+        }
+    }
+
+    // Write the reference ids of this record, with the orientation bit of
+    // the mate restored from `mate_dirs` (if any), as collation leaves them.
+    fn write_bare_refs<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        if self.mate_dirs.is_empty() {
+            return writer.write_all(as_u8_slice(&self.refs[..]));
+        }
+        for (r, mate) in self.refs.iter().zip(self.mate_dirs.iter()) {
+            let v = if *mate {
+                r | utils::MASK_MATE_ORI_U32
+            } else {
+                *r
+            };
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes<T: Read>(reader: &mut T, bct: &RadIntId, umit: &RadIntId) -> Self {
+        Self::from_bytes_checked(reader, bct, umit).unwrap()
+    }
+
+    /// Like `from_bytes`, but returns an error, rather than panicking, if
+    /// the input ends before the record does, or the record declares more
+    /// than `MAX_ALIGNMENTS_PER_RECORD` alignments.
+    pub fn from_bytes_checked<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> std::io::Result<Self> {
+        let mut rbuf = [0u8; 4];
 
-        reader.read_exact(&mut rbuf[0..4]).unwrap();
+        reader.read_exact(&mut rbuf)?;
         let na = rbuf.pread::<u32>(0).unwrap();
-        let bc = read_into_u64(reader, bct);
-        let umi = read_into_u64(reader, umit);
+        check_limit_io(
+            "alignment count",
+            na as u64,
+            MAX_ALIGNMENTS_PER_RECORD as u64,
+        )?;
+        let bc = B::read_from(reader, bct)?;
+        let umi = umit.read_from(reader)?;
 
         let mut rec = Self {
             bc,
             umi,
             dirs: Vec::with_capacity(na as usize),
+            mate_dirs: Vec::new(),
             refs: Vec::with_capacity(na as usize),
+            tags: None,
         };
 
-        //println!("number of records : {:?}",na);
-
         for _ in 0..(na as usize) {
-            reader.read_exact(&mut rbuf[0..4]).unwrap();
+            reader.read_exact(&mut rbuf)?;
             let v = rbuf.pread::<u32>(0).unwrap();
             let dir = (v & MASK_LOWER_31_U32) != 0;
             rec.dirs.push(dir);
             rec.refs.push(v & MASK_TOP_BIT_U32);
         }
 
-        rec
+        Ok(rec)
+    }
+
+    /// Parse the next record from `reader` into `self`, as `from_bytes_checked`
+    /// would, but reusing the existing `dirs` and `refs` vectors rather than
+    /// allocating new ones.
+    pub fn read_into<T: Read>(
+        &mut self,
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> std::io::Result<()> {
+        let (bc, umi, na) = Self::from_bytes_record_header_checked(reader, bct, umit)?;
+        self.bc = bc;
+        self.umi = umi;
+        self.dirs.clear();
+        self.mate_dirs.clear();
+        self.refs.clear();
+
+        let mut rbuf = [0u8; 4];
+        for _ in 0..(na as usize) {
+            reader.read_exact(&mut rbuf)?;
+            let v = rbuf.pread::<u32>(0).unwrap();
+            self.dirs.push((v & MASK_LOWER_31_U32) != 0);
+            self.refs.push(v & MASK_TOP_BIT_U32);
+        }
+        Ok(())
+    }
+
+    /// Parse a record as `from_bytes_checked` does, but retain only those
+    /// alignments whose reference id is in `keep`.  All `na` alignments are
+    /// always read, so `reader` is left at the start of the next record even
+    /// if the returned record is empty.
+    pub fn from_bytes_filtered<T: Read, S: BuildHasher>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        keep: &HashSet<u32, S>,
+    ) -> std::io::Result<Self> {
+        let (bc, umi, na) = Self::from_bytes_record_header_checked(reader, bct, umit)?;
+
+        let mut rbuf = [0u8; 4];
+        let mut rec = Self {
+            bc,
+            umi,
+            dirs: Vec::new(),
+            mate_dirs: Vec::new(),
+            refs: Vec::new(),
+            tags: None,
+        };
+
+        for _ in 0..(na as usize) {
+            reader.read_exact(&mut rbuf)?;
+            let v = rbuf.pread::<u32>(0).unwrap();
+            let r = v & MASK_TOP_BIT_U32;
+            if keep.contains(&r) {
+                rec.dirs.push((v & MASK_LOWER_31_U32) != 0);
+                rec.refs.push(r);
+            }
+        }
+
+        Ok(rec)
     }
 
     pub fn from_bytes_record_header<T: Read>(
         reader: &mut T,
         bct: &RadIntId,
         umit: &RadIntId,
-    ) -> (u64, u64, u32) {
+    ) -> (B, u64, u32) {
+        Self::from_bytes_record_header_checked(reader, bct, umit).unwrap()
+    }
+
+    /// Like `from_bytes_record_header`, but returns an error, rather than
+    /// panicking, if the input ends before the record header does, or the
+    /// record declares more than `MAX_ALIGNMENTS_PER_RECORD` alignments.
+    pub fn from_bytes_record_header_checked<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> std::io::Result<(B, u64, u32)> {
         let mut rbuf = [0u8; 4];
-        reader.read_exact(&mut rbuf).unwrap();
-        let na = u32::from_le_bytes(rbuf); //.pread::<u32>(0).unwrap();
-        let bc = read_into_u64(reader, bct);
-        let umi = read_into_u64(reader, umit);
-        (bc, umi, na)
+        reader.read_exact(&mut rbuf)?;
+        let na = u32::from_le_bytes(rbuf);
+        check_limit_io(
+            "alignment count",
+            na as u64,
+            MAX_ALIGNMENTS_PER_RECORD as u64,
+        )?;
+        let bc = B::read_from(reader, bct)?;
+        let umi = umit.read_from(reader)?;
+        Ok((bc, umi, na))
     }
+}
 
+impl ReadRecord {
     pub fn from_bytes_with_header_keep_ori<T: Read>(
         reader: &mut T,
         bc: u64,
@@ -547,16 +1563,59 @@ impl ReadRecord {
         na: u32,
         expected_ori: &Strand,
     ) -> Self {
-        let mut rbuf = [0u8; 255];
         let mut rec = Self {
             bc,
             umi,
-            dirs: Vec::with_capacity(na as usize),
-            refs: Vec::with_capacity(na as usize),
+            dirs: Vec::with_capacity(prealloc(na as u64)),
+            mate_dirs: Vec::new(),
+            refs: Vec::with_capacity(prealloc(na as u64)),
+            tags: None,
         };
+        rec.read_with_header_keep_ori_into(reader, bc, umi, na, expected_ori);
+        rec
+    }
+
+    /// Like `from_bytes_with_header_keep_ori`, but parses into `self`,
+    /// reusing its `dirs` and `refs` vectors rather than allocating new ones.
+    pub fn read_with_header_keep_ori_into<T: Read>(
+        &mut self,
+        reader: &mut T,
+        bc: u64,
+        umi: u64,
+        na: u32,
+        expected_ori: &Strand,
+    ) {
+        self.read_with_header_keep_ori_into_checked(
+            reader,
+            bc,
+            umi,
+            na,
+            &AlnLayout::default(),
+            expected_ori,
+        )
+        .unwrap()
+    }
+
+    /// Like `read_with_header_keep_ori_into`, but for alignments (holding
+    /// just a reference id) in the layout `layout`, and returns an error,
+    /// rather than panicking, if the input ends before the `na` alignments
+    /// do.
+    pub fn read_with_header_keep_ori_into_checked<T: Read>(
+        &mut self,
+        reader: &mut T,
+        bc: u64,
+        umi: u64,
+        na: u32,
+        layout: &AlnLayout,
+        expected_ori: &Strand,
+    ) -> std::io::Result<()> {
+        let mut rbuf = [0u8; 4];
+        self.bc = bc;
+        self.umi = umi;
+        self.refs.clear();
 
         for _ in 0..(na as usize) {
-            reader.read_exact(&mut rbuf[0..4]).unwrap();
+            reader.read_exact(&mut rbuf)?;
             let v = rbuf.pread::<u32>(0).unwrap();
 
             // fw if the leftmost bit is 1, otherwise rc
@@ -567,13 +1626,14 @@ impl ReadRecord {
             };
 
             if expected_ori.same(&strand) || expected_ori.is_unknown() {
-                rec.refs.push(v & utils::MASK_TOP_BIT_U32);
+                // keep the orientation bit for now so that it
+                // travels along with the reference id when sorting
+                self.refs.push(v);
             }
         }
 
-        // make sure these are sorted in this step.
-        quickersort::sort(&mut rec.refs[..]);
-        rec
+        self.sort_and_split_ori(layout);
+        Ok(())
     }
 
     pub fn from_bytes_keep_ori<T: Read>(
@@ -582,24 +1642,84 @@ impl ReadRecord {
         umit: &RadIntId,
         expected_ori: &Strand,
     ) -> Self {
-        let mut rbuf = [0u8; 255];
+        ReadRecord::from_bytes_keep_ori_checked(reader, bct, umit, expected_ori).unwrap()
+    }
 
-        reader.read_exact(&mut rbuf[0..4]).unwrap();
-        let na = rbuf.pread::<u32>(0).unwrap();
+    /// Like `from_bytes_keep_ori`, but returns an error, rather than
+    /// panicking, if the input ends before the record does, or the record
+    /// declares more than `MAX_ALIGNMENTS_PER_RECORD` alignments.
+    pub fn from_bytes_keep_ori_checked<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        expected_ori: &Strand,
+    ) -> std::io::Result<Self> {
+        ReadRecord::from_bytes_keep_ori_filtered_checked(
+            reader,
+            bct,
+            umit,
+            &AlnLayout::default(),
+            expected_ori,
+            |_| true,
+        )
+    }
+
+    /// Like `from_bytes_keep_ori`, but for records whose alignments have
+    /// the layout `layout`, keeping only the alignments for which `keep`
+    /// returns true (e.g. to drop those whose score is too low).  Fields
+    /// of the layout beyond the reference id and orientation are passed to
+    /// `keep`, but not retained in the record.
+    pub fn from_bytes_keep_ori_filtered<T: Read, F: FnMut(&AlnEntry) -> bool>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        layout: &AlnLayout,
+        expected_ori: &Strand,
+        keep: F,
+    ) -> Self {
+        ReadRecord::from_bytes_keep_ori_filtered_checked(
+            reader,
+            bct,
+            umit,
+            layout,
+            expected_ori,
+            keep,
+        )
+        .unwrap()
+    }
 
-        let bc = read_into_u64(reader, bct);
-        let umi = read_into_u64(reader, umit);
+    /// Like `from_bytes_keep_ori_filtered`, but returns an error, rather
+    /// than panicking, on malformed input (see `from_bytes_keep_ori_checked`).
+    pub fn from_bytes_keep_ori_filtered_checked<T: Read, F: FnMut(&AlnEntry) -> bool>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        layout: &AlnLayout,
+        expected_ori: &Strand,
+        mut keep: F,
+    ) -> std::io::Result<Self> {
+        let (bc, umi, na) = ReadRecord::from_bytes_record_header_checked(reader, bct, umit)?;
 
         let mut rec = Self {
             bc,
             umi,
             dirs: Vec::with_capacity(na as usize),
+            mate_dirs: Vec::new(),
             refs: Vec::with_capacity(na as usize),
+            tags: None,
         };
 
+        let mut rbuf = [0u8; 4];
+        let mut next_field = |present: bool, reader: &mut T| -> std::io::Result<Option<u32>> {
+            if present {
+                reader.read_exact(&mut rbuf)?;
+                Ok(Some(u32::from_le_bytes(rbuf)))
+            } else {
+                Ok(None)
+            }
+        };
         for _ in 0..(na as usize) {
-            reader.read_exact(&mut rbuf[0..4]).unwrap();
-            let v = rbuf.pread::<u32>(0).unwrap();
+            let v = next_field(true, reader)?.unwrap();
 
             // fw if the leftmost bit is 1, otherwise rc
             let strand = if (v & utils::MASK_LOWER_31_U32) > 0 {
@@ -607,817 +1727,8322 @@ impl ReadRecord {
             } else {
                 Strand::Reverse
             };
+            let entry = AlnEntry {
+                ref_id: v & layout.ref_mask(),
+                strand,
+                pos: next_field(layout.pos, reader)?,
+                frag_len: next_field(layout.frag_len, reader)?,
+                score: next_field(layout.score, reader)?,
+            };
 
-            if expected_ori.same(&strand) || expected_ori.is_unknown() {
-                rec.refs.push(v & utils::MASK_TOP_BIT_U32);
+            if (expected_ori.same(&strand) || expected_ori.is_unknown()) && keep(&entry) {
+                // keep the orientation bit for now so that it
+                // travels along with the reference id when sorting
+                rec.refs.push(v);
             }
         }
 
-        // make sure these are sorted in this step.
-        quickersort::sort(&mut rec.refs[..]);
-        rec
+        rec.sort_and_split_ori(layout);
+        Ok(rec)
     }
-}
 
-#[inline]
-pub fn dump_chunk(v: &mut CorrectedCbChunk, owriter: &Mutex<BufWriter<File>>) {
-    v.data.set_position(0);
-    let nbytes = (v.data.get_ref().len()) as u32;
-    let nrec = v.nrec;
-    v.data.write_all(&nbytes.to_le_bytes()).unwrap();
-    v.data.write_all(&nrec.to_le_bytes()).unwrap();
-    owriter.lock().unwrap().write_all(v.data.get_ref()).unwrap();
-}
+    /// Read a record, placing the ids of its forward and reverse-complement
+    /// alignments in separate vectors, so that callers needing both
+    /// orientations don't have to parse the record twice.  Returns an error
+    /// if the input ends before the record does, or the record declares more
+    /// than `MAX_ALIGNMENTS_PER_RECORD` alignments.
+    pub fn from_bytes_split_ori<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> std::io::Result<SplitOriRecord> {
+        let (bc, umi, na) = Self::from_bytes_record_header_checked(reader, bct, umit)?;
 
-pub fn collate_temporary_bucket_twopass<T: Read + Seek, U: Write>(
-    reader: &mut BufReader<T>,
-    bct: &RadIntId,
-    umit: &RadIntId,
-    nrec: u32,
-    owriter: &Mutex<U>,
-    compress: bool,
-    cb_byte_map: &mut HashMap<u64, TempCellInfo, ahash::RandomState>,
-) -> usize {
-    let mut tbuf = vec![0u8; 65536];
-    let mut total_bytes = 0usize;
-    let header_size = 2 * std::mem::size_of::<u32>() as u64;
-    let size_of_u32 = std::mem::size_of::<u32>();
-    let size_of_bc = bct.bytes_for_type();
-    let size_of_umi = umit.bytes_for_type();
+        let mut rbuf = [0u8; 4];
+        let mut rec = SplitOriRecord {
+            bc,
+            umi,
+            fw_refs: Vec::new(),
+            rc_refs: Vec::new(),
+        };
 
-    let calc_record_bytes = |num_aln: usize| -> usize {
-        size_of_u32 + size_of_bc + size_of_umi + (size_of_u32 * num_aln)
-    };
+        for _ in 0..(na as usize) {
+            reader.read_exact(&mut rbuf)?;
+            let v = rbuf.pread::<u32>(0).unwrap();
 
-    // read each record
-    for _ in 0..(nrec as usize) {
-        // read the header of the record
-        // we don't bother reading the whole thing here
-        // because we will just copy later as need be
-        let tup = ReadRecord::from_bytes_record_header(reader, &bct, &umit);
+            // fw if the leftmost bit is 1, otherwise rc
+            if (v & utils::MASK_LOWER_31_U32) > 0 {
+                rec.fw_refs.push(v & utils::MASK_TOP_BIT_U32);
+            } else {
+                rec.rc_refs.push(v & utils::MASK_TOP_BIT_U32);
+            }
+        }
 
-        // get the entry for this chunk, or create a new one
-        let v = cb_byte_map.entry(tup.0).or_insert(TempCellInfo {
-            offset: header_size,
-            nbytes: header_size as u32,
-            nrec: 0_u32,
-        });
+        rec.fw_refs.sort_unstable();
+        rec.rc_refs.sort_unstable();
+        Ok(rec)
+    }
+}
 
-        // read the alignment records from the input file
-        let na = tup.2 as usize;
-        let req_size = size_of_u32 * na;
-        if tbuf.len() < req_size {
-            tbuf.resize(req_size, 0);
+impl AlnLayout {
+    /// The layout declared by the alignment-level tag section `aln_tags`
+    /// of a file whose header has `is_paired` set as given.  The
+    /// "compressed_ori_refid" must come first, followed by any of the
+    /// `POS_TAG`, `FRAG_LEN_TAG` and `SCORE_TAG` (of type u32), in that
+    /// order.
+    pub fn from_tags(is_paired: bool, aln_tags: &TagSection) -> Result<AlnLayout, RadError> {
+        let mut layout = AlnLayout {
+            paired: is_paired,
+            ..AlnLayout::default()
+        };
+        let mut tags = aln_tags.tags.iter();
+        match tags.next() {
+            Some(t) if t.name == "compressed_ori_refid" => {}
+            _ => return Err(RadError::MissingTag("compressed_ori_refid".to_string())),
         }
-        reader.read_exact(&mut tbuf[0..(size_of_u32 * na)]).unwrap();
-        // compute the total number of bytes this record requires
-        let nbytes = calc_record_bytes(na);
-        (*v).offset += nbytes as u64;
-        (*v).nbytes += nbytes as u32;
-        (*v).nrec += 1;
-        total_bytes += nbytes as usize;
+        let mut last_rank = 0;
+        for t in tags {
+            if t.typeid != RadIntId::U32.type_tag() {
+                return Err(RadError::UnsupportedLayout(format!(
+                    "alignment tag '{}' is not a u32",
+                    t.name
+                )));
+            }
+            // the position of each optional field in the required order
+            let rank = match t.name.as_str() {
+                POS_TAG => 1,
+                FRAG_LEN_TAG => 2,
+                SCORE_TAG => 3,
+                _ => 0,
+            };
+            if rank <= last_rank {
+                return Err(RadError::UnsupportedLayout(format!(
+                    "unexpected alignment tag '{}'",
+                    t.name
+                )));
+            }
+            match rank {
+                1 => layout.pos = true,
+                2 => layout.frag_len = true,
+                _ => layout.score = true,
+            }
+            last_rank = rank;
+        }
+        Ok(layout)
     }
 
-    // each cell will have a header (8 bytes each)
-    total_bytes += cb_byte_map.len() * header_size as usize;
-    let mut output_buffer = Cursor::new(vec![0u8; total_bytes]);
-
-    let mut next_offset = 0u64;
-    for (_, v) in cb_byte_map.iter_mut() {
-        // jump to the position where this chunk should start
-        // and write the header
-        output_buffer.set_position(next_offset);
-        let cell_bytes = (*v).nbytes as u32;
-        let cell_rec = (*v).nrec as u32;
-        output_buffer.write_all(&cell_bytes.to_le_bytes()).unwrap();
-        output_buffer.write_all(&cell_rec.to_le_bytes()).unwrap();
-        // where we will start writing records for this cell
-        (*v).offset = output_buffer.position();
-        // the number of bytes allocated to this chunk
-        let nbytes = (*v).nbytes as u64;
-        // the next record will start after this one
-        next_offset += nbytes;
+    /// The alignment-level tag section declaring this layout.
+    pub fn tag_section(&self) -> TagSection {
+        let desc = |name: &str| TagDesc {
+            name: name.to_string(),
+            typeid: RadIntId::U32.type_tag(),
+        };
+        let mut ts = TagSection {
+            tags: vec![desc("compressed_ori_refid")],
+        };
+        if self.pos {
+            ts.tags.push(desc(POS_TAG));
+        }
+        if self.frag_len {
+            ts.tags.push(desc(FRAG_LEN_TAG));
+        }
+        if self.score {
+            ts.tags.push(desc(SCORE_TAG));
+        }
+        ts
     }
 
-    // now each key points to where we should write the next record for the CB
-    // reset the input pointer
-    reader
-        .get_mut()
-        .seek(SeekFrom::Start(0))
-        .expect("could not get read pointer.");
-
-    // for each record, read it
-    for _ in 0..(nrec as usize) {
-        // read the header of the record
-        // we don't bother reading the whole thing here
-        // because we will just copy later as need be
-        let tup = ReadRecord::from_bytes_record_header(reader, &bct, &umit);
-
-        // get the entry for this chunk, or create a new one
-        if let Some(v) = cb_byte_map.get_mut(&tup.0) {
-            output_buffer.set_position(v.offset);
-
-            // write the num align
-            let na = tup.2 as usize;
-            let nau32 = na as u32;
-            output_buffer.write_all(&nau32.to_le_bytes()).unwrap();
+    /// The number of bytes each alignment takes in a record.
+    pub fn aln_bytes(&self) -> usize {
+        mem::size_of::<u32>()
+            * (1 + self.pos as usize + self.frag_len as usize + self.score as usize)
+    }
 
-            // write the corrected barcode
-            bct.write_to(tup.0, &mut output_buffer).unwrap();
-            umit.write_to(tup.1, &mut output_buffer).unwrap();
+    /// Check that each alignment holds only a (single-end) reference id, for
+    /// the consumers (named by `what`) that parse records as `ReadRecord`s.
+    pub fn require_plain(&self, what: &str) -> Result<(), RadError> {
+        if self.paired || self.aln_bytes() != mem::size_of::<u32>() {
+            return Err(RadError::UnsupportedLayout(format!(
+                "{} can only read single-end alignments holding just a reference id",
+                what
+            )));
+        }
+        Ok(())
+    }
 
-            // read the alignment records
-            reader
-                .read_exact(&mut tbuf[0..(size_of_u32 as usize * na)])
-                .unwrap();
-            // write them
-            output_buffer
-                .write_all(&tbuf[..(size_of_u32 as usize * na)])
-                .unwrap();
+    /// Check that each alignment holds just a reference id (and, for
+    /// paired-end layouts, the orientation of the mate), for the consumers
+    /// (named by `what`) that parse records as `ReadRecord`s and split the
+    /// mate orientation out with `ReadRecord::split_mate_ori`.
+    pub fn require_ref_only(&self, what: &str) -> Result<(), RadError> {
+        if self.aln_bytes() != mem::size_of::<u32>() {
+            return Err(RadError::UnsupportedLayout(format!(
+                "{} can only read alignments holding just a reference id",
+                what
+            )));
+        }
+        Ok(())
+    }
 
-            (*v).offset = output_buffer.position();
+    /// The index of the (u32) word holding the score within each
+    /// alignment, if the layout has one.
+    pub fn score_index(&self) -> Option<usize> {
+        if self.score {
+            Some(self.aln_bytes() / mem::size_of::<u32>() - 1)
         } else {
-            panic!("should not have any barcodes we can't find");
+            None
         }
     }
 
-    output_buffer.set_position(0);
+    /// The bits of the first word of an alignment holding the reference id.
+    pub fn ref_mask(&self) -> u32 {
+        if self.paired {
+            utils::MASK_LOWER_30_U32
+        } else {
+            utils::MASK_TOP_BIT_U32
+        }
+    }
+}
 
-    if compress {
-        // compress the contents of output_buffer to compressed_output
-        let mut compressed_output =
-            snap::write::FrameEncoder::new(Cursor::new(Vec::<u8>::with_capacity(total_bytes)));
-        compressed_output
-            .write_all(output_buffer.get_ref())
-            .expect("could not compress the output chunk.");
-
-        output_buffer = compressed_output
-            .into_inner()
-            .expect("couldn't unwrap the FrameEncoder.");
-        output_buffer.set_position(0);
+impl ExtReadRecord {
+    /// Read a record whose alignments have the layout `layout` from `reader`.
+    pub fn from_bytes_checked<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        layout: &AlnLayout,
+    ) -> std::io::Result<Self> {
+        let mut rbuf = [0u8; 4];
+        reader.read_exact(&mut rbuf)?;
+        let na = rbuf.pread::<u32>(0).unwrap() as usize;
+        check_limit_io(
+            "alignment count",
+            na as u64,
+            MAX_ALIGNMENTS_PER_RECORD as u64,
+        )?;
+        let bc = bct.read_from(reader)?;
+        let umi = umit.read_from(reader)?;
+
+        let cap = |present: bool| if present { na } else { 0 };
+        let mut rec = Self {
+            bc,
+            umi,
+            dirs: Vec::with_capacity(na),
+            mate_dirs: Vec::with_capacity(cap(layout.paired)),
+            refs: Vec::with_capacity(na),
+            positions: Vec::with_capacity(cap(layout.pos)),
+            frag_lens: Vec::with_capacity(cap(layout.frag_len)),
+            scores: Vec::with_capacity(cap(layout.score)),
+        };
+        let mut next_u32 = |reader: &mut T| -> std::io::Result<u32> {
+            reader.read_exact(&mut rbuf)?;
+            Ok(rbuf.pread::<u32>(0).unwrap())
+        };
+        for _ in 0..na {
+            let v = next_u32(reader)?;
+            rec.dirs.push((v & utils::MASK_LOWER_31_U32) != 0);
+            if layout.paired {
+                rec.mate_dirs.push((v & utils::MASK_MATE_ORI_U32) != 0);
+            }
+            rec.refs.push(v & layout.ref_mask());
+            if layout.pos {
+                rec.positions.push(next_u32(reader)?);
+            }
+            if layout.frag_len {
+                rec.frag_lens.push(next_u32(reader)?);
+            }
+            if layout.score {
+                rec.scores.push(next_u32(reader)?);
+            }
+        }
+        Ok(rec)
     }
 
-    owriter
-        .lock()
-        .unwrap()
-        .write_all(output_buffer.get_ref())
-        .unwrap();
+    /// Write this record, with its alignments in the layout `layout`, in
+    /// the form read by `from_bytes_checked`.  Fields of the layout that
+    /// are absent from the record are written as 0 (or, for orientations,
+    /// as reverse).
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        layout: &AlnLayout,
+    ) -> std::io::Result<()> {
+        writer.write_all(&(self.refs.len() as u32).to_le_bytes())?;
+        bct.write_to(self.bc, writer)?;
+        umit.write_to(self.umi, writer)?;
+        let get = |v: &Vec<u32>, i: usize| v.get(i).copied().unwrap_or(0);
+        for (i, r) in self.refs.iter().enumerate() {
+            let mut v = r & layout.ref_mask();
+            if self.dirs.get(i).copied().unwrap_or(false) {
+                v |= utils::MASK_LOWER_31_U32;
+            }
+            if layout.paired && self.mate_dirs.get(i).copied().unwrap_or(false) {
+                v |= utils::MASK_MATE_ORI_U32;
+            }
+            writer.write_all(&v.to_le_bytes())?;
+            if layout.pos {
+                writer.write_all(&get(&self.positions, i).to_le_bytes())?;
+            }
+            if layout.frag_len {
+                writer.write_all(&get(&self.frag_lens, i).to_le_bytes())?;
+            }
+            if layout.score {
+                writer.write_all(&get(&self.scores, i).to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
 
-    cb_byte_map.len()
+    /// The number of bytes this record takes in a file whose alignments
+    /// have the layout `layout`.
+    pub fn encoded_len(&self, bct: &RadIntId, umit: &RadIntId, layout: &AlnLayout) -> usize {
+        mem::size_of::<u32>()
+            + bct.bytes_for_type()
+            + umit.bytes_for_type()
+            + self.refs.len() * layout.aln_bytes()
+    }
 }
 
-pub fn collate_temporary_bucket<T: Read>(
-    reader: &mut T,
-    bct: &RadIntId,
-    umit: &RadIntId,
-    _nchunks: u32,
-    nrec: u32,
-    output_cache: &mut HashMap<u64, CorrectedCbChunk, ahash::RandomState>,
-) {
-    let mut tbuf = [0u8; 65536];
-    // estimated average number of records per barcode
-    // this is just for trying to pre-allocate buffers
-    // right; should not affect correctness
-    let est_num_rec = 1; //(nrec / nchunks) + 1;
-
-    // for each record, read it
-    for _ in 0..(nrec as usize) {
-        // read the header of the record
-        // we don't bother reading the whole thing here
-        // because we will just copy later as need be
-        let tup = ReadRecord::from_bytes_record_header(reader, &bct, &umit);
+impl ExtChunk {
+    /// Like `Chunk::from_bytes_checked`, but for records whose alignments
+    /// have the layout `layout`.
+    pub fn from_bytes_checked<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        layout: &AlnLayout,
+    ) -> Result<Self, RadError> {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf)?;
+        let nbytes = buf.pread::<u32>(0).unwrap();
+        let nrec = buf.pread::<u32>(4).unwrap();
+        check_chunk_header(nbytes, nrec, bct, umit)?;
+        let mut c = Self {
+            nbytes,
+            nrec,
+            reads: Vec::with_capacity(prealloc(nrec as u64)),
+        };
 
-        // get the entry for this chunk, or create a new one
-        let v = output_cache
-            .entry(tup.0)
-            .or_insert_with(|| CorrectedCbChunk::from_label_and_counter(tup.0, est_num_rec));
+        let mut consumed = buf.len() as u64;
+        for i in 0..nrec {
+            let rec = match ExtReadRecord::from_bytes_checked(reader, bct, umit, layout) {
+                Ok(rec) => rec,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(RadError::TruncatedChunk { nrec, read: i });
+                }
+                Err(e) => return Err(e.into()),
+            };
+            consumed += rec.encoded_len(bct, umit, layout) as u64;
+            c.reads.push(rec);
+        }
 
-        // keep track of the number of records we're writing
-        (*v).nrec += 1;
-        // write the num align
-        let na = tup.2;
-        (*v).data.write_all(&na.to_le_bytes()).unwrap();
-        // write the corrected barcode
-        bct.write_to(tup.0, &mut (*v).data).unwrap();
-        umit.write_to(tup.1, &mut (*v).data).unwrap();
-        // read the alignment records
-        reader.read_exact(&mut tbuf[0..(4 * na as usize)]).unwrap();
-        // write them
-        (*v).data.write_all(&tbuf[..(4 * na as usize)]).unwrap();
+        if consumed != nbytes as u64 {
+            return Err(RadError::ChunkSizeMismatch {
+                declared: nbytes,
+                consumed,
+            });
+        }
+        Ok(c)
     }
 }
 
-pub fn process_corrected_cb_chunk<T: Read>(
-    reader: &mut T,
-    bct: &RadIntId,
-    umit: &RadIntId,
-    correct_map: &HashMap<u64, u64>,
-    expected_ori: &Strand,
-    output_cache: &DashMap<u64, CorrectedCbChunk>,
-    owriter: &Mutex<BufWriter<File>>,
-) {
-    let mut buf = [0u8; 8];
-    let mut tbuf = [0u8; 65536];
+#[inline]
+pub fn dump_chunk(v: &mut CorrectedCbChunk, owriter: &Mutex<BufWriter<File>>) {
+    v.data.set_position(0);
+    let nbytes = (v.data.get_ref().len()) as u32;
+    let nrec = v.nrec;
+    v.data.write_all(&nbytes.to_le_bytes()).unwrap();
+    v.data.write_all(&nrec.to_le_bytes()).unwrap();
+    owriter.lock().unwrap().write_all(v.data.get_ref()).unwrap();
+}
 
-    // get the number of bytes and records for
-    // the next chunk
-    reader.read_exact(&mut buf).unwrap();
-    let _nbytes = buf.pread::<u32>(0).unwrap();
-    let nrec = buf.pread::<u32>(4).unwrap();
-    // for each record, read it
-    for _ in 0..(nrec as usize) {
-        let tup = ReadRecord::from_bytes_record_header(reader, &bct, &umit);
-        //let rr = ReadRecord::from_bytes_keep_ori(reader, &bct, &umit, expected_ori);
-        // if this record had a correct or correctable barcode
-        if let Some(corrected_id) = correct_map.get(&tup.0) {
-            let rr = ReadRecord::from_bytes_with_header_keep_ori(
-                reader,
-                tup.0,
-                tup.1,
-                tup.2,
-                expected_ori,
+/// Map the `na` raw alignment entries, in the layout `layout`, held in
+/// `buf` through `remap` (i.e. reference id `r` becomes `remap[r]`),
+/// preserving the orientation bits and any fields following the reference
+/// id, and place the result in `out`, sorted by the new reference id.  If
+/// several entries map to the same target reference, only the first of
+/// them is kept.
+fn remap_alignments(buf: &[u8], na: usize, layout: &AlnLayout, remap: &[u32], out: &mut Vec<u32>) {
+    let aln_bytes = layout.aln_bytes();
+    let stride = aln_bytes / mem::size_of::<u32>();
+    let mask = layout.ref_mask();
+    out.clear();
+    for i in 0..na {
+        let v = buf.pread::<u32>(i * aln_bytes).unwrap();
+        let target = remap[(v & mask) as usize];
+        // the position of the first entry whose reference isn't less than
+        // `target`; the entries mapped so far are kept in sorted order
+        let pos = out
+            .chunks(stride)
+            .position(|o| (o[0] & mask) >= target)
+            .unwrap_or(out.len() / stride);
+        if out
+            .get(pos * stride)
+            .map_or(false, |o| (o & mask) == target)
+        {
+            continue;
+        }
+        out.insert(pos * stride, target | (v & !mask));
+        for j in 1..stride {
+            out.insert(
+                pos * stride + j,
+                buf.pread::<u32>(i * aln_bytes + j * 4).unwrap(),
             );
-
-            if let Some(mut v) = output_cache.get_mut(corrected_id) {
-                // update the corresponding corrected chunk entry
-                v.remaining_records -= 1;
-                let last_record = v.remaining_records == 0;
-                // if there are no alignments in the record
-                // (potentially b/c of orientation filtering)
-                // then don't push info on to the vector.
-                if rr.is_empty() {
-                    if last_record {
-                        dump_chunk(&mut v, owriter);
-                    }
-                    continue;
-                }
-                v.nrec += 1;
-                let na = rr.refs.len() as u32;
-                v.data.write_all(&na.to_le_bytes()).unwrap();
-                bct.write_to(*corrected_id, &mut v.data).unwrap();
-                umit.write_to(rr.umi, &mut v.data).unwrap();
-                v.data.write_all(as_u8_slice(&rr.refs[..])).unwrap();
-                if last_record {
-                    dump_chunk(&mut v, owriter);
-                }
-            }
-        } else {
-            reader
-                .read_exact(&mut tbuf[0..(4 * (tup.2 as usize))])
-                .unwrap();
         }
     }
 }
 
-pub struct TempBucket {
-    pub bucket_id: u32,
-    pub bucket_writer: Arc<Mutex<BufWriter<File>>>,
-    pub num_chunks: u32,
-    pub num_records: u32,
-    pub num_records_written: AtomicU32,
-    pub num_bytes_written: AtomicU64,
-}
+/// Read the `na` alignment entries, in the layout `layout`, of a record
+/// from `reader`, and place in `out` those whose orientation agrees with
+/// `expected_ori` and whose score is at least `min_score` (if given),
+/// sorted by reference id and with the orientation bit cleared, as
+/// `ReadRecord::read_with_header_keep_ori_into` does for entries holding
+/// only a reference id.  For paired-end layouts, the orientation bit of
+/// the mate is kept.  Returns the number of entries kept.
+fn read_filtered_alignments<T: Read>(
+    reader: &mut T,
+    na: usize,
+    layout: &AlnLayout,
+    expected_ori: &Strand,
+    min_score: Option<u32>,
+    raw: &mut Vec<u8>,
+    out: &mut Vec<u32>,
+) -> std::io::Result<usize> {
+    let aln_bytes = layout.aln_bytes();
+    let stride = aln_bytes / mem::size_of::<u32>();
+    let score_filter = layout.score_index().zip(min_score);
+    raw.resize(na * aln_bytes, 0);
+    reader.read_exact(&mut raw[..])?;
+
+    let mut kept: Vec<(u32, usize)> = (0..na)
+        .map(|i| (raw.pread::<u32>(i * aln_bytes).unwrap(), i))
+        .filter(|(v, _)| {
+            // fw if the leftmost bit is 1, otherwise rc
+            let strand = if (v & utils::MASK_LOWER_31_U32) > 0 {
+                Strand::Forward
+            } else {
+                Strand::Reverse
+            };
+            expected_ori.same(&strand) || expected_ori.is_unknown()
+        })
+        .filter(|(_, i)| match score_filter {
+            Some((j, min)) => raw.pread::<u32>(i * aln_bytes + j * 4).unwrap() >= min,
+            None => true,
+        })
+        .collect();
+    kept.sort_by_key(|(v, _)| v & layout.ref_mask());
 
-impl TempBucket {
-    pub fn from_id_and_parent(bucket_id: u32, parent: &std::path::Path) -> Self {
-        TempBucket {
-            bucket_id,
-            bucket_writer: Arc::new(Mutex::new(BufWriter::with_capacity(
-                4096_usize,
-                File::create(parent.join(&format!("bucket_{}.tmp", bucket_id))).unwrap(),
-            ))),
-            num_chunks: 0u32,
-            num_records: 0u32,
-            num_records_written: AtomicU32::new(0u32),
-            num_bytes_written: AtomicU64::new(0u64),
+    out.clear();
+    for (v, i) in kept.iter() {
+        out.push(v & utils::MASK_TOP_BIT_U32);
+        for j in 1..stride {
+            out.push(raw.pread::<u32>(i * aln_bytes + j * 4).unwrap());
         }
     }
+    Ok(kept.len())
+}
+
+/// Write the header of each cell in `cb_byte_map` into `output_buffer`,
+/// laying the cells out contiguously in order of barcode (so that the
+/// output doesn't depend on the iteration order of the map), and set the
+/// `offset` of each cell to the position at which its first record
+/// should be written.
+fn layout_cell_headers<B: BarcodeT>(
+    cb_byte_map: &mut HashMap<B, TempCellInfo, ahash::RandomState>,
+    output_buffer: &mut Cursor<Vec<u8>>,
+) {
+    let mut barcodes: Vec<B> = cb_byte_map.keys().copied().collect();
+    barcodes.sort_unstable();
+    let mut next_offset = 0u64;
+    for bc in barcodes {
+        let v = cb_byte_map.get_mut(&bc).unwrap();
+        // jump to the position where this chunk should start
+        // and write the header
+        output_buffer.set_position(next_offset);
+        let cell_bytes = (*v).nbytes as u32;
+        let cell_rec = (*v).nrec as u32;
+        output_buffer.write_all(&cell_bytes.to_le_bytes()).unwrap();
+        output_buffer.write_all(&cell_rec.to_le_bytes()).unwrap();
+        // where we will start writing records for this cell
+        (*v).offset = output_buffer.position();
+        // the number of bytes allocated to this chunk
+        let nbytes = (*v).nbytes as u64;
+        // the next record will start after this one
+        next_offset += nbytes;
+    }
 }
 
+/// Read `nrec` records from `reader` and write each one at the current
+/// `offset` of its cell (as set up by `layout_cell_headers`) in `output_buffer`.
+/// Records of cells missing from `cb_byte_map` are skipped if `skip_missing`
+/// is true, and are a bug otherwise.
 #[allow(clippy::too_many_arguments)]
-pub fn dump_corrected_cb_chunk_to_temp_file<T: Read>(
-    reader: &mut BufReader<T>,
+fn scatter_records<T: Read, B: BarcodeT>(
+    reader: &mut T,
     bct: &RadIntId,
     umit: &RadIntId,
-    correct_map: &HashMap<u64, u64>,
-    expected_ori: &Strand,
-    output_cache: &HashMap<u64, Arc<TempBucket>>,
-    local_buffers: &mut [Cursor<&mut [u8]>],
-    flush_limit: usize,
+    nrec: u32,
+    cb_byte_map: &mut HashMap<B, TempCellInfo, ahash::RandomState>,
+    output_buffer: &mut Cursor<Vec<u8>>,
+    remap: Option<&[u32]>,
+    aln_layout: &AlnLayout,
+    tbuf: &mut Vec<u8>,
+    skip_missing: bool,
 ) {
-    let mut buf = [0u8; 8];
-    let mut tbuf = vec![0u8; 4096];
-    //let mut tcursor = Cursor::new(tbuf);
-    //tcursor.set_position(0);
-
-    // get the number of bytes and records for
-    // the next chunk
-    reader.read_exact(&mut buf).unwrap();
-    let _nbytes = buf.pread::<u32>(0).unwrap();
-    let nrec = buf.pread::<u32>(4).unwrap();
-
-    let bc_bytes = bct.bytes_for_type();
-    let umi_bytes = umit.bytes_for_type();
-    let na_bytes = std::mem::size_of::<u32>();
-    let target_id_bytes = std::mem::size_of::<u32>();
+    let aln_bytes = aln_layout.aln_bytes();
+    let mut remapped = Vec::<u32>::new();
 
     // for each record, read it
     for _ in 0..(nrec as usize) {
-        let tup = ReadRecord::from_bytes_record_header(reader, &bct, &umit);
+        // read the header of the record
+        // we don't bother reading the whole thing here
+        // because we will just copy later as need be
+        let tup = ReadRecord::<B>::from_bytes_record_header(reader, &bct, &umit);
 
-        // if this record had a correct or correctable barcode
-        if let Some(corrected_id) = correct_map.get(&tup.0) {
-            let rr = ReadRecord::from_bytes_with_header_keep_ori(
-                reader,
-                tup.0,
-                tup.1,
-                tup.2,
-                expected_ori,
-            );
+        // get the entry for this chunk, or create a new one
+        if let Some(v) = cb_byte_map.get_mut(&tup.0) {
+            output_buffer.set_position(v.offset);
 
-            if rr.is_empty() {
-                continue;
+            // read the alignment records
+            let na = tup.2 as usize;
+            if tbuf.len() < aln_bytes * na {
+                tbuf.resize(aln_bytes * na, 0);
             }
-            if let Some(v) = output_cache.get(corrected_id) {
-                // if this is a valid barcode, then
-                // write the corresponding entry to the
-                // thread-local buffer for this bucket
-
-                // the total number of bytes this record will take
-                let nb = (rr.refs.len() * target_id_bytes + na_bytes + bc_bytes + umi_bytes) as u64;
-
-                // the buffer index for this corrected barcode
-                let buffidx = v.bucket_id as usize;
-                // the current cursor for this buffer
-                let bcursor = &mut local_buffers[buffidx];
-                // the current position of the cursor
-                let len = bcursor.position() as usize;
-
-                // if writing the next record (nb bytes) will put us over
-                // the flush size for the thread-local buffer for this bucket
-                // then first flush the buffer to file.
-                if len + nb as usize >= flush_limit {
-                    let mut filebuf = v.bucket_writer.lock().unwrap();
-                    filebuf
-                        .write_all(&bcursor.get_ref()[0..len as usize])
-                        .unwrap();
-                    // and reset the local buffer cursor
-                    bcursor.set_position(0);
+            reader.read_exact(&mut tbuf[0..(aln_bytes * na)]).unwrap();
+            let alns = match remap {
+                Some(rm) => {
+                    remap_alignments(tbuf, na, aln_layout, rm, &mut remapped);
+                    as_u8_slice(&remapped[..])
                 }
+                None => &tbuf[..(aln_bytes * na)],
+            };
 
-                // now, write the record to the buffer
-                let na = rr.refs.len() as u32;
-                bcursor.write_all(&na.to_le_bytes()).unwrap();
-                bct.write_to(*corrected_id, bcursor).unwrap();
-                umit.write_to(rr.umi, bcursor).unwrap();
-                bcursor.write_all(as_u8_slice(&rr.refs[..])).unwrap();
-
-                // update number of written records
-                v.num_records_written.fetch_add(1, Ordering::SeqCst);
-                // update number of written bytes
-                v.num_bytes_written.fetch_add(nb, Ordering::SeqCst);
-            }
-        } else {
-            // in this branch, we don't have access to a correct barcode for
-            // what we observed, so we need to discard the remaining part of
-            // the record.
-            let req_len = target_id_bytes * (tup.2 as usize);
-            let do_resize = req_len > tbuf.len();
+            // write the num align
+            let nau32 = (alns.len() / aln_bytes) as u32;
+            output_buffer.write_all(&nau32.to_le_bytes()).unwrap();
 
-            if do_resize {
-                tbuf.resize(req_len, 0);
-            }
+            // write the corrected barcode
+            bct.write_to(tup.0, output_buffer).unwrap();
+            umit.write_to(tup.1, output_buffer).unwrap();
 
-            reader
-                .read_exact(&mut tbuf[0..(target_id_bytes * (tup.2 as usize))])
-                .unwrap();
+            // write the alignment records
+            output_buffer.write_all(alns).unwrap();
 
-            if do_resize {
-                tbuf.resize(4096, 0);
-                tbuf.shrink_to_fit();
+            (*v).offset = output_buffer.position();
+        } else if skip_missing {
+            let nbytes = aln_bytes * tup.2 as usize;
+            if tbuf.len() < nbytes {
+                tbuf.resize(nbytes, 0);
             }
+            reader.read_exact(&mut tbuf[0..nbytes]).unwrap();
+        } else {
+            panic!("should not have any barcodes we can't find");
         }
     }
 }
 
-pub(crate) fn as_u8_slice(v: &[u32]) -> &[u8] {
+/// Write the collated cells in `output_buffer` to `owriter`, compressing
+/// them first if `compress` is true.
+fn write_collated_output<U: Write>(
+    mut output_buffer: Cursor<Vec<u8>>,
+    total_bytes: usize,
+    compress: bool,
+    owriter: &Mutex<U>,
+) {
+    output_buffer.set_position(0);
+
+    if compress {
+        // compress the contents of output_buffer to compressed_output
+        let mut compressed_output =
+            snap::write::FrameEncoder::new(Cursor::new(Vec::<u8>::with_capacity(total_bytes)));
+        compressed_output
+            .write_all(output_buffer.get_ref())
+            .expect("could not compress the output chunk.");
+
+        output_buffer = compressed_output
+            .into_inner()
+            .expect("couldn't unwrap the FrameEncoder.");
+        output_buffer.set_position(0);
+    }
+
+    owriter
+        .lock()
+        .unwrap()
+        .write_all(output_buffer.get_ref())
+        .unwrap();
+}
+
+/// The compression, if any, applied to a collated file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompressionCodec {
+    None,
+    /// the snappy frame format, as written by collation with compression on
+    Snappy,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Wrap `reader` in a streaming decoder for `codec`, so that the contents
+/// of a (possibly compressed) collated file can be read without first
+/// decompressing the whole file.
+pub fn open_collated_bucket<'a, R: Read + 'a>(
+    reader: R,
+    codec: CompressionCodec,
+) -> std::io::Result<Box<dyn Read + 'a>> {
+    Ok(match codec {
+        CompressionCodec::None => Box::new(reader),
+        CompressionCodec::Snappy => Box::new(snap::read::FrameDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        CompressionCodec::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+/// Iterates over the `(barcode, umi, refs)` of every record in a stream of
+/// collated chunks (e.g. the decoded output of `open_collated_bucket`,
+/// positioned after the file header).  Records are yielded in file order,
+/// chunk boundaries are skipped over, and the alignment entries in `refs`
+/// are returned exactly as they were written.  Iteration ends cleanly at
+/// the end of the stream, and stops after the first error.
+pub struct CollatedBucketReader<R> {
+    reader: R,
+    bct: RadIntId,
+    umit: RadIntId,
+    remaining: u32,
+    done: bool,
+}
+
+impl<R: Read> CollatedBucketReader<R> {
+    pub fn new(reader: R, bct: &RadIntId, umit: &RadIntId) -> CollatedBucketReader<R> {
+        CollatedBucketReader {
+            reader,
+            bct: *bct,
+            umit: *umit,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn read_record(&mut self) -> std::io::Result<(u64, u64, Vec<u32>)> {
+        let mut buf = [0u8; 4];
+        self.reader.read_exact(&mut buf)?;
+        let na = u32::from_le_bytes(buf);
+        let bc = self.bct.read_from(&mut self.reader)?;
+        let umi = self.umit.read_from(&mut self.reader)?;
+        let mut refs = Vec::with_capacity(prealloc(na as u64));
+        for _ in 0..na {
+            self.reader.read_exact(&mut buf)?;
+            refs.push(u32::from_le_bytes(buf));
+        }
+        Ok((bc, umi, refs))
+    }
+}
+
+impl<R: Read> Iterator for CollatedBucketReader<R> {
+    type Item = std::io::Result<(u64, u64, Vec<u32>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.remaining == 0 {
+            match read_chunk_header_opt(&mut self.reader) {
+                Ok(Some((_nbytes, nrec))) => self.remaining = nrec,
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if self.done {
+            return None;
+        }
+        self.remaining -= 1;
+        let res = self.read_record();
+        if res.is_err() {
+            self.done = true;
+        }
+        Some(res)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn collate_temporary_bucket_twopass<T: Read + Seek, U: Write, B: BarcodeT>(
+    reader: &mut BufReader<T>,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    nrec: u32,
+    owriter: &Mutex<U>,
+    compress: bool,
+    cb_byte_map: &mut HashMap<B, TempCellInfo, ahash::RandomState>,
+    remap: Option<&[u32]>,
+    aln_layout: &AlnLayout,
+    progress: Option<&dyn Fn(CollateProgress)>,
+) -> usize {
+    let aln_bytes = aln_layout.aln_bytes();
+    let mut tbuf = vec![0u8; 65536];
+    let mut remapped = Vec::<u32>::new();
+    let mut total_bytes = 0usize;
+    let header_size = 2 * std::mem::size_of::<u32>() as u64;
+    let size_of_u32 = std::mem::size_of::<u32>();
+    let size_of_bc = bct.bytes_for_type();
+    let size_of_umi = umit.bytes_for_type();
+
+    let calc_record_bytes = |num_aln: usize| -> usize {
+        size_of_u32 + size_of_bc + size_of_umi + (aln_bytes * num_aln)
+    };
+
+    // read each record
+    for i in 0..(nrec as u64) {
+        if (i + 1) % COLLATE_PROGRESS_INTERVAL == 0 {
+            report_progress(
+                progress,
+                CollateProgress {
+                    records_processed: i + 1,
+                    bytes_written: 0,
+                    buckets_touched: 1,
+                },
+            );
+        }
+        // read the header of the record
+        // we don't bother reading the whole thing here
+        // because we will just copy later as need be
+        let tup = ReadRecord::<B>::from_bytes_record_header(reader, &bct, &umit);
+
+        // get the entry for this chunk, or create a new one
+        let v = cb_byte_map.entry(tup.0).or_insert(TempCellInfo {
+            offset: header_size,
+            nbytes: header_size as u32,
+            nrec: 0_u32,
+        });
+
+        // read the alignment records from the input file
+        let na = tup.2 as usize;
+        let req_size = aln_bytes * na;
+        if tbuf.len() < req_size {
+            tbuf.resize(req_size, 0);
+        }
+        reader.read_exact(&mut tbuf[0..req_size]).unwrap();
+        // if we are remapping, the number of alignments written
+        // is the number that remain after collapsing duplicates
+        let out_na = match remap {
+            Some(rm) => {
+                remap_alignments(&tbuf, na, aln_layout, rm, &mut remapped);
+                remapped.len() * size_of_u32 / aln_bytes
+            }
+            None => na,
+        };
+        // compute the total number of bytes this record requires
+        let nbytes = calc_record_bytes(out_na);
+        (*v).offset += nbytes as u64;
+        (*v).nbytes += nbytes as u32;
+        (*v).nrec += 1;
+        total_bytes += nbytes as usize;
+    }
+
+    // each cell will have a header (8 bytes each)
+    total_bytes += cb_byte_map.len() * header_size as usize;
+    let mut output_buffer = Cursor::new(vec![0u8; total_bytes]);
+    layout_cell_headers(cb_byte_map, &mut output_buffer);
+
+    // now each key points to where we should write the next record for the CB
+    // reset the input pointer
+    reader
+        .get_mut()
+        .seek(SeekFrom::Start(0))
+        .expect("could not get read pointer.");
+
+    scatter_records(
+        reader,
+        bct,
+        umit,
+        nrec,
+        cb_byte_map,
+        &mut output_buffer,
+        remap,
+        aln_layout,
+        &mut tbuf,
+        false,
+    );
+    write_collated_output(output_buffer, total_bytes, compress, owriter);
+    report_progress(
+        progress,
+        CollateProgress {
+            records_processed: nrec as u64,
+            bytes_written: total_bytes as u64,
+            buckets_touched: 1,
+        },
+    );
+
+    cb_byte_map.len()
+}
+
+/// Collate the `nrec` records of a temporary bucket, as does
+/// `collate_temporary_bucket_twopass`, but reading `reader` only once, so
+/// that it need not be seekable.  The records of each cell are buffered in
+/// memory until more than `memory_budget` bytes are held, at which point
+/// the buffered and all subsequent records are spilled to a temporary file
+/// at `spill_path` (removed before returning).  The output is then assembled
+/// from the spill file in batches of cells of at most `memory_budget` bytes
+/// (or a single cell, if it is larger), making one pass over the file per
+/// batch, so that no more than about `memory_budget` bytes of records are
+/// held in memory at once.  The output is streamed to `owriter`, which is
+/// kept locked while it is written, and is identical to that of the two-pass
+/// version given the same `cb_byte_map`.
+#[allow(clippy::too_many_arguments)]
+pub fn collate_temporary_bucket_onepass<T: Read, U: Write, B: BarcodeT>(
+    reader: &mut T,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    nrec: u32,
+    owriter: &Mutex<U>,
+    compress: bool,
+    cb_byte_map: &mut HashMap<B, TempCellInfo, ahash::RandomState>,
+    remap: Option<&[u32]>,
+    aln_layout: &AlnLayout,
+    memory_budget: usize,
+    spill_path: &std::path::Path,
+    progress: Option<&dyn Fn(CollateProgress)>,
+) -> usize {
+    let aln_bytes = aln_layout.aln_bytes();
+    let mut tbuf = vec![0u8; 65536];
+    let mut remapped = Vec::<u32>::new();
+    let mut rec_bytes = Vec::<u8>::new();
+    let mut total_bytes = 0usize;
+    let header_size = 2 * std::mem::size_of::<u32>() as u64;
+
+    let mut cell_bufs: HashMap<B, Vec<u8>, ahash::RandomState> =
+        HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+    let mut spill: Option<BufWriter<File>> = None;
+
+    for i in 0..(nrec as u64) {
+        if (i + 1) % COLLATE_PROGRESS_INTERVAL == 0 {
+            report_progress(
+                progress,
+                CollateProgress {
+                    records_processed: i + 1,
+                    bytes_written: 0,
+                    buckets_touched: 1,
+                },
+            );
+        }
+        let tup = ReadRecord::<B>::from_bytes_record_header(reader, &bct, &umit);
+
+        // read the alignment records from the input file
+        let na = tup.2 as usize;
+        if tbuf.len() < aln_bytes * na {
+            tbuf.resize(aln_bytes * na, 0);
+        }
+        reader.read_exact(&mut tbuf[0..(aln_bytes * na)]).unwrap();
+        let alns = match remap {
+            Some(rm) => {
+                remap_alignments(&tbuf, na, aln_layout, rm, &mut remapped);
+                as_u8_slice(&remapped[..])
+            }
+            None => &tbuf[..(aln_bytes * na)],
+        };
+
+        // the record exactly as it will appear in the output
+        rec_bytes.clear();
+        rec_bytes
+            .write_all(&((alns.len() / aln_bytes) as u32).to_le_bytes())
+            .unwrap();
+        bct.write_to(tup.0, &mut rec_bytes).unwrap();
+        umit.write_to(tup.1, &mut rec_bytes).unwrap();
+        rec_bytes.write_all(alns).unwrap();
+
+        let v = cb_byte_map.entry(tup.0).or_insert(TempCellInfo {
+            offset: header_size,
+            nbytes: header_size as u32,
+            nrec: 0_u32,
+        });
+        (*v).offset += rec_bytes.len() as u64;
+        (*v).nbytes += rec_bytes.len() as u32;
+        (*v).nrec += 1;
+        total_bytes += rec_bytes.len();
+
+        match spill.as_mut() {
+            Some(sw) => sw.write_all(&rec_bytes).unwrap(),
+            None => {
+                cell_bufs
+                    .entry(tup.0)
+                    .or_insert_with(Vec::new)
+                    .extend_from_slice(&rec_bytes);
+                if total_bytes > memory_budget {
+                    // move everything buffered so far out to the spill file.
+                    // the records of each cell remain in their original order.
+                    let mut sw = BufWriter::new(
+                        File::create(spill_path).expect("could not create spill file."),
+                    );
+                    for (_, b) in cell_bufs.drain() {
+                        sw.write_all(&b).unwrap();
+                    }
+                    spill = Some(sw);
+                }
+            }
+        }
+    }
+
+    // each cell will have a header (8 bytes each)
+    total_bytes += cb_byte_map.len() * header_size as usize;
+    let mut barcodes: Vec<B> = cb_byte_map.keys().copied().collect();
+    barcodes.sort_unstable();
+
+    let mut guard = owriter.lock().unwrap();
+    let mut out: Box<dyn Write> = if compress {
+        Box::new(snap::write::FrameEncoder::new(&mut *guard))
+    } else {
+        Box::new(&mut *guard)
+    };
+
+    match spill {
+        Some(sw) => {
+            drop(sw.into_inner().expect("could not flush spill file."));
+            let mut start = 0usize;
+            while start < barcodes.len() {
+                // the next cells that, together, fit within the budget
+                let mut end = start;
+                let mut batch_bytes = 0usize;
+                while end < barcodes.len() {
+                    let cell_bytes = cb_byte_map[&barcodes[end]].nbytes as usize;
+                    if end > start && batch_bytes + cell_bytes > memory_budget {
+                        break;
+                    }
+                    batch_bytes += cell_bytes;
+                    end += 1;
+                }
+
+                let mut batch: HashMap<B, TempCellInfo, ahash::RandomState> =
+                    HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+                for bc in &barcodes[start..end] {
+                    let v = &cb_byte_map[bc];
+                    batch.insert(
+                        *bc,
+                        TempCellInfo {
+                            offset: 0,
+                            nbytes: v.nbytes,
+                            nrec: v.nrec,
+                        },
+                    );
+                }
+                let mut output_buffer = Cursor::new(vec![0u8; batch_bytes]);
+                layout_cell_headers(&mut batch, &mut output_buffer);
+
+                let mut sreader =
+                    BufReader::new(File::open(spill_path).expect("could not open spill file."));
+                // the spilled records have already been remapped
+                scatter_records(
+                    &mut sreader,
+                    bct,
+                    umit,
+                    nrec,
+                    &mut batch,
+                    &mut output_buffer,
+                    None,
+                    aln_layout,
+                    &mut tbuf,
+                    true,
+                );
+                out.write_all(output_buffer.get_ref()).unwrap();
+                start = end;
+            }
+            std::fs::remove_file(spill_path).expect("could not delete spill file.");
+        }
+        None => {
+            for bc in &barcodes {
+                let v = &cb_byte_map[bc];
+                out.write_all(&v.nbytes.to_le_bytes()).unwrap();
+                out.write_all(&v.nrec.to_le_bytes()).unwrap();
+                out.write_all(&cell_bufs.remove(bc).unwrap()).unwrap();
+            }
+        }
+    }
+    out.flush().unwrap();
+    drop(out);
+    drop(guard);
+    report_progress(
+        progress,
+        CollateProgress {
+            records_processed: nrec as u64,
+            bytes_written: total_bytes as u64,
+            buckets_touched: 1,
+        },
+    );
+
+    cb_byte_map.len()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn collate_temporary_bucket<T: Read>(
+    reader: &mut T,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    _nchunks: u32,
+    nrec: u32,
+    output_cache: &mut HashMap<u64, CorrectedCbChunk, ahash::RandomState>,
+    remap: Option<&[u32]>,
+    params: &CollateParams,
+    progress: Option<&dyn Fn(CollateProgress)>,
+) {
+    let aln_bytes = params.aln_layout.aln_bytes();
+    let mut tbuf = vec![0u8; 65536];
+    let mut remapped = Vec::<u32>::new();
+    // estimated average number of records per barcode
+    // this is just for trying to pre-allocate buffers
+    // right; should not affect correctness
+    let est_num_rec = 1; //(nrec / nchunks) + 1;
+
+    let mut prog = CollateProgress {
+        buckets_touched: 1,
+        ..CollateProgress::default()
+    };
+
+    // for each record, read it
+    for _ in 0..(nrec as usize) {
+        prog.records_processed += 1;
+        if prog.records_processed % COLLATE_PROGRESS_INTERVAL == 0 {
+            report_progress(progress, prog);
+        }
+        // read the header of the record
+        // we don't bother reading the whole thing here
+        // because we will just copy later as need be
+        let tup = ReadRecord::from_bytes_record_header(reader, &bct, &umit);
+
+        // get the entry for this chunk, or create a new one
+        let v = output_cache.entry(tup.0).or_insert_with(|| {
+            CorrectedCbChunk::from_label_and_counter(tup.0, est_num_rec, params)
+        });
+
+        // keep track of the number of records we're writing
+        (*v).nrec += 1;
+        // read the alignment records
+        let na = tup.2 as usize;
+        let req_size = aln_bytes * na;
+        if tbuf.len() < req_size {
+            tbuf.resize(req_size, 0);
+        }
+        reader.read_exact(&mut tbuf[0..req_size]).unwrap();
+        let alns = match remap {
+            Some(rm) => {
+                remap_alignments(&tbuf, na, &params.aln_layout, rm, &mut remapped);
+                as_u8_slice(&remapped[..])
+            }
+            None => &tbuf[..req_size],
+        };
+        // write the num align
+        let out_na = (alns.len() / aln_bytes) as u32;
+        (*v).data.write_all(&out_na.to_le_bytes()).unwrap();
+        // write the corrected barcode
+        bct.write_to(tup.0, &mut (*v).data).unwrap();
+        umit.write_to(tup.1, &mut (*v).data).unwrap();
+        // write them
+        (*v).data.write_all(alns).unwrap();
+        prog.bytes_written +=
+            (alns.len() + 4 + bct.bytes_for_type() + umit.bytes_for_type()) as u64;
+    }
+    report_progress(progress, prog);
+}
+
+/// Per-record outcomes of barcode correction, as reported by
+/// `process_corrected_cb_chunk` and `dump_corrected_cb_chunk_to_temp_file`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CorrectionStats {
+    /// records with a correctable barcode that were kept
+    pub corrected: u64,
+    /// records whose barcode has no entry in the correction map
+    pub uncorrectable: u64,
+    /// records with a correctable barcode but no alignments left
+    /// after orientation filtering
+    pub filtered_empty: u64,
+}
+
+impl std::ops::AddAssign for CorrectionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.corrected += other.corrected;
+        self.uncorrectable += other.uncorrectable;
+        self.filtered_empty += other.filtered_empty;
+    }
+}
+
+// tally a record, corrected into a cell of the output, that has `na`
+// alignments left after filtering, returning whether it should be written;
+// records with no alignments (potentially b/c of orientation filtering) aren't
+fn tally_corrected_record(stats: &mut CorrectionStats, na: usize) -> bool {
+    if na == 0 {
+        stats.filtered_empty += 1;
+        false
+    } else {
+        stats.corrected += 1;
+        true
+    }
+}
+
+/// Read the next chunk from `reader`, appending each record whose barcode
+/// has an entry in `correct_map` to the `CorrectedCbChunk` of its corrected
+/// barcode in `output_cache`.  Barcodes are corrected as u64s; a `U128`
+/// barcode that doesn't fit in one is an `InvalidData` error.
+#[allow(clippy::too_many_arguments)]
+pub fn process_corrected_cb_chunk<T: Read>(
+    reader: &mut T,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    correct_map: &HashMap<u64, u64>,
+    aln_layout: &AlnLayout,
+    expected_ori: &Strand,
+    output_cache: &DashMap<u64, CorrectedCbChunk>,
+    owriter: &Mutex<BufWriter<File>>,
+) -> Result<CorrectionStats, RadError> {
+    let mut stats = CorrectionStats::default();
+    let mut tbuf = vec![0u8; 65536];
+
+    // get the number of bytes and records for
+    // the next chunk
+    let (_nbytes, nrec) = Chunk::read_header_checked(reader)?;
+    // reused for every record of the chunk
+    let mut rr = ReadRecord::default();
+    for _ in 0..(nrec as usize) {
+        let tup = ReadRecord::from_bytes_record_header_checked(reader, &bct, &umit)?;
+        if let Some(corrected_id) = correct_map.get(&tup.0) {
+            rr.read_with_header_keep_ori_into_checked(
+                reader,
+                *corrected_id,
+                tup.1,
+                tup.2,
+                aln_layout,
+                expected_ori,
+            )?;
+            if let Some(mut v) = output_cache.get_mut(corrected_id) {
+                if tally_corrected_record(&mut stats, rr.refs.len()) {
+                    v.nrec += 1;
+                    let na = rr.refs.len() as u32;
+                    v.data.write_all(&na.to_le_bytes()).unwrap();
+                    bct.write_to(rr.bc, &mut v.data).unwrap();
+                    umit.write_to(rr.umi, &mut v.data).unwrap();
+                    rr.write_bare_refs(&mut v.data).unwrap();
+                }
+                // but every record counts towards those expected for its
+                // cell, which is written out once all of them have been seen
+                v.remaining_records -= 1;
+                if v.remaining_records == 0 {
+                    dump_chunk(&mut v, owriter);
+                }
+            }
+        } else {
+            stats.uncorrectable += 1;
+            let skip = 4 * (tup.2 as usize);
+            if skip > tbuf.len() {
+                tbuf.resize(skip, 0);
+            }
+            reader.read_exact(&mut tbuf[0..skip])?;
+        }
+    }
+    Ok(stats)
+}
+
+/// Copy the `config.num_chunks` chunks available from `reader` to `writer`,
+/// replacing the barcode of every record with its corrected value from
+/// `correct_map`.  Records whose barcode has no entry in `correct_map` are
+/// dropped.  Unlike collation, each input chunk produces exactly one output
+/// chunk (possibly with no records), so the chunking of the input is preserved
+/// and the `num_chunks` field of the original header remains valid.  An error
+/// is returned if a chunk's records don't fit within the size it declares.
+/// Barcodes are corrected as u64s; a `U128` barcode that doesn't fit in one
+/// is an `InvalidData` error.
+pub fn correct_in_place<T: Read, W: Write>(
+    reader: &mut T,
+    config: &ChunkConfig,
+    correct_map: &HashMap<u64, u64>,
+    writer: &mut W,
+) -> Result<(), RadError> {
+    let bct =
+        decode_int_type_tag(config.bc_type).ok_or(RadError::UnsupportedTypeTag(config.bc_type))?;
+    let umit = decode_int_type_tag(config.umi_type)
+        .ok_or(RadError::UnsupportedTypeTag(config.umi_type))?;
+
+    let mut buf = [0u8; 8];
+    let mut ibuf = Vec::<u8>::new();
+    let mut obuf = Cursor::new(Vec::<u8>::new());
+
+    for _ in 0..config.num_chunks {
+        reader.read_exact(&mut buf)?;
+        let nbytes = buf.pread::<u32>(0).unwrap();
+        let nrec = buf.pread::<u32>(4).unwrap();
+        if (nbytes as usize) < buf.len() {
+            return Err(RadError::InvalidChunkSize(nbytes));
+        }
+
+        // pull in the whole body of the chunk
+        ibuf.clear();
+        read_exact_bounded(reader, &mut ibuf, nbytes as usize - buf.len())?;
+        let mut icursor = Cursor::new(&ibuf[..]);
+
+        // leave space for the chunk header, which we fill in
+        // once we know how many records survived.
+        obuf.get_mut().clear();
+        obuf.set_position(0);
+        obuf.write_all(&buf)?;
+        let mut nrec_out = 0u32;
+
+        for i in 0..nrec {
+            let (bc, umi, na) =
+                match ReadRecord::from_bytes_record_header_checked(&mut icursor, &bct, &umit) {
+                    Ok(h) => h,
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return Err(RadError::TruncatedChunk { nrec, read: i });
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+            let ref_start = icursor.position() as usize;
+            let ref_end = ref_start + (na as usize) * std::mem::size_of::<u32>();
+            if ref_end > ibuf.len() {
+                return Err(RadError::ChunkSizeMismatch {
+                    declared: nbytes,
+                    consumed: (buf.len() + ref_end) as u64,
+                });
+            }
+            icursor.set_position(ref_end as u64);
+
+            if let Some(corrected_bc) = correct_map.get(&bc) {
+                obuf.write_all(&na.to_le_bytes())?;
+                bct.write_to(*corrected_bc, &mut obuf)?;
+                umit.write_to(umi, &mut obuf)?;
+                obuf.write_all(&ibuf[ref_start..ref_end])?;
+                nrec_out += 1;
+            }
+        }
+
+        let nbytes_out = obuf.get_ref().len() as u32;
+        obuf.set_position(0);
+        obuf.write_all(&nbytes_out.to_le_bytes())?;
+        obuf.write_all(&nrec_out.to_le_bytes())?;
+        writer.write_all(obuf.get_ref())?;
+    }
+    Ok(())
+}
+
+/// A snapshot of the work done so far by one of the collation functions,
+/// passed to the optional progress callback those functions accept.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CollateProgress {
+    pub records_processed: u64,
+    pub bytes_written: u64,
+    pub buckets_touched: u64,
+}
+
+/// The number of records processed between invocations of a progress callback.
+pub const COLLATE_PROGRESS_INTERVAL: u64 = 100_000;
+
+#[inline]
+fn report_progress(progress: Option<&dyn Fn(CollateProgress)>, p: CollateProgress) {
+    if let Some(f) = progress {
+        f(p);
+    }
+}
+
+/// Buffer sizes used when distributing records to temporary buckets and
+/// collating them.  The defaults are suitable for most data; very small
+/// buckets (e.g. heavily multiplexed samples) may benefit from smaller
+/// buffers, and very large buckets from larger ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollateParams {
+    /// capacity, in bytes, of the `BufWriter` of each `TempBucket`
+    pub writer_capacity: usize,
+    /// the number of bytes to preallocate per expected record in a `CorrectedCbChunk`
+    pub bytes_per_record_hint: usize,
+    /// the size, in bytes, at which a thread-local buffer is flushed to its bucket
+    pub flush_limit: usize,
+    /// the layout of the alignments of the input records
+    pub aln_layout: AlnLayout,
+    /// if set, alignments with a lower score (see `AlnLayout`) are dropped
+    pub min_score: Option<u32>,
+}
+
+impl Default for CollateParams {
+    fn default() -> Self {
+        CollateParams {
+            writer_capacity: 4096,
+            bytes_per_record_hint: 24,
+            flush_limit: 262_144,
+            aln_layout: AlnLayout::default(),
+            min_score: None,
+        }
+    }
+}
+
+pub struct TempBucket {
+    pub bucket_id: u32,
+    pub path: std::path::PathBuf,
+    pub bucket_writer: Arc<Mutex<BufWriter<File>>>,
+    pub num_chunks: u32,
+    pub num_records: u32,
+    pub num_records_written: AtomicU32,
+    pub num_bytes_written: AtomicU64,
+}
+
+impl TempBucket {
+    pub fn from_id_and_parent(
+        bucket_id: u32,
+        parent: &std::path::Path,
+        params: &CollateParams,
+    ) -> Self {
+        let path = parent.join(&format!("bucket_{}.tmp", bucket_id));
+        TempBucket {
+            bucket_id,
+            bucket_writer: Arc::new(Mutex::new(BufWriter::with_capacity(
+                params.writer_capacity,
+                File::create(&path).unwrap(),
+            ))),
+            path,
+            num_chunks: 0u32,
+            num_records: 0u32,
+            num_records_written: AtomicU32::new(0u32),
+            num_bytes_written: AtomicU64::new(0u64),
+        }
+    }
+}
+
+/// Totals reported by `merge_temp_buckets`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MergeStats {
+    pub num_buckets: u64,
+    pub num_records: u64,
+    pub num_barcodes: u64,
+}
+
+/// Collate the records of each of `buckets` into per-barcode chunks,
+/// written to `owriter` (as by `collate_temporary_bucket_twopass`).  The
+/// writer of each bucket is flushed first, and the number of records read
+/// from it is that recorded in its `num_records_written`, each of whose
+/// alignments has the layout `aln_layout`.  Each bucket's temporary file
+/// is removed once it has been merged.
+pub fn merge_temp_buckets<'a, I, U>(
+    buckets: I,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    aln_layout: &AlnLayout,
+    owriter: &Mutex<U>,
+) -> std::io::Result<MergeStats>
+where
+    I: IntoIterator<Item = &'a TempBucket>,
+    U: Write,
+{
+    let mut stats = MergeStats::default();
+    let mut cmap: HashMap<u64, TempCellInfo, ahash::RandomState> =
+        HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+    for b in buckets {
+        b.bucket_writer.lock().unwrap().flush()?;
+        let nrec = b.num_records_written.load(Ordering::SeqCst);
+
+        let mut reader = BufReader::new(File::open(&b.path)?);
+        cmap.clear();
+        let nbarcodes = collate_temporary_bucket_twopass(
+            &mut reader,
+            bct,
+            umit,
+            nrec,
+            owriter,
+            false,
+            &mut cmap,
+            None,
+            aln_layout,
+            None,
+        );
+        drop(reader);
+        std::fs::remove_file(&b.path)?;
+
+        stats.num_buckets += 1;
+        stats.num_records += nrec as u64;
+        stats.num_barcodes += nbarcodes as u64;
+    }
+    Ok(stats)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn dump_corrected_cb_chunk_to_temp_file<T: Read>(
+    reader: &mut BufReader<T>,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    correct_map: &HashMap<u64, u64>,
+    expected_ori: &Strand,
+    output_cache: &HashMap<u64, Arc<TempBucket>>,
+    local_buffers: &mut [Cursor<&mut [u8]>],
+    params: &CollateParams,
+    progress: Option<&dyn Fn(CollateProgress)>,
+) -> Result<CorrectionStats, RadError> {
+    let mut stats = CorrectionStats::default();
+    let mut tbuf = vec![0u8; 4096];
+    let mut prog = CollateProgress::default();
+    let mut touched = vec![false; local_buffers.len()];
+    //let mut tcursor = Cursor::new(tbuf);
+    //tcursor.set_position(0);
+
+    // get the number of bytes and records for
+    // the next chunk
+    let (_nbytes, nrec) = Chunk::read_header_checked(reader)?;
+
+    let bc_bytes = bct.bytes_for_type();
+    let umi_bytes = umit.bytes_for_type();
+    let na_bytes = std::mem::size_of::<u32>();
+    let target_id_bytes = std::mem::size_of::<u32>();
+    let aln_bytes = params.aln_layout.aln_bytes();
+
+    // reused for every record of the chunk
+    let mut rr = ReadRecord::default();
+    // used in place of `rr` for alignments holding more than a target id,
+    // or those of paired-end records, whose mate orientation bit is kept
+    let mut raw_alns = Vec::<u8>::new();
+    let mut ext_alns = Vec::<u32>::new();
+    // for each record, read it
+    for _ in 0..(nrec as usize) {
+        prog.records_processed += 1;
+        if prog.records_processed % COLLATE_PROGRESS_INTERVAL == 0 {
+            report_progress(progress, prog);
+        }
+
+        let tup = ReadRecord::from_bytes_record_header_checked(reader, &bct, &umit)?;
+
+        // if this record had a correct or correctable barcode
+        if let Some(corrected_id) = correct_map.get(&tup.0) {
+            let (na, alns) = if aln_bytes == target_id_bytes && !params.aln_layout.paired {
+                rr.read_with_header_keep_ori_into_checked(
+                    reader,
+                    tup.0,
+                    tup.1,
+                    tup.2,
+                    &params.aln_layout,
+                    expected_ori,
+                )?;
+                (rr.refs.len(), &rr.refs[..])
+            } else {
+                let na = read_filtered_alignments(
+                    reader,
+                    tup.2 as usize,
+                    &params.aln_layout,
+                    expected_ori,
+                    params.min_score,
+                    &mut raw_alns,
+                    &mut ext_alns,
+                )?;
+                (na, &ext_alns[..])
+            };
+
+            if let Some(v) = output_cache.get(corrected_id) {
+                if !tally_corrected_record(&mut stats, na) {
+                    continue;
+                }
+                // if this is a valid barcode, then
+                // write the corresponding entry to the
+                // thread-local buffer for this bucket
+
+                // the total number of bytes this record will take
+                let nb = (na * aln_bytes + na_bytes + bc_bytes + umi_bytes) as u64;
+
+                // the buffer index for this corrected barcode
+                let buffidx = v.bucket_id as usize;
+                // the current cursor for this buffer
+                let bcursor = &mut local_buffers[buffidx];
+                // the current position of the cursor
+                let len = bcursor.position() as usize;
+
+                // if writing the next record (nb bytes) will put us over
+                // the flush size for the thread-local buffer for this bucket
+                // then first flush the buffer to file.
+                if len + nb as usize >= params.flush_limit {
+                    let mut filebuf = v.bucket_writer.lock().unwrap();
+                    filebuf
+                        .write_all(&bcursor.get_ref()[0..len as usize])
+                        .unwrap();
+                    // and reset the local buffer cursor
+                    bcursor.set_position(0);
+                }
+
+                // now, write the record to the buffer
+                bcursor.write_all(&(na as u32).to_le_bytes()).unwrap();
+                bct.write_to(*corrected_id, bcursor).unwrap();
+                umit.write_to(tup.1, bcursor).unwrap();
+                bcursor.write_all(as_u8_slice(alns)).unwrap();
+
+                // update number of written records
+                v.num_records_written.fetch_add(1, Ordering::SeqCst);
+                // update number of written bytes
+                v.num_bytes_written.fetch_add(nb, Ordering::SeqCst);
+
+                prog.bytes_written += nb;
+                if !touched[buffidx] {
+                    touched[buffidx] = true;
+                    prog.buckets_touched += 1;
+                }
+            }
+        } else {
+            // in this branch, we don't have access to a correct barcode for
+            // what we observed, so we need to discard the remaining part of
+            // the record.
+            stats.uncorrectable += 1;
+            let req_len = aln_bytes * (tup.2 as usize);
+            let do_resize = req_len > tbuf.len();
+
+            if do_resize {
+                tbuf.resize(req_len, 0);
+            }
+
+            reader.read_exact(&mut tbuf[0..req_len])?;
+
+            if do_resize {
+                tbuf.resize(4096, 0);
+                tbuf.shrink_to_fit();
+            }
+        }
+    }
+    report_progress(progress, prog);
+    Ok(stats)
+}
+
+pub(crate) fn as_u8_slice(v: &[u32]) -> &[u8] {
     unsafe {
         std::slice::from_raw_parts(
             v.as_ptr() as *const u8,
             v.len() * std::mem::size_of::<u32>(),
         )
     }
-}
+}
+
+//pub fn dump_output_cache(
+//    mut owriter: &mut BufWriter<File>,
+//    output_cache: &DashMap<u64, CorrectedCBChunk>,
+//    chunk_config: &ChunkConfig,
+//) {
+//    // NOTE: since the chunks are independent, this part could be multithreaded
+//    let bc_type = decode_int_type_tag(chunk_config.bc_type).expect("unknown barcode type id.");
+//    let umi_type = decode_int_type_tag(chunk_config.umi_type).expect("unknown barcode type id.");
+//
+//    for entry_ref in output_cache.iter() {
+//        let _bc = entry_ref.key();
+//        let chunk = entry_ref.value();
+//        // number of bytes
+//        let mut nbytes: u32 = 0;
+//        let bytes_for_u32 = std::mem::size_of::<u32>();
+//
+//        let bytes_for_bc = bc_type.bytes_for_type();
+//        let bytes_for_umi = umi_type.bytes_for_type();
+//
+//        // new
+//        /*nbytes += chunk.data.get_ref().len() as u32;
+//        owriter
+//            .write_all(&nbytes.to_le_bytes())
+//            .expect("couldn't write output.");
+//        let nrec = chunk.nrec;
+//        owriter
+//            .write_all(&nrec.to_le_bytes())
+//            .expect("couldn't write output.");
+//        */
+//        owriter
+//            .write_all(&chunk.data.get_ref())
+//            .expect("couldn't write output.");
+//        // end new
+//        /*
+//        // for reference IDs in this chunk
+//        nbytes += (chunk.ref_ids.len() * bytes_for_u32) as u32;
+//        // umis
+//        nbytes += (chunk.umis.len() * bytes_for_umi) as u32;
+//        // barcodes
+//        nbytes += (chunk.umis.len() * bytes_for_bc) as u32;
+//        // num alignment fields
+//        nbytes += (chunk.umis.len() * bytes_for_u32) as u32;
+//
+//        let nrec = chunk.umis.len() as u32;
+//
+//        owriter
+//            .write_all(&nbytes.to_le_bytes())
+//            .expect("couldn't write output.");
+//        owriter
+//            .write_all(&nrec.to_le_bytes())
+//            .expect("couldn't write output.");
+//
+//        for i in 0..chunk.umis.len() {
+//            let s = chunk.ref_offsets[i];
+//            let e = if i == chunk.umis.len() - 1 {
+//                chunk.ref_ids.len() as u32
+//            } else {
+//                chunk.ref_offsets[i + 1]
+//            };
+//
+//            // num alignments
+//            let num_aln = (e - s) as u32;
+//            owriter
+//                .write_all(&num_aln.to_le_bytes())
+//                .expect("couldn't write output.");
+//
+//            bc_type
+//                .write_to(*_bc, &mut owriter)
+//                .expect("couldn't write output.");
+//            umi_type
+//                .write_to(chunk.umis[i], &mut owriter)
+//                .expect("couldn't write output.");
+//            owriter
+//                .write_all(as_u8_slice(&chunk.ref_ids[(s as usize)..(e as usize)]))
+//                .expect("couldn't write output.");
+//        }
+//        */
+//    }
+//}
+
+// Append exactly `n` bytes of `reader` to `buf`, growing `buf` only as the
+// bytes arrive, so that a size read from a truncated or malformed input
+// doesn't cause a huge allocation up front.
+pub(crate) fn read_exact_bounded<T: Read>(
+    reader: &mut T,
+    buf: &mut Vec<u8>,
+    n: usize,
+) -> std::io::Result<()> {
+    let start = buf.len();
+    reader.take(n as u64).read_to_end(buf)?;
+    if buf.len() - start < n {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "expected {} bytes, but the input ended after {}",
+                n,
+                buf.len() - start
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Check that a chunk declaring `nbytes` bytes and `nrec` records is
+// plausible: that it has room for its own header, and for the fixed-size
+// part of each of its records.
+fn check_chunk_header(
+    nbytes: u32,
+    nrec: u32,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> Result<(), RadError> {
+    if nbytes < 8 {
+        return Err(RadError::InvalidChunkSize(nbytes));
+    }
+    check_limit("record count", nrec as u64, MAX_RECORDS_PER_CHUNK as u64)?;
+    let min_rec_bytes =
+        (mem::size_of::<u32>() + bct.bytes_for_type() + umit.bytes_for_type()) as u64;
+    let min_bytes = 8 + nrec as u64 * min_rec_bytes;
+    if min_bytes > nbytes as u64 {
+        return Err(RadError::ChunkSizeMismatch {
+            declared: nbytes,
+            consumed: min_bytes,
+        });
+    }
+    Ok(())
+}
+
+impl<B: BarcodeT> Chunk<B> {
+    pub fn from_bytes<T: Read>(reader: &mut T, bct: &RadIntId, umit: &RadIntId) -> Self {
+        let mut buf = [0u8; 8];
+
+        reader.read_exact(&mut buf).unwrap();
+        let nbytes = buf.pread::<u32>(0).unwrap();
+        let nrec = buf.pread::<u32>(4).unwrap();
+        let mut c = Self {
+            nbytes,
+            nrec,
+            reads: Vec::with_capacity(prealloc(nrec as u64)),
+        };
+
+        for _ in 0..(nrec as usize) {
+            c.reads.push(ReadRecord::from_bytes(reader, &bct, &umit));
+        }
+
+        c
+    }
+}
+
+impl Chunk {
+    pub fn read_header<T: Read>(reader: &mut T) -> (u32, u32) {
+        Chunk::read_header_checked(reader).expect("could not read chunk header.")
+    }
+
+    /// Like `read_header`, but returns an error, rather than panicking, if
+    /// the input ends before the chunk header does, or the chunk declares a
+    /// size too small to hold its own header.
+    pub fn read_header_checked<T: Read>(reader: &mut T) -> Result<(u32, u32), RadError> {
+        let (nbytes, nrec) = read_chunk_header_opt(reader)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "unexpected end of file while reading chunk header",
+            )
+        })?;
+        if nbytes < 8 {
+            return Err(RadError::InvalidChunkSize(nbytes));
+        }
+        Ok((nbytes, nrec))
+    }
+
+    /// Parse the chunk at the start of `buf` (including its 8 byte header),
+    /// as `from_bytes` would, but reading directly out of the slice, e.g.
+    /// a memory-mapped file, rather than through an intermediate buffer.
+    /// Returns an error if the records run past the end of `buf`.
+    pub fn from_slice(buf: &[u8], bct: &RadIntId, umit: &RadIntId) -> Result<Self, RadError> {
+        let views = RecordViews::new(buf, bct, umit)?;
+        let nrec = views.nrec;
+        let reads = views
+            .map(|r| r.map(|v| v.to_record()))
+            .collect::<Result<Vec<ReadRecord>, RadError>>()?;
+        Ok(Self {
+            nbytes: buf.pread::<u32>(0).unwrap(),
+            nrec,
+            reads,
+        })
+    }
+
+    /// Read the whole of the next chunk from `reader`, header included,
+    /// into `buf` (replacing its contents), e.g. to be iterated over with
+    /// `RecordViews`.  `buf` can be reused across chunks, so that no
+    /// allocation is done once it has grown to the largest chunk.
+    pub fn read_raw<T: Read>(reader: &mut T, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        if Chunk::read_raw_opt(reader, buf)? {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the input ended where a chunk was expected",
+            ))
+        }
+    }
+
+    /// Like `read_raw`, but returns `false`, leaving `buf` empty, if
+    /// `reader` is already at the end of its input.
+    pub fn read_raw_opt<T: Read>(reader: &mut T, buf: &mut Vec<u8>) -> std::io::Result<bool> {
+        buf.clear();
+        let (nbytes, nrec) = match read_chunk_header_opt(reader)? {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        let nbytes = nbytes as usize;
+        if nbytes < 8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid chunk size of {} bytes", nbytes),
+            ));
+        }
+        buf.extend_from_slice(&(nbytes as u32).to_le_bytes());
+        buf.extend_from_slice(&nrec.to_le_bytes());
+        read_exact_bounded(reader, buf, nbytes - 8)?;
+        Ok(true)
+    }
+
+    /// Read the next chunk from `reader` without materializing it, calling `f`
+    /// on each of its records in turn.  A single `ReadRecord` is reused for
+    /// every record, so no allocation is done per record once its vectors have
+    /// grown to the largest record seen.  Returns the `nbytes` and `nrec` of
+    /// the chunk, or an error if the chunk can't be read.
+    pub fn for_each_record<T: Read, F: FnMut(&ReadRecord)>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        mut f: F,
+    ) -> Result<(u32, u32), RadError> {
+        let (nbytes, nrec) = Self::read_header_checked(reader)?;
+        let mut rec = ReadRecord::default();
+        for _ in 0..(nrec as usize) {
+            rec.read_into(reader, bct, umit)?;
+            f(&rec);
+        }
+        Ok((nbytes, nrec))
+    }
+
+    /// Split the orientation of the mate out of the reference ids of every
+    /// record, for a chunk of a paired-end file (see
+    /// `ReadRecord::split_mate_ori`).
+    pub fn split_mate_ori(&mut self) {
+        for r in self.reads.iter_mut() {
+            r.split_mate_ori();
+        }
+    }
+
+    /// Iterate over the records of this chunk that have at least one alignment.
+    pub fn non_empty_records(&self) -> impl Iterator<Item = &ReadRecord> {
+        self.reads.iter().filter(|r| !r.is_empty())
+    }
+
+    /// Consume this chunk, yielding only the records that have at least one alignment.
+    pub fn into_non_empty_records(self) -> impl Iterator<Item = ReadRecord> {
+        self.reads.into_iter().filter(|r| !r.is_empty())
+    }
+
+    /// Parse a chunk, keeping only the alignments to references in `keep`
+    /// (see `ReadRecord::from_bytes_filtered`).  Records left with no
+    /// alignments are dropped, so `reads` may hold fewer than `nrec` records.
+    /// Returns an error if the chunk can't be read.
+    pub fn from_bytes_filtered<T: Read, S: BuildHasher>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+        keep: &HashSet<u32, S>,
+    ) -> Result<Self, RadError> {
+        let (nbytes, nrec) = Self::read_header_checked(reader)?;
+        check_limit("record count", nrec as u64, MAX_RECORDS_PER_CHUNK as u64)?;
+        let mut c = Self {
+            nbytes,
+            nrec,
+            reads: Vec::with_capacity(prealloc(nrec as u64)),
+        };
+
+        for _ in 0..(nrec as usize) {
+            let rec = ReadRecord::from_bytes_filtered(reader, bct, umit, keep)?;
+            if !rec.is_empty() {
+                c.reads.push(rec);
+            }
+        }
+
+        Ok(c)
+    }
+
+    /// Like `from_bytes`, but verifies that the records parsed account
+    /// for exactly the `nbytes` declared in the chunk header, returning
+    /// an error if they do not (or if the header is implausible, see
+    /// `MAX_RECORDS_PER_CHUNK`).
+    pub fn from_bytes_checked<T: Read>(
+        reader: &mut T,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> Result<Self, RadError> {
+        let mut buf = [0u8; 8];
+
+        reader.read_exact(&mut buf)?;
+        let nbytes = buf.pread::<u32>(0).unwrap();
+        let nrec = buf.pread::<u32>(4).unwrap();
+        check_chunk_header(nbytes, nrec, bct, umit)?;
+        let mut c = Self {
+            nbytes,
+            nrec,
+            reads: Vec::with_capacity(prealloc(nrec as u64)),
+        };
+
+        let rec_header_size =
+            (mem::size_of::<u32>() + bct.bytes_for_type() + umit.bytes_for_type()) as u64;
+        let mut consumed = buf.len() as u64;
+        for i in 0..nrec {
+            let rec = match ReadRecord::from_bytes_checked(reader, bct, umit) {
+                Ok(rec) => rec,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(RadError::TruncatedChunk { nrec, read: i });
+                }
+                Err(e) => return Err(e.into()),
+            };
+            consumed += rec_header_size + (rec.refs.len() * mem::size_of::<u32>()) as u64;
+            c.reads.push(rec);
+        }
+
+        if consumed != nbytes as u64 {
+            return Err(RadError::ChunkSizeMismatch {
+                declared: nbytes,
+                consumed,
+            });
+        }
+        Ok(c)
+    }
+
+    /// peeks to the first record in the buffer `buf`, and returns
+    /// the barcode and umi associated with this record.  It is assumed
+    /// that there is at least one record present in the buffer.  A `U128`
+    /// barcode or umi that doesn't fit in a u64 is an `InvalidData` error.
+    pub fn peek_record(buf: &[u8], bct: &RadIntId, umit: &RadIntId) -> std::io::Result<(u64, u64)> {
+        let (bc, umi, _na) = Chunk::peek_record_header(buf, bct, umit)?;
+        Ok((bc, umi))
+    }
+
+    /// Like `peek_record`, but also returns the number of alignments of
+    /// the first record, as `(bc, umi, na)`.
+    pub fn peek_record_header(
+        buf: &[u8],
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> std::io::Result<(u64, u64, u32)> {
+        let na_size = mem::size_of::<u32>();
+        let bc_size = bct.bytes_for_type();
+
+        let na = buf.pread::<u32>(0).unwrap();
+        let bc = pread_into_u64(buf, na_size, bct)?;
+        let umi = pread_into_u64(buf, na_size + bc_size, umit)?;
+        Ok((bc, umi, na))
+    }
+}
+
+/// Iterates over the records of the next chunk of `reader`, parsing each
+/// one only when it is requested, so that the chunk is never held in
+/// memory as a whole.  The chunk header is read by `new`, and its fields
+/// are available before any record is.  If the iterator is dropped before
+/// it is exhausted, `reader` is left partway through the chunk.  A record
+/// that can't be read is yielded as an error, after which the iterator
+/// yields nothing more.
+pub struct ChunkRecords<'a, R> {
+    reader: &'a mut R,
+    bct: RadIntId,
+    umit: RadIntId,
+    pub nbytes: u32,
+    pub nrec: u32,
+    remaining: u32,
+}
+
+impl<'a, R: Read> ChunkRecords<'a, R> {
+    pub fn new(
+        reader: &'a mut R,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> Result<ChunkRecords<'a, R>, RadError> {
+        let (nbytes, nrec) = Chunk::read_header_checked(reader)?;
+        Ok(ChunkRecords {
+            reader,
+            bct: *bct,
+            umit: *umit,
+            nbytes,
+            nrec,
+            remaining: nrec,
+        })
+    }
+}
+
+impl<'a, R: Read> Iterator for ChunkRecords<'a, R> {
+    type Item = Result<ReadRecord, RadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let r = ReadRecord::from_bytes_checked(self.reader, &self.bct, &self.umit);
+        if r.is_err() {
+            // the reader is no longer at a record boundary
+            self.remaining = 0;
+        }
+        Some(r.map_err(RadError::from))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // an error ends the iteration early
+        (
+            self.remaining.min(1) as usize,
+            Some(self.remaining as usize),
+        )
+    }
+}
+
+/// A record of a chunk that borrows its alignments from the bytes of the
+/// chunk, rather than copying them into vectors as `ReadRecord` does.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadRecordView<'a> {
+    pub bc: u64,
+    pub umi: u64,
+    // the raw alignment entries (reference id plus orientation bit)
+    alns: &'a [u8],
+}
+
+impl<'a> ReadRecordView<'a> {
+    /// The number of alignments of the record.
+    pub fn len(&self) -> usize {
+        self.alns.len() / mem::size_of::<u32>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alns.is_empty()
+    }
+
+    fn raw(&self) -> impl Iterator<Item = u32> + 'a {
+        self.alns
+            .chunks_exact(mem::size_of::<u32>())
+            .map(|b| b.pread::<u32>(0).unwrap())
+    }
+
+    /// The reference ids of the alignments, as in `ReadRecord::refs`.
+    pub fn refs(&self) -> impl Iterator<Item = u32> + 'a {
+        self.raw().map(|v| v & MASK_TOP_BIT_U32)
+    }
+
+    /// The orientations of the alignments, as in `ReadRecord::dirs`.
+    pub fn dirs(&self) -> impl Iterator<Item = bool> + 'a {
+        self.raw().map(|v| (v & MASK_LOWER_31_U32) != 0)
+    }
+
+    /// Copy this view into an owned `ReadRecord`.
+    pub fn to_record(&self) -> ReadRecord {
+        ReadRecord {
+            bc: self.bc,
+            umi: self.umi,
+            dirs: self.dirs().collect(),
+            mate_dirs: Vec::new(),
+            refs: self.refs().collect(),
+            tags: None,
+        }
+    }
+}
+
+/// Iterates over views of the records of the chunk held in a buffer
+/// (header included, e.g. as filled by `Chunk::read_raw` or a
+/// `MappedChunk`), without allocating.  A record that runs past the end
+/// of the buffer, or whose `U128` barcode or umi doesn't fit in a u64, is
+/// yielded as an error, after which the iterator yields nothing more.
+pub struct RecordViews<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    pub nrec: u32,
+    remaining: u32,
+    bct: RadIntId,
+    umit: RadIntId,
+}
+
+impl<'a> RecordViews<'a> {
+    /// Returns an error if `buf` is too short to hold a chunk header.
+    pub fn new(
+        buf: &'a [u8],
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> Result<RecordViews<'a>, RadError> {
+        let nrec = buf
+            .pread::<u32>(4)
+            .map_err(|_| RadError::InvalidChunkSize(buf.len() as u32))?;
+        Ok(RecordViews {
+            buf,
+            offset: 8,
+            nrec,
+            remaining: nrec,
+            bct: *bct,
+            umit: *umit,
+        })
+    }
+
+    /// The view of the record at `self.offset`, or `None` if it doesn't
+    /// fit in the buffer.  A `U128` barcode or umi that doesn't fit in a
+    /// u64 is an `InvalidData` error.
+    fn next_view(&mut self) -> Option<std::io::Result<ReadRecordView<'a>>> {
+        let na = self.buf.pread::<u32>(self.offset).ok()? as usize;
+        let bc_start = self.offset + mem::size_of::<u32>();
+        let umi_start = bc_start + self.bct.bytes_for_type();
+        let alns_start = umi_start + self.umit.bytes_for_type();
+        let end = alns_start.checked_add(na.checked_mul(mem::size_of::<u32>())?)?;
+        let alns = self.buf.get(alns_start..end)?;
+        let bc = pread_into_u64(self.buf, bc_start, &self.bct);
+        let umi = pread_into_u64(self.buf, umi_start, &self.umit);
+        self.offset = end;
+        Some(bc.and_then(|bc| {
+            Ok(ReadRecordView {
+                bc,
+                umi: umi?,
+                alns,
+            })
+        }))
+    }
+}
+
+impl<'a> Iterator for RecordViews<'a> {
+    type Item = Result<ReadRecordView<'a>, RadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.next_view() {
+            Some(Ok(v)) => {
+                self.remaining -= 1;
+                Some(Ok(v))
+            }
+            Some(Err(e)) => {
+                self.remaining = 0;
+                Some(Err(e.into()))
+            }
+            None => {
+                let read = self.nrec - self.remaining;
+                self.remaining = 0;
+                Some(Err(RadError::TruncatedChunk {
+                    nrec: self.nrec,
+                    read,
+                }))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // an error ends the iteration early
+        (
+            self.remaining.min(1) as usize,
+            Some(self.remaining as usize),
+        )
+    }
+}
+
+/// Read the `(nbytes, nrec)` header of the next chunk from `reader`.
+/// Returns `Ok(None)` if `reader` is already at the end of its input, and
+/// an `UnexpectedEof` error if the input ends partway through the header.
+pub fn read_chunk_header_opt<R: Read>(reader: &mut R) -> std::io::Result<Option<(u32, u32)>> {
+    let mut buf = [0u8; 8];
+    let mut filled = 0usize;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    match filled {
+        0 => Ok(None),
+        8 => Ok(Some((
+            buf.pread::<u32>(0).unwrap(),
+            buf.pread::<u32>(4).unwrap(),
+        ))),
+        n => Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("found only {} of the 8 bytes of a chunk header", n),
+        )),
+    }
+}
+
+/// Starting from the current position of `reader`, which must be the
+/// beginning of the first chunk, walk over the headers of the next
+/// `num_chunks` chunks and return the absolute byte offset at which each
+/// chunk begins.  Only the 8-byte chunk headers are read; the chunk bodies
+/// are skipped over with `seek`.
+pub fn chunk_offsets<T: Read + Seek>(reader: &mut T, num_chunks: u64) -> std::io::Result<Vec<u64>> {
+    let mut offsets = Vec::with_capacity(prealloc(num_chunks));
+    let mut pos = reader.seek(SeekFrom::Current(0))?;
+    for _ in 0..num_chunks {
+        offsets.push(pos);
+        let (nbytes, _nrec) = read_chunk_header_opt(reader)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the file has fewer chunks than its header declares",
+            )
+        })?;
+        if nbytes < 8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid chunk size of {} bytes", nbytes),
+            ));
+        }
+        pos = reader.seek(SeekFrom::Current(nbytes as i64 - 8))?;
+    }
+    Ok(offsets)
+}
+
+/// Scan the `header.num_chunks` chunks starting at the current position
+/// of `reader` and return the largest number of alignments of any record,
+/// e.g. to record as the `MAX_AMBIGUITY_TAG` when rewriting a file.  The
+/// position of `reader` is restored afterward.
+pub fn compute_max_ambiguity<R: Read + Seek>(
+    reader: &mut R,
+    header: &RadHeader,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> std::io::Result<u32> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let mut max_ambiguity = 0u32;
+    // a u128 holds a barcode of any type
+    let mut rec = ReadRecord::<u128>::default();
+    for _ in 0..header.num_chunks {
+        let (_nbytes, nrec) = read_chunk_header_opt(reader)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the file has fewer chunks than its header declares",
+            )
+        })?;
+        for _ in 0..nrec {
+            rec.read_into(reader, bct, umit)?;
+            max_ambiguity = max_ambiguity.max(rec.refs.len() as u32);
+        }
+    }
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(max_ambiguity)
+}
+
+/// The bytes ending a RAD file that has a `ChunkIndex`; they are preceded
+/// by the (u64) offset at which the index begins.
+pub const CHUNK_INDEX_MAGIC: [u8; 8] = *b"RADCIDX1";
+
+/// As `CHUNK_INDEX_MAGIC`, but ending an index whose barcodes are stored
+/// as u128s rather than u64s, which is written only if some barcode
+/// doesn't fit in a u64.
+pub const CHUNK_INDEX_MAGIC_WIDE: [u8; 8] = *b"RADCIDX2";
+
+/// The location and barcode range of one chunk, as recorded in a `ChunkIndex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkIndexEntry {
+    /// The byte offset of the chunk from the start of the file.
+    pub offset: u64,
+    pub nrec: u32,
+    /// The barcodes of the first and last records of the chunk (0 if the
+    /// chunk is empty), widened to u128s.
+    pub first_bc: u128,
+    pub last_bc: u128,
+    /// The CRC-32 of the bytes of the chunk, including its header.
+    pub crc32: u32,
+}
+
+/// An index of the chunks of a RAD file, optionally written after the last
+/// chunk by `RadWriter::enable_chunk_index`, so that a chunk can be reached
+/// without reading those before it, and each chunk can be checked for
+/// corruption (see `RadReader::validate`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkIndex {
+    pub entries: Vec<ChunkIndexEntry>,
+}
+
+impl ChunkIndex {
+    /// Write the index, followed by the trailer giving its offset, which
+    /// must be `offset`.  The barcodes are written as u64s, unless one of
+    /// them doesn't fit in a u64, in which case they are all written as
+    /// u128s and the index ends with `CHUNK_INDEX_MAGIC_WIDE`.
+    pub fn write_to<W: Write>(&self, writer: &mut W, offset: u64) -> std::io::Result<()> {
+        let wide = self
+            .entries
+            .iter()
+            .any(|e| e.first_bc.max(e.last_bc) > u64::MAX as u128);
+        let bct = if wide { RadIntId::U128 } else { RadIntId::U64 };
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for e in &self.entries {
+            writer.write_all(&e.offset.to_le_bytes())?;
+            writer.write_all(&e.nrec.to_le_bytes())?;
+            bct.write_to(e.first_bc, writer)?;
+            bct.write_to(e.last_bc, writer)?;
+            writer.write_all(&e.crc32.to_le_bytes())?;
+        }
+        writer.write_all(&offset.to_le_bytes())?;
+        if wide {
+            writer.write_all(&CHUNK_INDEX_MAGIC_WIDE)
+        } else {
+            writer.write_all(&CHUNK_INDEX_MAGIC)
+        }
+    }
+
+    /// Read the index at the end of the file read by `reader`, or return
+    /// `None` if it has none.  The position of `reader` is restored afterward.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Option<ChunkIndex>, RadError> {
+        let start = reader.seek(SeekFrom::Current(0))?;
+        let res = match chunk_index_offset(reader)? {
+            None => None,
+            Some((offset, bct)) => {
+                reader.seek(SeekFrom::Start(offset))?;
+                let bc_size = bct.bytes_for_type();
+                let mut buf = vec![0u8; 16 + 2 * bc_size];
+                reader.read_exact(&mut buf[..8])?;
+                let n = buf.pread::<u64>(0).unwrap();
+                let mut entries = Vec::with_capacity(prealloc(n));
+                for _ in 0..n {
+                    reader.read_exact(&mut buf)?;
+                    entries.push(ChunkIndexEntry {
+                        offset: buf.pread::<u64>(0).unwrap(),
+                        nrec: buf.pread::<u32>(8).unwrap(),
+                        first_bc: pread_into_u128(&buf, 12, &bct),
+                        last_bc: pread_into_u128(&buf, 12 + bc_size, &bct),
+                        crc32: buf.pread::<u32>(12 + 2 * bc_size).unwrap(),
+                    });
+                }
+                Some(ChunkIndex { entries })
+            }
+        };
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(res)
+    }
+
+    /// The number of the first non-empty chunk whose barcode range includes
+    /// `bc`.  In a collated file, this is the chunk holding the cell `bc`.
+    pub fn find_cell(&self, bc: u128) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|e| e.nrec > 0 && e.first_bc <= bc && bc <= e.last_bc)
+    }
+}
+
+// The offset of the chunk index of the file read by `reader`, if it has
+// one, and the type with which its barcodes are stored.  The position of
+// `reader` is left at the end of the file.
+fn chunk_index_offset<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<(u64, RadIntId)>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < 16 {
+        return Ok(None);
+    }
+    let mut buf = [0u8; 16];
+    reader.seek(SeekFrom::End(-16))?;
+    reader.read_exact(&mut buf)?;
+    let bct = if buf[8..] == CHUNK_INDEX_MAGIC[..] {
+        RadIntId::U64
+    } else if buf[8..] == CHUNK_INDEX_MAGIC_WIDE[..] {
+        RadIntId::U128
+    } else {
+        return Ok(None);
+    };
+    Ok(Some((buf.pread::<u64>(0).unwrap(), bct)))
+}
+
+// The offset just past the last chunk of the file read by `reader`: the
+// start of its chunk index if it has one, and otherwise the end of the
+// file.  The position of `reader` is restored afterward.
+fn chunk_data_end<R: Read + Seek>(reader: &mut R) -> std::io::Result<u64> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let end = match chunk_index_offset(reader)? {
+        Some((offset, _)) => offset,
+        None => reader.seek(SeekFrom::End(0))?,
+    };
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(end)
+}
+
+/// Reads, in a single pass and without seeking, the chunks of input whose
+/// number of chunks wasn't known when its header was written (see
+/// `RadReader::from_stream`).  The chunks end at the end of the input, or
+/// at the `ChunkIndex` that may follow the last of them.  The index is
+/// recognized by its number of entries, which is the number of chunks read
+/// before it, when the rest of the input is exactly the rest of an index.
+/// Otherwise, the bytes read ahead to check are read back as chunks.
+#[derive(Debug, Default)]
+pub struct StreamChunks {
+    chunks_read: u64,
+    // bytes read ahead from the input, that come before the rest of it
+    lookahead: Cursor<Vec<u8>>,
+}
+
+impl StreamChunks {
+    /// The number of chunks whose headers have been read so far.
+    pub fn chunks_read(&self) -> u64 {
+        self.chunks_read
+    }
+
+    /// The rest of the input read by `reader`, from which the body of a
+    /// chunk is read after its header.
+    pub fn input<'a, R: Read>(&'a mut self, reader: &'a mut R) -> impl Read + 'a {
+        (&mut self.lookahead).chain(reader)
+    }
+
+    /// Read the header of the next chunk, returning its `nbytes` and
+    /// `nrec`, or `None` once the chunks have ended.
+    pub fn next_header<R: Read>(&mut self, reader: &mut R) -> std::io::Result<Option<(u32, u32)>> {
+        let (nbytes, nrec) = match read_chunk_header_opt(&mut self.input(reader))? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        if (nbytes as u64 | ((nrec as u64) << 32)) == self.chunks_read {
+            // an index holds 32 bytes per chunk (48 if its barcodes are
+            // u128s), then its offset and the magic
+            let rest_len = 32 * self.chunks_read + 16;
+            let wide_len = 48 * self.chunks_read + 16;
+            let mut rest = Vec::new();
+            self.input(reader)
+                .take(wide_len + 1)
+                .read_to_end(&mut rest)?;
+            if (rest.len() as u64 == rest_len && rest.ends_with(&CHUNK_INDEX_MAGIC))
+                || (rest.len() as u64 == wide_len && rest.ends_with(&CHUNK_INDEX_MAGIC_WIDE))
+            {
+                return Ok(None);
+            }
+            self.lookahead = Cursor::new(rest);
+        }
+        self.chunks_read += 1;
+        Ok(Some((nbytes, nrec)))
+    }
+
+    /// Like `Chunk::read_raw_opt`, but returns `false` once the chunks
+    /// have ended.
+    pub fn read_raw<R: Read>(
+        &mut self,
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+    ) -> std::io::Result<bool> {
+        buf.clear();
+        let (nbytes, nrec) = match self.next_header(reader)? {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        if nbytes < 8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid chunk size of {} bytes", nbytes),
+            ));
+        }
+        buf.extend_from_slice(&nbytes.to_le_bytes());
+        buf.extend_from_slice(&nrec.to_le_bytes());
+        read_exact_bounded(&mut self.input(reader), buf, nbytes as usize - 8)?;
+        Ok(true)
+    }
+}
+
+/// Walk the chunks starting at the current position of `reader` (the
+/// beginning of the first chunk) up to the end of the input (or the chunk
+/// index, if the file has one), reading only
+/// the chunk headers and skipping over the bodies, and return the number
+/// of chunks found.  Returns `ChunkCountMismatch` if this differs from
+/// `header.num_chunks`, and `InvalidChunkSize` if a chunk is too small to
+/// hold its header and records, or extends past the end of the input.
+/// If `zstd` is set (i.e. the file's `FileTags::zstd_chunks`), the records
+/// are compressed, so a chunk need only be large enough for its header.
+/// The position of `reader` is restored afterward.
+pub fn validate_chunk_count<R: Read + Seek>(
+    reader: &mut R,
+    header: &RadHeader,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> Result<u64, RadError> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let end = chunk_data_end(reader)?;
+
+    // the smallest possible record has no alignments
+    let min_rec_size = if zstd {
+        0
+    } else {
+        (mem::size_of::<u32>() + bct.bytes_for_type() + umit.bytes_for_type()) as u64
+    };
+    let mut pos = start;
+    let mut observed = 0u64;
+    while pos < end {
+        let (nbytes, nrec) = match read_chunk_header_opt(reader)? {
+            Some(h) => h,
+            None => break,
+        };
+        if (nbytes as u64) < 8 + nrec as u64 * min_rec_size || pos + nbytes as u64 > end {
+            reader.seek(SeekFrom::Start(start))?;
+            return Err(RadError::InvalidChunkSize(nbytes));
+        }
+        pos = reader.seek(SeekFrom::Current(nbytes as i64 - 8))?;
+        observed += 1;
+    }
+    reader.seek(SeekFrom::Start(start))?;
+
+    if observed != header.num_chunks {
+        return Err(RadError::ChunkCountMismatch {
+            expected: header.num_chunks,
+            observed,
+        });
+    }
+    Ok(observed)
+}
+
+/// The largest number of alignments of any record, taken from `file_tags`
+/// if it was recorded there, and otherwise computed by scanning the file
+/// with `compute_max_ambiguity`.
+pub fn max_ambiguity_or_scan<R: Read + Seek>(
+    file_tags: &FileTags,
+    reader: &mut R,
+    header: &RadHeader,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> std::io::Result<u32> {
+    match file_tags.max_ambiguity {
+        Some(m) => Ok(m),
+        None => compute_max_ambiguity(reader, header, bct, umit),
+    }
+}
+
+/// Recompute the checksum of a file written with `RadWriter::enable_checksum`
+/// and compare it with the one stored in its `CHECKSUM_TAG`.  `reader` should
+/// be positioned just after `header` (i.e. at the tag descriptions); the file
+/// is streamed rather than loaded, and the position of `reader` is restored
+/// afterward.  Returns `Err(RadError::MissingTag)` for files that have no
+/// checksum, e.g. because they were written before checksums were added.
+pub fn verify_checksum<R: Read + Seek>(
+    reader: &mut R,
+    header: &RadHeader,
+) -> Result<bool, RadError> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let res = checksum_matches(reader, header);
+    reader.seek(SeekFrom::Start(start))?;
+    res
+}
+
+// The body of `verify_checksum`, which leaves `reader` wherever it stops.
+fn checksum_matches<R: Read>(reader: &mut R, header: &RadHeader) -> Result<bool, RadError> {
+    let fl_tags = TagSection::from_bytes_checked(reader)?;
+    let _rl_tags = TagSection::from_bytes_checked(reader)?;
+    let _al_tags = TagSection::from_bytes_checked(reader)?;
+    let vals = tags::TagSchema::new(&fl_tags)?.read_values(reader)?;
+    let stored = vals
+        .get(CHECKSUM_TAG)
+        .and_then(|v| v.as_u64())
+        .map(|v| v as u32)
+        .ok_or_else(|| RadError::MissingTag(CHECKSUM_TAG.to_string()))?;
+
+    let mut crc = flate2::Crc::new();
+    let mut buf = vec![0u8; 65536];
+    for _ in 0..header.num_chunks {
+        reader.read_exact(&mut buf[..8])?;
+        let nbytes = buf.pread::<u32>(0).unwrap() as usize;
+        if nbytes < 8 {
+            return Err(RadError::InvalidChunkSize(nbytes as u32));
+        }
+        crc.update(&buf[..8]);
+        let mut remaining = nbytes - 8;
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            reader.read_exact(&mut buf[..n])?;
+            crc.update(&buf[..n]);
+            remaining -= n;
+        }
+    }
+    Ok(crc.sum() == stored)
+}
+
+/// Iterator over a range of chunks, returned by `read_chunk_range`.
+pub struct ChunkRange<'a, R> {
+    reader: &'a mut R,
+    index: &'a [u64],
+    next: usize,
+    end: usize,
+    zstd: bool,
+    bct: RadIntId,
+    umit: RadIntId,
+    started: bool,
+    done: bool,
+}
+
+/// Return an iterator over the chunks `[start, end)` of a RAD file, given
+/// the byte offset of every chunk in `index` (as computed by
+/// `chunk_offsets`).  The reader is first positioned at `index[start]`.
+/// After each chunk is parsed, the position of `reader` is checked against
+/// the start of the next chunk in `index` (or, for the last chunk of the
+/// file, the end of the stream or the start of the chunk index), so that an index that doesn't match the
+/// file is reported as an `InvalidData` error rather than yielding garbage.
+/// An invalid range is reported as an `InvalidInput` error.  Iteration stops
+/// after the first error.  The records of each chunk are decompressed first
+/// if `zstd` is set (i.e. the file's `FileTags::zstd_chunks`).
+pub fn read_chunk_range<'a, R: Read + Seek>(
+    reader: &'a mut R,
+    index: &'a [u64],
+    start: usize,
+    end: usize,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> ChunkRange<'a, R> {
+    ChunkRange {
+        reader,
+        index,
+        next: start,
+        end,
+        zstd,
+        bct: *bct,
+        umit: *umit,
+        started: false,
+        done: false,
+    }
+}
+
+impl<'a, R: Read + Seek> ChunkRange<'a, R> {
+    fn read_next(&mut self) -> std::io::Result<Chunk> {
+        if !self.started {
+            self.started = true;
+            if self.next > self.end || self.end > self.index.len() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid chunk range [{}, {}) for a file with {} chunks",
+                        self.next,
+                        self.end,
+                        self.index.len()
+                    ),
+                ));
+            }
+            self.reader.seek(SeekFrom::Start(self.index[self.next]))?;
+        }
+
+        let c = read_chunk(&mut *self.reader, self.zstd, &self.bct, &self.umit)
+            .map_err(rad_error_to_io)?;
+
+        let pos = self.reader.seek(SeekFrom::Current(0))?;
+        let expected = match self.index.get(self.next + 1) {
+            Some(o) => *o,
+            None => chunk_data_end(self.reader)?,
+        };
+        if pos != expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "chunk {} ends at byte {}, but the index expects {}",
+                    self.next, pos, expected
+                ),
+            ));
+        }
+        self.next += 1;
+        Ok(c)
+    }
+}
+
+impl<'a, R: Read + Seek> Iterator for ChunkRange<'a, R> {
+    type Item = std::io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || (self.started && self.next >= self.end) {
+            return None;
+        }
+        if !self.started && self.next == self.end && self.end <= self.index.len() {
+            return None;
+        }
+        let res = self.read_next();
+        if res.is_err() {
+            self.done = true;
+        }
+        Some(res)
+    }
+}
+
+/// Return the barcode and umi of the first record of the chunk beginning
+/// at byte `offset` of `reader` (e.g. as given by `chunk_offsets`).  Only
+/// the chunk header and the fixed-width fields of the first record are
+/// read (unless `zstd` is set, i.e. the file's `FileTags::zstd_chunks`, in
+/// which case the whole chunk is read and decompressed), and the position
+/// of `reader` is restored before returning, even if an error occurs.
+pub fn peek_chunk_first_record<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> std::io::Result<(u64, u64)> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let res = read_first_record_at(reader, offset, zstd, bct, umit);
+    reader.seek(SeekFrom::Start(start))?;
+    res
+}
+
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+fn read_first_record_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> std::io::Result<(u64, u64)> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let len = 12 + bct.bytes_for_type() + umit.bytes_for_type();
+    #[cfg(feature = "zstd")]
+    if zstd {
+        let c = decompress_chunk(reader).map_err(rad_error_to_io)?;
+        return first_record_of(&c, offset, len, bct, umit);
+    }
+    let mut buf = [0u8; 28];
+    reader.read_exact(&mut buf[..len])?;
+    first_record_of(&buf[..len], offset, len, bct, umit)
+}
+
+// the barcode and umi of the first record of the (uncompressed) chunk
+// `chunk`, found at `offset`, of which the first `len` bytes are needed
+fn first_record_of(
+    chunk: &[u8],
+    offset: u64,
+    len: usize,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> std::io::Result<(u64, u64)> {
+    let nrec = chunk.pread::<u32>(4).unwrap();
+    if nrec == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("the chunk at offset {} has no records", offset),
+        ));
+    }
+    if chunk.len() < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "the first record of the chunk at offset {} is truncated",
+                offset
+            ),
+        ));
+    }
+    Chunk::peek_record(&chunk[8..len], bct, umit)
+}
+
+/// Reads a RAD file from any `Read`: the header, tag descriptions and
+/// file-level tag values are parsed by `new`, after which the reader is
+/// an iterator over the `header.num_chunks` chunks of the file, each read
+/// only when requested.  Iteration stops after the first error.
+pub struct RadReader<R> {
+    reader: R,
+    pub header: RadHeader,
+    pub file_tags: TagSection,
+    pub read_tags: TagSection,
+    pub aln_tags: TagSection,
+    pub file_tag_vals: FileTags,
+    pub bc_type: RadIntId,
+    pub umi_type: RadIntId,
+    remaining: u64,
+    // read on the first call to `seek_to_cell`
+    chunk_index: Option<ChunkIndex>,
+    // set if chunks are read until the input ends (see `from_stream`)
+    stream: Option<StreamChunks>,
+}
+
+impl<R: Read> RadReader<R> {
+    pub fn new(mut reader: R) -> Result<RadReader<R>, RadError> {
+        let header = RadHeader::from_bytes_checked(&mut reader)?;
+        let file_tags = TagSection::from_bytes_checked(&mut reader)?;
+        let read_tags = TagSection::from_bytes_checked(&mut reader)?;
+        let aln_tags = TagSection::from_bytes_checked(&mut reader)?;
+        let file_tag_vals = FileTags::from_bytes_with_section(&mut reader, &file_tags)?;
+
+        let int_type = |name: &str| {
+            read_tags
+                .get(name)
+                .and_then(|t| decode_int_type_tag(t.typeid))
+                .ok_or_else(|| RadError::MissingTag(name.to_string()))
+        };
+        let bc_type = int_type("b")?;
+        let umi_type = int_type("u")?;
+        file_tag_vals.validate(&bc_type, &umi_type)?;
+
+        Ok(RadReader {
+            reader,
+            remaining: header.num_chunks,
+            header,
+            file_tags,
+            read_tags,
+            aln_tags,
+            file_tag_vals,
+            bc_type,
+            umi_type,
+            chunk_index: None,
+            stream: None,
+        })
+    }
+
+    /// Like `new`, but for input that may have been written before the
+    /// number of chunks was known (e.g. piped directly from the mapper,
+    /// with a `num_chunks` of 0 in the header).  If `header.num_chunks` is
+    /// 0, chunks are read until the input ends (or its chunk index, see
+    /// `StreamChunks`), rather than not at all.  Only `Read` is required,
+    /// so the input may be stdin or a pipe.
+    pub fn from_stream(reader: R) -> Result<RadReader<R>, RadError> {
+        let mut rr = RadReader::new(reader)?;
+        if rr.header.num_chunks == 0 {
+            rr.remaining = u64::MAX;
+            rr.stream = Some(StreamChunks::default());
+        }
+        Ok(rr)
+    }
+
+    /// A parser for records with every read- and alignment-level tag of
+    /// this file, for use instead of iterating over `Chunk`s when tags
+    /// beyond the barcode, umi and reference id are needed.  The records
+    /// it reads keep the values of these tags in `ReadRecord::tags`.
+    pub fn record_schema(&self) -> Result<tags::RecordSchema, RadError> {
+        let mut schema = tags::RecordSchema::new(&self.read_tags, &self.aln_tags)?;
+        schema.paired = self.header.is_paired != 0;
+        Ok(schema)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Consumes this `RadReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read + Seek> RadReader<R> {
+    /// Position the reader so that the next chunk it yields is the one
+    /// holding the cell with barcode `bc` (see `ChunkIndex::find_cell`),
+    /// followed by the chunks after it.  Returns `false`, leaving the reader
+    /// where it was, if no chunk holds `bc`, and `MissingChunkIndex` if the
+    /// file has no chunk index.  The number of chunks left is taken from the
+    /// index, so this works as well for a file whose header has a
+    /// `num_chunks` of 0 (see `from_stream`).
+    pub fn seek_to_cell(&mut self, bc: u128) -> Result<bool, RadError> {
+        if self.chunk_index.is_none() {
+            self.chunk_index =
+                Some(ChunkIndex::read_from(&mut self.reader)?.ok_or(RadError::MissingChunkIndex)?);
+        }
+        let index = self.chunk_index.as_ref().unwrap();
+        match index.find_cell(bc) {
+            Some(i) => {
+                self.reader.seek(SeekFrom::Start(index.entries[i].offset))?;
+                self.remaining = (index.entries.len() - i) as u64;
+                // any input read ahead while streaming is stale now
+                self.stream = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Check every chunk against the CRC-32 recorded for it in the chunk
+    /// index, returning the offset of the first chunk that doesn't match,
+    /// or `None` if all do.  Returns `MissingChunkIndex` if the file has no
+    /// chunk index.  The position of the reader is restored afterward.
+    pub fn validate(&mut self) -> Result<Option<u64>, RadError> {
+        if self.chunk_index.is_none() {
+            self.chunk_index =
+                Some(ChunkIndex::read_from(&mut self.reader)?.ok_or(RadError::MissingChunkIndex)?);
+        }
+        let index = self.chunk_index.as_ref().unwrap();
+        let start = self.reader.seek(SeekFrom::Current(0))?;
+        let data_end = chunk_data_end(&mut self.reader)?;
+
+        let mut buf = Vec::new();
+        let mut corrupt = None;
+        for (i, e) in index.entries.iter().enumerate() {
+            // the extent of a chunk is taken from the index rather than from
+            // its (possibly corrupt) header
+            let end = index.entries.get(i + 1).map_or(data_end, |n| n.offset);
+            buf.resize(end.saturating_sub(e.offset) as usize, 0);
+            self.reader.seek(SeekFrom::Start(e.offset))?;
+            let mut crc = flate2::Crc::new();
+            if self.reader.read_exact(&mut buf).is_ok() {
+                crc.update(&buf);
+            }
+            if crc.sum() != e.crc32 {
+                corrupt = Some(e.offset);
+                break;
+            }
+        }
+        self.reader.seek(SeekFrom::Start(start))?;
+        Ok(corrupt)
+    }
+}
+
+impl<R: Read> RadReader<R> {
+    /// The layout of the alignments of this file.
+    pub fn aln_layout(&self) -> Result<AlnLayout, RadError> {
+        AlnLayout::from_tags(self.header.is_paired != 0, &self.aln_tags)
+    }
+
+    /// Read the next chunk as an `ExtChunk`, with every field of the
+    /// `aln_layout` of the file, in place of iterating over `Chunk`s (e.g.
+    /// for paired-end files, or those with alignment positions).
+    pub fn next_ext(&mut self) -> Option<Result<ExtChunk, RadError>> {
+        match self.aln_layout() {
+            Ok(layout) => self.next_with(&layout),
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+
+    // Read the next chunk with `layout`, if any chunks remain.
+    fn next_with<L: ChunkLayout>(&mut self, layout: &L) -> Option<Result<L::Chunk, RadError>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let zstd = self.file_tag_vals.zstd_chunks;
+        let res = match self.stream.as_mut() {
+            Some(stream) => match stream.next_header(&mut self.reader) {
+                Ok(None) => {
+                    self.remaining = 0;
+                    return None;
+                }
+                Ok(Some((nbytes, nrec))) => {
+                    let mut hdr = [0u8; 8];
+                    hdr[..4].copy_from_slice(&nbytes.to_le_bytes());
+                    hdr[4..].copy_from_slice(&nrec.to_le_bytes());
+                    layout.read(
+                        &mut Cursor::new(hdr).chain(stream.input(&mut self.reader)),
+                        zstd,
+                        &self.bc_type,
+                        &self.umi_type,
+                    )
+                }
+                Err(e) => Err(e.into()),
+            },
+            None => layout.read(&mut self.reader, zstd, &self.bc_type, &self.umi_type),
+        };
+        self.remaining = if res.is_ok() { self.remaining - 1 } else { 0 };
+        Some(res)
+    }
+}
+
+impl<R: Read> Iterator for RadReader<R> {
+    type Item = Result<Chunk, RadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let paired = self.header.is_paired != 0;
+        self.next_with(&PlainLayout { paired })
+    }
+}
+
+// How the chunks of a file are parsed, given whether they are compressed
+// and the barcode and umi types.
+trait ChunkLayout {
+    type Chunk;
+    fn read<T: Read>(
+        &self,
+        reader: &mut T,
+        zstd: bool,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> Result<Self::Chunk, RadError>;
+}
+
+// Records holding just a reference id for each alignment, of a paired-end
+// file if `paired` is set.
+struct PlainLayout {
+    paired: bool,
+}
+
+impl ChunkLayout for PlainLayout {
+    type Chunk = Chunk;
+    fn read<T: Read>(
+        &self,
+        reader: &mut T,
+        zstd: bool,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> Result<Chunk, RadError> {
+        let mut c = read_chunk(reader, zstd, bct, umit)?;
+        if self.paired {
+            c.split_mate_ori();
+        }
+        Ok(c)
+    }
+}
+
+impl ChunkLayout for AlnLayout {
+    type Chunk = ExtChunk;
+    fn read<T: Read>(
+        &self,
+        reader: &mut T,
+        zstd: bool,
+        bct: &RadIntId,
+        umit: &RadIntId,
+    ) -> Result<ExtChunk, RadError> {
+        read_ext_chunk(reader, zstd, bct, umit, self)
+    }
+}
+
+// an io error for `e`, for the readers that report io errors
+pub(crate) fn rad_error_to_io(e: RadError) -> std::io::Error {
+    match e {
+        RadError::Io(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Read the next chunk from `reader`, decompressing it first if `zstd` is
+/// set (i.e. the file's `FileTags::zstd_chunks`).
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(crate) fn read_chunk<T: Read>(
+    reader: &mut T,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+) -> Result<Chunk, RadError> {
+    #[cfg(feature = "zstd")]
+    if zstd {
+        let c = decompress_chunk(reader)?;
+        return Chunk::from_bytes_checked(&mut Cursor::new(c), bct, umit);
+    }
+    Chunk::from_bytes_checked(reader, bct, umit)
+}
+
+/// Like `read_chunk`, but for records whose alignments have the layout
+/// `layout`.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+pub(crate) fn read_ext_chunk<T: Read>(
+    reader: &mut T,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    layout: &AlnLayout,
+) -> Result<ExtChunk, RadError> {
+    #[cfg(feature = "zstd")]
+    if zstd {
+        let c = decompress_chunk(reader)?;
+        return ExtChunk::from_bytes_checked(&mut Cursor::new(c), bct, umit, layout);
+    }
+    ExtChunk::from_bytes_checked(reader, bct, umit, layout)
+}
+
+/// The on-disk form of the (uncompressed) chunk `chunk`: its header, with
+/// the size updated, followed by its records compressed with zstd at `level`.
+#[cfg(feature = "zstd")]
+fn compress_chunk(chunk: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+    let records = zstd::stream::encode_all(&chunk[8..], level)?;
+    let mut out = Vec::with_capacity(8 + records.len());
+    out.extend_from_slice(&((8 + records.len()) as u32).to_le_bytes());
+    out.extend_from_slice(&chunk[4..8]);
+    out.extend_from_slice(&records);
+    Ok(out)
+}
+
+/// Read the next chunk written by `compress_chunk` from `reader`, and
+/// return it uncompressed, in the form read by `Chunk::from_bytes`.
+#[cfg(feature = "zstd")]
+fn decompress_chunk<R: Read>(reader: &mut R) -> Result<Vec<u8>, RadError> {
+    let mut hdr = [0u8; 8];
+    reader.read_exact(&mut hdr)?;
+    let nbytes = hdr.pread::<u32>(0).unwrap();
+    if nbytes < 8 {
+        return Err(RadError::InvalidChunkSize(nbytes));
+    }
+    let mut records = Vec::new();
+    read_exact_bounded(reader, &mut records, nbytes as usize - 8)?;
+    let records = zstd::stream::decode_all(&records[..])?;
+    let mut out = Vec::with_capacity(8 + records.len());
+    out.extend_from_slice(&((8 + records.len()) as u32).to_le_bytes());
+    out.extend_from_slice(&hdr[4..8]);
+    out.extend_from_slice(&records);
+    Ok(out)
+}
+
+/// Wraps a reader and keeps a running total of the number of bytes read
+/// through it, e.g. to report progress through a RAD file while parsing.
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        CountingReader { inner, count: 0 }
+    }
+
+    /// The total number of bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Parse every chunk of the RAD file at `path` in parallel, using a rayon
+/// pool of `num_threads` threads, and apply `f` to each one.  `chunk_start`
+/// is the offset of the first chunk (i.e. the position just after the file-level
+/// tag values).  Each worker reads through its own file handle and seeks to the
+/// chunks it is assigned, so only the chunks currently being processed are held
+/// in memory.  The results of `f` are returned in the order in which the chunks
+/// appear in the file, so that any reduction over them is deterministic.  If
+/// any chunk can't be read or parsed (see `Chunk::from_bytes_checked`), the
+/// first such error is returned instead.  The records of each chunk are
+/// decompressed first if `zstd` is set (i.e. the file's `FileTags::zstd_chunks`).
+#[allow(clippy::too_many_arguments)]
+pub fn for_each_chunk_parallel<P, F, R>(
+    path: P,
+    header: &RadHeader,
+    chunk_start: u64,
+    zstd: bool,
+    bct: &RadIntId,
+    umit: &RadIntId,
+    num_threads: usize,
+    f: F,
+) -> Result<Vec<R>, Box<dyn std::error::Error>>
+where
+    P: AsRef<std::path::Path>,
+    F: Fn(Chunk) -> R + Sync,
+    R: Send,
+{
+    let path = path.as_ref();
+    let mut br = BufReader::new(File::open(path)?);
+    br.seek(SeekFrom::Start(chunk_start))?;
+    let offsets = chunk_offsets(&mut br, header.num_chunks)?;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()?;
+
+    let res = pool.install(|| {
+        offsets
+            .par_iter()
+            .map_init(
+                || File::open(path).map(BufReader::new),
+                |reader, &offset| -> Result<R, RadError> {
+                    // io::Error isn't Clone, so each worker that failed to
+                    // open the file reports a copy of its error
+                    let reader = reader
+                        .as_mut()
+                        .map_err(|e| std::io::Error::new(e.kind(), e.to_string()))?;
+                    reader.seek(SeekFrom::Start(offset))?;
+                    Ok(f(read_chunk(reader, zstd, bct, umit)?))
+                },
+            )
+            .collect::<Result<Vec<R>, RadError>>()
+    })?;
+    Ok(res)
+}
+
+impl FileTags {
+    /// Read the file-level tag values of a file whose only file-level tags
+    /// are the barcode and umi lengths (in that order).  For files that may
+    /// have others, use `from_bytes_with_section`.
+    pub fn from_bytes<T: Read>(reader: &mut T) -> Self {
+        FileTags::from_bytes_checked(reader).unwrap()
+    }
+
+    /// Like `from_bytes`, but returns an error, rather than panicking, if
+    /// the input ends before the tag values do.
+    pub fn from_bytes_checked<T: Read>(reader: &mut T) -> Result<Self, RadError> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+
+        Ok(Self {
+            bclen: buf.pread::<u16>(0).unwrap(),
+            umilen: buf.pread::<u16>(2).unwrap(),
+            max_ambiguity: None,
+            variable_umi: false,
+            zstd_chunks: false,
+            extra: Vec::new(),
+        })
+    }
+
+    /// Read the value of every file-level tag described in `fl_tags`,
+    /// keeping the barcode length ("cblen"), umi length ("ulen") and,
+    /// if present, the `MAX_AMBIGUITY_TAG`, `VARIABLE_UMI_TAG` and
+    /// `CHUNK_COMPRESSION_TAG`.  The values of other tags, except the
+    /// `CHECKSUM_TAG`, are kept in `extra`.
+    pub fn from_bytes_with_section<T: Read>(
+        reader: &mut T,
+        fl_tags: &TagSection,
+    ) -> Result<Self, RadError> {
+        let mut bclen = None;
+        let mut umilen = None;
+        let mut max_ambiguity = None;
+        let mut variable_umi = false;
+        let mut zstd_chunks = false;
+        let mut extra = Vec::new();
+        let vals = tags::TagSchema::new(fl_tags)?.read_values(reader)?;
+        for (name, v) in vals.iter() {
+            match name {
+                "cblen" => bclen = v.as_u64().map(|v| v as u16),
+                "ulen" => umilen = v.as_u64().map(|v| v as u16),
+                MAX_AMBIGUITY_TAG => max_ambiguity = v.as_u64().map(|v| v as u32),
+                VARIABLE_UMI_TAG => variable_umi = *v == tags::TagValue::Bool(true),
+                CHUNK_COMPRESSION_TAG => match v.as_str() {
+                    Some("zstd") => zstd_chunks = true,
+                    _ => return Err(RadError::UnsupportedCompression(format!("{:?}", v))),
+                },
+                CHECKSUM_TAG => {}
+                _ => extra.push((name.to_string(), v.clone())),
+            }
+        }
+        Ok(Self {
+            bclen: bclen.ok_or_else(|| RadError::MissingTag("cblen".to_string()))?,
+            umilen: umilen.ok_or_else(|| RadError::MissingTag("ulen".to_string()))?,
+            max_ambiguity,
+            variable_umi,
+            zstd_chunks,
+            extra,
+        })
+    }
+
+    /// The descriptions of the file-level tags written by `write_to`: the
+    /// barcode and umi lengths, the `MAX_AMBIGUITY_TAG` if it is set, the
+    /// `VARIABLE_UMI_TAG` if UMIs vary in length, the
+    /// `CHUNK_COMPRESSION_TAG` if chunks are compressed, and those of the
+    /// tags in `extra`.
+    pub fn tag_section(&self) -> TagSection {
+        let desc = |name: &str, t: RadIntId| TagDesc {
+            name: name.to_string(),
+            typeid: t.type_tag(),
+        };
+        let mut ts = TagSection {
+            tags: vec![desc("cblen", RadIntId::U16), desc("ulen", RadIntId::U16)],
+        };
+        if self.max_ambiguity.is_some() {
+            ts.tags.push(desc(MAX_AMBIGUITY_TAG, RadIntId::U32));
+        }
+        if self.variable_umi {
+            ts.tags.push(TagDesc {
+                name: VARIABLE_UMI_TAG.to_string(),
+                typeid: encode_type_tag(RadType::Bool).unwrap(),
+            });
+        }
+        if self.zstd_chunks {
+            ts.tags.push(TagDesc {
+                name: CHUNK_COMPRESSION_TAG.to_string(),
+                typeid: encode_type_tag(RadType::String).unwrap(),
+            });
+        }
+        for (name, v) in &self.extra {
+            ts.tags.push(TagDesc {
+                name: name.clone(),
+                typeid: encode_type_tag(v.rad_type()).unwrap(),
+            });
+        }
+        ts
+    }
+
+    /// Write the values of the tags described by `tag_section`, in the
+    /// form read by `from_bytes_with_section`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.bclen.to_le_bytes())?;
+        writer.write_all(&self.umilen.to_le_bytes())?;
+        if let Some(m) = self.max_ambiguity {
+            writer.write_all(&m.to_le_bytes())?;
+        }
+        if self.variable_umi {
+            writer.write_all(&[1u8])?;
+        }
+        if self.zstd_chunks {
+            tags::TagValue::String("zstd".to_string()).write_to(writer)?;
+        }
+        for (_, v) in &self.extra {
+            v.write_to(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Check that a barcode of `bclen` bases fits in the integer type `bct`
+    /// (and likewise for the umi and `umit`), at 2 bits per base.
+    pub fn validate(&self, bct: &RadIntId, umit: &RadIntId) -> Result<(), RadError> {
+        for (tag, len, rt) in &[("barcode", self.bclen, bct), ("umi", self.umilen, umit)] {
+            let bits = 8 * rt.bytes_for_type();
+            if 2 * (*len as usize) > bits {
+                return Err(RadError::TagWidthMismatch {
+                    tag: tag.to_string(),
+                    len: *len,
+                    bits,
+                });
+            }
+        }
+        // the length of each umi is kept in the top bits of a u64
+        if self.variable_umi
+            && (*umit != RadIntId::U64 || self.umilen > utils::MAX_VARIABLE_UMI_LEN)
+        {
+            return Err(RadError::TagWidthMismatch {
+                tag: "variable length umi".to_string(),
+                len: self.umilen,
+                bits: 8 * umit.bytes_for_type(),
+            });
+        }
+        if self.zstd_chunks && cfg!(not(feature = "zstd")) {
+            return Err(RadError::UnsupportedCompression(
+                "zstd (libradicl was built without the zstd feature)".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that the chunks of the file aren't compressed, for the
+    /// consumers (named by `what`) that parse the bytes of each chunk
+    /// themselves rather than through `RadReader`.
+    pub fn require_uncompressed(&self, what: &str) -> Result<(), RadError> {
+        if self.zstd_chunks {
+            return Err(RadError::UnsupportedCompression(format!(
+                "zstd ({} can't yet read compressed chunks)",
+                what
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that the barcodes of the file fit in a u64, for the consumers
+    /// (named by `what`) that correct and count barcodes as u64s, such as
+    /// `generate-permit-list`, `collate` and `quant`.
+    pub fn require_u64_barcodes(&self, what: &str) -> Result<(), RadError> {
+        if self.bclen as u32 > <u64 as BarcodeT>::MAX_LEN {
+            return Err(RadError::TagWidthMismatch {
+                tag: format!("barcode (as stored by {})", what),
+                len: self.bclen,
+                bits: 64,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl TagDesc {
+    pub fn from_bytes<T: Read>(reader: &mut T) -> TagDesc {
+        TagDesc::from_bytes_checked(reader).unwrap()
+    }
+
+    /// Like `from_bytes`, but returns an error, rather than panicking,
+    /// if the description can't be read or its name isn't valid UTF-8.
+    pub fn from_bytes_checked<T: Read>(reader: &mut T) -> Result<TagDesc, RadError> {
+        // space for the string length (1 byte)
+        // the longest string possible (255 char)
+        // and the typeid
+        let mut buf = [0u8; 257];
+        reader.read_exact(&mut buf[0..2])?;
+        let str_len = buf.pread::<u16>(0).unwrap() as usize;
+
+        // read str_len + 1 to get the type id that follows the string
+        reader.read_exact(&mut buf[0..str_len + 1])?;
+        Ok(TagDesc {
+            name: utf8_name(&buf[0..str_len], || "tag name".to_string())?,
+            typeid: buf.pread(str_len).unwrap(),
+        })
+    }
+
+    /// Write the description in the form read by `from_bytes`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write_str_bin(&self.name, &RadIntId::U16, writer)?;
+        writer.write_all(&self.typeid.to_le_bytes())
+    }
+}
+
+/// Decode `bytes` as a UTF-8 string, or return an `InvalidUtf8` error
+/// described by `context`.
+fn utf8_name<F: FnOnce() -> String>(bytes: &[u8], context: F) -> Result<String, RadError> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => Err(RadError::InvalidUtf8 {
+            context: context(),
+            bytes: bytes.to_vec(),
+        }),
+    }
+}
+
+impl TagSection {
+    pub fn from_bytes<T: Read>(reader: &mut T) -> TagSection {
+        TagSection::from_bytes_checked(reader).unwrap()
+    }
+
+    /// The description of the tag named `name`, if present.
+    pub fn get(&self, name: &str) -> Option<&TagDesc> {
+        self.tags.iter().find(|t| t.name == name)
+    }
+
+    /// Like `from_bytes`, but returns an error rather than panicking.
+    pub fn from_bytes_checked<T: Read>(reader: &mut T) -> Result<TagSection, RadError> {
+        let mut buf = [0u8; 2];
+        reader.read_exact(&mut buf)?;
+        let num_tags = buf.pread::<u16>(0).unwrap() as usize;
+
+        let mut ts = TagSection {
+            tags: Vec::with_capacity(num_tags),
+        };
+
+        for _ in 0..num_tags {
+            ts.tags.push(TagDesc::from_bytes_checked(reader)?);
+        }
+
+        Ok(ts)
+    }
+
+    /// Write the section in the form read by `from_bytes`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&(self.tags.len() as u16).to_le_bytes())?;
+        for t in &self.tags {
+            t.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl RadHeader {
+    pub fn from_bytes<T: Read>(reader: &mut T) -> RadHeader {
+        RadHeader::from_bytes_checked(reader).unwrap()
+    }
+
+    /// Like `from_bytes`, but returns an error, rather than panicking, if
+    /// the header can't be read or a reference name isn't valid UTF-8.
+    /// Both legacy files and those with a version header are accepted.
+    pub fn from_bytes_checked<T: Read>(reader: &mut T) -> Result<RadHeader, RadError> {
+        RadHeader::from_bytes_with_version(reader).map(|(rh, _)| rh)
+    }
+
+    /// Read the header, along with the version of the file.  Returns
+    /// `InvalidMagic` if the input begins with neither `RAD_MAGIC` nor a
+    /// valid `is_paired` byte, and `UnsupportedVersion` if the file's version
+    /// can't be read by this library.
+    pub fn from_bytes_with_version<T: Read>(
+        reader: &mut T,
+    ) -> Result<(RadHeader, RadVersion), RadError> {
+        let mut first = [0u8; 1];
+        reader.read_exact(&mut first)?;
+        let (version, is_paired) = match first[0] {
+            0 | 1 => (RadVersion::Legacy, first[0]),
+            _ => {
+                let mut buf = [0u8; 10];
+                reader.read_exact(&mut buf[..RAD_MAGIC.len() - 1])?;
+                let mut magic = vec![first[0]];
+                magic.extend_from_slice(&buf[..RAD_MAGIC.len() - 1]);
+                if magic[..] != RAD_MAGIC[..] {
+                    return Err(RadError::InvalidMagic(magic));
+                }
+                reader.read_exact(&mut buf[..VERSION_FIELDS_LEN])?;
+                RadVersion::from_fields(&buf)?
+            }
+        };
+        let mut rh = RadHeader {
+            is_paired: 0,
+            ref_count: 0,
+            ref_names: vec![],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+
+        // size of the longest allowable string.
+        let mut buf = [0u8; 65536];
+        reader.read_exact(&mut buf[0..8])?;
+        rh.is_paired = is_paired;
+        rh.ref_count = buf.pread::<u64>(0).unwrap();
+
+        // we know how many names we will read in, but the count comes
+        // from the input, so isn't trusted to size the allocation
+        rh.ref_names.reserve_exact(prealloc(rh.ref_count));
+
+        let mut num_read = 0u64;
+        while num_read < rh.ref_count {
+            reader.read_exact(&mut buf[0..2])?;
+            let l: usize = buf.pread::<u16>(0).unwrap() as usize;
+            check_limit("reference name length", l as u64, MAX_REF_NAME_LEN as u64)?;
+            reader.read_exact(&mut buf[0..l])?;
+            rh.ref_names.push(utf8_name(&buf[0..l], || {
+                format!("the name of reference {}", num_read)
+            })?);
+            num_read += 1;
+        }
+
+        reader.read_exact(&mut buf[0..8])?;
+        rh.num_chunks = buf.pread::<u64>(0).unwrap();
+        if version.has_ref_info() {
+            rh.read_ref_info(reader)?;
+        }
+        Ok((rh, version))
+    }
+
+    // read the block written by `write_ref_info`
+    pub(crate) fn read_ref_info<T: Read>(&mut self, reader: &mut T) -> Result<(), RadError> {
+        let mut flags = [0u8; 1];
+        reader.read_exact(&mut flags)?;
+        if flags[0] & REF_INFO_LENGTHS != 0 {
+            let mut buf = [0u8; 8];
+            self.ref_lengths = Vec::with_capacity(prealloc(self.ref_count));
+            for _ in 0..self.ref_count {
+                reader.read_exact(&mut buf)?;
+                self.ref_lengths.push(u64::from_le_bytes(buf));
+            }
+        }
+        if flags[0] & REF_INFO_DIGEST != 0 {
+            let mut digest = [0u8; 32];
+            reader.read_exact(&mut digest)?;
+            self.ref_digest = Some(digest);
+        }
+        Ok(())
+    }
+
+    /// Write the reference lengths (if known for every reference) and
+    /// digest (if set) of this header, in the form that follows the header
+    /// of files whose version `has_ref_info`.
+    pub fn write_ref_info<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&[self.ref_info_flags()])?;
+        if self.has_ref_lengths() {
+            for l in &self.ref_lengths {
+                writer.write_all(&l.to_le_bytes())?;
+            }
+        }
+        if let Some(d) = &self.ref_digest {
+            writer.write_all(d)?;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes written by `write_ref_info`.
+    pub fn ref_info_len(&self) -> usize {
+        1 + RadHeader::ref_info_body_len(self.ref_info_flags(), self.ref_names.len() as u64)
+            as usize
+    }
+
+    // the flags byte beginning the block written by `write_ref_info`
+    fn ref_info_flags(&self) -> u8 {
+        let mut flags = 0u8;
+        if self.has_ref_lengths() {
+            flags |= REF_INFO_LENGTHS;
+        }
+        if self.ref_digest.is_some() {
+            flags |= REF_INFO_DIGEST;
+        }
+        flags
+    }
+
+    // the number of bytes following the flags byte of the block written
+    // by `write_ref_info`, given those flags and the number of references
+    pub(crate) fn ref_info_body_len(flags: u8, ref_count: u64) -> u64 {
+        let mut len = 0;
+        if flags & REF_INFO_LENGTHS != 0 {
+            len += ref_count * mem::size_of::<u64>() as u64;
+        }
+        if flags & REF_INFO_DIGEST != 0 {
+            len += 32;
+        }
+        len
+    }
+
+    fn has_ref_lengths(&self) -> bool {
+        !self.ref_names.is_empty() && self.ref_lengths.len() == self.ref_names.len()
+    }
+
+    /// The SHA-256 digest of the set of (name, length) pairs of the
+    /// references, if the length of every reference is known.  The pairs
+    /// are sorted by name, and each contributes its name, a tab, its length
+    /// in decimal and a newline, so that the digest doesn't depend on the
+    /// order of the references.
+    pub fn compute_ref_digest(&self) -> Option<[u8; 32]> {
+        if !self.has_ref_lengths() {
+            return None;
+        }
+        let mut refs: Vec<(&String, &u64)> =
+            self.ref_names.iter().zip(self.ref_lengths.iter()).collect();
+        refs.sort_unstable();
+        let mut hasher = Sha256::new();
+        for (n, l) in refs {
+            hasher.update(format!("{}\t{}\n", n, l).as_bytes());
+        }
+        Some(hasher.finalize().into())
+    }
+
+    /// Check that `other` describes the same references as this header (e.g.
+    /// that two files were mapped against the same transcriptome build).
+    /// The digests are compared if both headers have one.  Since the
+    /// digest doesn't depend on the order of the references, while the
+    /// reference ids of records do, the reference names are then compared
+    /// in order, as are the lengths if both headers have them.
+    pub fn check_compatibility(&self, other: &RadHeader) -> Result<(), RadError> {
+        if let (Some(a), Some(b)) = (&self.ref_digest, &other.ref_digest) {
+            if a != b {
+                return Err(RadError::ReferenceMismatch(
+                    "the reference digests differ".to_string(),
+                ));
+            }
+        }
+        if self.ref_names.len() != other.ref_names.len() {
+            return Err(RadError::ReferenceMismatch(format!(
+                "{} references, but {} expected",
+                other.ref_names.len(),
+                self.ref_names.len()
+            )));
+        }
+        if let Some(i) =
+            (0..self.ref_names.len()).find(|&i| self.ref_names[i] != other.ref_names[i])
+        {
+            return Err(RadError::ReferenceMismatch(format!(
+                "reference {} is named '{}', but '{}' expected",
+                i, other.ref_names[i], self.ref_names[i]
+            )));
+        }
+        if self.has_ref_lengths() && other.has_ref_lengths() {
+            if let Some(i) =
+                (0..self.ref_lengths.len()).find(|&i| self.ref_lengths[i] != other.ref_lengths[i])
+            {
+                return Err(RadError::ReferenceMismatch(format!(
+                    "reference '{}' has length {}, but {} expected",
+                    self.ref_names[i], other.ref_lengths[i], self.ref_lengths[i]
+                )));
+            }
+        }
+        Ok(())
+    }
+    pub fn from_bam_header(header: &HeaderView) -> RadHeader {
+        let mut rh = RadHeader {
+            is_paired: 0,
+            ref_count: 0,
+            ref_names: vec![],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+
+        rh.ref_count = header.target_count() as u64;
+        // we know how many names we will read in.
+        rh.ref_names.reserve_exact(rh.ref_count as usize);
+        for (_i, t) in header
+            .target_names()
+            .iter()
+            .map(|a| std::str::from_utf8(a).unwrap())
+            .enumerate()
+        {
+            rh.ref_names.push(t.to_owned());
+        }
+        rh.ref_lengths = (0..header.target_count())
+            .map(|tid| header.target_len(tid).unwrap_or(0))
+            .collect();
+        rh
+    }
+
+    /// The name and length of the reference with id `ref_id`, if such a
+    /// reference exists and its length is known.
+    pub fn get(&self, ref_id: u32) -> Option<(&str, u64)> {
+        let i = ref_id as usize;
+        match (self.ref_names.get(i), self.ref_lengths.get(i)) {
+            (Some(n), Some(l)) => Some((n.as_str(), *l)),
+            _ => None,
+        }
+    }
+    /// Write the header in the form read by `from_bytes`.  The reference
+    /// count written is the number of `ref_names`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.is_paired.to_le_bytes())?;
+        writer.write_all(&(self.ref_names.len() as u64).to_le_bytes())?;
+        for n in &self.ref_names {
+            write_str_bin(n, &RadIntId::U16, writer)?;
+        }
+        writer.write_all(&self.num_chunks.to_le_bytes())
+    }
+
+    pub fn get_size(&self) -> usize {
+        let mut tot_size = 0usize;
+        tot_size += std::mem::size_of::<u8>() + std::mem::size_of::<u64>();
+        for (_i, t) in self.ref_names.iter().map(|a| a.len()).enumerate() {
+            tot_size += t;
+        }
+        tot_size += std::mem::size_of::<u64>();
+        tot_size
+    }
+}
+
+/// Summary of a single pass of histogram construction over a chunk.
+/// The orientation counts are tallied per alignment (from `ReadRecord::dirs`),
+/// regardless of the expected orientation, while `num_strand_compat_reads`
+/// counts the reads that were actually added to the histogram.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HistStats {
+    pub num_reads: usize,
+    pub num_strand_compat_reads: usize,
+    pub num_orientation_forward: usize,
+    pub num_orientation_reverse: usize,
+    pub max_ambiguity: usize,
+    /// reads with an unmatched barcode that weren't tracked because the
+    /// cap of `update_barcode_hist_unfiltered_capped` had been reached
+    pub num_unmatched_dropped: usize,
+}
+
+impl HistStats {
+    /// Accumulate the statistics of `other` (e.g. from another chunk) into `self`.
+    pub fn merge(&mut self, other: &HistStats) {
+        self.num_reads += other.num_reads;
+        self.num_strand_compat_reads += other.num_strand_compat_reads;
+        self.num_orientation_forward += other.num_orientation_forward;
+        self.num_orientation_reverse += other.num_orientation_reverse;
+        self.max_ambiguity = self.max_ambiguity.max(other.max_ambiguity);
+        self.num_unmatched_dropped += other.num_unmatched_dropped;
+    }
+}
+
+/// Tally the orientations `dirs` of the `len` alignments of a record into
+/// `stats`, and return the number of them compatible with `expected_ori`
+/// (all of them, if it is `Strand::Unknown`), or `None` if there are none.
+#[inline]
+fn tally_dirs<I: Iterator<Item = bool>>(
+    stats: &mut HistStats,
+    len: usize,
+    dirs: I,
+    expected_ori: &Strand,
+) -> Option<usize> {
+    let (nfw, nrc) = dirs.fold((0, 0), |(f, r), d| if d { (f + 1, r) } else { (f, r + 1) });
+    stats.num_reads += 1;
+    stats.num_orientation_forward += nfw;
+    stats.num_orientation_reverse += nrc;
+    match expected_ori {
+        Strand::Unknown => Some(len),
+        Strand::Forward if nfw > 0 => Some(nfw),
+        Strand::Reverse if nrc > 0 => Some(nrc),
+        _ => None,
+    }
+}
+
+/// The body shared by the barcode histograms and `alignment_count_histogram`:
+/// tallies each of `records`, given as its barcode, number of alignments and
+/// alignment orientations, and passes the barcode of each one compatible
+/// with `expected_ori`, along with its number of compatible alignments, to
+/// `count`.  Stops at the first error in `records`.
+fn tally_records<E, D, I, F>(
+    records: I,
+    max_ambiguity_read: &mut usize,
+    expected_ori: &Strand,
+    mut count: F,
+) -> Result<HistStats, E>
+where
+    D: Iterator<Item = bool>,
+    I: Iterator<Item = Result<(u64, usize, D), E>>,
+    F: FnMut(u64, usize),
+{
+    let mut stats = HistStats::default();
+    for r in records {
+        let (bc, len, dirs) = r?;
+        if let Some(ncompat) = tally_dirs(&mut stats, len, dirs, expected_ori) {
+            stats.num_strand_compat_reads += 1;
+            stats.max_ambiguity = len.max(stats.max_ambiguity);
+            count(bc, ncompat);
+        }
+    }
+    *max_ambiguity_read = stats.max_ambiguity.max(*max_ambiguity_read);
+    Ok(stats)
+}
+
+/// The records of `chunk`, in the form taken by `tally_records`.
+#[allow(clippy::type_complexity)]
+fn chunk_tally_records(
+    chunk: &Chunk,
+) -> impl Iterator<
+    Item = Result<(u64, usize, std::iter::Copied<std::slice::Iter<'_, bool>>), Infallible>,
+> + '_ {
+    chunk
+        .reads
+        .iter()
+        .map(|r| Ok((r.bc, r.refs.len(), r.dirs.iter().copied())))
+}
+
+pub fn update_barcode_hist_unfiltered<S: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    unmatched_bc: &mut Vec<u64>,
+    max_ambiguity_read: &mut usize,
+    chunk: &Chunk,
+    expected_ori: &Strand,
+) -> HistStats {
+    tally_records(
+        chunk_tally_records(chunk),
+        max_ambiguity_read,
+        expected_ori,
+        |bc, _| count_known_barcode(hist, unmatched_bc, bc),
+    )
+    .unwrap_or_else(|e| match e {})
+}
+
+// lookup the barcode in the map of unfiltered known barcodes; if we find
+// a match, increment the count, otherwise push it into the unmatched list
+#[inline]
+fn count_known_barcode<S: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    unmatched_bc: &mut Vec<u64>,
+    bc: u64,
+) {
+    match hist.get_mut(&bc) {
+        Some(c) => *c += 1,
+        None => unmatched_bc.push(bc),
+    }
+}
+
+/// Like `update_barcode_hist_unfiltered`, but bounds the memory used for
+/// unmatched barcodes.  These are counted in `unmatched` rather than listed
+/// one entry per read, and once `unmatched` holds `max_distinct` barcodes,
+/// reads with a barcode not already present in it are dropped (and counted
+/// in `num_unmatched_dropped` of the returned statistics).  Barcodes in
+/// `hist` are always counted exactly, so when the cap is never reached the
+/// result is the same as that of `update_barcode_hist_unfiltered`.
+pub fn update_barcode_hist_unfiltered_capped<S: BuildHasher, T: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    unmatched: &mut HashMap<u64, u64, T>,
+    max_distinct: usize,
+    max_ambiguity_read: &mut usize,
+    chunk: &Chunk,
+    expected_ori: &Strand,
+) -> HistStats {
+    let mut num_dropped = 0;
+    let mut stats = tally_records(
+        chunk_tally_records(chunk),
+        max_ambiguity_read,
+        expected_ori,
+        |bc, _| {
+            if let Some(c) = hist.get_mut(&bc) {
+                *c += 1;
+            } else if let Some(c) = unmatched.get_mut(&bc) {
+                *c += 1;
+            } else if unmatched.len() < max_distinct {
+                unmatched.insert(bc, 1);
+            } else {
+                num_dropped += 1;
+            }
+        },
+    )
+    .unwrap_or_else(|e| match e {});
+    stats.num_unmatched_dropped = num_dropped;
+    stats
+}
+
+/// A multi-threaded analog of `update_barcode_hist_unfiltered` that processes
+/// `chunks` concurrently.  The counts in `hist` and the contents of
+/// `unmatched_bc` (up to order) are the same as those obtained by calling
+/// `update_barcode_hist_unfiltered` on each chunk in turn, and the returned
+/// statistics are the totals over all chunks.
+pub fn update_barcode_hist_unfiltered_parallel<I: ParallelIterator<Item = Chunk>>(
+    hist: &DashMap<u64, u64>,
+    unmatched_bc: &Mutex<Vec<u64>>,
+    chunks: I,
+    expected_ori: &Strand,
+) -> HistStats {
+    chunks
+        .map(|chunk| {
+            let mut local_unmatched = Vec::new();
+            let stats = tally_records(
+                chunk_tally_records(&chunk),
+                &mut 0,
+                expected_ori,
+                |bc, _| match hist.get_mut(&bc) {
+                    Some(mut c) => *c += 1,
+                    None => local_unmatched.push(bc),
+                },
+            )
+            .unwrap_or_else(|e| match e {});
+            if !local_unmatched.is_empty() {
+                unmatched_bc.lock().unwrap().extend(local_unmatched);
+            }
+            stats
+        })
+        .reduce(HistStats::default, |mut a, b| {
+            a.merge(&b);
+            a
+        })
+}
+
+pub fn update_barcode_hist<S: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    max_ambiguity_read: &mut usize,
+    chunk: &Chunk,
+    expected_ori: &Strand,
+) -> HistStats {
+    tally_records(
+        chunk_tally_records(chunk),
+        max_ambiguity_read,
+        expected_ori,
+        |bc, _| *hist.entry(bc).or_insert(0) += 1,
+    )
+    .unwrap_or_else(|e| match e {})
+}
+
+/// Like `update_barcode_hist_unfiltered`, but over the records of a chunk
+/// read with `Chunk::read_raw`, so that no allocation is done per record.
+/// Returns an error if a record runs past the end of the chunk.
+pub fn update_barcode_hist_unfiltered_views<S: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    unmatched_bc: &mut Vec<u64>,
+    max_ambiguity_read: &mut usize,
+    records: RecordViews,
+    expected_ori: &Strand,
+) -> Result<HistStats, RadError> {
+    tally_records(
+        records.map(|r| r.map(|r| (r.bc, r.len(), r.dirs()))),
+        max_ambiguity_read,
+        expected_ori,
+        |bc, _| count_known_barcode(hist, unmatched_bc, bc),
+    )
+}
+
+/// Like `update_barcode_hist`, but over the records of a chunk read with
+/// `Chunk::read_raw`, so that no allocation is done per record.  Returns
+/// an error if a record runs past the end of the chunk.
+pub fn update_barcode_hist_views<S: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    max_ambiguity_read: &mut usize,
+    records: RecordViews,
+    expected_ori: &Strand,
+) -> Result<HistStats, RadError> {
+    tally_records(
+        records.map(|r| r.map(|r| (r.bc, r.len(), r.dirs()))),
+        max_ambiguity_read,
+        expected_ori,
+        |bc, _| *hist.entry(bc).or_insert(0) += 1,
+    )
+}
+
+/// Like `update_barcode_hist`, but also adds, for each counted read, the
+/// number of its alignments compatible with `expected_ori` to the entry
+/// for its barcode in `aln_hist` (all alignments, if `expected_ori` is
+/// `Strand::Unknown`).  `hist` is updated exactly as `update_barcode_hist`
+/// would update it.
+pub fn update_barcode_hist_with_alignments<S: BuildHasher, T: BuildHasher>(
+    hist: &mut HashMap<u64, u64, S>,
+    aln_hist: &mut HashMap<u64, u64, T>,
+    max_ambiguity_read: &mut usize,
+    chunk: &Chunk,
+    expected_ori: &Strand,
+) -> HistStats {
+    tally_records(
+        chunk_tally_records(chunk),
+        max_ambiguity_read,
+        expected_ori,
+        |bc, ncompat| {
+            *hist.entry(bc).or_insert(0) += 1;
+            *aln_hist.entry(bc).or_insert(0) += ncompat as u64;
+        },
+    )
+    .unwrap_or_else(|e| match e {})
+}
+
+/// Increments `out[n]` for each read of `chunk` having `n` alignments
+/// compatible with `expected_ori`, growing `out` as needed.  Reads are
+/// selected exactly as in `update_barcode_hist`, so those with no
+/// compatible alignment are not counted.
+pub fn alignment_count_histogram(chunk: &Chunk, out: &mut Vec<u64>, expected_ori: &Strand) {
+    tally_records(chunk_tally_records(chunk), &mut 0, expected_ori, |_, n| {
+        if n >= out.len() {
+            out.resize(n + 1, 0);
+        }
+        out[n] += 1;
+    })
+    .unwrap_or_else(|e| match e {});
+}
+
+pub fn permit_list_from_threshold<S: BuildHasher>(
+    hist: &HashMap<u64, u64, S>,
+    min_freq: u64,
+) -> Vec<u64> {
+    let valid_bc: Vec<u64> = hist
+        .iter()
+        .filter_map(|(k, v)| if v >= &min_freq { Some(*k) } else { None })
+        .collect();
+    valid_bc
+}
+
+/// Returns exactly the `n` most frequent barcodes of `hist` (or all of
+/// them, if there are fewer), along with the frequency of the least
+/// frequent barcode kept (0 if none are).  Barcodes of equal frequency
+/// are ranked by barcode, smallest first, so the result is deterministic.
+pub fn permit_list_top_n<S: BuildHasher>(hist: &HashMap<u64, u64, S>, n: usize) -> (Vec<u64>, u64) {
+    if n == 0 || hist.is_empty() {
+        return (Vec::new(), 0);
+    }
+    let mut freq: Vec<u64> = hist.values().cloned().collect();
+    freq.sort_unstable_by(|a, b| b.cmp(a));
+    let cutoff = freq[n.min(freq.len()) - 1];
+
+    // everything at least as frequent as the cutoff, of which only
+    // some of those exactly at the cutoff may need to be dropped
+    let mut valid_bc = permit_list_from_threshold(hist, cutoff);
+    valid_bc.sort_unstable_by(|a, b| hist[b].cmp(&hist[a]).then(a.cmp(b)));
+    valid_bc.truncate(n);
+    (valid_bc, cutoff)
+}
+
+/// Returns the fraction of all reads in `hist` whose barcode appears in
+/// the permit list `permit` (the "fraction of reads in cells" metric).
+/// If `hist` is empty, this is 0.
+pub fn fraction_reads_in_cells<S: BuildHasher>(hist: &HashMap<u64, u64, S>, permit: &[u64]) -> f64 {
+    let total: u64 = hist.values().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let in_cells: u64 = permit.iter().filter_map(|bc| hist.get(bc)).sum();
+    in_cells as f64 / total as f64
+}
+
+/// Collapse the `records` of a single corrected barcode into distinct
+/// (UMI, reference set) groups, returning each as `(umi, refs, count)`,
+/// where `refs` is the sorted set of reference ids and `count` the number
+/// of reads in the group.  Reads sharing a UMI but aligning to different
+/// reference sets form separate groups.  If `collapse_one_mismatch` is true,
+/// a group is merged into a group with the same references whose UMI is 1
+/// mismatch away and has been seen at least as many times, provided that
+/// there is exactly one such group (mirroring barcode correction, where
+/// ambiguous neighbors are not corrected).  The output is sorted by UMI and
+/// then by references, so it is the same for any ordering of `records`.
+pub fn dedup_umis(
+    records: &[ReadRecord],
+    collapse_one_mismatch: bool,
+) -> Vec<(u64, Vec<u32>, u32)> {
+    let mut groups: BTreeMap<(u64, Vec<u32>), u32> = BTreeMap::new();
+    for r in records {
+        let mut refs = r.refs.clone();
+        refs.sort_unstable();
+        refs.dedup();
+        *groups.entry((r.umi, refs)).or_insert(0) += 1;
+    }
+
+    if collapse_one_mismatch {
+        // consider the UMIs of each reference set from the most to least
+        // abundant, so that every UMI can only be absorbed by one that
+        // has been seen at least as many times.
+        let mut by_count: Vec<((u64, Vec<u32>), u32)> = groups.into_iter().collect();
+        by_count.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut kept: Vec<((u64, Vec<u32>), u32)> = Vec::with_capacity(by_count.len());
+        for (key, count) in by_count {
+            let mut parent = None;
+            let mut num_parents = 0usize;
+            for (i, ((umi, refs), _)) in kept.iter().enumerate() {
+                if *refs == key.1 && utils::hamming_distance_packed(*umi, key.0) == 1 {
+                    parent = Some(i);
+                    num_parents += 1;
+                }
+            }
+            match (parent, num_parents) {
+                (Some(i), 1) => kept[i].1 += count,
+                _ => kept.push((key, count)),
+            }
+        }
+        groups = kept.into_iter().collect();
+    }
+
+    groups
+        .into_iter()
+        .map(|((umi, refs), count)| (umi, refs, count))
+        .collect()
+}
+
+/// Write the barcode frequencies in `hist` to `writer` as tab-separated
+/// `barcode<TAB>count` lines, sorted by decreasing count (ties broken by
+/// barcode), with barcodes decoded to strings of length `bclen`.  If
+/// `top_n` is given, only the first `top_n` lines are written.
+pub fn write_frequency_tsv<W: Write, S: BuildHasher>(
+    hist: &HashMap<u64, u64, S>,
+    bclen: u16,
+    top_n: Option<usize>,
+    writer: &mut W,
+) -> std::io::Result<()> {
+    let mut freqs: Vec<(u64, u64)> = hist.iter().map(|(bc, c)| (*bc, *c)).collect();
+    freqs.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let n = top_n.unwrap_or(freqs.len()).min(freqs.len());
+    for (bc, c) in &freqs[..n] {
+        writeln!(writer, "{}\t{}", utils::decode_kmer(*bc, bclen), c)?;
+    }
+    Ok(())
+}
+
+pub fn permit_list_from_file(ifile: String, bclen: u16) -> Vec<u64> {
+    let f = File::open(ifile).expect("couldn't open input barcode file.");
+    let br = BufReader::new(f);
+    let mut bc = Vec::<u64>::with_capacity(10_000);
+
+    for l in br.lines() {
+        let line = l.expect("couldn't read line from barcode file.");
+        // as before, only the first `bclen` bases of each line are kept, so
+        // trailing whitespace or other fields are ignored
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bases = &line.as_bytes()[..line.len().min(bclen as usize)];
+        bc.push(utils::pack_barcode(bases, bclen).expect("invalid barcode in permit list"));
+    }
+    bc
+}
+
+/// Like `permit_list_from_file`, but checks the lengths of the barcodes in
+/// `ifile` rather than trusting them.  All (non-empty) lines must have the
+/// same length, and that length must be the barcode length `bclen` recorded
+/// in `file_tags`, otherwise an error giving the offending lengths is
+/// returned.
+pub fn permit_list_from_file_checked<P: AsRef<std::path::Path>>(
+    ifile: P,
+    file_tags: &FileTags,
+) -> Result<Vec<u64>, RadError> {
+    let br = BufReader::new(File::open(ifile)?);
+    let mut bc = Vec::<u64>::with_capacity(10_000);
+    let mut first_len = None;
+
+    for (i, l) in br.lines().enumerate() {
+        let line = l?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let first = *first_len.get_or_insert(line.len());
+        if line.len() != first {
+            return Err(RadError::InconsistentBarcodeLength {
+                line: i as u64 + 1,
+                len: line.len(),
+                first,
+            });
+        }
+        if line.len() != file_tags.bclen as usize {
+            return Err(RadError::BarcodeLengthMismatch {
+                permit_len: line.len(),
+                file_len: file_tags.bclen,
+            });
+        }
+        bc.push(utils::pack_barcode(line.as_bytes(), file_tags.bclen)?);
+    }
+    Ok(bc)
+}
+
+/// Write the barcodes in `bcs` to `writer`, one per line, decoded to
+/// strings of length `bclen`.  Barcodes are written in ascending order
+/// of their packed value, so the output is stable across runs.
+pub fn write_permit_list<W: Write>(bcs: &[u64], bclen: u16, writer: &mut W) -> std::io::Result<()> {
+    let mut sorted = bcs.to_vec();
+    sorted.sort_unstable();
+    for bc in sorted {
+        writeln!(writer, "{}", utils::decode_kmer(bc, bclen))?;
+    }
+    Ok(())
+}
+
+/// Like `write_permit_list`, but writes a gzip compressed file at `path`.
+pub fn write_permit_list_gz<P: AsRef<std::path::Path>>(
+    path: P,
+    bcs: &[u64],
+    bclen: u16,
+) -> std::io::Result<()> {
+    let gz = flate2::write::GzEncoder::new(File::create(path)?, flate2::Compression::default());
+    let mut writer = BufWriter::new(gz);
+    write_permit_list(bcs, bclen, &mut writer)?;
+    writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
+
+/// Write the string `v` to `owriter`, prefixed by its length encoded
+/// as the integer type `type_id`.  If the length of `v` can't be represented
+/// by `type_id`, nothing is written and an `InvalidInput` error is returned.
+pub fn write_str_bin<W: Write>(
+    v: &str,
+    type_id: &RadIntId,
+    owriter: &mut W,
+) -> std::io::Result<()> {
+    let max_len = match type_id {
+        RadIntId::U8 => u8::MAX as u64,
+        RadIntId::U16 => u16::MAX as u64,
+        RadIntId::U32 => u32::MAX as u64,
+        RadIntId::U64 | RadIntId::U128 => u64::MAX,
+    };
+    if v.len() as u64 > max_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "string of length {} is too long for a length prefix of {} bytes",
+                v.len(),
+                type_id.bytes_for_type()
+            ),
+        ));
+    }
+    type_id.write_to(v.len(), owriter)?;
+    owriter.write_all(v.as_bytes())
+}
+
+// The encoding of records into chunks, and the totals over the chunks
+// written that are needed to finish a file: their number, the offset at
+// which the next one begins, the largest number of alignments of a record
+// and, if enabled, the checksum and index of the chunks.  Shared by
+// `RadWriter` and the tools of `merge` that rewrite the records of a file.
+pub(crate) struct ChunkEncoder {
+    pub(crate) bct: RadIntId,
+    pub(crate) umit: RadIntId,
+    pub(crate) aln_layout: AlnLayout,
+    // the zstd level at which chunks are compressed, if enabled
+    pub(crate) zstd_level: Option<i32>,
+    pub(crate) checksum: Option<flate2::Crc>,
+    pub(crate) chunk_index: Option<ChunkIndex>,
+    pub(crate) bytes_written: u64,
+    pub(crate) chunks_written: u64,
+    pub(crate) max_ambiguity: u32,
+    // the pending chunk, behind a placeholder for its nbytes and nrec
+    data: Vec<u8>,
+    nrec: u32,
+    last_bc: u128,
+}
+
+impl ChunkEncoder {
+    pub(crate) fn new(bct: RadIntId, umit: RadIntId) -> ChunkEncoder {
+        ChunkEncoder {
+            bct,
+            umit,
+            aln_layout: AlnLayout::default(),
+            zstd_level: None,
+            checksum: None,
+            chunk_index: None,
+            bytes_written: 0,
+            chunks_written: 0,
+            max_ambiguity: 0,
+            data: vec![0u8; 8],
+            nrec: 0,
+            last_bc: 0,
+        }
+    }
+
+    // Start a new, empty, pending chunk.
+    pub(crate) fn begin(&mut self) {
+        self.data.clear();
+        self.data.extend_from_slice(&[0u8; 8]);
+        self.nrec = 0;
+    }
+
+    // The number of records of the pending chunk.
+    pub(crate) fn nrec(&self) -> u32 {
+        self.nrec
+    }
+
+    // Append `rec` to the pending chunk, with its barcode replaced by `bc`.
+    // For paired-end layouts, the orientation of the mate is taken from
+    // `mate_dirs`, if the record has them.
+    pub(crate) fn push<B: BarcodeT>(&mut self, rec: &ReadRecord<B>, bc: B) -> std::io::Result<()> {
+        let buf = &mut self.data;
+        buf.write_all(&(rec.refs.len() as u32).to_le_bytes())?;
+        self.bct.write_to(bc, buf)?;
+        self.umit.write_to(rec.umi, buf)?;
+        for (i, r) in rec.refs.iter().enumerate() {
+            let mut v = r & MASK_TOP_BIT_U32;
+            if rec.dirs.get(i).copied().unwrap_or(false) {
+                v |= MASK_LOWER_31_U32;
+            }
+            if self.aln_layout.paired && rec.mate_dirs.get(i).copied().unwrap_or(false) {
+                v |= utils::MASK_MATE_ORI_U32;
+            }
+            buf.write_all(&v.to_le_bytes())?;
+        }
+        self.pushed(bc.as_(), rec.refs.len());
+        Ok(())
+    }
+
+    // Append `rec` to the pending chunk, in the layout `self.aln_layout`.
+    pub(crate) fn push_ext(&mut self, rec: &ExtReadRecord) -> std::io::Result<()> {
+        rec.write_to(&mut self.data, &self.bct, &self.umit, &self.aln_layout)?;
+        self.pushed(rec.bc as u128, rec.refs.len());
+        Ok(())
+    }
+
+    fn pushed(&mut self, bc: u128, nalns: usize) {
+        self.nrec += 1;
+        self.last_bc = bc;
+        self.max_ambiguity = self.max_ambiguity.max(nalns as u32);
+    }
+
+    // Fill in the header of the pending chunk, compress it if enabled, and
+    // write it to `writer`.
+    pub(crate) fn finish<W: Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        let nbytes = self.data.len() as u32;
+        self.data[0..4].copy_from_slice(&nbytes.to_le_bytes());
+        self.data[4..8].copy_from_slice(&self.nrec.to_le_bytes());
+        // the first barcode is taken from the uncompressed records
+        let (first_bc, last_bc) = if self.nrec > 0 {
+            (pread_into_u128(&self.data, 12, &self.bct), self.last_bc)
+        } else {
+            (0, 0)
+        };
+        let data = mem::take(&mut self.data);
+        #[cfg(feature = "zstd")]
+        let compressed = match self.zstd_level {
+            Some(level) => Some(compress_chunk(&data, level)?),
+            None => None,
+        };
+        #[cfg(not(feature = "zstd"))]
+        let compressed: Option<Vec<u8>> = None;
+        let res = self.write_raw(
+            writer,
+            compressed.as_deref().unwrap_or(&data),
+            self.nrec,
+            first_bc,
+            last_bc,
+        );
+        self.data = data;
+        res
+    }
+
+    // Write `chunk`, which is already encoded (and compressed, if chunks
+    // are), to `writer`.  `nrec`, `first_bc` and `last_bc` are used only
+    // for the entry of the chunk in the index, if enabled.
+    pub(crate) fn write_raw<W: Write>(
+        &mut self,
+        writer: &mut W,
+        chunk: &[u8],
+        nrec: u32,
+        first_bc: u128,
+        last_bc: u128,
+    ) -> std::io::Result<()> {
+        writer.write_all(chunk)?;
+        if let Some(index) = self.chunk_index.as_mut() {
+            let mut crc = flate2::Crc::new();
+            crc.update(chunk);
+            index.entries.push(ChunkIndexEntry {
+                offset: self.bytes_written,
+                nrec,
+                first_bc,
+                last_bc,
+                crc32: crc.sum(),
+            });
+        }
+        if let Some(crc) = self.checksum.as_mut() {
+            crc.update(chunk);
+        }
+        self.bytes_written += chunk.len() as u64;
+        self.chunks_written += 1;
+        Ok(())
+    }
+
+    // Append the chunk index, if enabled, to `writer`.
+    pub(crate) fn write_index<W: Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        if let Some(index) = self.chunk_index.take() {
+            index.write_to(writer, self.bytes_written)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a complete RAD file to the underlying writer `W`.  The
+/// header (including the tag descriptions and file-level tag values) must
+/// be written first, followed by exactly `num_chunks` chunks, each delimited
+/// by `begin_chunk` and `finish_chunk`.  The `nbytes` and `nrec` fields
+/// of each chunk are filled in when the chunk is finished.  `finalize`
+/// checks that the number of chunks written matches the header.
+pub struct RadWriter<W: Write> {
+    writer: W,
+    header_written: bool,
+    // position of the num_chunks field, relative to where writing began
+    num_chunks_pos: u64,
+    expected_chunks: u64,
+    // position of the max_ambiguity value, if that tag was written
+    max_ambiguity_pos: Option<u64>,
+    declared_max_ambiguity: u32,
+    // the position of the checksum value, if enabled
+    checksum_pos: Option<u64>,
+    // additional file-level tags, written after all of the above
+    extra_file_tags: Vec<(String, tags::TagValue)>,
+    version: RadVersion,
+    in_chunk: bool,
+    // the optional alignment fields enabled
+    positions: bool,
+    frag_len: bool,
+    scores: bool,
+    enc: ChunkEncoder,
+}
+
+impl<W: Write> RadWriter<W> {
+    pub fn new(writer: W) -> RadWriter<W> {
+        RadWriter {
+            writer,
+            header_written: false,
+            num_chunks_pos: 0,
+            expected_chunks: 0,
+            max_ambiguity_pos: None,
+            declared_max_ambiguity: 0,
+            checksum_pos: None,
+            extra_file_tags: Vec::new(),
+            version: RadVersion::Legacy,
+            in_chunk: false,
+            positions: false,
+            frag_len: false,
+            scores: false,
+            enc: ChunkEncoder::new(RadIntId::U32, RadIntId::U32),
+        }
+    }
+
+    /// Record a checksum of the chunks written, as the `CHECKSUM_TAG`
+    /// file-level tag, so that the file can later be checked with
+    /// `verify_checksum`.  Must be called before the header is written, and
+    /// the output must then be finished with `finalize_with_chunk_count`.
+    pub fn enable_checksum(&mut self) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "the checksum must be enabled before the header is written",
+            ));
+        }
+        self.enc.checksum = Some(flate2::Crc::new());
+        Ok(())
+    }
+
+    /// Begin the file with `RAD_MAGIC` and `RAD_VERSION`.  Files written
+    /// this way can't be read by tools that predate the version header.
+    /// Must be called before the header is written.
+    pub fn enable_version_header(&mut self) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "the version header must be enabled before the header is written",
+            ));
+        }
+        self.version = RAD_VERSION;
+        Ok(())
+    }
+
+    /// Write a `ChunkIndex`, with the offset, barcode range and CRC-32 of
+    /// every chunk, after the last chunk when the output is finalized.
+    /// Must be called before any chunk is written.
+    pub fn enable_chunk_index(&mut self) -> Result<(), RadError> {
+        if self.enc.chunks_written > 0 || self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "the chunk index must be enabled before any chunk is written",
+            ));
+        }
+        self.enc.chunk_index = Some(ChunkIndex::default());
+        Ok(())
+    }
+
+    /// Compress the records of each chunk with zstd at `level`, recording
+    /// this as the `CHUNK_COMPRESSION_TAG` (whatever the `zstd_chunks` of
+    /// the `FileTags` passed to `write_header`).  Chunk headers are left
+    /// uncompressed, and hold the compressed size of the chunk, so that
+    /// chunks can still be skipped and indexed without decompressing them.
+    /// Must be called before the header is written.
+    #[cfg(feature = "zstd")]
+    pub fn enable_chunk_compression(&mut self, level: i32) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "chunk compression must be enabled before the header is written",
+            ));
+        }
+        self.enc.zstd_level = Some(level);
+        Ok(())
+    }
+
+    /// Describe a `POS_TAG` alignment-level tag after the
+    /// "compressed_ori_refid", so that the records pushed with
+    /// `push_ext_record` carry a start position for each alignment.  Must
+    /// be called before the header is written.
+    pub fn enable_positions(&mut self) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "positions must be enabled before the header is written",
+            ));
+        }
+        self.positions = true;
+        Ok(())
+    }
+
+    /// Describe a `FRAG_LEN_TAG` alignment-level tag (after the `POS_TAG`,
+    /// if enabled), so that the records pushed with `push_ext_record`
+    /// carry a fragment length for each alignment.  Must be called before
+    /// the header is written.
+    pub fn enable_frag_len(&mut self) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "fragment lengths must be enabled before the header is written",
+            ));
+        }
+        self.frag_len = true;
+        Ok(())
+    }
+
+    /// Describe a `SCORE_TAG` alignment-level tag (after any other optional
+    /// alignment fields), so that the records pushed with `push_ext_record`
+    /// carry a score for each alignment.  Must be called before the header
+    /// is written.
+    pub fn enable_scores(&mut self) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "scores must be enabled before the header is written",
+            ));
+        }
+        self.scores = true;
+        Ok(())
+    }
+
+    /// Add a file-level tag named `name` with value `value` (e.g. a sample
+    /// name or software version), to be written by `write_header` after
+    /// the standard file-level tags.  Must be called before the header is
+    /// written.
+    pub fn add_file_tag(&mut self, name: &str, value: tags::TagValue) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "file tags must be added before the header is written",
+            ));
+        }
+        self.extra_file_tags.push((name.to_string(), value));
+        Ok(())
+    }
+
+    /// Write the header `hdr`, the standard single-cell tag descriptions
+    /// (barcode and umi lengths at the file level, barcode "b" and umi "u"
+    /// of types `bct` and `umit` at the read level, and the
+    /// "compressed_ori_refid" at the alignment level), and the file-level
+    /// tag values `file_tags`.  If `file_tags.max_ambiguity` is set, it is
+    /// also written, as the `MAX_AMBIGUITY_TAG` file-level tag.
+    pub fn write_header(
+        &mut self,
+        hdr: &RadHeader,
+        file_tags: &FileTags,
+        bct: RadIntId,
+        umit: RadIntId,
+    ) -> Result<(), RadError> {
+        if self.header_written {
+            return Err(RadError::InvalidWriterState(
+                "the header has already been written",
+            ));
+        }
+        self.version.write_to(&mut self.writer)?;
+        hdr.write_to(&mut self.writer)?;
+        self.num_chunks_pos = self.version.size()
+            + (mem::size_of::<u8>() + mem::size_of::<u64>()) as u64
+            + hdr
+                .ref_names
+                .iter()
+                .map(|n| (mem::size_of::<u16>() + n.len()) as u64)
+                .sum::<u64>();
+
+        // the tag sections are buffered so that we know where
+        // the file-level tag values will land
+        let mut tags = Vec::<u8>::new();
+        let w = &mut tags;
+        if self.version.has_ref_info() {
+            hdr.write_ref_info(w)?;
+        }
+
+        let desc = |name: &str, t: RadIntId| TagDesc {
+            name: name.to_string(),
+            typeid: t.type_tag(),
+        };
+
+        let file_tags = &FileTags {
+            zstd_chunks: self.enc.zstd_level.is_some(),
+            extra: file_tags.extra.clone(),
+            ..*file_tags
+        };
+
+        // file-level
+        let mut file_section = file_tags.tag_section();
+        if self.enc.checksum.is_some() {
+            file_section.tags.push(desc(CHECKSUM_TAG, RadIntId::U32));
+        }
+        for (name, v) in &self.extra_file_tags {
+            file_section.tags.push(TagDesc {
+                name: name.clone(),
+                typeid: encode_type_tag(v.rad_type()).unwrap(),
+            });
+        }
+        file_section.write_to(w)?;
+
+        // read-level
+        TagSection {
+            tags: vec![desc("b", bct), desc("u", umit)],
+        }
+        .write_to(w)?;
+
+        // alignment-level
+        self.enc.aln_layout = AlnLayout {
+            paired: hdr.is_paired != 0,
+            pos: self.positions,
+            frag_len: self.frag_len,
+            score: self.scores,
+        };
+        self.enc.aln_layout.tag_section().write_to(w)?;
+
+        // file-level tag values
+        let vals_pos = self.num_chunks_pos + mem::size_of::<u64>() as u64 + w.len() as u64;
+        file_tags.write_to(w)?;
+        if let Some(m) = file_tags.max_ambiguity {
+            // follows the barcode and umi lengths
+            self.max_ambiguity_pos = Some(vals_pos + 2 * mem::size_of::<u16>() as u64);
+            self.declared_max_ambiguity = m;
+        }
+        if self.enc.checksum.is_some() {
+            // filled in by finalize_with_chunk_count
+            self.checksum_pos =
+                Some(self.num_chunks_pos + mem::size_of::<u64>() as u64 + w.len() as u64);
+            w.write_all(&0u32.to_le_bytes())?;
+        }
+        for (_, v) in &self.extra_file_tags {
+            v.write_to(w)?;
+        }
+        self.writer.write_all(&tags)?;
+        self.enc.bytes_written =
+            self.num_chunks_pos + mem::size_of::<u64>() as u64 + tags.len() as u64;
+
+        self.enc.bct = bct;
+        self.enc.umit = umit;
+        self.expected_chunks = hdr.num_chunks;
+        self.header_written = true;
+        Ok(())
+    }
+
+    pub fn begin_chunk(&mut self) -> Result<(), RadError> {
+        if !self.header_written || self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "a chunk can only begin after the header, and outside of another chunk",
+            ));
+        }
+        self.enc.begin();
+        self.in_chunk = true;
+        Ok(())
+    }
+
+    /// Append `rec` to the current chunk.  The orientation of each alignment
+    /// is taken from `rec.dirs` if present, and is otherwise written as reverse.
+    pub fn push_record(&mut self, rec: &ReadRecord) -> Result<(), RadError> {
+        self.push_generic_record(rec)
+    }
+
+    /// As `push_record`, for a record of any barcode type, e.g. a
+    /// `ReadRecord<u128>` to be written with a barcode type of `U128`.
+    pub fn push_generic_record<B: BarcodeT>(
+        &mut self,
+        rec: &ReadRecord<B>,
+    ) -> Result<(), RadError> {
+        if !self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "records can only be pushed within a chunk",
+            ));
+        }
+        self.enc.push(rec, rec.bc)?;
+        Ok(())
+    }
+
+    /// Append `rec` to the current chunk, with its alignments in the
+    /// layout given by the `is_paired` field of the header and the optional
+    /// fields enabled (see `AlnLayout`).
+    pub fn push_ext_record(&mut self, rec: &ExtReadRecord) -> Result<(), RadError> {
+        if !self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "records can only be pushed within a chunk",
+            ));
+        }
+        self.enc.push_ext(rec)?;
+        Ok(())
+    }
+
+    pub fn finish_chunk(&mut self) -> Result<(), RadError> {
+        if !self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "can't finish a chunk that was never begun",
+            ));
+        }
+        self.enc.finish(&mut self.writer)?;
+        self.in_chunk = false;
+        Ok(())
+    }
+
+    /// Flush the output and return the underlying writer, provided that
+    /// the number of chunks written agrees with the header.
+    pub fn finalize(mut self) -> Result<W, RadError> {
+        if self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "the last chunk was never finished",
+            ));
+        }
+        if self.enc.chunks_written != self.expected_chunks {
+            return Err(RadError::ChunkCountMismatch {
+                expected: self.expected_chunks,
+                observed: self.enc.chunks_written,
+            });
+        }
+        if self.enc.checksum.is_some() {
+            return Err(RadError::InvalidWriterState(
+                "a checksum can only be written by finalize_with_chunk_count",
+            ));
+        }
+        if self.max_ambiguity_pos.is_some() && self.enc.max_ambiguity > self.declared_max_ambiguity
+        {
+            return Err(RadError::InvalidWriterState(
+                "a record has more alignments than the declared max_ambiguity",
+            ));
+        }
+        self.enc.write_index(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// The number of chunks finished so far.
+    pub fn chunks_written(&self) -> u64 {
+        self.enc.chunks_written
+    }
+}
+
+impl<W: Write + Seek> RadWriter<W> {
+    /// Finish writing for output whose number of chunks wasn't known when
+    /// the header was written: the `num_chunks` field of the header is
+    /// overwritten with the number of chunks actually written (and the
+    /// `MAX_AMBIGUITY_TAG` value, if one was written, with the largest
+    /// number of alignments actually seen, and the `CHECKSUM_TAG` value,
+    /// if enabled, with the checksum of the chunks).  The writer must have been
+    /// at position 0 when the header was written.
+    pub fn finalize_with_chunk_count(mut self) -> Result<W, RadError> {
+        if self.in_chunk {
+            return Err(RadError::InvalidWriterState(
+                "the last chunk was never finished",
+            ));
+        }
+        self.enc.write_index(&mut self.writer)?;
+        let end = self.writer.seek(SeekFrom::Current(0))?;
+        self.writer.seek(SeekFrom::Start(self.num_chunks_pos))?;
+        self.writer
+            .write_all(&self.enc.chunks_written.to_le_bytes())?;
+        if let Some(pos) = self.max_ambiguity_pos {
+            self.writer.seek(SeekFrom::Start(pos))?;
+            self.writer
+                .write_all(&self.enc.max_ambiguity.to_le_bytes())?;
+        }
+        if let (Some(pos), Some(crc)) = (self.checksum_pos, self.enc.checksum.as_ref()) {
+            self.writer.seek(SeekFrom::Start(pos))?;
+            self.writer.write_all(&crc.sum().to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::libradicl::error::RadError;
+    use self::libradicl::utils;
+    use self::libradicl::*;
+    use crate as libradicl;
+    use bio_types::strand::Strand;
+    use dashmap::DashMap;
+    use rayon::prelude::*;
+    use scroll::Pread;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{BufReader, Cursor, Read, Write};
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    // write a single record with u32 barcode and umi
+    fn push_record(buf: &mut Vec<u8>, bc: u32, umi: u32, refs: &[u32]) {
+        buf.extend_from_slice(&(refs.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&bc.to_le_bytes());
+        buf.extend_from_slice(&umi.to_le_bytes());
+        for r in refs {
+            buf.extend_from_slice(&r.to_le_bytes());
+        }
+    }
+
+    // wrap the records in `body` into a chunk
+    fn push_chunk(buf: &mut Vec<u8>, nrec: u32, body: &[u8]) {
+        buf.extend_from_slice(&((body.len() + 8) as u32).to_le_bytes());
+        buf.extend_from_slice(&nrec.to_le_bytes());
+        buf.extend_from_slice(body);
+    }
+
+    #[test]
+    fn test_correct_in_place() {
+        let mut input = Vec::new();
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 10, &[0x80000001]);
+        push_record(&mut body, 2, 11, &[3, 0x80000004]);
+        push_chunk(&mut input, 2, &body);
+        body.clear();
+        push_record(&mut body, 5, 12, &[7]);
+        push_chunk(&mut input, 1, &body);
+
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 100u64);
+        correct_map.insert(5u64, 100u64);
+
+        let config = ChunkConfig {
+            num_chunks: 2,
+            bc_type: 3,
+            umi_type: 3,
+        };
+        let mut output = Vec::new();
+        correct_in_place(&mut Cursor::new(input), &config, &correct_map, &mut output).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let bct = RadIntId::U32;
+        let umit = RadIntId::U32;
+        let c1: Chunk = Chunk::from_bytes(&mut reader, &bct, &umit);
+        assert_eq!(c1.nrec, 1);
+        assert_eq!(c1.reads[0].bc, 100);
+        assert_eq!(c1.reads[0].umi, 10);
+        assert_eq!(c1.reads[0].refs, vec![1]);
+        let c2: Chunk = Chunk::from_bytes(&mut reader, &bct, &umit);
+        assert_eq!(c2.nrec, 1);
+        assert_eq!(c2.reads[0].bc, 100);
+        assert_eq!(c2.reads[0].refs, vec![7]);
+        assert_eq!(reader.position() as usize, reader.get_ref().len());
+
+        let correct = |input: Vec<u8>| {
+            correct_in_place(
+                &mut Cursor::new(input),
+                &ChunkConfig {
+                    num_chunks: 1,
+                    bc_type: 3,
+                    umi_type: 3,
+                },
+                &correct_map,
+                &mut Vec::new(),
+            )
+        };
+        // a record declaring more alignments than its chunk holds
+        let mut input = Vec::new();
+        body.clear();
+        push_record(&mut body, 1, 10, &[0, 1]);
+        body[0..4].copy_from_slice(&3u32.to_le_bytes());
+        push_chunk(&mut input, 1, &body);
+        assert!(matches!(
+            correct(input),
+            Err(RadError::ChunkSizeMismatch { .. })
+        ));
+        // a chunk declaring more records than it holds
+        let mut input = Vec::new();
+        body.clear();
+        push_record(&mut body, 1, 10, &[0]);
+        push_chunk(&mut input, 2, &body);
+        assert!(matches!(
+            correct(input),
+            Err(RadError::TruncatedChunk { nrec: 2, read: 1 })
+        ));
+    }
+    #[test]
+    fn test_find_neighbors_with_counts() {
+        // AAAA, AAAC, AAAG and AACA
+        let bcmap = BarcodeLookupMap::new_with_counts(vec![4, 2, 1, 0], vec![1, 9, 9, 5], 4);
+        assert_eq!(bcmap.barcodes, vec![0, 1, 2, 4]);
+        assert_eq!(bcmap.counts, vec![5, 9, 9, 1]);
+        let idx = |bc: u64| bcmap.find_exact(bc);
+
+        assert_eq!(
+            bcmap.find_neighbors_with_counts(4, &bcmap.counts),
+            (idx(4), 1)
+        );
+        // AAAT is 1 mismatch from AAAA, AAAC and AAAG, the last two tied
+        let (best, n) = bcmap.find_neighbors_with_counts(3, &bcmap.counts);
+        assert_eq!(n, 2);
+        assert!(best == idx(1) || best == idx(2));
+        assert_eq!(
+            bcmap.find_neighbors_with_counts(3, &[5, 9, 3, 1]),
+            (idx(1), 1)
+        );
+        // AATA is 1 mismatch from AAAA and AACA
+        assert_eq!(
+            bcmap.find_neighbors_with_counts(12, &bcmap.counts),
+            (idx(0), 1)
+        );
+        // CAAC is 1 mismatch from AAAC only
+        assert_eq!(
+            bcmap.find_neighbors_with_counts(65, &bcmap.counts),
+            (idx(1), 1)
+        );
+        assert_eq!(
+            bcmap.find_neighbors_with_counts(15, &bcmap.counts),
+            (None, 0)
+        );
+
+        // without counts, every neighbor ties
+        assert_eq!(bcmap.find_neighbors_with_counts(3, &[]).1, 2);
+        assert_eq!(bcmap.find_neighbors_with_counts(12, &[]).1, 2);
+        assert_eq!(bcmap.find_neighbors_with_counts(65, &[]), (idx(1), 1));
+        let bcmap = BarcodeLookupMap::new(vec![0u64, 1, 2, 4], 4);
+        assert_eq!(
+            bcmap.find_neighbors_with_counts(65, &bcmap.counts),
+            (idx(1), 1)
+        );
+    }
+    #[test]
+    fn test_fraction_reads_in_cells() {
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut hist = HashMap::with_hasher(s);
+        hist.insert(1u64, 60u64);
+        hist.insert(2u64, 20u64);
+        hist.insert(3u64, 15u64);
+        hist.insert(4u64, 5u64);
+
+        // barcode 5 isn't present in the histogram at all
+        let frac = fraction_reads_in_cells(&hist, &[1, 3, 5]);
+        assert!((frac - 0.75).abs() < 1e-12);
+    }
+    #[test]
+    fn test_type_tag_round_trip() {
+        let all_types = [
+            RadType::Bool,
+            RadType::U8,
+            RadType::U16,
+            RadType::U32,
+            RadType::U64,
+            RadType::U128,
+            RadType::F32,
+            RadType::F64,
+            RadType::String,
+        ];
+        for t in all_types.iter() {
+            let id = encode_type_tag(*t).unwrap();
+            assert_eq!(decode_type_tag(id), Some(*t));
+        }
+        assert_eq!(decode_type_tag(7), None);
+        assert!(decode_int_type_tag(0).is_none());
+        assert!(decode_int_type_tag(5).is_none());
+        assert!(decode_int_type_tag(6).is_none());
+        assert!(decode_int_type_tag(8).is_none());
+        assert_eq!(decode_int_type_tag(9), Some(RadIntId::U128));
+        assert_eq!(RadIntId::U128.type_tag(), 9);
+    }
+    #[test]
+    fn test_rad_writer_round_trip() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["txp1".to_string(), "txp2".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 2,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for c in 0..2u64 {
+            rw.begin_chunk().unwrap();
+            rw.push_record(&ReadRecord {
+                bc: 7 + c,
+                umi: 3,
+                dirs: vec![true, false],
+                mate_dirs: Vec::new(),
+                refs: vec![0, 1],
+                tags: None,
+            })
+            .unwrap();
+            rw.finish_chunk().unwrap();
+        }
+        let buf = rw.finalize().unwrap();
+
+        let mut reader = Cursor::new(buf);
+        let rhdr = RadHeader::from_bytes(&mut reader);
+        assert_eq!(rhdr.ref_names, hdr.ref_names);
+        assert_eq!(rhdr.num_chunks, 2);
+        let _fl = TagSection::from_bytes(&mut reader);
+        let rl = TagSection::from_bytes(&mut reader);
+        assert_eq!(rl.tags[0].name, "b");
+        let _al = TagSection::from_bytes(&mut reader);
+        let rft = FileTags::from_bytes(&mut reader);
+        assert_eq!(rft.bclen, 16);
+        for c in 0..2u64 {
+            let chunk: Chunk = Chunk::from_bytes(&mut reader, &RadIntId::U32, &RadIntId::U32);
+            assert_eq!(chunk.nrec, 1);
+            assert_eq!(chunk.reads[0].bc, 7 + c);
+            assert_eq!(chunk.reads[0].dirs, vec![true, false]);
+            assert_eq!(chunk.reads[0].refs, vec![0, 1]);
+        }
+
+        // writing fewer chunks than promised is an error
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        assert!(rw.finalize().is_err());
+    }
+    #[test]
+    fn test_from_bytes_checked() {
+        let mut buf = Vec::<u8>::new();
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 3]);
+        push_record(&mut body, 4, 5, &[1]);
+        push_chunk(&mut buf, 2, &body);
+        let c = Chunk::from_bytes_checked(&mut Cursor::new(&buf), &RadIntId::U32, &RadIntId::U32)
+            .unwrap();
+        assert_eq!(c.nrec, 2);
+
+        // overstate the chunk size by 4 bytes
+        let nbytes = buf.pread::<u32>(0).unwrap() + 4;
+        buf[0..4].copy_from_slice(&nbytes.to_le_bytes());
+        buf.extend_from_slice(&[0u8; 4]);
+        let r = Chunk::from_bytes_checked(&mut Cursor::new(&buf), &RadIntId::U32, &RadIntId::U32);
+        assert!(matches!(
+            r,
+            Err(RadError::ChunkSizeMismatch { declared, consumed }) if declared == consumed as u32 + 4
+        ));
+    }
+    #[test]
+    fn test_from_bytes_filtered() {
+        let mut buf = Vec::<u8>::new();
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 0x80000003, 5]);
+        push_record(&mut body, 4, 5, &[1]);
+        push_record(&mut body, 6, 7, &[0x80000005]);
+        push_chunk(&mut buf, 3, &body);
+
+        let keep: HashSet<u32> = [3u32, 5].iter().cloned().collect();
+        let c = Chunk::from_bytes_filtered(
+            &mut Cursor::new(&buf),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &keep,
+        )
+        .unwrap();
+        assert_eq!(c.nrec, 3);
+        assert_eq!(c.reads.len(), 2);
+        assert_eq!(c.reads[0].refs, vec![3, 5]);
+        assert_eq!(c.reads[0].dirs, vec![true, false]);
+        assert_eq!(c.reads[1].bc, 6);
+        assert_eq!(c.reads[1].refs, vec![5]);
+
+        // a truncated chunk is an error rather than a panic
+        assert!(Chunk::from_bytes_filtered(
+            &mut Cursor::new(&buf[..buf.len() - 2]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &keep,
+        )
+        .is_err());
+    }
+    #[test]
+    fn test_collate_twopass_remap() {
+        // isoforms 0, 1 and 2 collapse to gene 0, 3 maps to gene 1
+        let remap = vec![0u32, 0, 0, 1];
+        let mut bucket = Vec::new();
+        push_record(&mut bucket, 5, 1, &[0x80000000, 1, 0x80000003]);
+        push_record(&mut bucket, 5, 2, &[2]);
+        // the genes are out of order once remapped
+        push_record(&mut bucket, 5, 3, &[3, 0x80000001]);
+        let mut reader = BufReader::new(Cursor::new(bucket));
+
+        let owriter = Mutex::new(Vec::<u8>::new());
+        let mut cmap = HashMap::<u64, _, _>::with_hasher(ahash::RandomState::new());
+        let nchunks = collate_temporary_bucket_twopass(
+            &mut reader,
+            &RadIntId::U32,
+            &RadIntId::U32,
+            3,
+            &owriter,
+            false,
+            &mut cmap,
+            Some(&remap),
+            &AlnLayout::default(),
+            None,
+        );
+        assert_eq!(nchunks, 1);
+
+        let out = owriter.into_inner().unwrap();
+        let c = Chunk::from_bytes_checked(&mut Cursor::new(&out), &RadIntId::U32, &RadIntId::U32)
+            .unwrap();
+        assert_eq!(c.nbytes as usize, out.len());
+        assert_eq!(c.reads[0].refs, vec![0, 1]);
+        assert_eq!(c.reads[0].dirs, vec![true, true]);
+        assert_eq!(c.reads[1].refs, vec![0]);
+        assert_eq!(c.reads[2].refs, vec![0, 1]);
+        assert_eq!(c.reads[2].dirs, vec![true, false]);
+    }
+    #[test]
+    fn test_hist_unfiltered_parallel_matches_serial() {
+        let known = [1u64, 2, 3, 4];
+        let chunks: Vec<Chunk> = (0..64u64)
+            .map(|i| Chunk {
+                nbytes: 0,
+                nrec: 3,
+                reads: vec![
+                    ReadRecord {
+                        bc: i % 6,
+                        umi: i,
+                        dirs: vec![true],
+                        mate_dirs: Vec::new(),
+                        refs: vec![0],
+                        tags: None,
+                    },
+                    ReadRecord {
+                        bc: (i * 7) % 5,
+                        umi: i,
+                        dirs: vec![false, true],
+                        mate_dirs: Vec::new(),
+                        refs: vec![1, 2],
+                        tags: None,
+                    },
+                    ReadRecord {
+                        bc: 3,
+                        umi: i,
+                        dirs: vec![false],
+                        mate_dirs: Vec::new(),
+                        refs: vec![4],
+                        tags: None,
+                    },
+                ],
+            })
+            .collect();
+
+        let mut hist = HashMap::with_hasher(ahash::RandomState::new());
+        for k in &known {
+            hist.insert(*k, 0u64);
+        }
+        let mut unmatched = Vec::new();
+        let mut max_amb = 0usize;
+        let mut stats = HistStats::default();
+        for c in &chunks {
+            stats.merge(&update_barcode_hist_unfiltered(
+                &mut hist,
+                &mut unmatched,
+                &mut max_amb,
+                c,
+                &Strand::Forward,
+            ));
+        }
+
+        let phist = DashMap::new();
+        for k in &known {
+            phist.insert(*k, 0u64);
+        }
+        let punmatched = Mutex::new(Vec::new());
+        let pstats = update_barcode_hist_unfiltered_parallel(
+            &phist,
+            &punmatched,
+            chunks.into_par_iter(),
+            &Strand::Forward,
+        );
+
+        assert_eq!(pstats, stats);
+        for k in &known {
+            assert_eq!(*phist.get(k).unwrap(), hist[k]);
+        }
+        let mut punmatched = punmatched.into_inner().unwrap();
+        punmatched.sort_unstable();
+        unmatched.sort_unstable();
+        assert_eq!(punmatched, unmatched);
+    }
+    #[test]
+    fn test_counting_reader() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["t".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 3,
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(
+            &hdr,
+            &FileTags {
+                bclen: 16,
+                umilen: 12,
+                max_ambiguity: None,
+                variable_umi: false,
+                ..Default::default()
+            },
+            RadIntId::U32,
+            RadIntId::U64,
+        )
+        .unwrap();
+        for c in 0..3u32 {
+            rw.begin_chunk().unwrap();
+            for i in 0..c {
+                rw.push_record(&ReadRecord {
+                    bc: i as u64,
+                    umi: 1,
+                    dirs: vec![true; i as usize],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0; i as usize],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let buf = rw.finalize().unwrap();
+
+        let mut reader = CountingReader::new(Cursor::new(&buf));
+        let _hdr = RadHeader::from_bytes(&mut reader);
+        let _fl = TagSection::from_bytes(&mut reader);
+        let _rl = TagSection::from_bytes(&mut reader);
+        let _al = TagSection::from_bytes(&mut reader);
+        let _ft = FileTags::from_bytes(&mut reader);
+        let data_start = reader.bytes_read();
+
+        let mut chunk_bytes = 0u64;
+        for _ in 0..3 {
+            let before = reader.bytes_read();
+            let c: Chunk = Chunk::from_bytes(&mut reader, &RadIntId::U32, &RadIntId::U64);
+            assert_eq!(reader.bytes_read() - before, c.nbytes as u64);
+            chunk_bytes += c.nbytes as u64;
+        }
+        assert_eq!(chunk_bytes, buf.len() as u64 - data_start);
+        assert_eq!(reader.bytes_read(), buf.len() as u64);
+    }
+    #[test]
+    fn test_collate_onepass_matches_twopass() {
+        let mut bucket = Vec::new();
+        for i in 0..50u32 {
+            push_record(&mut bucket, i % 7, i, &[i % 3, 0x80000000 | (i % 5)]);
+        }
+        let new_map = || {
+            HashMap::<u64, _, _>::with_hasher(ahash::RandomState::with_seeds(
+                2u64, 7u64, 1u64, 8u64,
+            ))
+        };
+
+        for &compress in &[false, true] {
+            let expected = Mutex::new(Vec::<u8>::new());
+            let mut cmap = new_map();
+            let n = collate_temporary_bucket_twopass(
+                &mut BufReader::new(Cursor::new(bucket.clone())),
+                &RadIntId::U32,
+                &RadIntId::U32,
+                50,
+                &expected,
+                compress,
+                &mut cmap,
+                None,
+                &AlnLayout::default(),
+                None,
+            );
+            let expected = expected.into_inner().unwrap();
+
+            let spill_path =
+                std::env::temp_dir().join(format!("collate_spill_test_{}.tmp", std::process::id()));
+            // the first budget holds everything in memory, the others force a
+            // spill, and assemble the output one or a few cells at a time
+            for budget in &[usize::MAX, 64, 300] {
+                let observed = Mutex::new(Vec::<u8>::new());
+                let mut cmap = new_map();
+                let m = collate_temporary_bucket_onepass(
+                    &mut Cursor::new(&bucket),
+                    &RadIntId::U32,
+                    &RadIntId::U32,
+                    50,
+                    &observed,
+                    compress,
+                    &mut cmap,
+                    None,
+                    &AlnLayout::default(),
+                    *budget,
+                    &spill_path,
+                    None,
+                );
+                assert_eq!(m, n);
+                assert_eq!(observed.into_inner().unwrap(), expected);
+                assert!(!spill_path.exists());
+            }
+        }
+    }
+    #[test]
+    fn test_collate_u128_barcodes() {
+        // 40 base barcodes, which all agree in their low 64 bits
+        let bcs: Vec<u128> = (1..4u128).map(|i| (i << 70) | 0xffff).collect();
+        let mut bucket = Vec::new();
+        for i in 0..9u32 {
+            bucket.extend_from_slice(&1u32.to_le_bytes());
+            bucket.extend_from_slice(&bcs[i as usize % 3].to_le_bytes());
+            bucket.extend_from_slice(&i.to_le_bytes());
+            bucket.extend_from_slice(&(0x80000000 | i).to_le_bytes());
+        }
+        let new_map = || {
+            HashMap::<u128, _, _>::with_hasher(ahash::RandomState::with_seeds(
+                2u64, 7u64, 1u64, 8u64,
+            ))
+        };
+
+        let expected = Mutex::new(Vec::<u8>::new());
+        let n = collate_temporary_bucket_twopass(
+            &mut BufReader::new(Cursor::new(bucket.clone())),
+            &RadIntId::U128,
+            &RadIntId::U32,
+            9,
+            &expected,
+            false,
+            &mut new_map(),
+            None,
+            &AlnLayout::default(),
+            None,
+        );
+        assert_eq!(n, 3);
+        let expected = expected.into_inner().unwrap();
+
+        let spill_path =
+            std::env::temp_dir().join(format!("collate_u128_test_{}.tmp", std::process::id()));
+        for budget in &[usize::MAX, 64] {
+            let observed = Mutex::new(Vec::<u8>::new());
+            let m = collate_temporary_bucket_onepass(
+                &mut Cursor::new(&bucket),
+                &RadIntId::U128,
+                &RadIntId::U32,
+                9,
+                &observed,
+                false,
+                &mut new_map(),
+                None,
+                &AlnLayout::default(),
+                *budget,
+                &spill_path,
+                None,
+            );
+            assert_eq!(m, n);
+            assert_eq!(observed.into_inner().unwrap(), expected);
+        }
+
+        let mut reader = Cursor::new(&expected);
+        for bc in &bcs {
+            let c: Chunk<u128> = Chunk::from_bytes(&mut reader, &RadIntId::U128, &RadIntId::U32);
+            assert_eq!(c.nrec, 3);
+            assert!(c.reads.iter().all(|r| r.bc == *bc));
+        }
+
+        // the barcodes can't be read as u64s
+        let e = ReadRecord::<u64>::from_bytes_checked(
+            &mut Cursor::new(&bucket),
+            &RadIntId::U128,
+            &RadIntId::U32,
+        );
+        assert_eq!(e.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+    #[test]
+    fn test_hist_with_default_hasher() {
+        let chunk = Chunk {
+            nbytes: 0,
+            nrec: 3,
+            reads: (0..3u64)
+                .map(|i| ReadRecord {
+                    bc: i % 2,
+                    umi: i,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                })
+                .collect(),
+        };
+        let mut hist: HashMap<u64, u64> = HashMap::new();
+        let mut max_amb = 0usize;
+        update_barcode_hist(&mut hist, &mut max_amb, &chunk, &Strand::Unknown);
+        let mut permit = permit_list_from_threshold(&hist, 2);
+        permit.sort_unstable();
+        assert_eq!(permit, vec![0]);
+    }
+    #[test]
+    fn test_corrected_cb_chunk_records() {
+        let mut input = Vec::new();
+        push_record(&mut input, 9, 1, &[0x80000002]);
+        push_record(&mut input, 9, 2, &[4, 0x80000005]);
+        let mut cache = HashMap::with_hasher(ahash::RandomState::new());
+        collate_temporary_bucket(
+            &mut Cursor::new(&input),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            1,
+            2,
+            &mut cache,
+            None,
+            &CollateParams::default(),
+            None,
+        );
+
+        let recs: Vec<(u64, u64, Vec<u32>)> = cache[&9]
+            .records(&RadIntId::U32, &RadIntId::U32)
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            recs,
+            vec![(9, 1, vec![0x80000002]), (9, 2, vec![4, 0x80000005])]
+        );
+    }
+    #[test]
+    fn test_dedup_umis() {
+        let rec = |umi: u64, refs: Vec<u32>| ReadRecord {
+            bc: 1,
+            umi,
+            dirs: vec![true; refs.len()],
+            mate_dirs: Vec::new(),
+            refs,
+            tags: None,
+        };
+        let make_records = || {
+            vec![
+                rec(0b0100, vec![2, 1]),
+                rec(0b0100, vec![1, 2]),
+                rec(0b0100, vec![7]),
+                // 1 mismatch from 0b0100, with the same references
+                rec(0b0101, vec![1, 2]),
+                // 1 mismatch from 0b0100, but to a different reference set
+                rec(0b0110, vec![9]),
+            ]
+        };
+        let records = make_records();
+
+        assert_eq!(
+            dedup_umis(&records, false),
+            vec![
+                (0b0100, vec![1, 2], 2),
+                (0b0100, vec![7], 1),
+                (0b0101, vec![1, 2], 1),
+                (0b0110, vec![9], 1),
+            ]
+        );
+
+        let mut rev = make_records();
+        rev.reverse();
+        let collapsed = dedup_umis(&rev, true);
+        assert_eq!(
+            collapsed,
+            vec![
+                (0b0100, vec![1, 2], 3),
+                (0b0100, vec![7], 1),
+                (0b0110, vec![9], 1),
+            ]
+        );
+        assert_eq!(collapsed, dedup_umis(&records, true));
+    }
+    #[test]
+    fn test_file_tags_validate() {
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        assert!(ft.validate(&RadIntId::U32, &RadIntId::U32).is_ok());
+        assert!(matches!(
+            ft.validate(&RadIntId::U16, &RadIntId::U32),
+            Err(RadError::TagWidthMismatch { ref tag, .. }) if tag == "barcode"
+        ));
+        assert!(ft.validate(&RadIntId::U64, &RadIntId::U16).is_err());
+
+        // barcodes of up to 32 bases can be corrected as u64s
+        assert!(ft.require_u64_barcodes("collate").is_ok());
+        let long = FileTags { bclen: 40, ..ft };
+        assert!(long.validate(&RadIntId::U128, &RadIntId::U32).is_ok());
+        assert!(matches!(
+            long.require_u64_barcodes("collate"),
+            Err(RadError::TagWidthMismatch {
+                len: 40,
+                bits: 64,
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn test_write_frequency_tsv() {
+        let mut hist = HashMap::with_hasher(ahash::RandomState::new());
+        // AAAC, AAAG, AAAT
+        hist.insert(1u64, 5u64);
+        hist.insert(2u64, 9u64);
+        hist.insert(3u64, 5u64);
+
+        let mut out = Vec::new();
+        write_frequency_tsv(&hist, 4, None, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "AAAG\t9\nAAAC\t5\nAAAT\t5\n"
+        );
+
+        let mut out = Vec::new();
+        write_frequency_tsv(&hist, 4, Some(1), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "AAAG\t9\n");
+    }
+    #[test]
+    fn test_merge_temp_buckets() {
+        let parent = std::env::temp_dir().join(format!("merge_test_{}", std::process::id()));
+        std::fs::create_dir_all(&parent).unwrap();
+        let params = CollateParams::default();
+
+        let buckets: Vec<TempBucket> = (0..2)
+            .map(|i| TempBucket::from_id_and_parent(i, &parent, &params))
+            .collect();
+        // bucket 0 holds barcodes 1 and 2, bucket 1 holds barcode 3
+        for (b, bcs) in buckets.iter().zip(&[vec![1u32, 2, 1], vec![3u32]]) {
+            let mut recs = Vec::new();
+            for bc in bcs {
+                push_record(&mut recs, *bc, 0, &[0]);
+            }
+            b.bucket_writer.lock().unwrap().write_all(&recs).unwrap();
+            b.num_records_written
+                .store(bcs.len() as u32, Ordering::SeqCst);
+        }
+
+        let owriter = Mutex::new(Vec::<u8>::new());
+        let stats = merge_temp_buckets(
+            &buckets,
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &AlnLayout::default(),
+            &owriter,
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            MergeStats {
+                num_buckets: 2,
+                num_records: 4,
+                num_barcodes: 3,
+            }
+        );
+        assert!(buckets.iter().all(|b| !b.path.exists()));
+
+        let out = owriter.into_inner().unwrap();
+        let mut reader = Cursor::new(&out);
+        let mut nrecs = HashMap::new();
+        for _ in 0..3 {
+            let c = Chunk::from_bytes_checked(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+            assert!(c.reads.iter().all(|r| r.bc == c.reads[0].bc));
+            nrecs.insert(c.reads[0].bc, c.nrec);
+        }
+        assert_eq!(nrecs[&1], 2);
+        assert_eq!(nrecs[&2], 1);
+        assert_eq!(nrecs[&3], 1);
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+    #[test]
+    fn test_for_each_record_matches_from_bytes() {
+        let mut buf = Vec::new();
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0x80000001, 2, 3]);
+        push_record(&mut body, 3, 4, &[]);
+        push_record(&mut body, 5, 6, &[0x80000007]);
+        push_chunk(&mut buf, 3, &body);
+
+        let c = Chunk::from_bytes(&mut Cursor::new(&buf), &RadIntId::U32, &RadIntId::U32);
+        let mut i = 0;
+        let (nbytes, nrec) = Chunk::for_each_record(
+            &mut Cursor::new(&buf),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            |r| {
+                let e = &c.reads[i];
+                assert_eq!((r.bc, r.umi), (e.bc, e.umi));
+                assert_eq!(r.refs, e.refs);
+                assert_eq!(r.dirs, e.dirs);
+                i += 1;
+            },
+        )
+        .unwrap();
+        assert_eq!((nbytes, nrec), (c.nbytes, c.nrec));
+        assert_eq!(i, 3);
+    }
+    #[test]
+    fn test_read_chunk_header_opt() {
+        let mut buf = Vec::new();
+        push_chunk(&mut buf, 0, &[]);
+        let mut reader = Cursor::new(&buf);
+        assert_eq!(read_chunk_header_opt(&mut reader).unwrap(), Some((8, 0)));
+        assert_eq!(read_chunk_header_opt(&mut reader).unwrap(), None);
+
+        let mut reader = Cursor::new(&buf[..5]);
+        let e = read_chunk_header_opt(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+    #[test]
+    fn test_find_neighbors_with_ns() {
+        let pack = |s: &str| utils::pack_kmer_with_ns(s.as_bytes()).unwrap();
+        let bcs: Vec<u64> = ["ACGTAC", "ACGTTT", "GGGGGG", "CCCCCA", "CCCCCT"]
+            .iter()
+            .map(|s| pack(s).0)
+            .collect();
+        let bcmap = BarcodeLookupMap::new(bcs, 6);
+        let idx_of = |s: &str| bcmap.find_exact(pack(s).0);
+
+        // an N that resolves to exactly one barcode
+        let (q, m) = pack("ACGTAN");
+        assert_eq!(
+            bcmap.find_neighbors_with_ns(q, m, true),
+            (idx_of("ACGTAC"), 1)
+        );
+        // an N plus one substitution
+        let (q, m) = pack("GNGGGA");
+        assert_eq!(
+            bcmap.find_neighbors_with_ns(q, m, true),
+            (idx_of("GGGGGG"), 1)
+        );
+        // an N that could be either of two barcodes
+        let (q, m) = pack("CCCCCN");
+        assert_eq!(bcmap.find_neighbors_with_ns(q, m, true).1, 2);
+        // without `try_exact`, the barcode the N resolves to is skipped,
+        // and only those a further substitution away are found
+        let (q, m) = pack("ACGTAN");
+        assert_eq!(
+            bcmap.find_neighbors_with_ns(q, m, false),
+            (idx_of("ACGTTT"), 1)
+        );
+        let (q, m) = pack("GNGGGA");
+        assert_eq!(
+            bcmap.find_neighbors_with_ns(q, m, false),
+            (idx_of("GGGGGG"), 1)
+        );
+        // two Ns are never corrected
+        let (q, m) = pack("ACGTNN");
+        assert_eq!(bcmap.find_neighbors_with_ns(q, m, true), (None, 0));
+    }
+    #[test]
+    fn test_barcode_map_capped_buckets() {
+        for &bclen in &[7u32, 8] {
+            let bcs: Vec<u64> = (0..2000u64)
+                .map(|i| (i * 2_654_435_761) % (1 << (2 * bclen)))
+                .collect();
+            let full = BarcodeLookupMap::new(bcs.clone(), bclen);
+            // at most 16 buckets, i.e. a 2 base prefix
+            let capped = BarcodeLookupMap::with_params(bcs, bclen, 16, false);
+            assert_eq!(full.barcodes, capped.barcodes);
+            for q in 0..(1u64 << (2 * bclen)) {
+                assert_eq!(full.find_exact(q), capped.find_exact(q));
+                assert_eq!(
+                    full.find_neighbors(q, true).1,
+                    capped.find_neighbors(q, true).1
+                );
+            }
+        }
+    }
+    #[test]
+    fn test_barcode_map_u128() {
+        // 40 base barcodes, which differ only in their top 6 bases
+        let bclen = 40u32;
+        let low = 0x5555_0f0f_3c3c_9696u128;
+        let bcs: Vec<u128> = (0..50u128).map(|i| (i << 70) | low).collect();
+        let bcmap = BarcodeLookupMap::new(bcs.clone(), bclen);
+        for &b in &bcs {
+            assert_eq!(bcmap.barcode_for_idx(bcmap.find_exact(b).unwrap()), b);
+        }
+
+        // a substitution at the 39th base
+        let q = bcs[3] ^ (1u128 << 76);
+        assert_eq!(bcmap.find_exact(q), None);
+        let (idx, n, dist) = bcmap.find_neighbors_with_distance(q, true);
+        assert_eq!(
+            (idx.map(|i| bcmap.barcodes[i]), n, dist),
+            (Some(bcs[3]), 1, 1)
+        );
+        // 52 isn't a barcode, but 4, 20 and 36 are each a base away from it
+        let q = (52u128 << 70) | low;
+        assert_eq!(bcmap.find_neighbors(q, true).1, 2);
+    }
+
+    #[test]
+    fn test_barcode_map_ignores_stray_high_bits() {
+        let bclen = 8u32;
+        let bcs: Vec<u64> = (0..500u64)
+            .map(|i| (i * 2_654_435_761) % (1 << (2 * bclen)))
+            .collect();
+        let stray = 1u64 << 40;
+        let bcmap = BarcodeLookupMap::new(bcs.clone(), bclen);
+        for bcmap_in in &[
+            BarcodeLookupMap::new(bcs.iter().map(|&v| v | stray).collect(), bclen),
+            BarcodeLookupMap::with_params(
+                bcs.iter().map(|&v| v | stray).collect(),
+                bclen,
+                16,
+                true,
+            ),
+        ] {
+            assert_eq!(bcmap_in.barcodes, bcmap.barcodes);
+        }
+        for bcmap in &[
+            bcmap,
+            BarcodeLookupMap::with_params(bcs.clone(), bclen, 16, false),
+        ] {
+            let queries: Vec<u64> = bcs.iter().map(|&v| v | stray).collect();
+            let mut found = Vec::new();
+            bcmap.find_exact_many(&queries, &mut found);
+            for (i, &q) in queries.iter().enumerate() {
+                assert_eq!(bcmap.find_exact(q), None);
+                assert_eq!(found[i], None);
+                // only checks that the lookup stays within the table
+                bcmap.find_neighbors(q, true);
+            }
+        }
+    }
+    #[test]
+    fn test_find_exact_many() {
+        let bclen = 8u32;
+        let bcs: Vec<u64> = (0..500u64)
+            .map(|i| (i * 2_654_435_761) % (1 << (2 * bclen)))
+            .collect();
+        // every other query is a barcode, in no particular order and with
+        // repeats, and the rest are (mostly) misses
+        let queries: Vec<u64> = (0..2000u64)
+            .map(|i| {
+                if i % 2 == 0 {
+                    bcs[((i * 7919) % 500) as usize]
+                } else {
+                    (i * 40_503) % (1 << (2 * bclen))
+                }
+            })
+            .collect();
+        for bcmap in &[
+            BarcodeLookupMap::new(bcs.clone(), bclen),
+            BarcodeLookupMap::with_params(bcs.clone(), bclen, 16, false),
+        ] {
+            let mut found = Vec::new();
+            bcmap.find_exact_many(&queries, &mut found);
+            let expected: Vec<Option<usize>> =
+                queries.iter().map(|&q| bcmap.find_exact(q)).collect();
+            assert_eq!(found, expected);
+            assert!(found.iter().any(|f| f.is_none()));
+            assert!(found.iter().filter(|f| f.is_some()).count() >= 1000);
+        }
+    }
+    #[test]
+    fn test_rad_writer_patches_chunk_count() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["txp".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.write_header(
+            &hdr,
+            &FileTags {
+                bclen: 4,
+                umilen: 4,
+                max_ambiguity: None,
+                variable_umi: false,
+                ..Default::default()
+            },
+            RadIntId::U8,
+            RadIntId::U8,
+        )
+        .unwrap();
+        for _ in 0..3 {
+            rw.begin_chunk().unwrap();
+            rw.finish_chunk().unwrap();
+        }
+        let buf = rw.finalize_with_chunk_count().unwrap().into_inner();
+        let rhdr = RadHeader::from_bytes(&mut Cursor::new(&buf));
+        assert_eq!(rhdr.num_chunks, 3);
+    }
+
+    #[test]
+    fn test_write_permit_list_gz_roundtrip() {
+        let bclen = 4u16;
+        let bcs = vec![
+            utils::pack_kmer_with_ns(b"TTTT").unwrap().0,
+            utils::pack_kmer_with_ns(b"ACGT").unwrap().0,
+            utils::pack_kmer_with_ns(b"AAAA").unwrap().0,
+        ];
+        let dir = std::env::temp_dir().join(format!("permit_gz_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("permit.txt.gz");
+        write_permit_list_gz(&path, &bcs, bclen).unwrap();
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "AAAA\nACGT\nTTTT\n");
+
+        let empty = dir.join("empty.txt.gz");
+        write_permit_list_gz(&empty, &[], bclen).unwrap();
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&empty).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert!(text.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rad_type_names_roundtrip() {
+        for id in 0..=6u8 {
+            let t = decode_type_tag(id).unwrap();
+            assert_eq!(t.to_string().parse::<RadType>().unwrap(), t);
+        }
+        for id in [1u8, 2, 3, 4, 9] {
+            let t = decode_int_type_tag(id).unwrap();
+            assert_eq!(t.to_string().parse::<RadIntId>().unwrap(), t);
+        }
+        assert_eq!("U32".parse::<RadIntId>().unwrap(), RadIntId::U32);
+        assert_eq!("f64".parse::<RadType>().unwrap(), RadType::F64);
+        assert!(matches!(
+            "f64".parse::<RadIntId>(),
+            Err(RadError::UnknownTypeName(_))
+        ));
+        assert_eq!("u128".parse::<RadType>().unwrap(), RadType::U128);
+        assert!("u256".parse::<RadType>().is_err());
+    }
+
+    #[test]
+    fn test_correction_stats() {
+        let fw = utils::MASK_LOWER_31_U32;
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 0, &[fw]); // kept
+        push_record(&mut body, 2, 1, &[fw | 1]); // corrected 2 -> 1, kept
+        push_record(&mut body, 1, 2, &[0]); // wrong orientation, filtered
+        push_record(&mut body, 9, 3, &[fw]); // not correctable
+        push_record(&mut body, 5, 4, &[fw]); // corrected, but not to an output cell
+        push_record(&mut body, 5, 5, &[0]); // likewise, and filtered
+        let mut chunk = Vec::new();
+        push_chunk(&mut chunk, 6, &body);
+
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 1u64);
+        correct_map.insert(2u64, 1u64);
+        correct_map.insert(5u64, 5u64);
+
+        let parent = std::env::temp_dir().join(format!("corr_stats_{}", std::process::id()));
+        std::fs::create_dir_all(&parent).unwrap();
+        let params = CollateParams::default();
+        let mut output_cache = HashMap::new();
+        output_cache.insert(
+            1u64,
+            Arc::new(TempBucket::from_id_and_parent(0, &parent, &params)),
+        );
+        let mut backing = vec![0u8; params.flush_limit];
+        let mut local_buffers = vec![Cursor::new(&mut backing[..])];
+
+        let stats = dump_corrected_cb_chunk_to_temp_file(
+            &mut BufReader::new(&chunk[..]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &correct_map,
+            &Strand::Forward,
+            &output_cache,
+            &mut local_buffers,
+            &params,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            CorrectionStats {
+                corrected: 2,
+                uncorrectable: 1,
+                filtered_empty: 1,
+            }
+        );
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn test_collate_progress() {
+        // one report every COLLATE_PROGRESS_INTERVAL records, and one at the end
+        let nrec = COLLATE_PROGRESS_INTERVAL as u32 + 1;
+        let mut input = Vec::new();
+        for i in 0..nrec {
+            push_record(&mut input, i % 3, i, &[0x80000002]);
+        }
+        let reports = std::cell::RefCell::new(Vec::new());
+        let mut cache = HashMap::with_hasher(ahash::RandomState::new());
+        collate_temporary_bucket(
+            &mut Cursor::new(&input),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            1,
+            nrec,
+            &mut cache,
+            None,
+            &CollateParams::default(),
+            Some(&|p| reports.borrow_mut().push(p)),
+        );
+        let reports = reports.into_inner();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].records_processed, COLLATE_PROGRESS_INTERVAL);
+        assert_eq!(reports[1].records_processed, nrec as u64);
+        // each record is its header (12 bytes) and one alignment
+        assert_eq!(reports[1].bytes_written, 16 * nrec as u64);
+
+        // the bucket lock isn't held while the callback runs
+        let fw = utils::MASK_LOWER_31_U32;
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 0, &[fw]);
+        push_record(&mut body, 2, 1, &[fw | 1]);
+        push_record(&mut body, 9, 3, &[fw]); // not correctable
+        let mut chunk = Vec::new();
+        push_chunk(&mut chunk, 3, &body);
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 1u64);
+        correct_map.insert(2u64, 1u64);
+
+        let parent = std::env::temp_dir().join(format!("corr_prog_{}", std::process::id()));
+        std::fs::create_dir_all(&parent).unwrap();
+        let params = CollateParams::default();
+        let bucket = Arc::new(TempBucket::from_id_and_parent(0, &parent, &params));
+        let mut output_cache = HashMap::new();
+        output_cache.insert(1u64, bucket.clone());
+        let mut backing = vec![0u8; params.flush_limit];
+        let mut local_buffers = vec![Cursor::new(&mut backing[..])];
+        let reports = std::cell::RefCell::new(Vec::new());
+        dump_corrected_cb_chunk_to_temp_file(
+            &mut BufReader::new(&chunk[..]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &correct_map,
+            &Strand::Forward,
+            &output_cache,
+            &mut local_buffers,
+            &params,
+            Some(&|p| {
+                assert!(bucket.bucket_writer.try_lock().is_ok());
+                reports.borrow_mut().push(p);
+            }),
+        )
+        .unwrap();
+        let reports = reports.into_inner();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].records_processed, 3);
+        assert_eq!(reports[0].buckets_touched, 1);
+        assert_eq!(
+            reports[0].bytes_written,
+            bucket.num_bytes_written.load(Ordering::SeqCst)
+        );
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn test_checked_readers_reject_truncated_input() {
+        let fw = utils::MASK_LOWER_31_U32;
+        assert!(Chunk::read_header_checked(&mut Cursor::new(Vec::<u8>::new())).is_err());
+        let mut small = Vec::new();
+        push_chunk(&mut small, 0, &[]);
+        small[0] = 4;
+        assert!(matches!(
+            Chunk::read_header_checked(&mut Cursor::new(&small)),
+            Err(RadError::InvalidChunkSize(4))
+        ));
+        assert!(FileTags::from_bytes_checked(&mut Cursor::new(vec![0u8; 3])).is_err());
+
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 0, &[fw, fw | 1]);
+        let rec = ReadRecord::from_bytes_keep_ori_checked(
+            &mut Cursor::new(&body),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &Strand::Forward,
+        )
+        .unwrap();
+        assert_eq!(rec.refs, vec![0, 1]);
+        assert!(ReadRecord::from_bytes_keep_ori_checked(
+            &mut Cursor::new(&body[..body.len() - 2]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &Strand::Forward,
+        )
+        .is_err());
+
+        // a chunk that claims more records than it holds
+        let mut chunk = Vec::new();
+        push_chunk(&mut chunk, 2, &body);
+        let path = std::env::temp_dir().join(format!("trunc_chunk_{}", std::process::id()));
+        let owriter = Mutex::new(BufWriter::new(File::create(&path).unwrap()));
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 1u64);
+        assert!(process_corrected_cb_chunk(
+            &mut BufReader::new(&chunk[..]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &correct_map,
+            &AlnLayout::default(),
+            &Strand::Forward,
+            &DashMap::new(),
+            &owriter,
+        )
+        .is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_process_corrected_cb_chunk() {
+        let fw = utils::MASK_LOWER_31_U32;
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 0, &[fw]); // kept
+        push_record(&mut body, 2, 1, &[fw | 1]); // corrected 2 -> 1, kept
+        push_record(&mut body, 1, 2, &[0]); // wrong orientation, filtered
+        push_record(&mut body, 9, 3, &[fw]); // not correctable
+        let mut chunk = Vec::new();
+        push_chunk(&mut chunk, 4, &body);
+
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 1u64);
+        correct_map.insert(2u64, 1u64);
+
+        let path = std::env::temp_dir().join(format!("corr_chunk_{}", std::process::id()));
+        let owriter = Mutex::new(BufWriter::new(File::create(&path).unwrap()));
+        let output_cache = DashMap::new();
+        output_cache.insert(
+            1u64,
+            CorrectedCbChunk::from_label_and_counter(1, 3, &CollateParams::default()),
+        );
+
+        let stats = process_corrected_cb_chunk(
+            &mut BufReader::new(&chunk[..]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &correct_map,
+            &AlnLayout::default(),
+            &Strand::Forward,
+            &output_cache,
+            &owriter,
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            CorrectionStats {
+                corrected: 2,
+                uncorrectable: 1,
+                filtered_empty: 1,
+            }
+        );
+
+        // the cell is written once its last (empty) record has been seen
+        owriter.into_inner().unwrap().flush().unwrap();
+        let out = std::fs::read(&path).unwrap();
+        let c = Chunk::from_bytes(&mut Cursor::new(&out), &RadIntId::U32, &RadIntId::U32);
+        assert_eq!(c.nrec, 2);
+        let recs: Vec<(u64, u64, Vec<u32>)> = c
+            .reads
+            .iter()
+            .map(|r| (r.bc, r.umi, r.refs.clone()))
+            .collect();
+        assert_eq!(recs, vec![(1, 0, vec![0]), (1, 1, vec![1])]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_process_corrected_cb_chunk_paired() {
+        let fw = utils::MASK_LOWER_31_U32;
+        let mate = utils::MASK_MATE_ORI_U32;
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 0, &[fw | mate | 2, fw | 1]);
+        let mut chunk = Vec::new();
+        push_chunk(&mut chunk, 1, &body);
+
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 1u64);
+        let path = std::env::temp_dir().join(format!("corr_paired_{}", std::process::id()));
+        let owriter = Mutex::new(BufWriter::new(File::create(&path).unwrap()));
+        let output_cache = DashMap::new();
+        output_cache.insert(
+            1u64,
+            CorrectedCbChunk::from_label_and_counter(1, 1, &CollateParams::default()),
+        );
+        let layout = AlnLayout {
+            paired: true,
+            ..AlnLayout::default()
+        };
+        process_corrected_cb_chunk(
+            &mut BufReader::new(&chunk[..]),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &correct_map,
+            &layout,
+            &Strand::Forward,
+            &output_cache,
+            &owriter,
+        )
+        .unwrap();
+
+        // the alignments are sorted by reference id alone, and the mate
+        // orientation of each is kept
+        owriter.into_inner().unwrap().flush().unwrap();
+        let out = std::fs::read(&path).unwrap();
+        let mut c = Chunk::from_bytes(&mut Cursor::new(&out), &RadIntId::U32, &RadIntId::U32);
+        assert_eq!(c.reads[0].refs, vec![1, mate | 2]);
+        c.split_mate_ori();
+        assert_eq!(c.reads[0].refs, vec![1, 2]);
+        assert_eq!(c.reads[0].mate_dirs, vec![false, true]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_non_empty_records() {
+        let rec = |bc: u64, refs: Vec<u32>| ReadRecord {
+            bc,
+            umi: 0,
+            dirs: vec![true; refs.len()],
+            mate_dirs: Vec::new(),
+            refs,
+            tags: None,
+        };
+        let c = Chunk {
+            nbytes: 0,
+            nrec: 4,
+            reads: vec![
+                rec(1, vec![]),
+                rec(2, vec![0, 3]),
+                rec(3, vec![]),
+                rec(4, vec![1]),
+            ],
+        };
+        let bcs: Vec<u64> = c.non_empty_records().map(|r| r.bc).collect();
+        assert_eq!(bcs, vec![2, 4]);
+        let recs: Vec<ReadRecord> = c.into_non_empty_records().collect();
+        assert_eq!(recs.len(), 2);
+        assert_eq!((recs[0].bc, &recs[0].refs), (2, &vec![0, 3]));
+        assert_eq!((recs[1].bc, &recs[1].refs), (4, &vec![1]));
+    }
+
+    #[test]
+    fn test_peek_chunk_first_record() {
+        let mut data = Vec::new();
+        for (bc, umi) in &[(7u32, 70u32), (8, 80)] {
+            let mut body = Vec::new();
+            push_record(&mut body, *bc, *umi, &[0, 1]);
+            push_record(&mut body, 99, 99, &[2]);
+            push_chunk(&mut data, 2, &body);
+        }
+        push_chunk(&mut data, 0, &[]);
+
+        let mut reader = Cursor::new(&data[..]);
+        let offsets = chunk_offsets(&mut reader, 3).unwrap();
+        reader.set_position(5);
+        let bc_umi = |r: &mut Cursor<&[u8]>, off| {
+            peek_chunk_first_record(r, off, false, &RadIntId::U32, &RadIntId::U32)
+        };
+        assert_eq!(bc_umi(&mut reader, offsets[0]).unwrap(), (7, 70));
+        assert_eq!(bc_umi(&mut reader, offsets[1]).unwrap(), (8, 80));
+        assert!(bc_umi(&mut reader, offsets[2]).is_err());
+        assert_eq!(reader.position(), 5);
+
+        // a chunk too small to hold its own header
+        let mut bad = Vec::new();
+        bad.extend_from_slice(&4u32.to_le_bytes());
+        bad.extend_from_slice(&0u32.to_le_bytes());
+        let e = chunk_offsets(&mut Cursor::new(&bad[..]), 2).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_for_each_chunk_parallel() {
+        let mut data = vec![0u8; 3];
+        for bc in 0..4u32 {
+            let mut body = Vec::new();
+            push_record(&mut body, bc, 0, &[0, 1]);
+            push_record(&mut body, bc, 1, &[2]);
+            push_chunk(&mut data, 2, &body);
+        }
+        let mut hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 3,
+            ref_names: vec!["t0".to_string(), "t1".to_string(), "t2".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 4,
+        };
+        let path = std::env::temp_dir().join(format!("par_chunks_{}.rad", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let (bct, umit) = (RadIntId::U32, RadIntId::U32);
+        let res = for_each_chunk_parallel(&path, &hdr, 3, false, &bct, &umit, 2, |c| {
+            (
+                c.reads[0].bc,
+                c.reads.iter().map(|r| r.refs.len()).sum::<usize>(),
+            )
+        })
+        .unwrap();
+        assert_eq!(res, vec![(0, 3), (1, 3), (2, 3), (3, 3)]);
+
+        // a last chunk declaring fewer bytes than its records span
+        let last = data.len() - 44;
+        data[last..last + 4].copy_from_slice(&40u32.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let res = for_each_chunk_parallel(&path, &hdr, 3, false, &bct, &umit, 2, |c| c.nrec);
+        assert!(res.is_err());
+
+        // more chunks declared than are present
+        hdr.num_chunks = 6;
+        let res = for_each_chunk_parallel(&path, &hdr, 3, false, &bct, &umit, 2, |c| c.nrec);
+        assert!(res.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_header_rejects_invalid_utf8() {
+        let mut buf = vec![0u8];
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        for name in &[&b"tx0"[..], &[b't', 0xff, b'1'][..]] {
+            buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            buf.extend_from_slice(name);
+        }
+        buf.extend_from_slice(&0u64.to_le_bytes());
+
+        match RadHeader::from_bytes_checked(&mut Cursor::new(&buf)) {
+            Err(RadError::InvalidUtf8 { context, bytes }) => {
+                assert!(context.contains('1'));
+                assert_eq!(bytes, vec![b't', 0xff, b'1']);
+            }
+            _ => panic!("expected an InvalidUtf8 error"),
+        }
+
+        let mut tbuf = Vec::new();
+        tbuf.extend_from_slice(&1u16.to_le_bytes());
+        tbuf.extend_from_slice(&[0xc3, 3]);
+        assert!(matches!(
+            TagDesc::from_bytes_checked(&mut Cursor::new(&tbuf)),
+            Err(RadError::InvalidUtf8 { .. })
+        ));
+    }
+
+    #[test]
+    fn test_int_read_from_round_trip() {
+        let mut buf = Vec::new();
+        let types = [RadIntId::U8, RadIntId::U16, RadIntId::U32, RadIntId::U64];
+        for t in &types {
+            t.write_to(200u64, &mut buf).unwrap();
+        }
+        let mut reader = Cursor::new(&buf);
+        assert_eq!(RadIntId::U8.read_from(&mut reader).unwrap(), 200);
+        assert_eq!(
+            RadIntId::U16.read_exact_width(&mut reader).unwrap(),
+            IntVal::U16(200)
+        );
+        let v = RadIntId::U32.read_exact_width(&mut reader).unwrap();
+        assert_eq!((v.int_type(), v.as_u64()), (RadIntId::U32, 200));
+        assert_eq!(RadIntId::U64.read_from(&mut reader).unwrap(), 200);
+        // nothing left to read
+        assert!(RadIntId::U8.read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_hist_with_alignments() {
+        let rec = |bc: u64, dirs: Vec<bool>| ReadRecord {
+            bc,
+            umi: 0,
+            refs: (0..dirs.len() as u32).collect(),
+            dirs,
+            mate_dirs: Vec::new(),
+            tags: None,
+        };
+        let chunk = Chunk {
+            nbytes: 0,
+            nrec: 3,
+            reads: vec![
+                rec(1, vec![true, true, false]),
+                rec(1, vec![false]),
+                rec(2, vec![true, false]),
+            ],
+        };
+
+        let mut plain = HashMap::new();
+        let mut ma = 0usize;
+        update_barcode_hist(&mut plain, &mut ma, &chunk, &Strand::Forward);
+
+        let mut hist = HashMap::new();
+        let mut aln_hist = HashMap::new();
+        let mut ma = 0usize;
+        update_barcode_hist_with_alignments(
+            &mut hist,
+            &mut aln_hist,
+            &mut ma,
+            &chunk,
+            &Strand::Forward,
+        );
+        assert_eq!(hist, plain);
+        assert_eq!(hist[&1], 1);
+        assert_eq!(aln_hist[&1], 2);
+        assert_eq!(aln_hist[&2], 1);
+
+        let mut hist = HashMap::new();
+        let mut aln_hist = HashMap::new();
+        update_barcode_hist_with_alignments(
+            &mut hist,
+            &mut aln_hist,
+            &mut ma,
+            &chunk,
+            &Strand::Unknown,
+        );
+        assert_eq!(hist[&1], 2);
+        assert_eq!(aln_hist[&1], 4);
+        assert_eq!(aln_hist[&2], 2);
+    }
+
+    #[test]
+    fn test_read_chunk_range() {
+        let mut data = Vec::new();
+        for bc in 0..4u32 {
+            let mut body = Vec::new();
+            push_record(&mut body, bc, 0, &[0]);
+            push_chunk(&mut data, 1, &body);
+        }
+        let mut reader = Cursor::new(&data[..]);
+        let index = chunk_offsets(&mut reader, 4).unwrap();
+        let (bct, umit) = (RadIntId::U32, RadIntId::U32);
+
+        let bcs: Vec<u64> = read_chunk_range(&mut reader, &index, 1, 4, false, &bct, &umit)
+            .map(|c| c.unwrap().reads[0].bc)
+            .collect();
+        assert_eq!(bcs, vec![1, 2, 3]);
+        assert_eq!(
+            read_chunk_range(&mut reader, &index, 2, 2, false, &bct, &umit).count(),
+            0
+        );
+
+        let mut it = read_chunk_range(&mut reader, &index, 3, 5, false, &bct, &umit);
+        assert_eq!(
+            it.next().unwrap().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert!(it.next().is_none());
+
+        // an index that doesn't line up with the file
+        let bad_index = vec![index[0], index[1] + 1];
+        let mut it = read_chunk_range(&mut reader, &bad_index, 0, 2, false, &bct, &umit);
+        assert_eq!(
+            it.next().unwrap().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_permit_list_from_file_checked() {
+        let dir = std::env::temp_dir().join(format!("permit_chk_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ft = FileTags {
+            bclen: 4,
+            umilen: 4,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let write = |name: &str, text: &str| {
+            let p = dir.join(name);
+            std::fs::write(&p, text).unwrap();
+            p
+        };
+
+        let good = write("good.txt", "ACGT\nTTTT\n");
+        assert_eq!(permit_list_from_file_checked(&good, &ft).unwrap().len(), 2);
+
+        let wrong_len = write("wrong.txt", "ACGTA\nTTTTA\n");
+        assert!(matches!(
+            permit_list_from_file_checked(&wrong_len, &ft),
+            Err(RadError::BarcodeLengthMismatch {
+                permit_len: 5,
+                file_len: 4
+            })
+        ));
+
+        let mixed = write("mixed.txt", "ACGT\nTTTT\nACGTA\n");
+        assert!(matches!(
+            permit_list_from_file_checked(&mixed, &ft),
+            Err(RadError::InconsistentBarcodeLength {
+                line: 3,
+                len: 5,
+                first: 4
+            })
+        ));
+
+        // the unchecked reader keeps the first bclen bases of each line
+        let padded = write("padded.txt", "ACGT \r\nTTTT\textra\n\n");
+        assert_eq!(
+            permit_list_from_file(padded.to_str().unwrap().to_string(), 4),
+            permit_list_from_file_checked(&good, &ft).unwrap()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collated_bucket_reader() {
+        let mut data = Vec::new();
+        let mut body = Vec::new();
+        push_record(&mut body, 5, 1, &[1, 2]);
+        push_record(&mut body, 5, 2, &[3]);
+        push_chunk(&mut data, 2, &body);
+        push_chunk(&mut data, 0, &[]);
+        let mut body = Vec::new();
+        push_record(&mut body, 6, 3, &[]);
+        push_chunk(&mut data, 1, &body);
+
+        let mut enc = snap::write::FrameEncoder::new(Vec::new());
+        enc.write_all(&data).unwrap();
+        let compressed = enc.into_inner().unwrap();
+
+        let expected: Vec<(u64, u64, Vec<u32>)> =
+            vec![(5, 1, vec![1, 2]), (5, 2, vec![3]), (6, 3, vec![])];
+        for (bytes, codec) in &[
+            (&data, CompressionCodec::None),
+            (&compressed, CompressionCodec::Snappy),
+        ] {
+            let r = open_collated_bucket(&bytes[..], *codec).unwrap();
+            let recs: Vec<(u64, u64, Vec<u32>)> =
+                CollatedBucketReader::new(r, &RadIntId::U32, &RadIntId::U32)
+                    .map(|r| r.unwrap())
+                    .collect();
+            assert_eq!(recs, expected);
+        }
+
+        // a stream cut off partway through a record
+        let r = open_collated_bucket(&data[..data.len() - 2], CompressionCodec::None).unwrap();
+        let mut it = CollatedBucketReader::new(r, &RadIntId::U32, &RadIntId::U32);
+        assert!(it.by_ref().take(2).all(|r| r.is_ok()));
+        assert!(it.next().unwrap().is_err());
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_max_ambiguity_tag() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 4,
+            ref_names: (0..4).map(|i| format!("t{}", i)).collect(),
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let write = |max_ambiguity: Option<u32>| {
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            let ft = FileTags {
+                bclen: 4,
+                umilen: 4,
+                max_ambiguity,
+                variable_umi: false,
+                ..Default::default()
+            };
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            rw.begin_chunk().unwrap();
+            for na in &[1usize, 3, 2] {
+                let rec = ReadRecord {
+                    bc: 1,
+                    umi: 1,
+                    dirs: vec![true; *na],
+                    mate_dirs: Vec::new(),
+                    refs: (0..*na as u32).collect(),
+                    tags: None,
+                };
+                rw.push_record(&rec).unwrap();
+            }
+            rw.finish_chunk().unwrap();
+            rw.finalize_with_chunk_count().unwrap().into_inner()
+        };
+
+        for (declared, expected) in &[(Some(0), Some(3)), (None, None)] {
+            let buf = write(*declared);
+            let mut reader = Cursor::new(&buf[..]);
+            let rhdr = RadHeader::from_bytes(&mut reader);
+            let fl = TagSection::from_bytes(&mut reader);
+            let _rl = TagSection::from_bytes(&mut reader);
+            let _al = TagSection::from_bytes(&mut reader);
+            let ft = FileTags::from_bytes_with_section(&mut reader, &fl).unwrap();
+            assert_eq!((ft.bclen, ft.umilen), (4, 4));
+            assert_eq!(ft.max_ambiguity, *expected);
+            assert_eq!(fl.get(MAX_AMBIGUITY_TAG).is_some(), expected.is_some());
+
+            let pos = reader.position();
+            let m = max_ambiguity_or_scan(&ft, &mut reader, &rhdr, &RadIntId::U32, &RadIntId::U32)
+                .unwrap();
+            assert_eq!(m, 3);
+            assert_eq!(reader.position(), pos);
+
+            // a scan of a truncated file is an error rather than a panic
+            if expected.is_none() {
+                let mut reader = Cursor::new(&buf[..buf.len() - 2]);
+                reader.set_position(pos);
+                assert!(
+                    compute_max_ambiguity(&mut reader, &rhdr, &RadIntId::U32, &RadIntId::U32)
+                        .is_err()
+                );
+            }
+        }
+
+        // declaring too small a value is an error without the chance to patch it
+        let mut rw = RadWriter::new(Vec::new());
+        let ft = FileTags {
+            bclen: 4,
+            umilen: 4,
+            max_ambiguity: Some(1),
+            variable_umi: false,
+            ..Default::default()
+        };
+        let one_chunk = RadHeader {
+            num_chunks: 1,
+            ..hdr
+        };
+        rw.write_header(&one_chunk, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        rw.begin_chunk().unwrap();
+        rw.push_record(&ReadRecord {
+            bc: 1,
+            umi: 1,
+            dirs: vec![],
+            mate_dirs: Vec::new(),
+            refs: vec![0, 1],
+            tags: None,
+        })
+        .unwrap();
+        rw.finish_chunk().unwrap();
+        assert!(matches!(
+            rw.finalize(),
+            Err(RadError::InvalidWriterState(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_bytes_split_ori() {
+        let fw = utils::MASK_LOWER_31_U32;
+        let mut buf = Vec::<u8>::new();
+        push_record(&mut buf, 7, 9, &[5 | fw, 2, 3 | fw, 8, 1 | fw]);
+        push_record(&mut buf, 1, 1, &[4]);
+
+        let mut reader = Cursor::new(&buf);
+        let rec =
+            ReadRecord::from_bytes_split_ori(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+        assert_eq!((rec.bc, rec.umi), (7, 9));
+        assert_eq!(rec.fw_refs, vec![1, 3, 5]);
+        assert_eq!(rec.rc_refs, vec![2, 8]);
+
+        // each orientation agrees with the single-orientation parser
+        let mut reader = Cursor::new(&buf);
+        let only_fw = ReadRecord::from_bytes_keep_ori(
+            &mut reader,
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &Strand::Forward,
+        );
+        assert_eq!(only_fw.refs, rec.fw_refs);
+
+        let mut reader = Cursor::new(&buf[buf.len() - 16..]);
+        let rec =
+            ReadRecord::from_bytes_split_ori(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+        assert!(rec.fw_refs.is_empty());
+        assert_eq!(rec.rc_refs, vec![4]);
+
+        let mut reader = Cursor::new(&buf[..buf.len() - 2]);
+        assert!(
+            ReadRecord::from_bytes_split_ori(&mut reader, &RadIntId::U32, &RadIntId::U32).is_ok()
+        );
+        let e = ReadRecord::from_bytes_split_ori(&mut reader, &RadIntId::U32, &RadIntId::U32)
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_keep_ori_parsers_keep_dirs() {
+        let fw = utils::MASK_LOWER_31_U32;
+        let mut buf = Vec::<u8>::new();
+        push_record(&mut buf, 7, 9, &[5 | fw, 2, 3 | fw, 8]);
+        let (bct, umit) = (RadIntId::U32, RadIntId::U32);
+
+        // the orientation of each alignment follows it through the sort
+        let rec =
+            ReadRecord::from_bytes_keep_ori(&mut Cursor::new(&buf), &bct, &umit, &Strand::Unknown);
+        assert_eq!(rec.refs, vec![2, 3, 5, 8]);
+        assert_eq!(rec.dirs, vec![false, true, true, false]);
+
+        let rec =
+            ReadRecord::from_bytes_keep_ori(&mut Cursor::new(&buf), &bct, &umit, &Strand::Reverse);
+        assert_eq!(rec.refs, vec![2, 8]);
+        assert_eq!(rec.dirs, vec![false, false]);
+
+        let mut reader = Cursor::new(&buf);
+        let (bc, umi, na) = ReadRecord::from_bytes_record_header(&mut reader, &bct, &umit);
+        let rec =
+            ReadRecord::from_bytes_with_header_keep_ori(&mut reader, bc, umi, na, &Strand::Forward);
+        assert_eq!((rec.bc, rec.umi), (7, 9));
+        assert_eq!(rec.refs, vec![3, 5]);
+        assert_eq!(rec.dirs, vec![true, true]);
+    }
+
+    #[test]
+    fn test_read_record_alignments() {
+        let rec: ReadRecord = ReadRecord {
+            bc: 1,
+            umi: 2,
+            dirs: vec![true, false],
+            mate_dirs: Vec::new(),
+            refs: vec![4, 6],
+            tags: None,
+        };
+        let alns: Vec<(u32, Strand)> = rec.alignments().collect();
+        assert_eq!(alns, vec![(4, Strand::Forward), (6, Strand::Reverse)]);
+
+        // records parsed without orientations report every strand as unknown
+        let rec: ReadRecord = ReadRecord {
+            bc: 1,
+            umi: 2,
+            dirs: vec![],
+            mate_dirs: Vec::new(),
+            refs: vec![4, 6],
+            tags: None,
+        };
+        // (`Strand::Unknown` never compares equal, so match on it instead)
+        let alns: Vec<(u32, Strand)> = rec.alignments().collect();
+        assert_eq!(alns.len(), 2);
+        assert!(matches!(alns[0], (4, Strand::Unknown)));
+        assert!(matches!(alns[1], (6, Strand::Unknown)));
+        assert_eq!(ReadRecord::<u64>::default().alignments().count(), 0);
+    }
+
+    #[test]
+    fn test_collate_output_is_ordered_by_barcode() {
+        let mut bucket = Vec::new();
+        for i in 0..40u32 {
+            push_record(&mut bucket, (i * 11) % 13, i, &[i % 4]);
+        }
+
+        let collate_with_seed = |seed: u64| {
+            let out = Mutex::new(Vec::<u8>::new());
+            let mut cmap = HashMap::<u64, _, _>::with_hasher(ahash::RandomState::with_seeds(
+                seed, 7u64, 1u64, 8u64,
+            ));
+            collate_temporary_bucket_twopass(
+                &mut BufReader::new(Cursor::new(bucket.clone())),
+                &RadIntId::U32,
+                &RadIntId::U32,
+                40,
+                &out,
+                false,
+                &mut cmap,
+                None,
+                &AlnLayout::default(),
+                None,
+            );
+            out.into_inner().unwrap()
+        };
+        let out = collate_with_seed(2);
+        for seed in 3..6 {
+            assert_eq!(collate_with_seed(seed), out);
+        }
+
+        let mut reader = Cursor::new(&out);
+        let mut barcodes = Vec::new();
+        while (reader.position() as usize) < out.len() {
+            let c: Chunk = Chunk::from_bytes(&mut reader, &RadIntId::U32, &RadIntId::U32);
+            barcodes.push(c.reads[0].bc);
+        }
+        assert_eq!(barcodes, (0..13).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_hist_unfiltered_capped() {
+        let chunk = Chunk {
+            nbytes: 0,
+            nrec: 10,
+            reads: [1u64, 5, 6, 1, 7, 5, 8, 6, 9, 2]
+                .iter()
+                .map(|&bc| ReadRecord {
+                    bc,
+                    umi: 0,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                })
+                .collect(),
+        };
+        let known = || -> HashMap<u64, u64> { [(1, 0), (2, 0)].iter().copied().collect() };
+
+        // without reaching the cap, the counts agree with the uncapped version
+        let mut hist = known();
+        let mut unmatched_bc = Vec::new();
+        let mut max_amb = 0;
+        let expected = update_barcode_hist_unfiltered(
+            &mut hist,
+            &mut unmatched_bc,
+            &mut max_amb,
+            &chunk,
+            &Strand::Unknown,
+        );
+
+        let mut capped_hist = known();
+        let mut unmatched: HashMap<u64, u64> = HashMap::new();
+        let stats = update_barcode_hist_unfiltered_capped(
+            &mut capped_hist,
+            &mut unmatched,
+            100,
+            &mut max_amb,
+            &chunk,
+            &Strand::Unknown,
+        );
+        assert_eq!(stats, expected);
+        assert_eq!(capped_hist, hist);
+        assert_eq!(unmatched.values().sum::<u64>() as usize, unmatched_bc.len());
+
+        // with a cap of 2, only the first two distinct unmatched barcodes are kept
+        let mut capped_hist = known();
+        let mut unmatched: HashMap<u64, u64> = HashMap::new();
+        let stats = update_barcode_hist_unfiltered_capped(
+            &mut capped_hist,
+            &mut unmatched,
+            2,
+            &mut max_amb,
+            &chunk,
+            &Strand::Unknown,
+        );
+        assert_eq!(capped_hist, hist);
+        let expected_unmatched: HashMap<u64, u64> = [(5, 2), (6, 2)].iter().copied().collect();
+        assert_eq!(unmatched, expected_unmatched);
+        assert_eq!(stats.num_unmatched_dropped, 3);
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["a".to_string(), "b".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let write = |checksum: bool| {
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            if checksum {
+                rw.enable_checksum().unwrap();
+            }
+            let ft = FileTags {
+                bclen: 4,
+                umilen: 4,
+                max_ambiguity: Some(0),
+                variable_umi: false,
+                ..Default::default()
+            };
+            rw.write_header(&hdr, &ft, RadIntId::U8, RadIntId::U8)
+                .unwrap();
+            for i in 0..5u64 {
+                rw.begin_chunk().unwrap();
+                rw.push_record(&ReadRecord {
+                    bc: i,
+                    umi: i + 1,
+                    dirs: vec![true, false],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0, 1],
+                    tags: None,
+                })
+                .unwrap();
+                rw.finish_chunk().unwrap();
+            }
+            rw.finalize_with_chunk_count().unwrap().into_inner()
+        };
+        let verify = |buf: &[u8]| {
+            let mut reader = Cursor::new(buf);
+            let rhdr = RadHeader::from_bytes(&mut reader);
+            let pos = reader.position();
+            let r = verify_checksum(&mut reader, &rhdr);
+            assert_eq!(reader.position(), pos);
+            r
+        };
+
+        let mut buf = write(true);
+        assert!(verify(&buf).unwrap());
+        // the other file-level tags are still readable
+        let mut reader = Cursor::new(&buf);
+        let _ = RadHeader::from_bytes(&mut reader);
+        let fl = TagSection::from_bytes(&mut reader);
+        let _ = TagSection::from_bytes(&mut reader);
+        let _ = TagSection::from_bytes(&mut reader);
+        let ft = FileTags::from_bytes_with_section(&mut reader, &fl).unwrap();
+        assert_eq!(ft.max_ambiguity, Some(2));
+
+        // flip a bit in the last record
+        let last = buf.len() - 1;
+        buf[last] ^= 0x1;
+        assert!(!verify(&buf).unwrap());
+
+        // a file without a checksum is reported as such
+        match verify(&write(false)) {
+            Err(RadError::MissingTag(t)) => assert_eq!(t, CHECKSUM_TAG),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // a checksum can't be written without the chance to seek back
+        let mut rw = RadWriter::new(Vec::new());
+        rw.enable_checksum().unwrap();
+        rw.write_header(
+            &hdr,
+            &FileTags {
+                bclen: 4,
+                umilen: 4,
+                max_ambiguity: None,
+                variable_umi: false,
+                ..Default::default()
+            },
+            RadIntId::U8,
+            RadIntId::U8,
+        )
+        .unwrap();
+        assert!(matches!(
+            rw.finalize(),
+            Err(RadError::InvalidWriterState(_))
+        ));
+    }
 
-//pub fn dump_output_cache(
-//    mut owriter: &mut BufWriter<File>,
-//    output_cache: &DashMap<u64, CorrectedCBChunk>,
-//    chunk_config: &ChunkConfig,
-//) {
-//    // NOTE: since the chunks are independent, this part could be multithreaded
-//    let bc_type = decode_int_type_tag(chunk_config.bc_type).expect("unknown barcode type id.");
-//    let umi_type = decode_int_type_tag(chunk_config.umi_type).expect("unknown barcode type id.");
-//
-//    for entry_ref in output_cache.iter() {
-//        let _bc = entry_ref.key();
-//        let chunk = entry_ref.value();
-//        // number of bytes
-//        let mut nbytes: u32 = 0;
-//        let bytes_for_u32 = std::mem::size_of::<u32>();
-//
-//        let bytes_for_bc = bc_type.bytes_for_type();
-//        let bytes_for_umi = umi_type.bytes_for_type();
-//
-//        // new
-//        /*nbytes += chunk.data.get_ref().len() as u32;
-//        owriter
-//            .write_all(&nbytes.to_le_bytes())
-//            .expect("couldn't write output.");
-//        let nrec = chunk.nrec;
-//        owriter
-//            .write_all(&nrec.to_le_bytes())
-//            .expect("couldn't write output.");
-//        */
-//        owriter
-//            .write_all(&chunk.data.get_ref())
-//            .expect("couldn't write output.");
-//        // end new
-//        /*
-//        // for reference IDs in this chunk
-//        nbytes += (chunk.ref_ids.len() * bytes_for_u32) as u32;
-//        // umis
-//        nbytes += (chunk.umis.len() * bytes_for_umi) as u32;
-//        // barcodes
-//        nbytes += (chunk.umis.len() * bytes_for_bc) as u32;
-//        // num alignment fields
-//        nbytes += (chunk.umis.len() * bytes_for_u32) as u32;
-//
-//        let nrec = chunk.umis.len() as u32;
-//
-//        owriter
-//            .write_all(&nbytes.to_le_bytes())
-//            .expect("couldn't write output.");
-//        owriter
-//            .write_all(&nrec.to_le_bytes())
-//            .expect("couldn't write output.");
-//
-//        for i in 0..chunk.umis.len() {
-//            let s = chunk.ref_offsets[i];
-//            let e = if i == chunk.umis.len() - 1 {
-//                chunk.ref_ids.len() as u32
-//            } else {
-//                chunk.ref_offsets[i + 1]
-//            };
-//
-//            // num alignments
-//            let num_aln = (e - s) as u32;
-//            owriter
-//                .write_all(&num_aln.to_le_bytes())
-//                .expect("couldn't write output.");
-//
-//            bc_type
-//                .write_to(*_bc, &mut owriter)
-//                .expect("couldn't write output.");
-//            umi_type
-//                .write_to(chunk.umis[i], &mut owriter)
-//                .expect("couldn't write output.");
-//            owriter
-//                .write_all(as_u8_slice(&chunk.ref_ids[(s as usize)..(e as usize)]))
-//                .expect("couldn't write output.");
-//        }
-//        */
-//    }
-//}
+    #[test]
+    fn test_get_knee_result() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        // 200 cells with many reads, followed by a long tail of
+        // barcodes with few
+        let mut freq: Vec<u64> = (0..200u64).map(|i| 5000 - 5 * i).collect();
+        freq.extend((0..5000u64).map(|i| 20 - (i % 20)));
+        freq.sort_unstable();
+        freq.reverse();
+
+        let k = cellfilter::get_knee(&freq, 100, &log);
+        assert_eq!(k.threshold, freq[k.rank]);
+        assert_eq!(
+            k.num_retained,
+            freq.iter().filter(|&&f| f >= k.threshold).count()
+        );
+        // the knee is at the last of the 200 cells
+        assert_eq!((k.rank, k.threshold, k.num_retained), (199, 4005, 200));
+    }
 
-impl Chunk {
-    pub fn read_header<T: Read>(reader: &mut T) -> (u32, u32) {
-        let mut buf = [0u8; 8];
+    #[test]
+    fn test_permit_list_top_n() {
+        let hist: HashMap<u64, u64> = [(10, 5), (11, 9), (12, 5), (13, 1), (14, 5), (15, 7)]
+            .iter()
+            .copied()
+            .collect();
+
+        // the tie at 5 reads is broken in favor of the smaller barcode
+        assert_eq!(permit_list_top_n(&hist, 3), (vec![11, 15, 10], 5));
+        assert_eq!(permit_list_top_n(&hist, 4), (vec![11, 15, 10, 12], 5));
+        assert_eq!(permit_list_top_n(&hist, 2), (vec![11, 15], 7));
+        assert_eq!(
+            permit_list_top_n(&hist, 100),
+            (vec![11, 15, 10, 12, 14, 13], 1)
+        );
+        assert_eq!(permit_list_top_n(&hist, 0), (vec![], 0));
+    }
 
-        reader.read_exact(&mut buf).unwrap();
-        let nbytes = buf.pread::<u32>(0).unwrap();
-        let nrec = buf.pread::<u32>(4).unwrap();
-        (nbytes, nrec)
+    #[test]
+    fn test_alignment_count_histogram() {
+        let rec = |refs: &[u32], dirs: &[bool]| ReadRecord {
+            bc: 0,
+            umi: 0,
+            refs: refs.to_vec(),
+            dirs: dirs.to_vec(),
+            mate_dirs: Vec::new(),
+            tags: None,
+        };
+        let chunk = Chunk {
+            nbytes: 0,
+            nrec: 4,
+            reads: vec![
+                rec(&[0], &[true]),
+                rec(&[0, 1, 2], &[true, false, true]),
+                rec(&[1], &[false]),
+                rec(&[0, 1], &[true, true]),
+            ],
+        };
+
+        let mut out = Vec::new();
+        alignment_count_histogram(&chunk, &mut out, &Strand::Unknown);
+        assert_eq!(out, vec![0, 2, 1, 1]);
+
+        let mut out = Vec::new();
+        alignment_count_histogram(&chunk, &mut out, &Strand::Forward);
+        assert_eq!(out, vec![0, 1, 2]);
+        // accumulates across calls
+        alignment_count_histogram(&chunk, &mut out, &Strand::Reverse);
+        assert_eq!(out, vec![0, 3, 2]);
     }
 
-    pub fn from_bytes<T: Read>(reader: &mut T, bct: &RadIntId, umit: &RadIntId) -> Self {
-        let mut buf = [0u8; 8];
+    #[test]
+    fn test_peek_record_header() {
+        let mut buf = Vec::new();
+        push_record(&mut buf, 17, 3, &[1, 2, 5]);
+        push_record(&mut buf, 18, 4, &[0]);
+        assert_eq!(
+            Chunk::peek_record_header(&buf, &RadIntId::U32, &RadIntId::U32).unwrap(),
+            (17, 3, 3)
+        );
+        assert_eq!(
+            Chunk::peek_record(&buf, &RadIntId::U32, &RadIntId::U32).unwrap(),
+            (17, 3)
+        );
 
-        reader.read_exact(&mut buf).unwrap();
-        let nbytes = buf.pread::<u32>(0).unwrap();
-        let nrec = buf.pread::<u32>(4).unwrap();
-        let mut c = Self {
-            nbytes,
-            nrec,
-            reads: Vec::with_capacity(nrec as usize),
+        // a u128 barcode is read if it fits in a u64, and is an error otherwise
+        for (bc, fits) in &[(17u128, true), (1u128 << 70, false)] {
+            let mut buf = 1u32.to_le_bytes().to_vec();
+            buf.extend_from_slice(&bc.to_le_bytes());
+            buf.extend_from_slice(&3u32.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            let res = Chunk::peek_record_header(&buf, &RadIntId::U128, &RadIntId::U32);
+            if *fits {
+                assert_eq!(res.unwrap(), (17, 3, 1));
+            } else {
+                assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_chunk_count() {
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 1]);
+        let mut chunks = Vec::new();
+        for _ in 0..3 {
+            push_chunk(&mut chunks, 1, &body);
+        }
+        let mut hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["a".to_string(), "b".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 3,
         };
 
-        for _ in 0..(nrec as usize) {
-            c.reads.push(ReadRecord::from_bytes(reader, &bct, &umit));
+        let mut reader = Cursor::new(&chunks);
+        let n =
+            validate_chunk_count(&mut reader, &hdr, false, &RadIntId::U32, &RadIntId::U32).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(reader.position(), 0);
+
+        hdr.num_chunks = 5;
+        match validate_chunk_count(&mut reader, &hdr, false, &RadIntId::U32, &RadIntId::U32) {
+            Err(RadError::ChunkCountMismatch { expected, observed }) => {
+                assert_eq!((expected, observed), (5, 3))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // a truncated final chunk
+        hdr.num_chunks = 3;
+        let mut reader = Cursor::new(&chunks[..chunks.len() - 2]);
+        assert!(matches!(
+            validate_chunk_count(&mut reader, &hdr, false, &RadIntId::U32, &RadIntId::U32),
+            Err(RadError::InvalidChunkSize(_))
+        ));
+    }
+
+    #[test]
+    fn test_tag_section_write_to_roundtrip() {
+        let ts = TagSection {
+            tags: vec![
+                TagDesc {
+                    name: "b".to_string(),
+                    typeid: RadIntId::U32.type_tag(),
+                },
+                TagDesc {
+                    name: "compressed_ori_refid".to_string(),
+                    typeid: RadIntId::U64.type_tag(),
+                },
+            ],
+        };
+        let mut buf = Vec::new();
+        ts.write_to(&mut buf).unwrap();
+        let back = TagSection::from_bytes_checked(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(back.tags.len(), 2);
+        for (a, b) in ts.tags.iter().zip(back.tags.iter()) {
+            assert_eq!((&a.name, a.typeid), (&b.name, b.typeid));
+        }
+    }
+
+    #[test]
+    fn test_write_str_bin() {
+        let mut buf = Vec::new();
+        write_str_bin("t0", &RadIntId::U16, &mut buf).unwrap();
+        assert_eq!(buf, vec![2, 0, b't', b'0']);
+
+        // the longest string whose length fits the prefix is written whole
+        let long = "a".repeat(u8::MAX as usize);
+        let mut buf = Vec::new();
+        write_str_bin(&long, &RadIntId::U8, &mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + long.len());
+        assert_eq!(buf[0], u8::MAX);
+
+        // one byte more and nothing is written
+        let too_long = "a".repeat(u8::MAX as usize + 1);
+        let mut buf = Vec::new();
+        let err = write_str_bin(&too_long, &RadIntId::U8, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+        write_str_bin(&too_long, &RadIntId::U16, &mut buf).unwrap();
+        assert_eq!(&buf[..2], &(too_long.len() as u16).to_le_bytes());
+    }
+
+    #[test]
+    fn test_rad_reader_iterates_chunks() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["a".to_string(), "b".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 4,
+        };
+        let file_tags = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &file_tags, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for i in 0..4u64 {
+            rw.begin_chunk().unwrap();
+            for j in 0..=i {
+                let rec = ReadRecord {
+                    bc: i,
+                    umi: j,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![(j % 2) as u32],
+                    tags: None,
+                };
+                rw.push_record(&rec).unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let bytes = rw.finalize().unwrap();
+
+        let reader = RadReader::new(Cursor::new(&bytes)).unwrap();
+        assert_eq!(reader.header.num_chunks, 4);
+        assert_eq!(
+            (reader.file_tag_vals.bclen, reader.file_tag_vals.umilen),
+            (16, 12)
+        );
+        let chunks: Vec<Chunk> = reader.map(|c| c.unwrap()).collect();
+        assert_eq!(chunks.len(), 4);
+        for (i, c) in chunks.iter().enumerate() {
+            assert_eq!(c.reads.len(), i + 1);
+            assert!(c.reads.iter().all(|r| r.bc == i as u64));
+        }
+
+        // a truncated file yields an error, and then stops
+        let mut reader = RadReader::new(Cursor::new(&bytes[..bytes.len() - 1])).unwrap();
+        for _ in 0..3 {
+            assert!(reader.next().unwrap().is_ok());
+        }
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_chunk_records_matches_from_bytes() {
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 3]);
+        push_record(&mut body, 1, 5, &[]);
+        push_record(&mut body, 4, 6, &[7]);
+        let mut buf = Vec::new();
+        push_chunk(&mut buf, 3, &body);
+        push_chunk(&mut buf, 1, &body[..12 + 8]);
+
+        let mut reader = Cursor::new(&buf);
+        let expected = Chunk::from_bytes(&mut reader, &RadIntId::U32, &RadIntId::U32);
+
+        let mut reader = Cursor::new(&buf);
+        let recs = ChunkRecords::new(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+        assert_eq!((recs.nbytes, recs.nrec), (expected.nbytes, expected.nrec));
+        assert_eq!(recs.size_hint(), (1, Some(3)));
+        let got: Vec<ReadRecord> = recs.collect::<Result<_, _>>().unwrap();
+        assert_eq!(got.len(), expected.reads.len());
+        for (a, b) in got.iter().zip(expected.reads.iter()) {
+            assert_eq!(
+                (a.bc, a.umi, &a.refs, &a.dirs),
+                (b.bc, b.umi, &b.refs, &b.dirs)
+            );
+        }
+
+        // the reader is left at the start of the next chunk
+        let mut next = ChunkRecords::new(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+        assert_eq!(next.nrec, 1);
+        assert_eq!(next.next().unwrap().unwrap().refs, vec![0, 3]);
+        assert!(next.next().is_none());
+        assert!(ChunkRecords::new(&mut reader, &RadIntId::U32, &RadIntId::U32).is_err());
+
+        // a truncated record is an error, and ends the iteration
+        let mut reader = Cursor::new(&buf[..buf.len() - 2]);
+        let mut recs = ChunkRecords::new(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+        for _ in 0..3 {
+            assert!(recs.next().unwrap().is_ok());
+        }
+        let mut last = ChunkRecords::new(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+        assert!(last.next().unwrap().is_err());
+        assert!(last.next().is_none());
+    }
+
+    #[test]
+    fn test_truncated_chunk_is_an_error() {
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 3]);
+        push_record(&mut body, 4, 6, &[7]);
+        let mut buf = Vec::new();
+        push_chunk(&mut buf, 2, &body);
+
+        let mut reader = Cursor::new(&buf[..buf.len() - 2]);
+        match Chunk::from_bytes_checked(&mut reader, &RadIntId::U32, &RadIntId::U32) {
+            Err(RadError::TruncatedChunk { nrec, read }) => assert_eq!((nrec, read), (2, 1)),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut reader = Cursor::new(&body[..10]);
+        let e = ReadRecord::<u64>::from_bytes_checked(&mut reader, &RadIntId::U32, &RadIntId::U32);
+        assert_eq!(e.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_header_write_to_roundtrip() {
+        let hdr = RadHeader {
+            is_paired: 1,
+            ref_count: 3,
+            ref_names: vec!["t1".to_string(), "tx2".to_string(), "gene_3".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 42,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 10,
+            max_ambiguity: Some(7),
+            variable_umi: false,
+            extra: vec![
+                (
+                    "sample".to_string(),
+                    tags::TagValue::String("s1".to_string()),
+                ),
+                ("frac".to_string(), tags::TagValue::F64(0.25)),
+            ],
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        hdr.write_to(&mut buf).unwrap();
+        let ts = ft.tag_section();
+        ts.write_to(&mut buf).unwrap();
+        ft.write_to(&mut buf).unwrap();
+
+        let mut reader = Cursor::new(&buf);
+        let back = RadHeader::from_bytes_checked(&mut reader).unwrap();
+        assert_eq!(back.is_paired, hdr.is_paired);
+        assert_eq!(back.ref_count, hdr.ref_count);
+        assert_eq!(back.ref_names, hdr.ref_names);
+        assert_eq!(back.num_chunks, hdr.num_chunks);
+        let back_ts = TagSection::from_bytes_checked(&mut reader).unwrap();
+        let back_ft = FileTags::from_bytes_with_section(&mut reader, &back_ts).unwrap();
+        assert_eq!(
+            (back_ft.bclen, back_ft.umilen, back_ft.max_ambiguity),
+            (16, 10, Some(7))
+        );
+        assert_eq!(back_ft.extra, ft.extra);
+        assert_eq!(reader.position() as usize, buf.len());
+    }
+
+    #[test]
+    fn test_writer_string_file_tag() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["a".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: Some(3),
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.enable_checksum().unwrap();
+        rw.add_file_tag("sample", tags::TagValue::String("pbmc_1k".to_string()))
+            .unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        // the max_ambiguity written is that observed in the records
+        rw.begin_chunk().unwrap();
+        rw.push_record(&ReadRecord {
+            bc: 1,
+            umi: 2,
+            dirs: vec![true; 3],
+            mate_dirs: Vec::new(),
+            refs: vec![0; 3],
+            tags: None,
+        })
+        .unwrap();
+        rw.finish_chunk().unwrap();
+        let bytes = rw.finalize_with_chunk_count().unwrap().into_inner();
+
+        let mut reader = Cursor::new(&bytes);
+        let hdr = RadHeader::from_bytes(&mut reader);
+        let fl = TagSection::from_bytes(&mut reader);
+        assert_eq!(fl.get("sample").unwrap().typeid, 8);
+        TagSection::from_bytes(&mut reader);
+        TagSection::from_bytes(&mut reader);
+        let pos = reader.position();
+        let vals = tags::TagSchema::new(&fl)
+            .unwrap()
+            .read_values(&mut reader)
+            .unwrap();
+        assert_eq!(vals.get("sample").unwrap().as_str(), Some("pbmc_1k"));
+        // the values end where the first chunk begins
+        let c: Chunk = Chunk::from_bytes(&mut reader, &RadIntId::U32, &RadIntId::U32);
+        assert_eq!(c.reads[0].refs, vec![0; 3]);
+        assert_eq!(reader.position() as usize, bytes.len());
+
+        // the standard tags are still read correctly, and the checksum holds
+        reader.set_position(pos);
+        let ft = FileTags::from_bytes_with_section(&mut reader, &fl).unwrap();
+        assert_eq!((ft.bclen, ft.umilen, ft.max_ambiguity), (16, 12, Some(3)));
+        assert_eq!(
+            ft.extra,
+            vec![(
+                "sample".to_string(),
+                tags::TagValue::String("pbmc_1k".to_string())
+            )]
+        );
+        let mut reader = Cursor::new(&bytes);
+        RadHeader::from_bytes(&mut reader);
+        assert!(verify_checksum(&mut reader, &hdr).unwrap());
+    }
+
+    #[test]
+    fn test_version_header() {
+        let hdr = RadHeader {
+            is_paired: 1,
+            ref_count: 2,
+            ref_names: vec!["a".to_string(), "b".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: Some(2),
+            variable_umi: false,
+            ..Default::default()
+        };
+        let write = |versioned: bool| {
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            if versioned {
+                rw.enable_version_header().unwrap();
+            }
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            rw.begin_chunk().unwrap();
+            rw.push_record(&ReadRecord {
+                bc: 1,
+                umi: 2,
+                dirs: vec![true, false],
+                mate_dirs: Vec::new(),
+                refs: vec![0, 1],
+                tags: None,
+            })
+            .unwrap();
+            rw.finish_chunk().unwrap();
+            rw.finalize_with_chunk_count().unwrap().into_inner()
+        };
+
+        for (versioned, expected) in &[(false, RadVersion::Legacy), (true, RAD_VERSION)] {
+            let bytes = write(*versioned);
+            let mut reader = Cursor::new(&bytes);
+            let (h, v) = RadHeader::from_bytes_with_version(&mut reader).unwrap();
+            assert_eq!(v, *expected);
+            assert_eq!((h.is_paired, h.num_chunks), (1, 1));
+            assert_eq!(h.ref_names, hdr.ref_names);
+            let fl = TagSection::from_bytes(&mut reader);
+            TagSection::from_bytes(&mut reader);
+            TagSection::from_bytes(&mut reader);
+            let ft = FileTags::from_bytes_with_section(&mut reader, &fl).unwrap();
+            assert_eq!(ft.max_ambiguity, Some(2));
         }
 
-        c
+        let mut bytes = write(true);
+        bytes[2] = b'X';
+        assert!(matches!(
+            RadHeader::from_bytes_checked(&mut Cursor::new(&bytes)),
+            Err(RadError::InvalidMagic(_))
+        ));
+        let mut bytes = write(true);
+        bytes[4] = 2;
+        assert!(matches!(
+            RadHeader::from_bytes_checked(&mut Cursor::new(&bytes)),
+            Err(RadError::UnsupportedVersion(RadVersion::Versioned {
+                major: 2,
+                ..
+            }))
+        ));
     }
 
-    /// peeks to the first record in the buffer `buf`, and returns
-    /// the barcode and umi associated with this record.  It is assumed
-    /// that there is at least one record present in the buffer.
-    pub fn peek_record(buf: &[u8], bct: &RadIntId, umit: &RadIntId) -> (u64, u64) {
-        let na_size = mem::size_of::<u32>();
-        let bc_size = bct.bytes_for_type();
+    #[test]
+    fn test_variable_umi_file_tag() {
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 14,
+            max_ambiguity: None,
+            variable_umi: true,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        ft.tag_section().write_to(&mut buf).unwrap();
+        ft.write_to(&mut buf).unwrap();
+        let mut reader = Cursor::new(&buf);
+        let ts = TagSection::from_bytes(&mut reader);
+        let back = FileTags::from_bytes_with_section(&mut reader, &ts).unwrap();
+        assert!(back.variable_umi);
+        assert_eq!(back.umilen, 14);
+
+        // the length is kept in the top bits, so the umi type must be a u64
+        assert!(ft.validate(&RadIntId::U32, &RadIntId::U64).is_ok());
+        assert!(matches!(
+            ft.validate(&RadIntId::U32, &RadIntId::U32),
+            Err(RadError::TagWidthMismatch { .. })
+        ));
+    }
 
-        let _na = buf.pread::<u32>(0).unwrap();
-        let bc;
-        match bct {
-            RadIntId::U8 => {
-                bc = buf.pread::<u8>(na_size).unwrap() as u64;
-            }
-            RadIntId::U16 => {
-                bc = buf.pread::<u16>(na_size).unwrap() as u64;
-            }
-            RadIntId::U32 => {
-                bc = buf.pread::<u32>(na_size).unwrap() as u64;
+    #[test]
+    fn test_chunk_index_seek_to_cell() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["a".to_string(), "b".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let write = |indexed: bool| {
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            if indexed {
+                rw.enable_chunk_index().unwrap();
             }
-            RadIntId::U64 => {
-                bc = buf.pread::<u64>(na_size).unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            // one chunk per cell, as in a collated file, plus an empty chunk
+            for bc in &[3u64, 8, 20] {
+                rw.begin_chunk().unwrap();
+                for umi in 0..*bc {
+                    let rec = ReadRecord {
+                        bc: *bc,
+                        umi,
+                        dirs: vec![true],
+                        mate_dirs: Vec::new(),
+                        refs: vec![0],
+                        tags: None,
+                    };
+                    rw.push_record(&rec).unwrap();
+                }
+                rw.finish_chunk().unwrap();
+                if *bc == 8 {
+                    rw.begin_chunk().unwrap();
+                    rw.finish_chunk().unwrap();
+                }
             }
+            rw.finalize_with_chunk_count().unwrap().into_inner()
+        };
+        let plain = write(false);
+        let bytes = write(true);
+
+        let index = ChunkIndex::read_from(&mut Cursor::new(&bytes))
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.entries.len(), 4);
+        let mut reader = Cursor::new(&bytes);
+        RadHeader::from_bytes(&mut reader);
+        for _ in 0..3 {
+            TagSection::from_bytes(&mut reader);
         }
-        let umi;
-        match umit {
-            RadIntId::U8 => {
-                umi = buf.pread::<u8>(na_size + bc_size).unwrap() as u64;
-            }
-            RadIntId::U16 => {
-                umi = buf.pread::<u16>(na_size + bc_size).unwrap() as u64;
-            }
-            RadIntId::U32 => {
-                umi = buf.pread::<u32>(na_size + bc_size).unwrap() as u64;
-            }
-            RadIntId::U64 => {
-                umi = buf.pread::<u64>(na_size + bc_size).unwrap();
-            }
+        FileTags::from_bytes(&mut reader);
+        let offsets = chunk_offsets(&mut reader, 4).unwrap();
+        assert_eq!(
+            index.entries.iter().map(|e| e.offset).collect::<Vec<u64>>(),
+            offsets
+        );
+        assert_eq!(
+            (index.entries[1].first_bc, index.entries[1].last_bc),
+            (8, 8)
+        );
+        assert_eq!(index.find_cell(20), Some(3));
+        assert_eq!(index.find_cell(5), None);
+
+        // the chunks themselves are unchanged, and still validate
+        assert_eq!(&bytes[..plain.len()], &plain[..]);
+        let hdr = RadHeader::from_bytes(&mut Cursor::new(&bytes));
+        reader.set_position(offsets[0]);
+        validate_chunk_count(&mut reader, &hdr, false, &RadIntId::U32, &RadIntId::U32).unwrap();
+        let all: Vec<Chunk> = read_chunk_range(
+            &mut reader,
+            &offsets,
+            0,
+            4,
+            false,
+            &RadIntId::U32,
+            &RadIntId::U32,
+        )
+        .map(|c| c.unwrap())
+        .collect();
+        assert_eq!(all.len(), 4);
+
+        let mut rr = RadReader::new(Cursor::new(&bytes)).unwrap();
+        assert!(rr.seek_to_cell(8).unwrap());
+        let c = rr.next().unwrap().unwrap();
+        assert_eq!((c.nrec, c.reads[0].bc), (8, 8));
+        assert_eq!(rr.count(), 2);
+        let mut rr = RadReader::new(Cursor::new(&bytes)).unwrap();
+        assert!(!rr.seek_to_cell(9).unwrap());
+
+        // a streamed file, whose header has a `num_chunks` of 0, followed by
+        // the chunks and the index
+        let mut streamed = {
+            let mut rw = RadWriter::new(Vec::new());
+            rw.enable_chunk_index().unwrap();
+            let hdr = RadHeader {
+                num_chunks: 0,
+                ..hdr
+            };
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            rw.finalize().unwrap()
+        };
+        let header_len = offsets[0] as usize;
+        streamed.truncate(header_len);
+        streamed.extend_from_slice(&bytes[header_len..]);
+        assert_eq!(
+            RadHeader::from_bytes(&mut Cursor::new(&streamed)).num_chunks,
+            0
+        );
+        for from_stream in &[false, true] {
+            let mut rr = if *from_stream {
+                RadReader::from_stream(Cursor::new(&streamed)).unwrap()
+            } else {
+                RadReader::new(Cursor::new(&streamed)).unwrap()
+            };
+            assert!(rr.seek_to_cell(8).unwrap());
+            let nrecs: Vec<u32> = rr.map(|c| c.unwrap().nrec).collect();
+            assert_eq!(nrecs, vec![8, 0, 20]);
         }
-        (bc, umi)
-    }
-}
-
-impl FileTags {
-    pub fn from_bytes<T: Read>(reader: &mut T) -> Self {
-        let mut buf = [0u8; 4];
-        reader.read_exact(&mut buf).unwrap();
 
-        Self {
-            bclen: buf.pread::<u16>(0).unwrap(),
-            umilen: buf.pread::<u16>(2).unwrap(),
-        }
+        let mut rr = RadReader::new(Cursor::new(&plain)).unwrap();
+        assert!(ChunkIndex::read_from(&mut Cursor::new(&plain))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            rr.seek_to_cell(8),
+            Err(RadError::MissingChunkIndex)
+        ));
     }
-}
-
-impl TagDesc {
-    pub fn from_bytes<T: Read>(reader: &mut T) -> TagDesc {
-        // space for the string length (1 byte)
-        // the longest string possible (255 char)
-        // and the typeid
-        let mut buf = [0u8; 257];
-        reader.read_exact(&mut buf[0..2]).unwrap();
-        let str_len = buf.pread::<u16>(0).unwrap() as usize;
 
-        // read str_len + 1 to get the type id that follows the string
-        reader.read_exact(&mut buf[0..str_len + 1]).unwrap();
-        TagDesc {
-            name: std::str::from_utf8(&buf[0..str_len]).unwrap().to_string(),
-            typeid: buf.pread(str_len).unwrap(),
+    #[test]
+    fn test_rad_reader_validate() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["a".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 3,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.enable_chunk_index().unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bc in 0..3u64 {
+            rw.begin_chunk().unwrap();
+            let rec = ReadRecord {
+                bc,
+                umi: 7,
+                dirs: vec![true, false],
+                mate_dirs: Vec::new(),
+                refs: vec![0, 0],
+                tags: None,
+            };
+            rw.push_record(&rec).unwrap();
+            rw.finish_chunk().unwrap();
         }
+        let bytes = rw.finalize().unwrap();
+
+        let mut rr = RadReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(rr.validate().unwrap(), None);
+        // validation doesn't disturb iteration
+        assert_eq!(rr.count(), 3);
+
+        let index = ChunkIndex::read_from(&mut Cursor::new(&bytes))
+            .unwrap()
+            .unwrap();
+        let mut corrupt = bytes.clone();
+        corrupt[index.entries[1].offset as usize + 14] ^= 0x10;
+        let mut rr = RadReader::new(Cursor::new(corrupt)).unwrap();
+        assert_eq!(rr.validate().unwrap(), Some(index.entries[1].offset));
     }
-}
-
-impl TagSection {
-    pub fn from_bytes<T: Read>(reader: &mut T) -> TagSection {
-        let mut buf = [0u8; 2];
-        reader.read_exact(&mut buf).unwrap();
-        let num_tags = buf.pread::<u16>(0).unwrap() as usize;
 
-        let mut ts = TagSection {
-            tags: Vec::with_capacity(num_tags),
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_chunks_roundtrip() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["a".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 3,
         };
-
-        for _ in 0..num_tags {
-            ts.tags.push(TagDesc::from_bytes(reader));
-        }
-
-        ts
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let write = |level: Option<i32>| {
+            let mut rw = RadWriter::new(Vec::new());
+            if let Some(l) = level {
+                rw.enable_chunk_compression(l).unwrap();
+            }
+            rw.enable_chunk_index().unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            for bc in 0..3u64 {
+                rw.begin_chunk().unwrap();
+                for umi in 0..200u64 {
+                    let rec = ReadRecord {
+                        bc,
+                        umi,
+                        dirs: vec![true],
+                        mate_dirs: Vec::new(),
+                        refs: vec![0],
+                        tags: None,
+                    };
+                    rw.push_record(&rec).unwrap();
+                }
+                rw.finish_chunk().unwrap();
+            }
+            rw.finalize().unwrap()
+        };
+        let plain = write(None);
+        let packed = write(Some(3));
+        assert!(packed.len() < plain.len());
+
+        let read = |bytes: &[u8]| {
+            let mut rr = RadReader::new(Cursor::new(bytes.to_vec())).unwrap();
+            assert_eq!(rr.validate().unwrap(), None);
+            (
+                rr.file_tag_vals.zstd_chunks,
+                rr.flat_map(|c| c.unwrap().reads)
+                    .map(|r| (r.bc, r.umi, r.refs))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let (plain_z, plain_recs) = read(&plain);
+        let (packed_z, packed_recs) = read(&packed);
+        assert!(!plain_z && packed_z);
+        assert_eq!(plain_recs.len(), 600);
+        assert_eq!(plain_recs, packed_recs);
+
+        // consumers of the raw chunk bytes reject compressed chunks
+        let rr = RadReader::new(Cursor::new(packed.clone())).unwrap();
+        assert!(matches!(
+            rr.file_tag_vals.require_uncompressed("collate"),
+            Err(RadError::UnsupportedCompression(_))
+        ));
+        let rr = RadReader::new(Cursor::new(plain)).unwrap();
+        assert!(rr.file_tag_vals.require_uncompressed("collate").is_ok());
+
+        // the chunk index is usable without decompressing
+        let mut rr = RadReader::new(Cursor::new(packed)).unwrap();
+        assert!(rr.seek_to_cell(2).unwrap());
+        let c = rr.next().unwrap().unwrap();
+        assert!(c.reads.iter().all(|r| r.bc == 2));
     }
-}
 
-impl RadHeader {
-    pub fn from_bytes<T: Read>(reader: &mut T) -> RadHeader {
-        let mut rh = RadHeader {
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_chunk_readers_decompress_zstd_chunks() {
+        let hdr = RadHeader {
             is_paired: 0,
-            ref_count: 0,
-            ref_names: vec![],
-            num_chunks: 0,
+            ref_count: 2,
+            ref_names: vec!["a".to_string(), "b".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 4,
         };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.enable_chunk_compression(3).unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bc in 0..4u64 {
+            rw.begin_chunk().unwrap();
+            for umi in 0..100u64 {
+                rw.push_record(&ReadRecord {
+                    bc,
+                    umi,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![(umi % 2) as u32],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let bytes = rw.finalize().unwrap();
+        let rr = RadReader::new(Cursor::new(&bytes)).unwrap();
+        let chunk_start = rr.get_ref().position();
+        let expected: Vec<Vec<(u64, u64)>> = rr
+            .map(|c| c.unwrap().reads.iter().map(|r| (r.bc, r.umi)).collect())
+            .collect();
+        let (bct, umit) = (RadIntId::U32, RadIntId::U32);
+
+        // the compressed records are smaller than the plain ones could be
+        let mut reader = Cursor::new(&bytes);
+        reader.set_position(chunk_start);
+        assert!(matches!(
+            validate_chunk_count(&mut reader, &hdr, false, &bct, &umit),
+            Err(RadError::InvalidChunkSize(_))
+        ));
+        assert_eq!(
+            validate_chunk_count(&mut reader, &hdr, true, &bct, &umit).unwrap(),
+            4
+        );
 
-        // size of the longest allowable string.
-        let mut buf = [0u8; 65536];
-        reader.read_exact(&mut buf[0..9]).unwrap();
-        rh.is_paired = buf.pread(0).unwrap();
-        rh.ref_count = buf.pread::<u64>(1).unwrap();
+        let offsets = chunk_offsets(&mut reader, 4).unwrap();
+        let observed: Vec<Vec<(u64, u64)>> =
+            read_chunk_range(&mut reader, &offsets, 0, 4, true, &bct, &umit)
+                .map(|c| c.unwrap().reads.iter().map(|r| (r.bc, r.umi)).collect())
+                .collect();
+        assert_eq!(observed, expected);
+        assert_eq!(
+            peek_chunk_first_record(&mut reader, offsets[2], true, &bct, &umit).unwrap(),
+            (2, 0)
+        );
 
-        // we know how many names we will read in.
-        rh.ref_names.reserve_exact(rh.ref_count as usize);
+        let path = std::env::temp_dir().join(format!("zstd_par_{}.rad", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let observed =
+            for_each_chunk_parallel(&path, &hdr, chunk_start, true, &bct, &umit, 2, |c| {
+                c.reads.iter().map(|r| (r.bc, r.umi)).collect::<Vec<_>>()
+            })
+            .unwrap();
+        assert_eq!(observed, expected);
+        let observed: Vec<Vec<(u64, u64)>> =
+            parallel_decode::parallel_decode(&path, &hdr, CompressionCodec::None, 2)
+                .map(|c| c.unwrap().reads.iter().map(|r| (r.bc, r.umi)).collect())
+                .collect();
+        assert_eq!(observed, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
 
-        let mut num_read = 0u64;
-        while num_read < rh.ref_count {
-            reader.read_exact(&mut buf[0..2]).unwrap();
-            let l: usize = buf.pread::<u16>(0).unwrap() as usize;
-            reader.read_exact(&mut buf[0..l]).unwrap();
-            rh.ref_names
-                .push(std::str::from_utf8(&buf[0..l]).unwrap().to_string());
-            num_read += 1;
+    #[test]
+    fn test_chunk_from_slice_matches_from_bytes() {
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 3 | 0x8000_0000]);
+        push_record(&mut body, 1, 5, &[]);
+        push_record(&mut body, 4, 6, &[7]);
+        let mut buf = Vec::new();
+        push_chunk(&mut buf, 3, &body);
+
+        let a = Chunk::from_slice(&buf, &RadIntId::U32, &RadIntId::U32).unwrap();
+        let b = Chunk::from_bytes(&mut Cursor::new(&buf), &RadIntId::U32, &RadIntId::U32);
+        assert_eq!((a.nbytes, a.nrec), (b.nbytes, b.nrec));
+        let fields = |c: &Chunk| {
+            c.reads
+                .iter()
+                .map(|r| (r.bc, r.umi, r.dirs.clone(), r.refs.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fields(&a), fields(&b));
+        assert!(a.reads[0].dirs[1]);
+
+        // records that run past the end of the chunk, or no header at all
+        assert!(Chunk::from_slice(&buf[..buf.len() - 2], &RadIntId::U32, &RadIntId::U32).is_err());
+        assert!(Chunk::from_slice(&buf[..6], &RadIntId::U32, &RadIntId::U32).is_err());
+        let mut huge = buf.clone();
+        huge[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Chunk::from_slice(&huge, &RadIntId::U32, &RadIntId::U32).is_err());
+    }
+
+    #[test]
+    fn test_record_views_match_records() {
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[0, 3 | 0x8000_0000]);
+        push_record(&mut body, 1, 5, &[]);
+        push_record(&mut body, 4, 6, &[7 | 0x8000_0000]);
+        let mut bytes = Vec::new();
+        push_chunk(&mut bytes, 3, &body);
+        push_chunk(&mut bytes, 3, &body);
+
+        let mut reader = Cursor::new(&bytes);
+        let mut buf = Vec::new();
+        let bct = RadIntId::U32;
+        let mut hist = HashMap::new();
+        let mut max_amb = 0;
+        for _ in 0..2 {
+            Chunk::read_raw(&mut reader, &mut buf).unwrap();
+            let views = RecordViews::new(&buf, &bct, &bct).unwrap();
+            assert_eq!(views.nrec, 3);
+            let recs: Vec<_> = views.map(|r| r.unwrap().to_record()).collect();
+            assert_eq!(recs[0].refs, vec![0, 3]);
+            assert_eq!(recs[0].dirs, vec![false, true]);
+            assert!(recs[1].is_empty());
+            update_barcode_hist_views(
+                &mut hist,
+                &mut max_amb,
+                RecordViews::new(&buf, &bct, &bct).unwrap(),
+                &Strand::Forward,
+            )
+            .unwrap();
+        }
+        assert!(Chunk::read_raw(&mut reader, &mut buf).is_err());
+
+        let c = Chunk::from_bytes(&mut Cursor::new(&bytes), &bct, &bct);
+        let mut expected = HashMap::new();
+        let mut expected_max = 0;
+        update_barcode_hist(&mut expected, &mut expected_max, &c, &Strand::Forward);
+        update_barcode_hist(&mut expected, &mut expected_max, &c, &Strand::Forward);
+        assert_eq!(hist, expected);
+        assert_eq!(max_amb, expected_max);
+
+        // the second record claims more alignments than the chunk holds
+        let mut bad = bytes[..8 + body.len()].to_vec();
+        bad[8 + 20..8 + 24].copy_from_slice(&100u32.to_le_bytes());
+        let mut views = RecordViews::new(&bad, &bct, &bct).unwrap();
+        assert!(views.next().unwrap().is_ok());
+        match views.next() {
+            Some(Err(RadError::TruncatedChunk { nrec, read })) => assert_eq!((nrec, read), (3, 1)),
+            r => panic!("unexpected result {:?}", r.map(|r| r.map(|v| v.bc))),
+        }
+        assert!(views.next().is_none());
+        assert!(update_barcode_hist_views(
+            &mut hist,
+            &mut max_amb,
+            RecordViews::new(&bad, &bct, &bct).unwrap(),
+            &Strand::Forward,
+        )
+        .is_err());
+
+        // a u128 barcode too wide for the u64 of a view is an error, not a panic
+        let mut wide = Vec::new();
+        for bc in &[9u128, 1u128 << 70] {
+            wide.extend_from_slice(&0u32.to_le_bytes());
+            wide.extend_from_slice(&bc.to_le_bytes());
+            wide.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let mut buf = Vec::new();
+        push_chunk(&mut buf, 2, &wide);
+        let mut views = RecordViews::new(&buf, &RadIntId::U128, &bct).unwrap();
+        assert_eq!(views.next().unwrap().unwrap().bc, 9);
+        match views.next() {
+            Some(Err(RadError::Io(e))) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            r => panic!("unexpected result {:?}", r.map(|r| r.map(|v| v.bc))),
         }
+        assert!(views.next().is_none());
+    }
 
-        reader.read_exact(&mut buf[0..8]).unwrap();
-        rh.num_chunks = buf.pread::<u64>(0).unwrap();
-        rh
+    #[test]
+    fn test_read_with_header_keep_ori_into_reuses_record() {
+        let mut body = Vec::new();
+        push_record(&mut body, 1, 2, &[5 | 0x8000_0000, 3, 2 | 0x8000_0000]);
+        push_record(&mut body, 4, 6, &[7]);
+
+        let mut reader = Cursor::new(&body);
+        let mut rr = ReadRecord::default();
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            let (bc, umi, na) =
+                ReadRecord::from_bytes_record_header(&mut reader, &RadIntId::U32, &RadIntId::U32);
+            rr.read_with_header_keep_ori_into(&mut reader, bc, umi, na, &Strand::Forward);
+            seen.push((rr.bc, rr.umi, rr.refs.clone(), rr.dirs.clone()));
+        }
+        assert_eq!(
+            seen,
+            vec![(1, 2, vec![2, 5], vec![true, true]), (4, 6, vec![], vec![]),]
+        );
     }
-    pub fn from_bam_header(header: &HeaderView) -> RadHeader {
-        let mut rh = RadHeader {
+
+    #[test]
+    fn test_rad_reader_from_stream() {
+        let hdr = RadHeader {
             is_paired: 0,
-            ref_count: 0,
-            ref_names: vec![],
+            ref_count: 1,
+            ref_names: vec!["a".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
             num_chunks: 0,
         };
-
-        rh.ref_count = header.target_count() as u64;
-        // we know how many names we will read in.
-        rh.ref_names.reserve_exact(rh.ref_count as usize);
-        for (_i, t) in header
-            .target_names()
-            .iter()
-            .map(|a| std::str::from_utf8(a).unwrap())
-            .enumerate()
-        {
-            rh.ref_names.push(t.to_owned());
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        let mut bytes = rw.finalize().unwrap();
+        // chunks appended by a writer that never went back to fill in the count
+        for bc in 0..2 {
+            let mut body = Vec::new();
+            push_record(&mut body, bc, 1, &[0]);
+            push_record(&mut body, bc, 2, &[0]);
+            push_chunk(&mut bytes, 2, &body);
         }
-        rh
+
+        assert_eq!(RadReader::new(&bytes[..]).unwrap().count(), 0);
+        let chunks: Vec<Chunk> = RadReader::from_stream(&bytes[..])
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].reads.iter().all(|r| r.bc == 1));
+
+        // a chunk cut short is still an error
+        let mut rr = RadReader::from_stream(&bytes[..bytes.len() - 2]).unwrap();
+        assert!(rr.next().unwrap().is_ok());
+        assert!(rr.next().unwrap().is_err());
+        assert!(rr.next().is_none());
     }
-    pub fn get_size(&self) -> usize {
-        let mut tot_size = 0usize;
-        tot_size += std::mem::size_of::<u8>() + std::mem::size_of::<u64>();
-        for (_i, t) in self.ref_names.iter().map(|a| a.len()).enumerate() {
-            tot_size += t;
-        }
-        tot_size += std::mem::size_of::<u64>();
-        tot_size
+
+    #[test]
+    fn test_chunk_index_u128_barcodes() {
+        let ft = FileTags {
+            bclen: 40,
+            umilen: 12,
+            ..Default::default()
+        };
+        let bcs = [1u128 << 70, (1u128 << 70) + 5];
+        let write = |num_chunks: u64| {
+            let hdr = RadHeader {
+                is_paired: 0,
+                ref_count: 1,
+                ref_names: vec!["a".to_string()],
+                ref_lengths: vec![],
+                ref_digest: None,
+                num_chunks,
+            };
+            let mut rw = RadWriter::new(Vec::new());
+            rw.enable_chunk_index().unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U128, RadIntId::U32)
+                .unwrap();
+            for bc in bcs.iter().take(num_chunks as usize) {
+                rw.begin_chunk().unwrap();
+                rw.push_generic_record(&ReadRecord {
+                    bc: *bc,
+                    umi: 3,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                })
+                .unwrap();
+                rw.finish_chunk().unwrap();
+            }
+            rw.finalize().unwrap()
+        };
+        let bytes = write(2);
+        assert!(bytes.ends_with(&CHUNK_INDEX_MAGIC_WIDE));
+
+        let index = ChunkIndex::read_from(&mut Cursor::new(&bytes))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            index
+                .entries
+                .iter()
+                .map(|e| (e.first_bc, e.last_bc))
+                .collect::<Vec<_>>(),
+            vec![(bcs[0], bcs[0]), (bcs[1], bcs[1])]
+        );
+        let mut rr = RadReader::new(Cursor::new(&bytes)).unwrap();
+        assert!(rr.validate().unwrap().is_none());
+        assert!(rr.seek_to_cell(bcs[1]).unwrap());
+        assert!(!rr.seek_to_cell(bcs[1] + 1).unwrap());
+        let mut reader = rr.into_inner();
+        let c = Chunk::<u128>::from_bytes(&mut reader, &RadIntId::U128, &RadIntId::U32);
+        assert_eq!((c.reads[0].bc, c.reads[0].umi), (bcs[1], 3));
+
+        // a stream of these chunks ends at the wide index
+        let mut stream = write(0);
+        let header_len = stream.len() - 24;
+        stream.truncate(header_len);
+        stream.extend_from_slice(&bytes[header_len..]);
+        let mut br = &stream[..];
+        RadReader::new(&mut br).unwrap();
+        let mut sc = StreamChunks::default();
+        let mut buf = Vec::new();
+        while sc.read_raw(&mut br, &mut buf).unwrap() {}
+        assert_eq!(sc.chunks_read(), 2);
     }
-}
 
-pub fn update_barcode_hist_unfiltered(
-    hist: &mut HashMap<u64, u64, ahash::RandomState>,
-    unmatched_bc: &mut Vec<u64>,
-    max_ambiguity_read: &mut usize,
-    chunk: &Chunk,
-    expected_ori: &Strand,
-) -> usize {
-    let mut num_strand_compat_reads = 0usize;
-    match expected_ori {
-        Strand::Unknown => {
-            for r in &chunk.reads {
-                num_strand_compat_reads += 1;
-                *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                // lookup the barcode in the map of unfiltered known
-                // barcodes
-                match hist.get_mut(&r.bc) {
-                    // if we find a match, increment the count
-                    Some(c) => *c += 1,
-                    // otherwise, push this into the unmatched list
-                    None => {
-                        unmatched_bc.push(r.bc);
-                    }
+    #[test]
+    fn test_rad_reader_from_stream_stops_at_chunk_index() {
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        // the first `nchunks` chunks of 1 record each, and then an empty
+        // chunk and one more (an empty chunk after 8 chunks starts like a
+        // chunk index)
+        let write = |nchunks: u64| {
+            let hdr = RadHeader {
+                is_paired: 0,
+                ref_count: 1,
+                ref_names: vec!["a".to_string()],
+                ref_lengths: vec![],
+                ref_digest: None,
+                num_chunks: nchunks,
+            };
+            let mut rw = RadWriter::new(Vec::new());
+            rw.enable_chunk_index().unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            for bc in 0..nchunks {
+                rw.begin_chunk().unwrap();
+                if bc != 8 {
+                    rw.push_record(&ReadRecord {
+                        bc,
+                        umi: 1,
+                        dirs: vec![true],
+                        mate_dirs: Vec::new(),
+                        refs: vec![0],
+                        tags: None,
+                    })
+                    .unwrap();
                 }
+                rw.finish_chunk().unwrap();
             }
+            rw.finalize().unwrap()
+        };
+        // a header with a `num_chunks` of 0, followed by the chunks and the index
+        let stream = |nchunks: u64| {
+            let full = write(nchunks);
+            let mut bytes = write(0);
+            let header_len = bytes.len() - 24;
+            bytes.truncate(header_len);
+            bytes.extend_from_slice(&full[header_len..]);
+            bytes
+        };
+
+        for nchunks in &[0u64, 3, 10] {
+            let bytes = stream(*nchunks);
+            let chunks: Vec<Chunk> = RadReader::from_stream(&bytes[..])
+                .unwrap()
+                .map(|c| c.unwrap())
+                .collect();
+            assert_eq!(chunks.len() as u64, *nchunks);
+            assert_eq!(
+                chunks.iter().filter(|c| c.nrec == 0).count(),
+                (*nchunks > 8) as usize
+            );
+
+            let mut br = &bytes[..];
+            let rr = RadReader::new(&mut br).unwrap();
+            assert_eq!(rr.header.num_chunks, 0);
+            let mut sc = StreamChunks::default();
+            let mut buf = Vec::new();
+            while sc.read_raw(&mut br, &mut buf).unwrap() {}
+            assert_eq!(sc.chunks_read(), *nchunks);
         }
-        Strand::Forward => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| x) {
-                    num_strand_compat_reads += 1;
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    // lookup the barcode in the map of unfiltered known
-                    // barcodes
-                    match hist.get_mut(&r.bc) {
-                        // if we find a match, increment the count
-                        Some(c) => *c += 1,
-                        // otherwise, push this into the unmatched list
-                        None => {
-                            unmatched_bc.push(r.bc);
-                        }
-                    }
-                }
+
+        // without the magic, the bytes after the chunks are not an index
+        let mut bytes = stream(3);
+        let n = bytes.len();
+        bytes[n - 1] ^= 1;
+        assert!(RadReader::from_stream(&bytes[..])
+            .unwrap()
+            .any(|c| c.is_err()));
+    }
+
+    #[test]
+    fn test_paired_records_roundtrip() {
+        let hdr = RadHeader {
+            is_paired: 1,
+            ref_count: 2,
+            ref_names: vec!["t0".to_string(), "t1".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 1,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let rec = ExtReadRecord {
+            bc: 7,
+            umi: 3,
+            dirs: vec![true, false],
+            mate_dirs: vec![false, true],
+            refs: vec![1, 0],
+            positions: vec![],
+            frag_lens: vec![250, 310],
+            scores: vec![],
+        };
+        for &frag_len in &[false, true] {
+            let mut rw = RadWriter::new(Vec::new());
+            if frag_len {
+                rw.enable_frag_len().unwrap();
             }
-        }
-        Strand::Reverse => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| !x) {
-                    num_strand_compat_reads += 1;
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    // lookup the barcode in the map of unfiltered known
-                    // barcodes
-                    match hist.get_mut(&r.bc) {
-                        // if we find a match, increment the count
-                        Some(c) => *c += 1,
-                        // otherwise, push this into the unmatched list
-                        None => {
-                            unmatched_bc.push(r.bc);
-                        }
-                    }
-                }
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            rw.begin_chunk().unwrap();
+            rw.push_ext_record(&rec).unwrap();
+            rw.finish_chunk().unwrap();
+            let buf = rw.finalize().unwrap();
+
+            let mut rr = RadReader::new(Cursor::new(buf)).unwrap();
+            assert_eq!(rr.header.is_paired, 1);
+            assert_eq!(rr.aln_layout().unwrap().frag_len, frag_len);
+            let c = rr.next_ext().unwrap().unwrap();
+            let back = &c.reads[0];
+            assert_eq!((back.bc, back.umi), (7, 3));
+            assert_eq!(back.refs, rec.refs);
+            assert_eq!(back.dirs, rec.dirs);
+            assert_eq!(back.mate_dirs, rec.mate_dirs);
+            if frag_len {
+                assert_eq!(back.frag_lens, rec.frag_lens);
+            } else {
+                assert!(back.frag_lens.is_empty());
             }
+            assert!(rr.next_ext().is_none());
         }
+
+        // without fragment lengths, the orientation of the second read is
+        // kept apart from the reference ids by the parsing done during
+        // collation, which sorts the alignments by reference id alone
+        let mut buf = Vec::new();
+        let layout = AlnLayout {
+            paired: true,
+            ..AlnLayout::default()
+        };
+        rec.write_to(&mut buf, &RadIntId::U32, &RadIntId::U32, &layout)
+            .unwrap();
+        let mut reader = Cursor::new(buf);
+        let (bc, umi, na) =
+            ReadRecord::from_bytes_record_header(&mut reader, &RadIntId::U32, &RadIntId::U32);
+        let mut rr = ReadRecord::default();
+        rr.read_with_header_keep_ori_into_checked(
+            &mut reader,
+            bc,
+            umi,
+            na,
+            &layout,
+            &Strand::Unknown,
+        )
+        .unwrap();
+        assert_eq!(rr.refs, vec![0, 1]);
+        assert_eq!(rr.dirs, vec![false, true]);
+        assert_eq!(rr.mate_dirs, vec![true, false]);
+
+        // as it is by the plain reader and the record schema of the file,
+        // and it is written back by `push_record`
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        rw.begin_chunk().unwrap();
+        rw.push_record(&rr).unwrap();
+        rw.finish_chunk().unwrap();
+        let buf = rw.finalize().unwrap();
+        let mut reader = RadReader::new(Cursor::new(buf.clone())).unwrap();
+        let c = reader.next().unwrap().unwrap();
+        assert_eq!(c.reads[0].refs, rr.refs);
+        assert_eq!(c.reads[0].mate_dirs, rr.mate_dirs);
+        let reader = RadReader::new(Cursor::new(buf)).unwrap();
+        let schema = reader.record_schema().unwrap();
+        let c = schema.read_chunk(&mut reader.into_inner()).unwrap();
+        assert_eq!(c.reads[0].refs, rr.refs);
+        assert_eq!(c.reads[0].dirs, rr.dirs);
+        assert_eq!(c.reads[0].mate_dirs, rr.mate_dirs);
     }
-    num_strand_compat_reads
-}
 
-pub fn update_barcode_hist(
-    hist: &mut HashMap<u64, u64, ahash::RandomState>,
-    max_ambiguity_read: &mut usize,
-    chunk: &Chunk,
-    expected_ori: &Strand,
-) {
-    match expected_ori {
-        Strand::Unknown => {
-            for r in &chunk.reads {
-                *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                *hist.entry(r.bc).or_insert(0) += 1;
-            }
-        }
-        Strand::Forward => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| x) {
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    *hist.entry(r.bc).or_insert(0) += 1;
-                }
-            }
+    #[test]
+    fn test_aln_layout_from_tags() {
+        let section = |names: &[&str]| TagSection {
+            tags: names
+                .iter()
+                .map(|n| TagDesc {
+                    name: n.to_string(),
+                    typeid: RadIntId::U32.type_tag(),
+                })
+                .collect(),
+        };
+        let layout = AlnLayout {
+            pos: true,
+            frag_len: true,
+            ..AlnLayout::default()
+        };
+        assert_eq!(
+            AlnLayout::from_tags(false, &layout.tag_section()).unwrap(),
+            layout
+        );
+        assert_eq!(layout.aln_bytes(), 12);
+        assert!(matches!(
+            layout.require_plain("quant"),
+            Err(RadError::UnsupportedLayout(_))
+        ));
+        let paired = AlnLayout::from_tags(true, &section(&["compressed_ori_refid"])).unwrap();
+        assert!(paired.require_plain("quant").is_err());
+        let plain = AlnLayout::from_tags(false, &section(&["compressed_ori_refid"])).unwrap();
+        assert!(plain.require_plain("quant").is_ok());
+        assert!(AlnLayout::from_tags(
+            false,
+            &section(&["compressed_ori_refid", "score", "frag_len"])
+        )
+        .is_err());
+        assert!(AlnLayout::from_tags(false, &section(&["pos"])).is_err());
+    }
+
+    #[test]
+    fn test_collate_passes_positions_through() {
+        let layout = AlnLayout {
+            pos: true,
+            frag_len: true,
+            ..AlnLayout::default()
+        };
+        let recs = vec![
+            ExtReadRecord {
+                bc: 2,
+                umi: 1,
+                refs: vec![0, 1],
+                positions: vec![100, 200],
+                frag_lens: vec![30, 40],
+                ..ExtReadRecord::default()
+            },
+            ExtReadRecord {
+                bc: 1,
+                umi: 5,
+                refs: vec![2],
+                positions: vec![300],
+                frag_lens: vec![50],
+                ..ExtReadRecord::default()
+            },
+        ];
+        let mut bucket = Vec::new();
+        for r in recs.iter() {
+            r.write_to(&mut bucket, &RadIntId::U32, &RadIntId::U32, &layout)
+                .unwrap();
         }
-        Strand::Reverse => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| !x) {
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    *hist.entry(r.bc).or_insert(0) += 1;
-                }
-            }
+
+        // references 0 and 1 collapse, keeping the fields of the first
+        let remap = vec![0u32, 0, 1];
+        let owriter = Mutex::new(Vec::<u8>::new());
+        let mut cmap = HashMap::<u64, _, _>::with_hasher(ahash::RandomState::with_seeds(
+            2u64, 7u64, 1u64, 8u64,
+        ));
+        let n = collate_temporary_bucket_twopass(
+            &mut BufReader::new(Cursor::new(bucket)),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            2,
+            &owriter,
+            false,
+            &mut cmap,
+            Some(&remap),
+            &layout,
+            None,
+        );
+        assert_eq!(n, 2);
+
+        let out = owriter.into_inner().unwrap();
+        let mut cursor = Cursor::new(&out);
+        let mut seen = Vec::new();
+        for _ in 0..n {
+            let c =
+                ExtChunk::from_bytes_checked(&mut cursor, &RadIntId::U32, &RadIntId::U32, &layout)
+                    .unwrap();
+            seen.extend(c.reads);
         }
+        seen.sort_by_key(|r| r.bc);
+        assert_eq!(
+            (
+                seen[0].refs.clone(),
+                seen[0].positions.clone(),
+                seen[0].frag_lens.clone()
+            ),
+            (vec![1], vec![300], vec![50])
+        );
+        assert_eq!(
+            (
+                seen[1].refs.clone(),
+                seen[1].positions.clone(),
+                seen[1].frag_lens.clone()
+            ),
+            (vec![0], vec![100], vec![30])
+        );
     }
-}
 
-pub fn permit_list_from_threshold(
-    hist: &HashMap<u64, u64, ahash::RandomState>,
-    min_freq: u64,
-) -> Vec<u64> {
-    let valid_bc: Vec<u64> = hist
-        .iter()
-        .filter_map(|(k, v)| if v >= &min_freq { Some(*k) } else { None })
-        .collect();
-    valid_bc
-}
+    #[test]
+    fn test_collate_temporary_bucket_with_positions() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 3,
+            ref_names: vec!["t0".to_string(), "t1".to_string(), "t2".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 1,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.enable_positions().unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        rw.begin_chunk().unwrap();
+        for (bc, umi, refs, positions) in vec![
+            (4, 1, vec![0, 1, 2], vec![10, 20, 30]),
+            (5, 2, vec![2], vec![40]),
+            (4, 3, vec![1], vec![50]),
+        ] {
+            rw.push_ext_record(&ExtReadRecord {
+                bc,
+                umi,
+                dirs: vec![true; refs.len()],
+                refs,
+                positions,
+                ..ExtReadRecord::default()
+            })
+            .unwrap();
+        }
+        rw.finish_chunk().unwrap();
+        let buf = rw.finalize().unwrap();
+
+        let rr = RadReader::new(Cursor::new(buf)).unwrap();
+        let layout = rr.aln_layout().unwrap();
+        assert!(layout.pos);
+        let mut reader = rr.into_inner();
+        let (_, nrec) = Chunk::read_header(&mut reader);
+
+        // references 0 and 1 collapse, keeping the position of the first
+        let remap = vec![0u32, 0, 1];
+        let params = CollateParams {
+            aln_layout: layout,
+            ..CollateParams::default()
+        };
+        let mut cache = HashMap::with_hasher(ahash::RandomState::new());
+        collate_temporary_bucket(
+            &mut reader,
+            &RadIntId::U32,
+            &RadIntId::U32,
+            1,
+            nrec,
+            &mut cache,
+            Some(&remap),
+            &params,
+            None,
+        );
 
-pub fn permit_list_from_file(ifile: String, bclen: u16) -> Vec<u64> {
-    let f = File::open(ifile).expect("couldn't open input barcode file.");
-    let br = BufReader::new(f);
-    let mut bc = Vec::<u64>::with_capacity(10_000);
+        let cell_records = |bc: u64| -> Vec<(u64, Vec<u32>, Vec<u32>)> {
+            let c = &cache[&bc];
+            let mut cursor = Cursor::new(&c.data.get_ref()[8..]);
+            (0..c.nrec)
+                .map(|_| {
+                    let r = ExtReadRecord::from_bytes_checked(
+                        &mut cursor,
+                        &RadIntId::U32,
+                        &RadIntId::U32,
+                        &layout,
+                    )
+                    .unwrap();
+                    (r.umi, r.refs, r.positions)
+                })
+                .collect()
+        };
+        assert_eq!(
+            cell_records(4),
+            vec![(1, vec![0, 1], vec![10, 30]), (3, vec![0], vec![50])]
+        );
+        assert_eq!(cell_records(5), vec![(2, vec![1], vec![40])]);
+    }
 
-    for l in br.lines() {
-        let line = l.expect("couldn't read line from barcode file.");
-        let mut bnk = BitNuclKmer::new(line.as_bytes(), bclen as u8, false);
-        let (_, k, _) = bnk.next().expect("can't extract kmer");
-        bc.push(k.0);
+    #[test]
+    fn test_from_bytes_keep_ori_filtered_by_score() {
+        let layout = AlnLayout {
+            score: true,
+            ..AlnLayout::default()
+        };
+        let rec = ExtReadRecord {
+            bc: 4,
+            umi: 9,
+            dirs: vec![true, true, false],
+            refs: vec![5, 2, 7],
+            scores: vec![10, 60, 40],
+            ..ExtReadRecord::default()
+        };
+        let mut buf = Vec::new();
+        rec.write_to(&mut buf, &RadIntId::U32, &RadIntId::U32, &layout)
+            .unwrap();
+
+        let rr = ReadRecord::from_bytes_keep_ori_filtered(
+            &mut Cursor::new(&buf),
+            &RadIntId::U32,
+            &RadIntId::U32,
+            &layout,
+            &Strand::Unknown,
+            |a| a.score.unwrap() >= 30,
+        );
+        assert_eq!((rr.bc, rr.umi), (4, 9));
+        assert_eq!(rr.refs, vec![2, 7]);
+        assert_eq!(rr.dirs, vec![true, false]);
     }
-    bc
-}
 
-pub fn write_str_bin(v: &str, type_id: &RadIntId, owriter: &mut Cursor<Vec<u8>>) {
-    match type_id {
-        RadIntId::U8 => {
-            owriter
-                .write_all(&(v.len() as u8).to_le_bytes())
-                .expect("coudn't write to output file");
-        }
-        RadIntId::U16 => {
-            owriter
-                .write_all(&(v.len() as u16).to_le_bytes())
-                .expect("coudn't write to output file");
-        }
-        RadIntId::U32 => {
-            owriter
-                .write_all(&(v.len() as u32).to_le_bytes())
-                .expect("coudn't write to output file");
-        }
-        RadIntId::U64 => {
-            owriter
-                .write_all(&(v.len() as u64).to_le_bytes())
-                .expect("coudn't write to output file");
-        }
+    #[test]
+    fn test_ref_info_roundtrip_and_compatibility() {
+        let mut hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["t0".to_string(), "t1".to_string()],
+            ref_lengths: vec![1500, 800],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        hdr.ref_digest = hdr.compute_ref_digest();
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.enable_version_header().unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        let bytes = rw.finalize_with_chunk_count().unwrap().into_inner();
+
+        let rr = RadReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(rr.header.ref_lengths, hdr.ref_lengths);
+        assert_eq!(rr.header.ref_digest, hdr.ref_digest);
+        assert_eq!(rr.file_tag_vals.bclen, 16);
+        assert!(hdr.check_compatibility(&rr.header).is_ok());
+
+        // the digest doesn't depend on the order of the references
+        let mut reordered = RadHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["t1".to_string(), "t0".to_string()],
+            ref_lengths: vec![800, 1500],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        assert_eq!(reordered.compute_ref_digest(), hdr.ref_digest);
+
+        // but names and lengths are compared in order, even if the digests
+        // agree, since records refer to references by their position
+        assert!(matches!(
+            hdr.check_compatibility(&reordered),
+            Err(RadError::ReferenceMismatch(_))
+        ));
+        reordered.ref_digest = reordered.compute_ref_digest();
+        assert!(matches!(
+            hdr.check_compatibility(&reordered),
+            Err(RadError::ReferenceMismatch(_))
+        ));
+        reordered.ref_names.swap(0, 1);
+        reordered.ref_lengths = vec![1500, 801];
+        reordered.ref_digest = reordered.compute_ref_digest();
+        assert!(hdr.check_compatibility(&reordered).is_err());
+    }
+
+    #[test]
+    fn test_malformed_input_is_an_error() {
+        // a truncated header claiming a vast number of references
+        let mut bytes = vec![0u8];
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            RadHeader::from_bytes_checked(&mut Cursor::new(&bytes)),
+            Err(RadError::Io(_))
+        ));
+
+        // a reference name longer than the limit
+        let mut bytes = vec![0u8];
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&(MAX_REF_NAME_LEN as u16 + 1).to_le_bytes());
+        bytes.resize(bytes.len() + MAX_REF_NAME_LEN + 1, b'a');
+        assert!(matches!(
+            RadHeader::from_bytes_checked(&mut Cursor::new(&bytes)),
+            Err(RadError::LimitExceeded { .. })
+        ));
+
+        // a chunk declaring more records than it could hold, or than the limit
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&64u32.to_le_bytes());
+        bytes.extend_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(
+            Chunk::from_bytes_checked(&mut Cursor::new(&bytes), &RadIntId::U32, &RadIntId::U32),
+            Err(RadError::ChunkSizeMismatch { .. })
+        ));
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Chunk::from_bytes_checked(&mut Cursor::new(&bytes), &RadIntId::U32, &RadIntId::U32),
+            Err(RadError::LimitExceeded { .. })
+        ));
+
+        // a chunk whose record declares too many alignments
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&20u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 8]);
+        assert!(matches!(
+            Chunk::from_bytes_checked(&mut Cursor::new(&bytes), &RadIntId::U32, &RadIntId::U32),
+            Err(RadError::LimitExceeded { .. })
+        ));
+
+        // a chunk whose body is cut short isn't read into a buffer of its
+        // declared size
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        let mut buf = Vec::new();
+        let err = Chunk::read_raw(&mut Cursor::new(&bytes), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.len(), 8);
     }
-    owriter
-        .write_all(v.as_bytes())
-        .expect("coudn't write to output file");
 }
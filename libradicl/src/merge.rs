@@ -0,0 +1,1016 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Merging of RAD files mapped separately (e.g. one per lane) against the
+// same reference into a single file, splitting of a single file into
+// shards by barcode, and filtering or subsampling of the records of a file.
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::{
+    decode_type_tag, read_chunk, Chunk, ChunkEncoder, ChunkIndex, CountingReader, RadHeader,
+    RadReader, RadVersion, ReadRecord, TagSection, CHECKSUM_TAG, MAX_AMBIGUITY_TAG, RAD_MAGIC,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use scroll::Pread;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+type InputReader = RadReader<CountingReader<BufReader<File>>>;
+
+// The number of bytes a tag section takes in the file.
+fn section_len(ts: &TagSection) -> usize {
+    2 + ts.tags.iter().map(|t| 3 + t.name.len()).sum::<usize>()
+}
+
+fn same_section(a: &TagSection, b: &TagSection) -> bool {
+    a.tags.len() == b.tags.len()
+        && a.tags
+            .iter()
+            .zip(b.tags.iter())
+            .all(|(x, y)| x.name == y.name && x.typeid == y.typeid)
+}
+
+// Describe the first way in which `other` can't be merged with `first`.
+fn incompatibility(first: &InputReader, other: &InputReader) -> Option<&'static str> {
+    let (a, b) = (&first.file_tag_vals, &other.file_tag_vals);
+    if first.header.is_paired != other.header.is_paired {
+        Some("paired-endedness")
+    } else if first.header.check_compatibility(&other.header).is_err() {
+        Some("references")
+    } else if !same_section(&first.file_tags, &other.file_tags)
+        || !same_section(&first.read_tags, &other.read_tags)
+        || !same_section(&first.aln_tags, &other.aln_tags)
+    {
+        Some("tag descriptions")
+    } else if (a.bclen, a.umilen, a.variable_umi, a.zstd_chunks)
+        != (b.bclen, b.umilen, b.variable_umi, b.zstd_chunks)
+    {
+        Some("barcode or umi encoding")
+    } else {
+        None
+    }
+}
+
+// The offset within `prelude` of the value of the file-level tag `name`,
+// given that the file-level values begin at `vals_start`.
+fn file_tag_value_offset(
+    prelude: &[u8],
+    vals_start: usize,
+    file_tags: &TagSection,
+    name: &str,
+) -> Option<usize> {
+    let mut off = vals_start;
+    for t in &file_tags.tags {
+        if t.name == name {
+            return Some(off);
+        }
+        off += match decode_type_tag(t.typeid)?.fixed_size() {
+            Some(n) => n,
+            None => 2 + prelude.pread::<u16>(off).ok()? as usize,
+        };
+    }
+    None
+}
+
+fn open_input<P: AsRef<Path>>(path: P) -> Result<InputReader, RadError> {
+    let f = File::open(path)?;
+    RadReader::new(CountingReader::new(BufReader::new(f)))
+}
+
+// The bytes of the header, tag descriptions and file-level tag values of
+// an input, and the positions of the values rewritten in its outputs.
+struct Prelude {
+    bytes: Vec<u8>,
+    num_chunks_pos: usize,
+    max_ambiguity_pos: Option<usize>,
+    checksum_pos: Option<usize>,
+    // where the tag descriptions begin, and whether the header is
+    // followed by the reference info block (see `RadVersion::has_ref_info`)
+    sections_pos: usize,
+    ref_info: bool,
+}
+
+impl Prelude {
+    // `rr` must have just been opened on `path` by `open_input`.
+    fn read<P: AsRef<Path>>(path: P, rr: &InputReader) -> Result<Prelude, RadError> {
+        let mut bytes = vec![0u8; rr.get_ref().bytes_read() as usize];
+        File::open(path)?.read_exact(&mut bytes)?;
+        let (version_len, ref_info_len) = if bytes[0] > 1 {
+            let version = RadVersion::Versioned {
+                major: bytes.pread::<u16>(RAD_MAGIC.len()).unwrap(),
+                minor: bytes.pread::<u16>(RAD_MAGIC.len() + 2).unwrap(),
+                patch: bytes.pread::<u16>(RAD_MAGIC.len() + 4).unwrap(),
+            };
+            let ref_info_len = if version.has_ref_info() {
+                Some(rr.header.ref_info_len())
+            } else {
+                None
+            };
+            (RAD_MAGIC.len() + 7, ref_info_len)
+        } else {
+            (1, None)
+        };
+        let num_chunks_pos = version_len
+            + 8
+            + rr.header
+                .ref_names
+                .iter()
+                .map(|n| 2 + n.len())
+                .sum::<usize>();
+        let sections_pos = num_chunks_pos + 8 + ref_info_len.unwrap_or(0);
+        let vals_start = sections_pos
+            + section_len(&rr.file_tags)
+            + section_len(&rr.read_tags)
+            + section_len(&rr.aln_tags);
+        let value_pos = |name| file_tag_value_offset(&bytes, vals_start, &rr.file_tags, name);
+        Ok(Prelude {
+            num_chunks_pos,
+            max_ambiguity_pos: value_pos(MAX_AMBIGUITY_TAG),
+            checksum_pos: value_pos(CHECKSUM_TAG),
+            sections_pos,
+            ref_info: ref_info_len.is_some(),
+            bytes,
+        })
+    }
+
+    // This prelude with the header replaced by `hdr`.
+    fn with_header(&self, hdr: &RadHeader) -> std::io::Result<Prelude> {
+        // everything up to is_paired, which begins the header proper
+        let version_len = if self.bytes[0] > 1 {
+            RAD_MAGIC.len() + 6
+        } else {
+            0
+        };
+        let mut bytes = self.bytes[..version_len].to_vec();
+        hdr.write_to(&mut bytes)?;
+        let num_chunks_pos = bytes.len() - 8;
+        if self.ref_info {
+            hdr.write_ref_info(&mut bytes)?;
+        }
+        let sections_pos = bytes.len();
+        bytes.extend_from_slice(&self.bytes[self.sections_pos..]);
+        let shift = |pos: usize| pos - self.sections_pos + sections_pos;
+        Ok(Prelude {
+            bytes,
+            num_chunks_pos,
+            max_ambiguity_pos: self.max_ambiguity_pos.map(shift),
+            checksum_pos: self.checksum_pos.map(shift),
+            sections_pos,
+            ref_info: self.ref_info,
+        })
+    }
+}
+
+// An output file that begins with a copy of a `Prelude`, whose chunk
+// count, max ambiguity and checksum are filled in by `finish`.  Records
+// pushed to it are encoded, and compressed if the chunks of `rr` are, by
+// the `ChunkEncoder` that `RadWriter` uses.
+struct RadOutput {
+    writer: BufWriter<File>,
+    enc: ChunkEncoder,
+}
+
+impl RadOutput {
+    fn create<P: AsRef<Path>>(
+        path: P,
+        prelude: &Prelude,
+        rr: &InputReader,
+    ) -> std::io::Result<RadOutput> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&prelude.bytes)?;
+        let mut enc = ChunkEncoder::new(rr.bc_type, rr.umi_type);
+        enc.aln_layout.paired = rr.header.is_paired != 0;
+        enc.bytes_written = prelude.bytes.len() as u64;
+        if prelude.checksum_pos.is_some() {
+            enc.checksum = Some(flate2::Crc::new());
+        }
+        #[cfg(feature = "zstd")]
+        if rr.file_tag_vals.zstd_chunks {
+            enc.zstd_level = Some(zstd::DEFAULT_COMPRESSION_LEVEL);
+        }
+        Ok(RadOutput { writer, enc })
+    }
+
+    // Append `r` to the pending chunk, with its barcode replaced by `bc`.
+    fn push(&mut self, r: &ReadRecord, bc: u64) -> std::io::Result<()> {
+        self.enc.push(r, bc)
+    }
+
+    // Write the pending chunk, if it has any records.
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.enc.nrec() > 0 {
+            self.enc.finish(&mut self.writer)?;
+            self.enc.begin();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, prelude: &Prelude) -> std::io::Result<()> {
+        self.enc.write_index(&mut self.writer)?;
+        self.writer.flush()?;
+        let mut f = self.writer.into_inner().unwrap();
+        let mut patch = |pos: usize, bytes: &[u8]| -> std::io::Result<()> {
+            f.seek(SeekFrom::Start(pos as u64))?;
+            f.write_all(bytes)
+        };
+        patch(
+            prelude.num_chunks_pos,
+            &self.enc.chunks_written.to_le_bytes(),
+        )?;
+        if let Some(pos) = prelude.max_ambiguity_pos {
+            patch(pos, &self.enc.max_ambiguity.to_le_bytes())?;
+        }
+        if let (Some(pos), Some(crc)) = (prelude.checksum_pos, self.enc.checksum.as_ref()) {
+            patch(pos, &crc.sum().to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+// Records can only be re-encoded if they carry nothing but a barcode, a umi
+// and their alignments.
+fn check_standard_tags<P: AsRef<Path>>(path: P, rr: &InputReader) -> Result<(), RadError> {
+    let names = |ts: &TagSection| ts.tags.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+    if names(&rr.read_tags) != ["b", "u"] || names(&rr.aln_tags) != ["compressed_ori_refid"] {
+        return Err(RadError::IncompatibleInputs(format!(
+            "{} has tags other than the barcode, umi and alignments",
+            path.as_ref().display()
+        )));
+    }
+    Ok(())
+}
+
+/// Merge the RAD files `inputs`, which must have been mapped against the
+/// same references with the same tag descriptions, into a single file at
+/// `output`.  The header and file-level tags of the first input are kept,
+/// except that `num_chunks` is rewritten to the total number of chunks, and
+/// the `MAX_AMBIGUITY_TAG` and `CHECKSUM_TAG` values, if present, are
+/// recomputed over all inputs.  The chunks of each input are copied as they
+/// are, in the order given.  If `write_chunk_index` is set, a fresh
+/// `ChunkIndex` of the merged chunks is appended.  Returns the number of
+/// chunks written.
+pub fn merge_rad_files<P: AsRef<Path>, Q: AsRef<Path>>(
+    inputs: &[P],
+    output: Q,
+    write_chunk_index: bool,
+) -> Result<u64, RadError> {
+    let first_path = inputs
+        .first()
+        .ok_or_else(|| RadError::IncompatibleInputs("no input files were given".to_string()))?;
+    let first = open_input(first_path)?;
+    let prelude = Prelude::read(first_path, &first)?;
+    let mut out = RadOutput::create(output, &prelude, &first)?;
+    if write_chunk_index {
+        out.enc.chunk_index = Some(ChunkIndex::default());
+    }
+
+    let zstd = first.file_tag_vals.zstd_chunks;
+    let (bct, umit) = (first.bc_type, first.umi_type);
+    let mut buf = Vec::new();
+
+    for path in inputs {
+        let rr = open_input(path)?;
+        if let Some(what) = incompatibility(&first, &rr) {
+            return Err(RadError::IncompatibleInputs(format!(
+                "{} differs in its {} from {}",
+                path.as_ref().display(),
+                what,
+                first_path.as_ref().display()
+            )));
+        }
+        out.enc.max_ambiguity = out
+            .enc
+            .max_ambiguity
+            .max(rr.file_tag_vals.max_ambiguity.unwrap_or(0));
+        let n = rr.header.num_chunks;
+        let mut reader = rr.into_inner();
+        for _ in 0..n {
+            Chunk::read_raw(&mut reader, &mut buf)?;
+            // the chunk is copied as is, and only decoded for its index entry
+            let (nrec, first_bc, last_bc) = if write_chunk_index {
+                let c = read_chunk(&mut Cursor::new(&buf), zstd, &bct, &umit)?;
+                (
+                    c.nrec,
+                    c.reads.first().map_or(0, |r| r.bc as u128),
+                    c.reads.last().map_or(0, |r| r.bc as u128),
+                )
+            } else {
+                (0, 0, 0)
+            };
+            out.enc
+                .write_raw(&mut out.writer, &buf, nrec, first_bc, last_bc)?;
+        }
+    }
+
+    let num_chunks = out.enc.chunks_written;
+    out.finish(&prelude)?;
+    Ok(num_chunks)
+}
+
+/// The shard, of `n_shards`, to which `split_rad_by_barcode` assigns the
+/// records of barcode `bc`.
+pub fn barcode_shard(bc: u64, n_shards: usize) -> usize {
+    // mix the bits, so that barcodes differing only in their last bases
+    // are spread evenly
+    let h = (bc ^ (bc >> 31)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    ((h >> 32) % n_shards as u64) as usize
+}
+
+/// Split the RAD file `input` into `n_shards` RAD files, written to
+/// `output_dir/shard_<i>/map.rad` (so that each can be handed to
+/// `generate-permit-list` as is), such that every record of a barcode lands
+/// in the same shard (see `barcode_shard`).  The records of each input chunk
+/// are written as one chunk to each shard receiving any of them.  Each shard
+/// keeps the header and file-level tags of the input, with the chunk count,
+/// and the `MAX_AMBIGUITY_TAG` and `CHECKSUM_TAG` values if present,
+/// recomputed.  Only the standard barcode, umi and alignment tags are
+/// supported.  Returns the paths of the shards.
+pub fn split_rad_by_barcode<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output_dir: Q,
+    n_shards: usize,
+) -> Result<Vec<PathBuf>, RadError> {
+    if n_shards == 0 {
+        return Err(RadError::IncompatibleInputs(
+            "the number of shards must be positive".to_string(),
+        ));
+    }
+    let rr = open_input(&input)?;
+    check_standard_tags(&input, &rr)?;
+    let prelude = Prelude::read(&input, &rr)?;
+
+    let mut paths = Vec::with_capacity(n_shards);
+    let mut outs = Vec::with_capacity(n_shards);
+    for i in 0..n_shards {
+        let dir = output_dir.as_ref().join(format!("shard_{}", i));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("map.rad");
+        outs.push(RadOutput::create(&path, &prelude, &rr)?);
+        paths.push(path);
+    }
+
+    let (zstd, bct, umit) = (rr.file_tag_vals.zstd_chunks, rr.bc_type, rr.umi_type);
+    let n = rr.header.num_chunks;
+    let mut reader = rr.into_inner();
+    for _ in 0..n {
+        let c = read_chunk(&mut reader, zstd, &bct, &umit)?;
+        for r in &c.reads {
+            outs[barcode_shard(r.bc, n_shards)].push(r, r.bc)?;
+        }
+        for out in outs.iter_mut() {
+            out.flush()?;
+        }
+    }
+
+    for out in outs {
+        out.finish(&prelude)?;
+    }
+    Ok(paths)
+}
+
+/// Write to `output` the records of the RAD file `input` whose barcode is a
+/// key of `correct_map`, with the barcode replaced by the value it maps to
+/// (so that a permit list can be given by mapping each barcode to itself,
+/// and a corrected-barcode map as is).  The kept records of each input chunk
+/// form one output chunk.  The header and file-level tags of the input are
+/// kept, with the chunk count, and the `MAX_AMBIGUITY_TAG` and
+/// `CHECKSUM_TAG` values if present, recomputed.  Only the standard barcode,
+/// umi and alignment tags are supported.  Returns the number of records
+/// written.
+pub fn filter_rad_by_barcodes<P: AsRef<Path>, Q: AsRef<Path>, S: BuildHasher>(
+    input: P,
+    output: Q,
+    correct_map: &HashMap<u64, u64, S>,
+) -> Result<u64, RadError> {
+    let rr = open_input(&input)?;
+    check_standard_tags(&input, &rr)?;
+    let prelude = Prelude::read(&input, &rr)?;
+    let mut out = RadOutput::create(output, &prelude, &rr)?;
+
+    let (zstd, bct, umit) = (rr.file_tag_vals.zstd_chunks, rr.bc_type, rr.umi_type);
+    let n = rr.header.num_chunks;
+    let mut reader = rr.into_inner();
+    let mut kept = 0u64;
+    for _ in 0..n {
+        let c = read_chunk(&mut reader, zstd, &bct, &umit)?;
+        for r in &c.reads {
+            if let Some(&bc) = correct_map.get(&r.bc) {
+                out.push(r, bc)?;
+                kept += 1;
+            }
+        }
+        out.flush()?;
+    }
+
+    out.finish(&prelude)?;
+    Ok(kept)
+}
+
+/// Write to `output` a random subsample of the records of the RAD file
+/// `input`, each kept independently with probability `fraction`, using a
+/// random number generator seeded with `seed` (so that the same `seed`
+/// always keeps the same records).  The kept records of each input chunk
+/// form one output chunk.  The header and file-level tags of the input are
+/// kept, with the chunk count, and the `MAX_AMBIGUITY_TAG` and
+/// `CHECKSUM_TAG` values if present, recomputed.  Only the standard barcode,
+/// umi and alignment tags are supported.  Returns the number of records
+/// written, or an `InvalidArgument` error if `fraction` is not in `[0, 1]`.
+pub fn subsample_rad<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    fraction: f64,
+    seed: u64,
+) -> Result<u64, RadError> {
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(RadError::InvalidArgument(format!(
+            "subsampling fraction {} is not in [0, 1]",
+            fraction
+        )));
+    }
+    let rr = open_input(&input)?;
+    check_standard_tags(&input, &rr)?;
+    let prelude = Prelude::read(&input, &rr)?;
+    let mut out = RadOutput::create(output, &prelude, &rr)?;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (zstd, bct, umit) = (rr.file_tag_vals.zstd_chunks, rr.bc_type, rr.umi_type);
+    let n = rr.header.num_chunks;
+    let mut reader = rr.into_inner();
+    let mut kept = 0u64;
+    for _ in 0..n {
+        let c = read_chunk(&mut reader, zstd, &bct, &umit)?;
+        for r in &c.reads {
+            if rng.gen_bool(fraction) {
+                out.push(r, r.bc)?;
+                kept += 1;
+            }
+        }
+        out.flush()?;
+    }
+
+    out.finish(&prelude)?;
+    Ok(kept)
+}
+
+/// Write to `output` the records of the RAD file `input` with each
+/// reference collapsed to its gene, as given by `t2g` (mapping reference
+/// names to gene names), so that gene-level tools need not resolve
+/// transcripts themselves.  The references of the output are the genes, in
+/// the order in which they are first reached from the references of the
+/// input.  The alignments of each record are mapped to genes, keeping only
+/// the first alignment (and its orientations) to each gene.  The records of
+/// each input chunk form one output chunk.  The file-level tags of the
+/// input are kept, with the chunk count, and the `MAX_AMBIGUITY_TAG` and
+/// `CHECKSUM_TAG` values if present, recomputed.  Only the standard barcode,
+/// umi and alignment tags are supported.  Returns the number of genes.
+pub fn remap_references<P: AsRef<Path>, Q: AsRef<Path>, S: BuildHasher>(
+    input: P,
+    output: Q,
+    t2g: &HashMap<String, String, S>,
+) -> Result<usize, RadError> {
+    let rr = open_input(&input)?;
+    check_standard_tags(&input, &rr)?;
+
+    let mut gene_names = Vec::<String>::new();
+    let mut gene_ids = HashMap::<&str, u32>::new();
+    let mut remap = Vec::with_capacity(rr.header.ref_names.len());
+    for name in &rr.header.ref_names {
+        let gene = t2g.get(name).ok_or_else(|| {
+            RadError::ReferenceMismatch(format!("no gene is given for reference '{}'", name))
+        })?;
+        let gid = *gene_ids.entry(gene.as_str()).or_insert_with(|| {
+            gene_names.push(gene.clone());
+            (gene_names.len() - 1) as u32
+        });
+        remap.push(gid);
+    }
+
+    let hdr = RadHeader {
+        is_paired: rr.header.is_paired,
+        ref_count: gene_names.len() as u64,
+        ref_names: gene_names,
+        ref_lengths: vec![],
+        ref_digest: None,
+        num_chunks: rr.header.num_chunks,
+    };
+    let prelude = Prelude::read(&input, &rr)?.with_header(&hdr)?;
+    let mut out = RadOutput::create(output, &prelude, &rr)?;
+
+    let (zstd, bct, umit) = (rr.file_tag_vals.zstd_chunks, rr.bc_type, rr.umi_type);
+    let n = rr.header.num_chunks;
+    let paired = rr.header.is_paired != 0;
+    let mut reader = rr.into_inner();
+    let mut collapsed = ReadRecord::default();
+    for _ in 0..n {
+        let mut c = read_chunk(&mut reader, zstd, &bct, &umit)?;
+        if paired {
+            c.split_mate_ori();
+        }
+        for r in &c.reads {
+            collapsed.umi = r.umi;
+            collapsed.refs.clear();
+            collapsed.dirs.clear();
+            collapsed.mate_dirs.clear();
+            for (i, (t, dir)) in r.refs.iter().zip(r.dirs.iter()).enumerate() {
+                let g = *remap.get(*t as usize).ok_or_else(|| {
+                    RadError::ReferenceMismatch(format!(
+                        "reference id {} is beyond the {} references of the header",
+                        t,
+                        remap.len()
+                    ))
+                })?;
+                if !collapsed.refs.contains(&g) {
+                    collapsed.refs.push(g);
+                    collapsed.dirs.push(*dir);
+                    if let Some(mate) = r.mate_dirs.get(i) {
+                        collapsed.mate_dirs.push(*mate);
+                    }
+                }
+            }
+            out.push(&collapsed, r.bc)?;
+        }
+        out.flush()?;
+    }
+
+    out.finish(&prelude)?;
+    Ok(hdr.ref_names.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_checksum, FileTags, RadIntId, RadWriter};
+
+    #[test]
+    fn test_merge_rad_files() {
+        let dir = std::env::temp_dir().join(format!("merge_rad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, refs: &[&str], bcs: &[u64], na: usize| {
+            let hdr = RadHeader {
+                is_paired: 0,
+                ref_count: refs.len() as u64,
+                ref_names: refs.iter().map(|r| r.to_string()).collect(),
+                ref_lengths: vec![],
+                ref_digest: None,
+                num_chunks: bcs.len() as u64,
+            };
+            let ft = FileTags {
+                bclen: 16,
+                umilen: 12,
+                max_ambiguity: Some(na as u32),
+                variable_umi: false,
+                ..Default::default()
+            };
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            rw.enable_checksum().unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            for &bc in bcs {
+                rw.begin_chunk().unwrap();
+                rw.push_record(&ReadRecord {
+                    bc,
+                    umi: 1,
+                    dirs: vec![true; na],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0; na],
+                    tags: None,
+                })
+                .unwrap();
+                rw.finish_chunk().unwrap();
+            }
+            let path = dir.join(name);
+            std::fs::write(&path, rw.finalize_with_chunk_count().unwrap().into_inner()).unwrap();
+            path
+        };
+        let a = write("a.rad", &["t0", "t1"], &[1, 2], 1);
+        let b = write("b.rad", &["t0", "t1"], &[3], 2);
+        let c = write("c.rad", &["t0", "t2"], &[4], 1);
+
+        let out = dir.join("merged.rad");
+        assert_eq!(merge_rad_files(&[&a, &b], &out, true).unwrap(), 3);
+        let mut rr = RadReader::new(File::open(&out).unwrap()).unwrap();
+        assert_eq!(rr.header.num_chunks, 3);
+        assert_eq!(rr.file_tag_vals.max_ambiguity, Some(2));
+        assert_eq!(rr.validate().unwrap(), None);
+        assert!(rr.seek_to_cell(3).unwrap());
+        let bcs: Vec<u64> = rr.map(|c| c.unwrap().reads[0].bc).collect();
+        assert_eq!(bcs, vec![3]);
+        let mut f = File::open(&out).unwrap();
+        let hdr = RadHeader::from_bytes_checked(&mut f).unwrap();
+        assert!(verify_checksum(&mut f, &hdr).unwrap());
+
+        match merge_rad_files(&[&a, &c], &out, false) {
+            Err(RadError::IncompatibleInputs(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_rad_by_barcode() {
+        let dir = std::env::temp_dir().join(format!("split_rad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["t0".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 4,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for i in 0..4u64 {
+            rw.begin_chunk().unwrap();
+            for bc in 0..20u64 {
+                rw.push_record(&ReadRecord {
+                    bc: bc * 7919,
+                    umi: i,
+                    dirs: vec![bc % 2 == 0],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let input = dir.join("map.rad");
+        std::fs::write(&input, rw.finalize().unwrap()).unwrap();
+
+        let paths = split_rad_by_barcode(&input, dir.join("shards"), 3).unwrap();
+        assert_eq!(paths.len(), 3);
+        let mut total = 0;
+        for (s, p) in paths.iter().enumerate() {
+            let rr = RadReader::new(File::open(p).unwrap()).unwrap();
+            for c in rr {
+                for r in c.unwrap().reads {
+                    assert_eq!(barcode_shard(r.bc, 3), s);
+                    assert_eq!(r.dirs, vec![(r.bc / 7919) % 2 == 0]);
+                    total += 1;
+                }
+            }
+        }
+        assert_eq!(total, 80);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filter_rad_by_barcodes() {
+        let dir = std::env::temp_dir().join(format!("filter_rad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["t0".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 2,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: Some(3),
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bcs in &[[1u64, 2], [3, 4]] {
+            rw.begin_chunk().unwrap();
+            for &bc in bcs {
+                rw.push_record(&ReadRecord {
+                    bc,
+                    umi: bc * 10,
+                    dirs: vec![true; bc as usize % 3 + 1],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0; bc as usize % 3 + 1],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let input = dir.join("map.rad");
+        std::fs::write(&input, rw.finalize().unwrap()).unwrap();
+
+        // 1 is kept as is, 2 is corrected to 1, 3 and 4 are dropped
+        let mut correct_map = HashMap::new();
+        correct_map.insert(1u64, 1u64);
+        correct_map.insert(2, 1);
+        let out = dir.join("filtered.rad");
+        assert_eq!(
+            filter_rad_by_barcodes(&input, &out, &correct_map).unwrap(),
+            2
+        );
+        let rr = RadReader::new(File::open(&out).unwrap()).unwrap();
+        assert_eq!(rr.header.num_chunks, 1);
+        assert_eq!(rr.file_tag_vals.max_ambiguity, Some(3));
+        let recs: Vec<(u64, u64)> = rr
+            .flat_map(|c| c.unwrap().reads)
+            .map(|r| (r.bc, r.umi))
+            .collect();
+        assert_eq!(recs, vec![(1, 10), (1, 20)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_filter_rad_by_barcodes_compressed() {
+        let dir = std::env::temp_dir().join(format!("filter_zstd_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["t0".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 0,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.enable_checksum().unwrap();
+        rw.enable_chunk_compression(19).unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for i in 0..3u64 {
+            rw.begin_chunk().unwrap();
+            for bc in 0..50u64 {
+                rw.push_record(&ReadRecord {
+                    bc,
+                    umi: i,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let input = dir.join("map.rad");
+        std::fs::write(&input, rw.finalize_with_chunk_count().unwrap().into_inner()).unwrap();
+
+        // the kept records are compressed again, and the checksum covers
+        // the compressed chunks
+        let correct_map: HashMap<u64, u64> = (0..50u64).step_by(5).map(|b| (b, b)).collect();
+        let out = dir.join("filtered.rad");
+        assert_eq!(
+            filter_rad_by_barcodes(&input, &out, &correct_map).unwrap(),
+            30
+        );
+        let rr = RadReader::new(File::open(&out).unwrap()).unwrap();
+        assert!(rr.file_tag_vals.zstd_chunks);
+        assert_eq!(rr.header.num_chunks, 3);
+        let bcs: Vec<u64> = rr.flat_map(|c| c.unwrap().reads).map(|r| r.bc).collect();
+        assert_eq!(bcs.len(), 30);
+        assert!(bcs.iter().all(|b| b % 5 == 0));
+        let mut f = BufReader::new(File::open(&out).unwrap());
+        let hdr = RadHeader::from_bytes_checked(&mut f).unwrap();
+        assert!(verify_checksum(&mut f, &hdr).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_rejects_reordered_references() {
+        let dir = std::env::temp_dir().join(format!("merge_order_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, refs: &[(&str, u64)]| {
+            let mut hdr = RadHeader {
+                is_paired: 0,
+                ref_count: refs.len() as u64,
+                ref_names: refs.iter().map(|(n, _)| n.to_string()).collect(),
+                ref_lengths: refs.iter().map(|(_, l)| *l).collect(),
+                ref_digest: None,
+                num_chunks: 1,
+            };
+            hdr.ref_digest = hdr.compute_ref_digest();
+            let ft = FileTags {
+                bclen: 16,
+                umilen: 12,
+                max_ambiguity: Some(1),
+                variable_umi: false,
+                ..Default::default()
+            };
+            let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+            rw.enable_version_header().unwrap();
+            rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+                .unwrap();
+            rw.begin_chunk().unwrap();
+            rw.push_record(&ReadRecord {
+                bc: 1,
+                umi: 1,
+                dirs: vec![true],
+                mate_dirs: Vec::new(),
+                refs: vec![0],
+                tags: None,
+            })
+            .unwrap();
+            rw.finish_chunk().unwrap();
+            let path = dir.join(name);
+            std::fs::write(&path, rw.finalize_with_chunk_count().unwrap().into_inner()).unwrap();
+            path
+        };
+        // the same references, and so the same digest, in another order
+        let a = write("a.rad", &[("t0", 100), ("t1", 200)]);
+        let b = write("b.rad", &[("t1", 200), ("t0", 100)]);
+
+        let out = dir.join("merged.rad");
+        match merge_rad_files(&[&a, &b], &out, false) {
+            Err(RadError::IncompatibleInputs(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_subsample_rad() {
+        let dir = std::env::temp_dir().join(format!("subsample_rad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["t0".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 10,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bc in 0..10u64 {
+            rw.begin_chunk().unwrap();
+            for umi in 0..100u64 {
+                rw.push_record(&ReadRecord {
+                    bc,
+                    umi,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let input = dir.join("map.rad");
+        std::fs::write(&input, rw.finalize().unwrap()).unwrap();
+
+        let read_all = |p: &Path| -> Vec<(u64, u64)> {
+            RadReader::new(File::open(p).unwrap())
+                .unwrap()
+                .flat_map(|c| c.unwrap().reads)
+                .map(|r| (r.bc, r.umi))
+                .collect()
+        };
+        let (a, b) = (dir.join("a.rad"), dir.join("b.rad"));
+        let kept = subsample_rad(&input, &a, 0.3, 7).unwrap();
+        assert!(kept > 200 && kept < 400);
+        assert_eq!(read_all(&a).len() as u64, kept);
+        // the same seed keeps the same records
+        subsample_rad(&input, &b, 0.3, 7).unwrap();
+        assert_eq!(read_all(&a), read_all(&b));
+        assert_eq!(subsample_rad(&input, &b, 1.0, 7).unwrap(), 1000);
+        assert_eq!(subsample_rad(&input, &b, 0.0, 7).unwrap(), 0);
+        let rr = RadReader::new(File::open(&b).unwrap()).unwrap();
+        assert_eq!(rr.header.num_chunks, 0);
+        assert!(matches!(
+            subsample_rad(&input, &b, 1.5, 7),
+            Err(RadError::InvalidArgument(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remap_references() {
+        let dir = std::env::temp_dir().join(format!("remap_rad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 3,
+            ref_names: vec!["t0".to_string(), "t1".to_string(), "t2".to_string()],
+            ref_lengths: vec![100, 200, 300],
+            ref_digest: None,
+            num_chunks: 1,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: Some(3),
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.enable_version_header().unwrap();
+        rw.enable_checksum().unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        rw.begin_chunk().unwrap();
+        rw.push_record(&ReadRecord {
+            bc: 1,
+            umi: 2,
+            dirs: vec![true, false, true],
+            mate_dirs: Vec::new(),
+            refs: vec![0, 1, 2],
+            tags: None,
+        })
+        .unwrap();
+        rw.push_record(&ReadRecord {
+            bc: 3,
+            umi: 4,
+            dirs: vec![false],
+            mate_dirs: Vec::new(),
+            refs: vec![1],
+            tags: None,
+        })
+        .unwrap();
+        rw.finish_chunk().unwrap();
+        let input = dir.join("map.rad");
+        std::fs::write(&input, rw.finalize_with_chunk_count().unwrap().into_inner()).unwrap();
+
+        let t2g: HashMap<String, String> = [("t0", "gB"), ("t1", "gA"), ("t2", "gB")]
+            .iter()
+            .map(|(t, g)| (t.to_string(), g.to_string()))
+            .collect();
+        let out = dir.join("genes.rad");
+        assert_eq!(remap_references(&input, &out, &t2g).unwrap(), 2);
+
+        let mut rr = RadReader::new(File::open(&out).unwrap()).unwrap();
+        assert_eq!(rr.header.ref_names, vec!["gB", "gA"]);
+        assert_eq!(rr.file_tag_vals.max_ambiguity, Some(2));
+        let c = rr.next().unwrap().unwrap();
+        assert_eq!(c.reads[0].refs, vec![0, 1]);
+        assert_eq!(c.reads[0].dirs, vec![true, false]);
+        assert_eq!((c.reads[1].refs.clone(), c.reads[1].umi), (vec![1], 4));
+        assert!(rr.next().is_none());
+        let mut f = File::open(&out).unwrap();
+        let hdr = RadHeader::from_bytes_checked(&mut f).unwrap();
+        assert!(verify_checksum(&mut f, &hdr).unwrap());
+
+        let partial: HashMap<String, String> = t2g
+            .iter()
+            .filter(|(t, _)| *t != "t2")
+            .map(|(t, g)| (t.clone(), g.clone()))
+            .collect();
+        assert!(matches!(
+            remap_references(&input, &out, &partial),
+            Err(RadError::ReferenceMismatch(_))
+        ));
+
+        // a record naming a reference the header doesn't have
+        let mut rw = RadWriter::new(Cursor::new(Vec::new()));
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        rw.begin_chunk().unwrap();
+        rw.push_record(&ReadRecord {
+            bc: 1,
+            umi: 2,
+            dirs: vec![true],
+            mate_dirs: Vec::new(),
+            refs: vec![3],
+            tags: None,
+        })
+        .unwrap();
+        rw.finish_chunk().unwrap();
+        std::fs::write(&input, rw.finalize_with_chunk_count().unwrap().into_inner()).unwrap();
+        assert!(matches!(
+            remap_references(&input, &out, &t2g),
+            Err(RadError::ReferenceMismatch(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+extern crate memmap2;
+
+use crate as libradicl;
+
+use self::libradicl::{find_exact_in, find_neighbors_in, BarcodeLookupMap};
+use memmap2::Mmap;
+use scroll::Pread;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// The on-disk layout of a prebuilt barcode map is
+//   magic (8 bytes)
+//   bclen, prefix_len, suffix_len, padding (4 x u32)
+//   num_barcodes, num_offsets (2 x u64)
+//   barcodes (num_barcodes x u64)
+//   offsets (num_offsets x u64)
+// all little-endian.  The header is a multiple of 8 bytes so that,
+// since the mapping itself is page-aligned, both arrays can be viewed
+// directly as `&[u64]`.
+const BCMAP_MAGIC: &[u8; 8] = b"RADBCMAP";
+const BCMAP_HEADER_LEN: usize = 40;
+
+impl BarcodeLookupMap {
+    /// Write this map out in the layout expected by
+    /// `MmapBarcodeLookupMap::open`, so that many processes
+    /// can later share a single copy of it through the page cache.
+    pub fn write_mmap_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let mut owriter = BufWriter::new(File::create(path)?);
+        owriter.write_all(BCMAP_MAGIC)?;
+        owriter.write_all(&self.bclen.to_le_bytes())?;
+        owriter.write_all(&self.prefix_len.to_le_bytes())?;
+        owriter.write_all(&self.suffix_len.to_le_bytes())?;
+        owriter.write_all(&0u32.to_le_bytes())?;
+        owriter.write_all(&(self.barcodes.len() as u64).to_le_bytes())?;
+        owriter.write_all(&(self.offsets.len() as u64).to_le_bytes())?;
+        for b in &self.barcodes {
+            owriter.write_all(&b.to_le_bytes())?;
+        }
+        for o in &self.offsets {
+            owriter.write_all(&(*o as u64).to_le_bytes())?;
+        }
+        owriter.flush()?;
+        Ok(())
+    }
+}
+
+/// A read-only `BarcodeLookupMap` whose barcode and offset arrays
+/// live in a memory-mapped file written by
+/// `BarcodeLookupMap::write_mmap_file`.
+pub struct MmapBarcodeLookupMap {
+    mmap: Mmap,
+    num_barcodes: usize,
+    num_offsets: usize,
+    bclen: u32,
+    prefix_len: u32,
+    suffix_len: u32,
+}
+
+impl MmapBarcodeLookupMap {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapBarcodeLookupMap, Box<dyn Error>> {
+        if cfg!(target_endian = "big") {
+            return Err(
+                "memory-mapped barcode maps are only supported on little-endian hosts".into(),
+            );
+        }
+
+        let file = File::open(path)?;
+        // safety: the map is only ever read, and the file is expected
+        // not to be modified while it is in use.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < BCMAP_HEADER_LEN || &mmap[0..8] != BCMAP_MAGIC {
+            return Err("not a prebuilt barcode map file".into());
+        }
+
+        let bclen = mmap.pread_with::<u32>(8, scroll::LE)?;
+        let prefix_len = mmap.pread_with::<u32>(12, scroll::LE)?;
+        let suffix_len = mmap.pread_with::<u32>(16, scroll::LE)?;
+        let num_barcodes = mmap.pread_with::<u64>(24, scroll::LE)? as usize;
+        let num_offsets = mmap.pread_with::<u64>(32, scroll::LE)? as usize;
+
+        let expected_len = BCMAP_HEADER_LEN + 8 * (num_barcodes + num_offsets);
+        if mmap.len() != expected_len {
+            return Err(format!(
+                "barcode map file has {} bytes, but its header implies {}",
+                mmap.len(),
+                expected_len
+            )
+            .into());
+        }
+        if 4usize.checked_pow(prefix_len).map(|n| n + 1) != Some(num_offsets) {
+            return Err("barcode map offset table does not match its prefix length".into());
+        }
+
+        Ok(MmapBarcodeLookupMap {
+            mmap,
+            num_barcodes,
+            num_offsets,
+            bclen,
+            prefix_len,
+            suffix_len,
+        })
+    }
+
+    fn words(&self) -> &[u64] {
+        // safety: the mapping is page-aligned and the header is 8 bytes
+        // wide, so the body is suitably aligned for u64.
+        let (pre, words, _) = unsafe { self.mmap[BCMAP_HEADER_LEN..].align_to::<u64>() };
+        assert!(pre.is_empty(), "mapped barcode map is not 8-byte aligned");
+        words
+    }
+
+    pub fn barcodes(&self) -> &[u64] {
+        &self.words()[..self.num_barcodes]
+    }
+
+    fn offsets(&self) -> &[u64] {
+        &self.words()[self.num_barcodes..(self.num_barcodes + self.num_offsets)]
+    }
+
+    pub fn bclen(&self) -> u32 {
+        self.bclen
+    }
+
+    pub fn barcode_for_idx(&self, idx: usize) -> u64 {
+        self.barcodes()[idx]
+    }
+
+    pub fn find_exact(&self, query: u64) -> Option<usize> {
+        find_exact_in(
+            self.barcodes(),
+            self.offsets(),
+            self.prefix_len,
+            self.suffix_len,
+            query,
+        )
+    }
+
+    /// Behaves exactly as `BarcodeLookupMap::find_neighbors`.
+    pub fn find_neighbors(&self, query: u64, try_exact: bool) -> (Option<usize>, usize) {
+        find_neighbors_in(
+            self.barcodes(),
+            self.offsets(),
+            self.prefix_len,
+            self.suffix_len,
+            query,
+            try_exact,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::libradicl::mmap_bcmap::*;
+    use self::libradicl::BarcodeLookupMap;
+    use crate as libradicl;
+
+    #[test]
+    fn test_mmap_matches_in_memory() {
+        let bclen = 8u32;
+        // a handful of barcodes, including some that are 1 edit apart
+        let bcs: Vec<u64> = vec![0, 1, 2, 17, 300, 301, 4096, 65000, 65535, 12345];
+        let bcmap = BarcodeLookupMap::new(bcs, bclen);
+
+        let path = std::env::temp_dir().join(format!("bcmap_test_{}.bin", std::process::id()));
+        bcmap.write_mmap_file(&path).unwrap();
+        let mmap_bcmap = MmapBarcodeLookupMap::open(&path).unwrap();
+
+        assert_eq!(mmap_bcmap.barcodes(), &bcmap.barcodes[..]);
+        for q in 0..(1u64 << (2 * bclen)) {
+            assert_eq!(mmap_bcmap.find_exact(q), bcmap.find_exact(q));
+            assert_eq!(
+                mmap_bcmap.find_neighbors(q, true),
+                bcmap.find_neighbors(q, true)
+            );
+            assert_eq!(
+                mmap_bcmap.find_neighbors(q, false),
+                bcmap.find_neighbors(q, false)
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+extern crate memmap2;
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::{
+    decode_int_type_tag, Chunk, ChunkIndex, FileTags, RadHeader, RadIntId, RecordViews, TagSection,
+};
+use memmap2::Mmap;
+use scroll::Pread;
+use std::error::Error;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+
+/// A RAD file that has been memory-mapped, so that repeated passes over
+/// the same file are served from the page cache and chunks are parsed
+/// directly out of the mapping rather than copied into temporary buffers.
+pub struct MmapRadFile {
+    mmap: Mmap,
+    pub header: RadHeader,
+    pub file_tags: TagSection,
+    pub read_tags: TagSection,
+    pub aln_tags: TagSection,
+    pub file_tag_vals: FileTags,
+    pub bc_type: RadIntId,
+    pub umi_type: RadIntId,
+    // offset of the first chunk within the mapping
+    data_start: usize,
+    chunk_index: Option<ChunkIndex>,
+}
+
+impl MmapRadFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapRadFile, Box<dyn Error>> {
+        let file = File::open(path)?;
+        // safety: the mapping is read-only, and the RAD file is expected
+        // not to be modified while it is being read.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut cursor = Cursor::new(&mmap[..]);
+        let header = RadHeader::from_bytes_checked(&mut cursor)?;
+        let file_tags = TagSection::from_bytes_checked(&mut cursor)?;
+        let read_tags = TagSection::from_bytes_checked(&mut cursor)?;
+        let aln_tags = TagSection::from_bytes_checked(&mut cursor)?;
+        let file_tag_vals = FileTags::from_bytes_with_section(&mut cursor, &file_tags)?;
+        let data_start = cursor.position() as usize;
+
+        let mut bc_type = None;
+        let mut umi_type = None;
+        for rt in &read_tags.tags {
+            if rt.name == "b" {
+                bc_type = decode_int_type_tag(rt.typeid);
+            } else if rt.name == "u" {
+                umi_type = decode_int_type_tag(rt.typeid);
+            }
+        }
+        let bc_type = bc_type.ok_or("no valid barcode tag description present")?;
+        let umi_type = umi_type.ok_or("no valid umi tag description present")?;
+        file_tag_vals.validate(&bc_type, &umi_type)?;
+        if file_tag_vals.zstd_chunks {
+            return Err("compressed chunks can't be read from a memory-mapped file".into());
+        }
+        let chunk_index = ChunkIndex::read_from(&mut cursor)?;
+
+        Ok(MmapRadFile {
+            mmap,
+            header,
+            file_tags,
+            read_tags,
+            aln_tags,
+            file_tag_vals,
+            bc_type,
+            umi_type,
+            data_start,
+            chunk_index,
+        })
+    }
+
+    /// The portion of the file following the header, tag descriptions
+    /// and file-level tag values; i.e. the chunks themselves.
+    pub fn data(&self) -> &[u8] {
+        &self.mmap[self.data_start..]
+    }
+
+    /// Iterate over the `num_chunks` chunks recorded in the header, without
+    /// copying any of the underlying bytes.
+    pub fn chunks(&self) -> MappedChunkIter<'_> {
+        MappedChunkIter {
+            data: self.data(),
+            offset: 0,
+            remaining: self.header.num_chunks,
+            bct: self.bc_type,
+            umit: self.umi_type,
+        }
+    }
+
+    /// The chunk beginning `offset` bytes into the file (e.g. the `offset`
+    /// of a `ChunkIndexEntry`), or `None` if it would extend past the end
+    /// of the file or can't hold its own header.
+    pub fn chunk_at(&self, offset: u64) -> Option<MappedChunk<'_>> {
+        let data = self.mmap.get(offset as usize..)?;
+        let nbytes = data.pread::<u32>(0).ok()?;
+        let nrec = data.pread::<u32>(4).ok()?;
+        if nbytes < 8 {
+            return None;
+        }
+        Some(MappedChunk {
+            nbytes,
+            nrec,
+            data: data.get(..nbytes as usize)?,
+            bct: self.bc_type,
+            umit: self.umi_type,
+        })
+    }
+
+    /// The chunk index of the file, if it has one.
+    pub fn chunk_index(&self) -> Option<&ChunkIndex> {
+        self.chunk_index.as_ref()
+    }
+
+    /// The chunk holding the cell with barcode `bc`, found through the
+    /// chunk index (see `ChunkIndex::find_cell`).  Returns `None` if the
+    /// file has no index or no chunk holds `bc`.
+    pub fn chunk_for_cell(&self, bc: u128) -> Option<MappedChunk<'_>> {
+        let index = self.chunk_index.as_ref()?;
+        let i = index.find_cell(bc)?;
+        self.chunk_at(index.entries[i].offset)
+    }
+}
+
+/// A view of a single chunk within a memory-mapped RAD file.  The
+/// `data` slice spans the whole chunk, including its 8 byte header.
+pub struct MappedChunk<'a> {
+    pub nbytes: u32,
+    pub nrec: u32,
+    pub data: &'a [u8],
+    bct: RadIntId,
+    umit: RadIntId,
+}
+
+impl<'a> MappedChunk<'a> {
+    /// The barcode and umi of the first record in this chunk, as
+    /// given by `Chunk::peek_record`.
+    pub fn peek_record(&self) -> std::io::Result<(u64, u64)> {
+        Chunk::peek_record(&self.data[8..], &self.bct, &self.umit)
+    }
+
+    /// Iterate over views of the records of this chunk, borrowing
+    /// directly from the mapping.  A record that runs past the end of
+    /// the chunk is yielded as an error.
+    pub fn records(&self) -> RecordViews<'a> {
+        // `data` always spans at least the chunk header
+        RecordViews::new(self.data, &self.bct, &self.umit).unwrap()
+    }
+
+    /// Parse this chunk into an owned `Chunk`, equivalent to calling
+    /// `Chunk::from_bytes_checked` on the same bytes, so that an error is
+    /// returned if its records don't exactly span the chunk.
+    pub fn to_chunk(&self) -> Result<Chunk, RadError> {
+        Chunk::from_bytes_checked(&mut &self.data[..], &self.bct, &self.umit)
+    }
+}
+
+pub struct MappedChunkIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+    remaining: u64,
+    bct: RadIntId,
+    umit: RadIntId,
+}
+
+impl<'a> Iterator for MappedChunkIter<'a> {
+    type Item = MappedChunk<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let nbytes = self.data.pread::<u32>(self.offset).ok()?;
+        let nrec = self.data.pread::<u32>(self.offset + 4).ok()?;
+        if nbytes < 8 {
+            return None;
+        }
+        let end = self.offset + nbytes as usize;
+        let data = self.data.get(self.offset..end)?;
+        self.offset = end;
+        self.remaining -= 1;
+        Some(MappedChunk {
+            nbytes,
+            nrec,
+            data,
+            bct: self.bct,
+            umit: self.umit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::libradicl::mmap_rad::*;
+    use self::libradicl::{FileTags, RadHeader, RadIntId, RadWriter, ReadRecord};
+    use crate as libradicl;
+
+    #[test]
+    fn test_mmap_chunk_for_cell() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 1,
+            ref_names: vec!["a".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 3,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.enable_chunk_index().unwrap();
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        for bc in 0..3u64 {
+            rw.begin_chunk().unwrap();
+            for umi in 0..=bc {
+                let rec = ReadRecord {
+                    bc: 10 * bc,
+                    umi,
+                    dirs: vec![true],
+                    mate_dirs: Vec::new(),
+                    refs: vec![0],
+                    tags: None,
+                };
+                rw.push_record(&rec).unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let bytes = rw.finalize().unwrap();
+
+        let path = std::env::temp_dir().join(format!("mmap_rad_test_{}.rad", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let mf = MmapRadFile::open(&path).unwrap();
+
+        assert_eq!(mf.chunk_index().unwrap().entries.len(), 3);
+        assert_eq!(mf.chunks().count(), 3);
+        let c = mf.chunk_for_cell(20).unwrap();
+        assert_eq!(c.peek_record().unwrap(), (20, 0));
+        let c = c.to_chunk().unwrap();
+        assert_eq!(c.reads.len(), 3);
+        assert!(c.reads.iter().all(|r| r.bc == 20));
+        let c = mf.chunk_for_cell(20).unwrap();
+        assert_eq!(
+            c.records().map(|r| r.unwrap().umi).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(mf.chunk_for_cell(5).is_none());
+        drop(mf);
+
+        // give the first record of the last chunk more alignments than fit
+        let mut bad = bytes.clone();
+        let off = MmapRadFile::open(&path)
+            .unwrap()
+            .chunk_index()
+            .unwrap()
+            .entries[2]
+            .offset;
+        let na = off as usize + 8;
+        bad[na..na + 4].copy_from_slice(&1000u32.to_le_bytes());
+        std::fs::write(&path, &bad).unwrap();
+        let mf = MmapRadFile::open(&path).unwrap();
+        assert!(mf.chunk_at(off).unwrap().to_chunk().is_err());
+        assert!(mf.chunk_at(off).unwrap().records().next().unwrap().is_err());
+        assert!(mf.chunk_for_cell(10).unwrap().to_chunk().is_ok());
+        drop(mf);
+
+        // a chunk too small to hold its own header
+        let mut bad = bytes;
+        bad[off as usize..off as usize + 4].copy_from_slice(&4u32.to_le_bytes());
+        std::fs::write(&path, &bad).unwrap();
+        let mf = MmapRadFile::open(&path).unwrap();
+        assert!(mf.chunk_at(off).is_none());
+        assert_eq!(mf.chunks().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,726 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Decoding of (possibly compressed) RAD files on several threads.  One
+// thread reads the file and splits it into independently decodable
+// blobs, a rayon pool decompresses and parses the blobs into chunks, and
+// the iterator handed to the caller puts the chunks back in file order.
+// `RadReader::par_chunks` does the same for an open `RadReader`, whose
+// reading thread hands batches of whole (possibly zstd compressed) chunks
+// to the pool.
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::{
+    decode_int_type_tag, rad_error_to_io, read_exact_bounded, Chunk, CompressionCodec, FileTags,
+    RadHeader, RadIntId, RadReader, TagSection,
+};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use scroll::Pread;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::Path;
+use std::thread;
+
+// the type of the frame that begins every snappy frame stream
+const SNAPPY_STREAM_ID: u8 = 0xff;
+
+enum Blob {
+    // whole, uncompressed chunks
+    Raw(Vec<u8>),
+    // a complete snappy frame stream that decodes to whole chunks
+    Snappy(Vec<u8>),
+}
+
+struct Job {
+    seq: u64,
+    blob: Blob,
+    // whether the records of each chunk are compressed with zstd
+    zstd: bool,
+    // whether the records are those of a paired-end file
+    paired: bool,
+    bct: RadIntId,
+    umit: RadIntId,
+}
+
+type Decoded = (u64, Result<Vec<Chunk>, RadError>);
+
+// Read the header, tag sections and file-level tag values at the start of
+// a RAD file, and return the types of the barcode and umi, whether the
+// records of each chunk are zstd compressed, and whether the file is
+// paired-end.
+fn read_prelude<R: Read>(reader: &mut R) -> Result<(RadIntId, RadIntId, bool, bool), RadError> {
+    let hdr = RadHeader::from_bytes_checked(reader)?;
+    let file_tags = TagSection::from_bytes_checked(reader)?;
+    let read_tags = TagSection::from_bytes_checked(reader)?;
+    TagSection::from_bytes_checked(reader)?;
+    let ft = FileTags::from_bytes_with_section(reader, &file_tags)?;
+
+    let tag_type = |name: &str| {
+        let t = read_tags
+            .get(name)
+            .ok_or_else(|| RadError::MissingTag(name.to_string()))?;
+        decode_int_type_tag(t.typeid).ok_or(RadError::UnsupportedTypeTag(t.typeid))
+    };
+    let bct = tag_type("b")?;
+    let umit = tag_type("u")?;
+    ft.validate(&bct, &umit)?;
+    Ok((bct, umit, ft.zstd_chunks, hdr.is_paired != 0))
+}
+
+// Read the 4 byte header of the next snappy frame, or None at the end of input.
+fn read_frame_header<R: Read>(reader: &mut R) -> io::Result<Option<[u8; 4]>> {
+    let mut hdr = [0u8; 4];
+    let mut filled = 0;
+    while filled < hdr.len() {
+        match reader.read(&mut hdr[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    match filled {
+        0 => Ok(None),
+        4 => Ok(Some(hdr)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated snappy frame header",
+        )),
+    }
+}
+
+// Splits a concatenation of snappy frame streams (as written by collation
+// with compression on, where the header and each bucket are compressed
+// separately) into the individual streams, without decompressing them.
+struct SnappyStreams<R> {
+    reader: R,
+    // the header of the first frame of the next stream, if already read
+    pending: Option<[u8; 4]>,
+}
+
+impl<R: Read> SnappyStreams<R> {
+    fn next_stream(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut hdr = match self.pending.take() {
+            Some(h) => h,
+            None => match read_frame_header(&mut self.reader)? {
+                Some(h) => h,
+                None => return Ok(None),
+            },
+        };
+        let mut stream = Vec::new();
+        loop {
+            let len = u32::from_le_bytes([hdr[1], hdr[2], hdr[3], 0]) as usize;
+            let start = stream.len() + hdr.len();
+            stream.extend_from_slice(&hdr);
+            stream.resize(start + len, 0);
+            self.reader.read_exact(&mut stream[start..])?;
+
+            match read_frame_header(&mut self.reader)? {
+                Some(h) if h[0] == SNAPPY_STREAM_ID => {
+                    self.pending = Some(h);
+                    break;
+                }
+                Some(h) => hdr = h,
+                None => break,
+            }
+        }
+        Ok(Some(stream))
+    }
+}
+
+fn decompress_snappy(stream: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    snap::read::FrameDecoder::new(stream).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+// Read the file at `path` and submit its chunks, as blobs, to `decoders`.
+// `seq` is the sequence number of the next blob, so that on error the
+// caller knows where in the output the error belongs.
+fn read_blobs(
+    path: &Path,
+    codec: CompressionCodec,
+    num_chunks: u64,
+    decoders: &Decoders,
+    seq: &mut u64,
+) -> io::Result<()> {
+    // returns false once the output was dropped
+    let mut send = |blob: Blob, (bct, umit, zstd, paired): (RadIntId, RadIntId, bool, bool)| {
+        let sent = decoders.submit(Job {
+            seq: *seq,
+            blob,
+            zstd,
+            paired,
+            bct,
+            umit,
+        });
+        *seq += 1;
+        sent
+    };
+
+    match codec {
+        CompressionCodec::None => {
+            let mut reader = BufReader::new(File::open(path)?);
+            let prelude = read_prelude(&mut reader).map_err(rad_error_to_io)?;
+            let mut buf = [0u8; 8];
+            for _ in 0..num_chunks {
+                reader.read_exact(&mut buf)?;
+                let nbytes = buf.pread::<u32>(0).unwrap();
+                if (nbytes as usize) < buf.len() {
+                    return Err(rad_error_to_io(RadError::InvalidChunkSize(nbytes)));
+                }
+                let mut blob = buf.to_vec();
+                read_exact_bounded(&mut reader, &mut blob, nbytes as usize - buf.len())?;
+                if !send(Blob::Raw(blob), prelude) {
+                    break;
+                }
+            }
+        }
+        CompressionCodec::Snappy => {
+            let mut streams = SnappyStreams {
+                reader: BufReader::new(File::open(path)?),
+                pending: None,
+            };
+            // the header is compressed along with (at most) the start of the data
+            let first = streams
+                .next_stream()?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "the file is empty"))?;
+            let first = decompress_snappy(&first)?;
+            let mut cursor = Cursor::new(&first[..]);
+            let prelude = read_prelude(&mut cursor).map_err(rad_error_to_io)?;
+            let rest = &first[cursor.position() as usize..];
+            if !rest.is_empty() && !send(Blob::Raw(rest.to_vec()), prelude) {
+                return Ok(());
+            }
+            while let Some(s) = streams.next_stream()? {
+                if !send(Blob::Snappy(s), prelude) {
+                    break;
+                }
+            }
+        }
+        #[cfg(feature = "zstd")]
+        CompressionCodec::Zstd => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "parallel decoding of zstd compressed files is not supported",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Decompress (if need be) and parse the chunks of `job`.
+fn decode(job: Job) -> Result<Vec<Chunk>, RadError> {
+    let data = match job.blob {
+        Blob::Raw(b) => b,
+        Blob::Snappy(b) => decompress_snappy(&b)?,
+    };
+    let mut chunks = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let nbytes = match rest.pread::<u32>(0) {
+            Ok(n) if n >= 8 && n as usize <= rest.len() => n as usize,
+            _ => {
+                return Err(RadError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("truncated chunk in a block of {} bytes", rest.len()),
+                )))
+            }
+        };
+        let mut c = libradicl::read_chunk(
+            &mut Cursor::new(&rest[..nbytes]),
+            job.zstd,
+            &job.bct,
+            &job.umit,
+        )?;
+        if job.paired {
+            c.split_mate_ori();
+        }
+        chunks.push(c);
+        rest = &rest[nbytes..];
+    }
+    Ok(chunks)
+}
+
+// Puts the chunks decoded by the pool back in the order of their blobs.
+struct Reorder {
+    results: Receiver<Decoded>,
+    // a permit is taken for each blob submitted to the pool, and given
+    // back once its chunks are handed out (see `Decoders`)
+    permits: Receiver<()>,
+    // decoded blobs that arrived before some blob preceding them
+    pending: BTreeMap<u64, Result<Vec<Chunk>, RadError>>,
+    next_seq: u64,
+    ready: VecDeque<Chunk>,
+    done: bool,
+}
+
+impl Reorder {
+    fn next_chunk(&mut self) -> Option<Result<Chunk, RadError>> {
+        loop {
+            if let Some(c) = self.ready.pop_front() {
+                return Some(Ok(c));
+            }
+            if self.done {
+                return None;
+            }
+            if let Some(r) = self.pending.remove(&self.next_seq) {
+                self.next_seq += 1;
+                // an error of the reading thread holds no permit, but
+                // nothing is handed out after it anyway
+                let _ = self.permits.try_recv();
+                match r {
+                    Ok(chunks) => self.ready.extend(chunks),
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+                continue;
+            }
+            match self.results.recv() {
+                Ok((seq, r)) => {
+                    self.pending.insert(seq, r);
+                }
+                Err(_) => {
+                    // every task has finished; anything still pending
+                    // means a blob went missing
+                    self.done = true;
+                    if !self.pending.is_empty() {
+                        return Some(Err(RadError::Io(io::Error::new(
+                            io::ErrorKind::Other,
+                            "a decoding task exited before finishing its work",
+                        ))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Decodes the jobs submitted by a reading thread on a rayon pool, sending
+// the results to the `Reorder` created along with it by `spawn_decoders`.
+// At most `2 * num_workers` blobs are held at once, whether waiting to be
+// decoded or waiting to be handed out in order, since a permit is needed to
+// submit a job and the `Reorder` gives it back only once it reaches that
+// job's chunks.
+struct Decoders {
+    // None if the pool couldn't be built, in which case the error has
+    // already been sent in place of the first blob
+    pool: Option<rayon::ThreadPool>,
+    permits: Sender<()>,
+    results: Sender<Decoded>,
+}
+
+impl Decoders {
+    // Decode `job` on the pool, once a permit is available.  Returns false
+    // if the output was dropped (or there is no pool), so that reading
+    // should stop.
+    fn submit(&self, job: Job) -> bool {
+        let pool = match self.pool.as_ref() {
+            Some(p) => p,
+            None => return false,
+        };
+        if self.permits.send(()).is_err() {
+            return false;
+        }
+        let results = self.results.clone();
+        pool.spawn(move || {
+            let seq = job.seq;
+            let _ = results.send((seq, decode(job)));
+        });
+        true
+    }
+
+    // Report an error of the reading thread, in place of blob `seq`.
+    fn fail(&self, seq: u64, e: RadError) {
+        let _ = self.results.send((seq, Err(e)));
+    }
+}
+
+// Build a pool of `num_workers` threads to decode the jobs submitted to the
+// returned `Decoders`, whose results are put back in order by the returned
+// `Reorder`.
+fn spawn_decoders(num_workers: usize) -> (Decoders, Reorder) {
+    let num_workers = num_workers.max(1);
+    let (permit_tx, permit_rx) = bounded::<()>(2 * num_workers);
+    // one more than the permits, for an error of the reading thread
+    let (res_tx, res_rx) = bounded::<Decoded>(2 * num_workers + 1);
+
+    let pool = match rayon::ThreadPoolBuilder::new()
+        .num_threads(num_workers)
+        .build()
+    {
+        Ok(p) => Some(p),
+        Err(e) => {
+            let e = io::Error::new(io::ErrorKind::Other, e.to_string());
+            let _ = res_tx.send((0, Err(RadError::Io(e))));
+            None
+        }
+    };
+    let decoders = Decoders {
+        pool,
+        permits: permit_tx,
+        results: res_tx,
+    };
+
+    let reorder = Reorder {
+        results: res_rx,
+        permits: permit_rx,
+        pending: BTreeMap::new(),
+        next_seq: 0,
+        ready: VecDeque::new(),
+        done: false,
+    };
+    (decoders, reorder)
+}
+
+/// The iterator returned by `parallel_decode`.
+pub struct ParallelDecoder {
+    inner: Reorder,
+}
+
+impl Iterator for ParallelDecoder {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next_chunk().map(|r| r.map_err(rad_error_to_io))
+    }
+}
+
+/// Decode the chunks of the RAD file at `path`, whose header is `header`,
+/// using one thread to read the file and `num_workers` threads to
+/// decompress (according to `codec`) and parse it.  Chunks are yielded in
+/// the order in which they appear in the file, and iteration stops after
+/// the first error.  The work queues are bounded, so that only a few
+/// blobs per worker are ever in memory, however large the file.
+///
+/// Uncompressed files are read up to `header.num_chunks` chunks.  Snappy
+/// compressed files are read to the end, and must consist of separately
+/// compressed streams that each hold whole chunks, as written by collation.
+pub fn parallel_decode<P: AsRef<Path>>(
+    path: P,
+    header: &RadHeader,
+    codec: CompressionCodec,
+    num_workers: usize,
+) -> ParallelDecoder {
+    let (decoders, inner) = spawn_decoders(num_workers);
+
+    let path = path.as_ref().to_path_buf();
+    let num_chunks = header.num_chunks;
+    thread::spawn(move || {
+        let mut seq = 0u64;
+        if let Err(e) = read_blobs(&path, codec, num_chunks, &decoders, &mut seq) {
+            decoders.fail(seq, RadError::Io(e));
+        }
+    });
+
+    ParallelDecoder { inner }
+}
+
+/// The iterator returned by `RadReader::par_chunks`.
+pub struct ParChunks {
+    inner: Reorder,
+}
+
+impl Iterator for ParChunks {
+    type Item = Result<Chunk, RadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next_chunk()
+    }
+}
+
+impl<R: Read + Send + 'static> RadReader<R> {
+    /// Consume the remaining chunks of this reader, decoding them on a
+    /// rayon pool of `num_workers` threads as `parallel_decode` does.  A
+    /// separate thread reads the chunks as raw bytes and hands them to the
+    /// pool `batch_size` at a time.  Chunks are yielded in file order, and
+    /// only a few batches per worker are held in memory at once.  Iteration
+    /// stops after the first error.
+    pub fn par_chunks(mut self, num_workers: usize, batch_size: usize) -> ParChunks {
+        let batch_size = batch_size.max(1);
+        let (decoders, inner) = spawn_decoders(num_workers);
+        thread::spawn(move || {
+            let zstd = self.file_tag_vals.zstd_chunks;
+            let paired = self.header.is_paired != 0;
+            let (bct, umit) = (self.bc_type, self.umi_type);
+            let mut seq = 0u64;
+            let mut buf = Vec::new();
+            while self.remaining > 0 {
+                let n = self.remaining.min(batch_size as u64);
+                self.remaining -= n;
+                // the chunks of the batch, one after the other
+                let mut blob = Vec::new();
+                let mut read_err = None;
+                for _ in 0..n {
+                    let res = match self.stream.as_mut() {
+                        Some(stream) => stream.read_raw(&mut self.reader, &mut buf),
+                        None => Chunk::read_raw(&mut self.reader, &mut buf).map(|_| true),
+                    };
+                    match res {
+                        Ok(true) => blob.extend_from_slice(&buf),
+                        Ok(false) => {
+                            self.remaining = 0;
+                            break;
+                        }
+                        Err(e) => {
+                            read_err = Some(RadError::from(e));
+                            break;
+                        }
+                    }
+                }
+
+                if !blob.is_empty() {
+                    let job = Job {
+                        seq,
+                        blob: Blob::Raw(blob),
+                        zstd,
+                        paired,
+                        bct,
+                        umit,
+                    };
+                    seq += 1;
+                    if !decoders.submit(job) {
+                        return;
+                    }
+                }
+                if let Some(e) = read_err {
+                    decoders.fail(seq, e);
+                    return;
+                }
+            }
+        });
+        ParChunks { inner }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RadWriter, ReadRecord};
+    use std::io::Write;
+
+    fn summarize(c: &Chunk) -> Vec<(u64, u64, Vec<u32>, Vec<bool>)> {
+        c.reads
+            .iter()
+            .map(|r| (r.bc, r.umi, r.refs.clone(), r.dirs.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_decode_preserves_order() {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 5,
+            ref_names: (0..5).map(|i| format!("t{}", i)).collect(),
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 40,
+        };
+        let ft = FileTags {
+            bclen: 8,
+            umilen: 8,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let header_len = {
+            let mut hw = RadWriter::new(Cursor::new(Vec::new()));
+            hw.write_header(&hdr, &ft, RadIntId::U16, RadIntId::U16)
+                .unwrap();
+            hw.finalize_with_chunk_count().unwrap().into_inner().len()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U16, RadIntId::U16)
+            .unwrap();
+        for i in 0..40u64 {
+            rw.begin_chunk().unwrap();
+            for j in 0..(i % 7) {
+                rw.push_record(&ReadRecord {
+                    bc: i,
+                    umi: j,
+                    dirs: vec![j % 2 == 0; (j % 3 + 1) as usize],
+                    mate_dirs: Vec::new(),
+                    refs: (0..(j % 3 + 1) as u32).collect(),
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        let buf = rw.finalize().unwrap();
+
+        let mut reader = Cursor::new(&buf[header_len..]);
+        let expected: Vec<_> = (0..40)
+            .map(|_| {
+                summarize(&Chunk::from_bytes(
+                    &mut reader,
+                    &RadIntId::U16,
+                    &RadIntId::U16,
+                ))
+            })
+            .collect();
+
+        let dir = std::env::temp_dir().join(format!("parallel_decode_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // uncompressed
+        let raw_path = dir.join("raw.rad");
+        std::fs::write(&raw_path, &buf).unwrap();
+        let observed: Vec<_> = parallel_decode(&raw_path, &hdr, CompressionCodec::None, 3)
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(observed, expected);
+
+        // snappy, with the header and then every 6 chunks compressed separately
+        let mut compressed = Vec::new();
+        let mut pieces = vec![&buf[..header_len]];
+        let mut offset = header_len;
+        let mut reader = Cursor::new(&buf[header_len..]);
+        for group in expected.chunks(6) {
+            let start = reader.position();
+            for _ in group {
+                Chunk::<u64>::from_bytes(&mut reader, &RadIntId::U16, &RadIntId::U16);
+            }
+            let len = (reader.position() - start) as usize;
+            pieces.push(&buf[offset..offset + len]);
+            offset += len;
+        }
+        for p in pieces {
+            let mut enc = snap::write::FrameEncoder::new(Vec::new());
+            enc.write_all(p).unwrap();
+            compressed.extend(enc.into_inner().unwrap());
+        }
+        let snappy_path = dir.join("snappy.rad");
+        std::fs::write(&snappy_path, &compressed).unwrap();
+        let observed: Vec<_> = parallel_decode(&snappy_path, &hdr, CompressionCodec::Snappy, 4)
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(observed, expected);
+
+        // a truncated file ends with an error after the chunks that are intact
+        std::fs::write(&raw_path, &buf[..buf.len() - 3]).unwrap();
+        let results: Vec<_> = parallel_decode(&raw_path, &hdr, CompressionCodec::None, 2).collect();
+        assert_eq!(results.len(), 40);
+        assert!(results[..39].iter().all(|r| r.is_ok()));
+        assert!(results[39].is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // `num_chunks` chunks of 1 to 4 records, with their records compressed
+    // if `zstd` is set
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    fn par_chunks_test_file(num_chunks: u64, zstd: bool) -> Vec<u8> {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 3,
+            ref_names: (0..3).map(|i| format!("t{}", i)).collect(),
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks,
+        };
+        let ft = FileTags {
+            bclen: 8,
+            umilen: 8,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        #[cfg(feature = "zstd")]
+        if zstd {
+            rw.enable_chunk_compression(3).unwrap();
+        }
+        rw.write_header(&hdr, &ft, RadIntId::U16, RadIntId::U32)
+            .unwrap();
+        for i in 0..num_chunks {
+            rw.begin_chunk().unwrap();
+            for j in 0..(i % 4 + 1) {
+                rw.push_record(&ReadRecord {
+                    bc: i,
+                    umi: j,
+                    dirs: vec![j % 2 == 0],
+                    mate_dirs: Vec::new(),
+                    refs: vec![(j % 3) as u32],
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        rw.finalize().unwrap()
+    }
+
+    #[test]
+    fn test_par_chunks_matches_sequential() {
+        let buf = par_chunks_test_file(11, false);
+        let expected: Vec<_> = RadReader::new(Cursor::new(buf.clone()))
+            .unwrap()
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(expected.len(), 11);
+        let observed: Vec<_> = RadReader::new(Cursor::new(buf.clone()))
+            .unwrap()
+            .par_chunks(2, 3)
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(observed, expected);
+
+        // a truncated file ends with an error after the chunks that are intact
+        let results: Vec<_> = RadReader::new(Cursor::new(buf[..buf.len() - 3].to_vec()))
+            .unwrap()
+            .par_chunks(3, 4)
+            .collect();
+        assert_eq!(results.len(), 11);
+        assert!(results[..10].iter().all(|r| r.is_ok()));
+        assert!(results[10].is_err());
+
+        // dropping the iterator early leaves no thread waiting on the pool
+        let first: Vec<_> = RadReader::new(Cursor::new(buf.clone()))
+            .unwrap()
+            .par_chunks(1, 1)
+            .take(2)
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(first, expected[..2]);
+
+        // the chunks of a file with no chunk count are read to its end
+        let mut unsized_buf = par_chunks_test_file(0, false);
+        let header_len = unsized_buf.len();
+        unsized_buf.extend_from_slice(&buf[header_len..]);
+        let rr = RadReader::from_stream(Cursor::new(unsized_buf)).unwrap();
+        let observed: Vec<_> = rr
+            .par_chunks(2, 4)
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(observed, expected);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_par_chunks_decompresses_zstd_chunks() {
+        let expected: Vec<_> = RadReader::new(Cursor::new(par_chunks_test_file(11, false)))
+            .unwrap()
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        let buf = par_chunks_test_file(11, true);
+        let rr = RadReader::new(Cursor::new(buf)).unwrap();
+        assert!(rr.file_tag_vals.zstd_chunks);
+        let observed: Vec<_> = rr
+            .par_chunks(3, 2)
+            .map(|c| summarize(&c.unwrap()))
+            .collect();
+        assert_eq!(observed, expected);
+    }
+}
@@ -17,13 +17,14 @@ use self::slog::{crit, warn};
 use ahash::{AHasher, RandomState};
 use arrayvec::ArrayVec;
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use petgraph::prelude::*;
 use petgraph::unionfind::*;
 use petgraph::visit::NodeIndexable;
+use rayon::prelude::*;
 
-use crate::schema::{EqMap, PugResolutionStatistics, SplicedAmbiguityModel};
+use crate::schema::{EqMap, PugEdgeType, PugResolutionStatistics, SplicedAmbiguityModel};
 use crate::utils;
 
 type CcMap = HashMap<u32, Vec<u32>, ahash::RandomState>;
@@ -704,6 +705,147 @@ fn get_num_molecules_large_component(
     counts
 }
 
+/// The number of leading UMI bases used to partition vertices
+/// in `extract_graph_parallel`.
+const PUG_PARTITION_BASES: usize = 3;
+
+type PugEdge = ((u32, u32), (u32, u32));
+
+/// Given 2 pairs (UMI, count), determine if an edge exists
+/// between them, and if so, what type.
+pub(super) fn pug_edge_type(x: &(u64, u32), y: &(u64, u32)) -> PugEdgeType {
+    let hdist = utils::count_diff_2_bit_packed(x.0, y.0);
+    if hdist == 0 {
+        return PugEdgeType::BiDirected;
+    }
+    if hdist < 2 {
+        if x.1 > (2 * y.1 - 1) {
+            return PugEdgeType::XToY;
+        } else if y.1 > (2 * x.1 - 1) {
+            return PugEdgeType::YToX;
+        } else {
+            return PugEdgeType::BiDirected;
+        }
+    }
+    PugEdgeType::NoEdge
+}
+
+/// Builds the same PUG as the sequential `extract_graph` in `quant`,
+/// but on the current rayon pool.  Vertices are partitioned by the
+/// first `PUG_PARTITION_BASES` bases of their UMI and each partition
+/// is searched for edges independently.  Two UMIs at distance 1 that
+/// fall in different partitions must share the rest of their bases, so
+/// the cross-partition edges are then found by grouping vertices on that
+/// remaining suffix.  All edges are sorted before being inserted, so the
+/// graph doesn't depend on the number of threads.
+pub(super) fn extract_graph_parallel(
+    eqmap: &EqMap,
+    umi_len: usize,
+) -> petgraph::graphmap::GraphMap<(u32, u32), (), petgraph::Directed> {
+    let shift = 2 * (umi_len - PUG_PARTITION_BASES.min(umi_len));
+    let suffix_mask: u64 = (1u64 << shift) - 1;
+
+    // all vertices, in the order `extract_graph` adds them
+    let mut nodes: Vec<(u32, u32)> = Vec::new();
+    for (eqid, eq) in eqmap.eqc_info.iter().enumerate() {
+        for xi in 0..eq.umis.len() {
+            nodes.push((eqid as u32, xi as u32));
+        }
+    }
+
+    // the (sorted) equivalence classes sharing a reference with each class
+    let neighbors: Vec<Vec<u32>> = (0..eqmap.num_eq_classes())
+        .into_par_iter()
+        .map(|eqid| {
+            let mut v: Vec<u32> = eqmap
+                .refs_for_eqc(eqid as u32)
+                .iter()
+                .flat_map(|r| eqmap.eq_classes_containing(*r).iter().copied())
+                .collect();
+            v.sort_unstable();
+            v.dedup();
+            v
+        })
+        .collect();
+
+    let umi_of =
+        |n: &(u32, u32)| -> &(u64, u32) { &eqmap.eqc_info[n.0 as usize].umis[n.1 as usize] };
+    let push_edges = |a: &(u32, u32), b: &(u32, u32), out: &mut Vec<PugEdge>| {
+        if a.0 != b.0 && neighbors[a.0 as usize].binary_search(&b.0).is_err() {
+            return;
+        }
+        match pug_edge_type(umi_of(a), umi_of(b)) {
+            PugEdgeType::BiDirected => {
+                out.push((*a, *b));
+                out.push((*b, *a));
+            }
+            PugEdgeType::XToY => out.push((*a, *b)),
+            PugEdgeType::YToX => out.push((*b, *a)),
+            PugEdgeType::NoEdge => {}
+        }
+    };
+
+    let mut by_prefix: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+    let mut by_suffix: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+    for (i, n) in nodes.iter().enumerate() {
+        let umi = umi_of(n).0;
+        by_prefix
+            .entry(umi >> shift)
+            .or_insert_with(Vec::new)
+            .push(i);
+        by_suffix
+            .entry(umi & suffix_mask)
+            .or_insert_with(Vec::new)
+            .push(i);
+    }
+    let by_prefix: Vec<Vec<usize>> = by_prefix.into_iter().map(|(_, v)| v).collect();
+    let by_suffix: Vec<Vec<usize>> = by_suffix.into_iter().map(|(_, v)| v).collect();
+
+    // edges within a prefix partition
+    let within: Vec<Vec<PugEdge>> = by_prefix
+        .par_iter()
+        .map(|part| {
+            let mut out = Vec::new();
+            for (i, a) in part.iter().enumerate() {
+                for b in &part[i + 1..] {
+                    push_edges(&nodes[*a], &nodes[*b], &mut out);
+                }
+            }
+            out
+        })
+        .collect();
+
+    // edges between vertices that differ only in their prefix
+    let across: Vec<Vec<PugEdge>> = by_suffix
+        .par_iter()
+        .map(|part| {
+            let mut out = Vec::new();
+            for (i, a) in part.iter().enumerate() {
+                for b in &part[i + 1..] {
+                    let (na, nb) = (&nodes[*a], &nodes[*b]);
+                    if (umi_of(na).0 >> shift) != (umi_of(nb).0 >> shift) {
+                        push_edges(na, nb, &mut out);
+                    }
+                }
+            }
+            out
+        })
+        .collect();
+
+    let mut edges: Vec<PugEdge> = within.into_iter().chain(across).flatten().collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    let mut graph = DiGraphMap::<(u32, u32), ()>::new();
+    for n in &nodes {
+        graph.add_node(*n);
+    }
+    for (a, b) in edges {
+        graph.add_edge(a, b, ());
+    }
+    graph
+}
+
 /// Given the digraph `g` representing the PUGs within the current
 /// cell, the EqMap `eqmap` to decode all equivalence classes
 /// and the transcript-to-gene map `tid_to_gid`, apply the parsimonious
@@ -22,6 +22,7 @@ use self::petgraph::prelude::*;
 use self::slog::{crit, info, warn};
 use crate as libradicl;
 use crossbeam_queue::ArrayQueue;
+use libradicl::exit_codes;
 
 use needletail::bitkmer::*;
 use num_format::{Locale, ToFormattedString};
@@ -44,7 +45,9 @@ use std::thread;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
-use self::libradicl::em::{em_optimize, em_optimize_subset, run_bootstrap, EmInitType};
+use self::libradicl::em::{
+    em_optimize_subset, em_optimize_with_params, run_bootstrap, EmInitType, EmParams,
+};
 use self::libradicl::pugutils;
 use self::libradicl::schema::{
     EqMap, IndexedEqList, PugEdgeType, ResolutionStrategy, SplicedAmbiguityModel,
@@ -74,23 +77,15 @@ fn extract_graph(
     // given 2 pairs (UMI, count), determine if an edge exists
     // between them, and if so, what type.
     let mut has_edge = |x: &(u64, u32), y: &(u64, u32)| -> PugEdgeType {
-        let hdist = count_diff_2_bit_packed(x.0, y.0);
-        if hdist == 0 {
-            zero_edit += 1;
-            return PugEdgeType::BiDirected;
-        }
-
-        if hdist < 2 {
-            one_edit += 1;
-            if x.1 > (2 * y.1 - 1) {
-                return PugEdgeType::XToY;
-            } else if y.1 > (2 * x.1 - 1) {
-                return PugEdgeType::YToX;
+        let et = pugutils::pug_edge_type(x, y);
+        if !matches!(et, PugEdgeType::NoEdge) {
+            if x.0 == y.0 {
+                zero_edit += 1;
             } else {
-                return PugEdgeType::BiDirected;
+                one_edit += 1;
             }
         }
-        PugEdgeType::NoEdge
+        et
     };
 
     let mut _bidirected = 0u64;
@@ -261,6 +256,28 @@ fn extract_graph(
     graph
 }
 
+/// Cells with at least this many reads have their PUG built on the
+/// `pug_pool` passed to `do_quantify`, if there is one.
+const PARALLEL_PUG_MIN_READS: u32 = 10_000;
+
+/// Build the PUG of the cell in `eqmap`, which has `nrec` reads, on
+/// `pug_pool` if there is one and the cell is large enough to be worth
+/// it, and sequentially otherwise.  Either way, the graph is the same.
+fn build_graph(
+    eqmap: &EqMap,
+    nrec: u32,
+    umi_len: usize,
+    pug_pool: Option<&rayon::ThreadPool>,
+    log: &slog::Logger,
+) -> petgraph::graphmap::GraphMap<(u32, u32), (), petgraph::Directed> {
+    match pug_pool {
+        Some(pool) if nrec >= PARALLEL_PUG_MIN_READS => {
+            pool.install(|| pugutils::extract_graph_parallel(eqmap, umi_len))
+        }
+        _ => extract_graph(eqmap, log),
+    }
+}
+
 type BufferedGzFile = BufWriter<GzEncoder<fs::File>>;
 struct BootstrapHelper {
     bsfile: Option<BufferedGzFile>,
@@ -312,11 +329,59 @@ impl BootstrapHelper {
     }
 }
 
+/// Writes a matrix in the MatrixMarket coordinate format one row at a time,
+/// producing exactly the output of `sprs::io::write_matrix_market` on a
+/// triplet matrix holding the same entries in the same order.  Since the
+/// header records the number of entries, the entries are written to a
+/// temporary file that is appended to the header by `finish`.
+struct MtxRowWriter {
+    body: BufWriter<fs::File>,
+    body_path: std::path::PathBuf,
+    rows: usize,
+    cols: usize,
+    nnz: usize,
+}
+
+impl MtxRowWriter {
+    fn new(body_path: std::path::PathBuf, rows: usize, cols: usize) -> io::Result<MtxRowWriter> {
+        Ok(MtxRowWriter {
+            body: BufWriter::new(fs::File::create(&body_path)?),
+            body_path,
+            rows,
+            cols,
+            nnz: 0,
+        })
+    }
+
+    fn add_row(&mut self, row: usize, cols: &[usize], vals: &[f32]) -> io::Result<()> {
+        for (col, val) in cols.iter().zip(vals.iter()) {
+            writeln!(self.body, "{} {} {}", row + 1, col + 1, val)?;
+        }
+        self.nnz += cols.len();
+        Ok(())
+    }
+
+    fn finish<P: AsRef<std::path::Path>>(self, path: P) -> io::Result<()> {
+        let body = self.body.into_inner().map_err(|e| e.into_error())?;
+        drop(body);
+
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        writeln!(out, "%%MatrixMarket matrix coordinate real general")?;
+        writeln!(out, "% written by sprs")?;
+        writeln!(out, "{} {} {}", self.rows, self.cols, self.nnz)?;
+        io::copy(&mut fs::File::open(&self.body_path)?, &mut out)?;
+        out.flush()?;
+        fs::remove_file(&self.body_path)
+    }
+}
+
 struct QuantOutputInfo {
     barcode_file: BufWriter<fs::File>,
     eds_file: BufWriter<GzEncoder<fs::File>>,
     feature_file: BufWriter<fs::File>,
     trimat: sprs::TriMatI<f32, u32>,
+    // if set, rows are written as they are produced rather than kept in `trimat`
+    mtx_stream: Option<MtxRowWriter>,
     row_index: usize,
     bootstrap_helper: BootstrapHelper, //sample_or_mean_and_var: (BufWriter<GzEncoder<fs::File>>)
 }
@@ -509,8 +574,7 @@ fn fill_work_queue<T: Read>(
             let boffset = cbytes as usize;
             buf.pwrite::<u32>(nbytes_chunk, boffset)?;
             buf.pwrite::<u32>(nrec_chunk, boffset + 4)?;
-            br.read_exact(&mut buf[(boffset + 8)..(boffset + nbytes_chunk as usize)])
-                .unwrap();
+            br.read_exact(&mut buf[(boffset + 8)..(boffset + nbytes_chunk as usize)])?;
             cells_in_chunk += 1;
             cbytes += nbytes_chunk;
             crec += nrec_chunk;
@@ -520,7 +584,7 @@ fn fill_work_queue<T: Read>(
         // and we are just filling up the buffer with the last cell, and there will be no more
         // headers left to read, so skip this
         if chunk_num < num_chunks {
-            let (nc, nr) = libradicl::Chunk::read_header(&mut br);
+            let (nc, nr) = libradicl::Chunk::read_header_checked(&mut br)?;
             nbytes_chunk = nc;
             nrec_chunk = nr;
         }
@@ -612,11 +676,10 @@ fn fill_work_queue_filtered<T: Read>(
             let boffset = cbytes as usize;
             buf.pwrite::<u32>(nbytes_chunk, boffset)?;
             buf.pwrite::<u32>(nrec_chunk, boffset + 4)?;
-            br.read_exact(&mut buf[(boffset + 8)..(boffset + nbytes_chunk as usize)])
-                .unwrap();
+            br.read_exact(&mut buf[(boffset + 8)..(boffset + nbytes_chunk as usize)])?;
             // get the barcode for this chunk
             let (bc, _umi) =
-                libradicl::Chunk::peek_record(&buf[boffset + 8..], &bc_type, &umi_type);
+                libradicl::Chunk::peek_record(&buf[boffset + 8..], &bc_type, &umi_type)?;
             if keep_set.contains(&bc) {
                 cells_in_chunk += 1;
                 cbytes += nbytes_chunk;
@@ -632,7 +695,7 @@ fn fill_work_queue_filtered<T: Read>(
         // and we are just filling up the buffer with the last cell, and there will be no more
         // headers left to read, so skip this
         if chunk_num < num_chunks {
-            let (nc, nr) = libradicl::Chunk::read_header(&mut br);
+            let (nc, nr) = libradicl::Chunk::read_header_checked(&mut br)?;
             nbytes_chunk = nc;
             nrec_chunk = nr;
         }
@@ -680,9 +743,12 @@ pub fn quantify(
     summary_stat: bool,
     dump_eq: bool,
     use_mtx: bool,
+    stream_mtx: bool,
     resolution: ResolutionStrategy,
     sa_model: SplicedAmbiguityModel,
     small_thresh: usize,
+    em_params: EmParams,
+    pug_threads: usize,
     filter_list: Option<&str>,
     cmdline: &str,
     log: &slog::Logger,
@@ -718,9 +784,12 @@ pub fn quantify(
             summary_stat,
             dump_eq,
             use_mtx,
+            stream_mtx,
             resolution,
             sa_model,
             small_thresh,
+            em_params,
+            pug_threads,
             filter_list,
             cmdline,
             &log,
@@ -745,9 +814,12 @@ pub fn quantify(
             summary_stat,
             dump_eq,
             use_mtx,
+            stream_mtx,
             resolution,
             sa_model,
             small_thresh,
+            em_params,
+            pug_threads,
             filter_list,
             cmdline,
             &log,
@@ -769,15 +841,18 @@ pub fn do_quantify<T: Read>(
     summary_stat: bool,
     dump_eq: bool,
     use_mtx: bool,
+    stream_mtx: bool,
     resolution: ResolutionStrategy,
     mut sa_model: SplicedAmbiguityModel,
     small_thresh: usize,
+    em_params: EmParams,
+    pug_threads: usize,
     filter_list: Option<&str>,
     cmdline: &str,
     log: &slog::Logger,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let parent = std::path::Path::new(&input_dir);
-    let hdr = libradicl::RadHeader::from_bytes(&mut br);
+    let hdr = libradicl::RadHeader::from_bytes_checked(&mut br)?;
 
     // in the collated rad file, we have 1 cell per chunk.
     // we make this value `mut` since, if we have a non-empty
@@ -873,16 +948,41 @@ pub fn do_quantify<T: Read>(
         Arc::new(bincode::deserialize_from(&bc_unmapped_file).unwrap());
 
     // file-level
-    let fl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let fl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} file-level tags", fl_tags.tags.len());
     // read-level
-    let rl_tags = libradicl::TagSection::from_bytes(&mut br);
+    let rl_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
     // alignment-level
-    let al_tags = libradicl::TagSection::from_bytes(&mut br);
+    let al_tags = libradicl::TagSection::from_bytes_checked(&mut br)?;
     info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
+    // the records are parsed assuming each alignment is just a reference id,
+    // along with the orientation of the mate for paired-end files
+    let paired = match libradicl::AlnLayout::from_tags(hdr.is_paired != 0, &al_tags)
+        .and_then(|layout| layout.require_ref_only("quant").map(|_| layout.paired))
+    {
+        Ok(paired) => paired,
+        Err(e) => {
+            crit!(log, "{}", e);
+            std::process::exit(exit_codes::EXIT_UNSUPPORTED_LAYOUT);
+        }
+    };
 
-    let ft_vals = libradicl::FileTags::from_bytes(&mut br);
+    let ft_vals = match libradicl::FileTags::from_bytes_with_section(&mut br, &fl_tags) {
+        Ok(v) => v,
+        Err(e) => {
+            crit!(log, "could not read the file-level tag values : {}", e);
+            std::process::exit(exit_codes::EXIT_INVALID_FILE_TAGS);
+        }
+    };
+    if let Err(e) = ft_vals.require_uncompressed("quant") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_UNSUPPORTED_COMPRESSION);
+    }
+    if let Err(e) = ft_vals.require_u64_barcodes("quant") {
+        crit!(log, "{}", e);
+        std::process::exit(exit_codes::EXIT_INVALID_TAG_WIDTH);
+    }
     info!(log, "File-level tag values {:?}", ft_vals);
 
     let bct = rl_tags.tags[0].typeid;
@@ -938,6 +1038,18 @@ pub fn do_quantify<T: Read>(
     };
     let q = Arc::new(ArrayQueue::<MetaChunk>::new(4 * n_workers));
 
+    // the pool on which the workers build the PUGs of large cells, if any
+    let pug_pool = if pug_threads > 1 {
+        Some(Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(pug_threads)
+                .build()
+                .expect("could not create the PUG thread pool."),
+        ))
+    } else {
+        None
+    };
+
     // the number of cells left to process
     let cells_to_process = Arc::new(AtomicUsize::new(num_cells as usize));
     // each thread needs a *read-only* copy of this transcript <-> gene map
@@ -974,7 +1086,7 @@ pub fn do_quantify<T: Read>(
     )?;
     let alt_res_cells = Arc::new(Mutex::new(Vec::<u64>::new()));
 
-    let tmcap = if use_mtx {
+    let tmcap = if use_mtx && !stream_mtx {
         (0.1f64 * num_genes as f64 * num_cells as f64).round() as usize
     } else {
         0usize
@@ -1003,12 +1115,22 @@ pub fn do_quantify<T: Read>(
 
     let trimat =
         sprs::TriMatI::<f32, u32>::with_capacity((num_cells as usize, num_rows as usize), tmcap);
+    let mtx_stream = if use_mtx && stream_mtx {
+        Some(MtxRowWriter::new(
+            output_matrix_path.join("quants_mat.mtx.entries"),
+            num_cells as usize,
+            num_rows,
+        )?)
+    } else {
+        None
+    };
 
     let bc_writer = Arc::new(Mutex::new(QuantOutputInfo {
         barcode_file: BufWriter::new(bc_file),
         eds_file: BufWriter::new(buffered),
         feature_file: BufWriter::new(ff_file),
         trimat,
+        mtx_stream,
         row_index: 0usize,
         bootstrap_helper: boot_helper,
     }));
@@ -1049,6 +1171,9 @@ pub fn do_quantify<T: Read>(
         let eqid_map_lockc = eqid_map_lock.clone();
         // and will need to know the barcode length
         let bclen = ft_vals.bclen;
+        // and the umi length, to partition the PUG of large cells
+        let umi_len = ft_vals.umilen as usize;
+        let pug_pool = pug_pool.clone();
         let alt_res_cells = alt_res_cells.clone();
         let unmapped_count = bc_unmapped_map.clone();
         let mmrate = mmrate.clone();
@@ -1118,7 +1243,18 @@ pub fn do_quantify<T: Read>(
                             BufReader::new(&buf[byte_offset..(byte_offset + nbytes as usize)]);
                         byte_offset += nbytes as usize;
 
-                        let mut c = libradicl::Chunk::from_bytes(&mut nbr, &bc_type, &umi_type);
+                        let mut c = match libradicl::Chunk::from_bytes_checked(
+                            &mut nbr, &bc_type, &umi_type,
+                        ) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                crit!(log, "could not read cell {} : {}", cell_num, e);
+                                std::process::exit(exit_codes::EXIT_MALFORMED_INPUT);
+                            }
+                        };
+                        if paired {
+                            c.split_mate_ori();
+                        }
                         if c.reads.is_empty() {
                             warn!(log, "Discovered empty chunk; should not happen! cell_num = {}, nbytes = {}, nrec = {}", cell_num, nbytes, nrec);
                         }
@@ -1196,20 +1332,23 @@ pub fn do_quantify<T: Read>(
                                                 em_init_type,
                                                 num_rows,
                                                 only_unique,
+                                                &em_params,
                                                 &log,
                                             );
                                         }
                                         (false, _) => {
                                             // not USA-mode
-                                            counts = em_optimize(
+                                            counts = em_optimize_with_params(
                                                 &gene_eqc,
                                                 &mut unique_evidence,
                                                 &mut no_ambiguity,
                                                 em_init_type,
                                                 num_genes,
                                                 only_unique,
+                                                &em_params,
                                                 &log,
-                                            );
+                                            )
+                                            .alphas;
                                         }
                                     }
                                 }
@@ -1227,7 +1366,13 @@ pub fn do_quantify<T: Read>(
                                 }
                                 ResolutionStrategy::Parsimony => {
                                     eq_map.init_from_chunk(&mut c);
-                                    let g = extract_graph(&eq_map, &log);
+                                    let g = build_graph(
+                                        &eq_map,
+                                        c.nrec,
+                                        umi_len,
+                                        pug_pool.as_deref(),
+                                        &log,
+                                    );
                                     let pug_stats = pugutils::get_num_molecules(
                                         &g,
                                         &eq_map,
@@ -1237,20 +1382,28 @@ pub fn do_quantify<T: Read>(
                                         &log,
                                     );
                                     alt_resolution = pug_stats.used_alternative_strategy; // alt_res;
-                                    counts = em_optimize(
+                                    counts = em_optimize_with_params(
                                         &gene_eqc,
                                         &mut unique_evidence,
                                         &mut no_ambiguity,
                                         em_init_type,
                                         num_genes,
                                         true, // only unqique evidence
+                                        &em_params,
                                         &log,
-                                    );
+                                    )
+                                    .alphas;
                                     eq_map.clear();
                                 }
                                 ResolutionStrategy::Full => {
                                     eq_map.init_from_chunk(&mut c);
-                                    let g = extract_graph(&eq_map, &log);
+                                    let g = build_graph(
+                                        &eq_map,
+                                        c.nrec,
+                                        umi_len,
+                                        pug_pool.as_deref(),
+                                        &log,
+                                    );
                                     let pug_stats = pugutils::get_num_molecules(
                                         &g,
                                         &eq_map,
@@ -1260,15 +1413,17 @@ pub fn do_quantify<T: Read>(
                                         &log,
                                     );
                                     alt_resolution = pug_stats.used_alternative_strategy; // alt_res;
-                                    counts = em_optimize(
+                                    counts = em_optimize_with_params(
                                         &gene_eqc,
                                         &mut unique_evidence,
                                         &mut no_ambiguity,
                                         em_init_type,
                                         num_genes,
                                         false, // only unqique evidence
+                                        &em_params,
                                         &log,
-                                    );
+                                    )
+                                    .alphas;
                                     eq_map.clear();
                                 }
                             }
@@ -1461,6 +1616,10 @@ pub fn do_quantify<T: Read>(
                                     .eds_file
                                     .write_all(&eds_bytes)
                                     .expect("can't write to matrix file.");
+                            } else if let Some(ms) = writer.mtx_stream.as_mut() {
+                                // write this row out directly
+                                ms.add_row(row_index as usize, &expressed_ind, &expressed_vec)
+                                    .expect("can't write to matrix file.");
                             } else {
                                 // fill out the triplet matrix in memory
                                 for (ind, val) in expressed_ind.iter().zip(expressed_vec.iter()) {
@@ -1594,7 +1753,10 @@ pub fn do_quantify<T: Read>(
         // now remove it
         fs::remove_file(&mat_path)?;
         let mtx_path = output_matrix_path.join("quants_mat.mtx");
-        sprs::io::write_matrix_market(&mtx_path, &writer.trimat)?;
+        match writer.mtx_stream.take() {
+            Some(ms) => ms.finish(&mtx_path)?,
+            None => sprs::io::write_matrix_market(&mtx_path, &writer.trimat)?,
+        }
     }
 
     let pb_msg = format!(
@@ -1676,3 +1838,210 @@ pub fn velo_quantify(
     unimplemented!("not implemented on this branch yet");
     //Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unique_umi_chunk() -> libradicl::Chunk {
+        // (umi, transcript) pairs; every umi is seen with a single transcript,
+        // and the umis differ from each other in at least two bases, so that
+        // none are collapsed as sequencing errors
+        let hits = [
+            (0x0u64, 0u32),
+            (0x0, 0),
+            (0xf, 0),
+            (0xf0, 1),
+            (0xf00, 2),
+            (0xf000, 2),
+            (0xf0000, 2),
+        ];
+        let reads: Vec<libradicl::ReadRecord> = hits
+            .iter()
+            .map(|&(umi, t)| libradicl::ReadRecord {
+                bc: 0,
+                umi,
+                dirs: vec![true],
+                mate_dirs: Vec::new(),
+                refs: vec![t],
+                tags: None,
+            })
+            .collect();
+        libradicl::Chunk {
+            nbytes: 0,
+            nrec: reads.len() as u32,
+            reads,
+        }
+    }
+
+    #[test]
+    fn test_trivial_matches_full_on_unique_umis() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let tid_to_gid = vec![0u32, 1, 2];
+        let num_genes = 3;
+
+        let mut eq_map = EqMap::new(tid_to_gid.len() as u32);
+        let mut c = unique_umi_chunk();
+        eq_map.init_from_chunk(&mut c);
+        let (trivial, _) = pugutils::get_num_molecules_trivial_discard_all_ambig(
+            &eq_map,
+            &tid_to_gid,
+            num_genes,
+            &log,
+        );
+        eq_map.clear();
+
+        let mut c = unique_umi_chunk();
+        eq_map.init_from_chunk(&mut c);
+        let g = extract_graph(&eq_map, &log);
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let mut gene_eqc: HashMap<Vec<u32>, u32, ahash::RandomState> = HashMap::with_hasher(s);
+        pugutils::get_num_molecules(&g, &eq_map, &tid_to_gid, num_genes, &mut gene_eqc, &log);
+        let mut unique_evidence = vec![false; num_genes];
+        let mut no_ambiguity = vec![false; num_genes];
+        let full = em_optimize_with_params(
+            &gene_eqc,
+            &mut unique_evidence,
+            &mut no_ambiguity,
+            EmInitType::Informative,
+            num_genes,
+            false,
+            &EmParams::default(),
+            &log,
+        )
+        .alphas;
+
+        assert_eq!(trivial, vec![2.0, 1.0, 3.0]);
+        for (t, f) in trivial.iter().zip(full.iter()) {
+            assert!((t - f).abs() < 1e-3, "trivial {} != full {}", t, f);
+        }
+    }
+
+    fn sorted_components(
+        g: &petgraph::graphmap::GraphMap<(u32, u32), (), petgraph::Directed>,
+    ) -> Vec<Vec<u32>> {
+        let mut comps: Vec<Vec<u32>> = pugutils::weakly_connected_components(g)
+            .into_iter()
+            .map(|(_, mut v)| {
+                v.sort_unstable();
+                v
+            })
+            .collect();
+        comps.sort();
+        comps
+    }
+
+    #[test]
+    fn test_parallel_graph_matches_sequential() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let umi_len = 6;
+        let mut rng = StdRng::seed_from_u64(1303);
+
+        // a handful of base umis, each with a few one-edit variants
+        // (anywhere in the umi, so some cross the prefix partition)
+        let mut reads = Vec::new();
+        for _ in 0..40 {
+            let base: u64 = rng.gen_range(0..(1u64 << (2 * umi_len)));
+            let t = rng.gen_range(0..8u32);
+            let refs = if rng.gen_bool(0.3) {
+                vec![t, (t + 1) % 8]
+            } else {
+                vec![t]
+            };
+            for _ in 0..rng.gen_range(1..4) {
+                let mut umi = base;
+                if rng.gen_bool(0.5) {
+                    let pos = 2 * rng.gen_range(0..umi_len);
+                    umi ^= rng.gen_range(1..4u64) << pos;
+                }
+                reads.push(libradicl::ReadRecord {
+                    bc: 0,
+                    umi,
+                    dirs: vec![true; refs.len()],
+                    mate_dirs: Vec::new(),
+                    refs: refs.clone(),
+                    tags: None,
+                });
+            }
+        }
+        let mut c = libradicl::Chunk {
+            nbytes: 0,
+            nrec: reads.len() as u32,
+            reads,
+        };
+
+        let mut eq_map = EqMap::new(8);
+        eq_map.init_from_chunk(&mut c);
+        let seq = extract_graph(&eq_map, &log);
+        let expected = sorted_components(&seq);
+        assert!(expected.len() < seq.node_count());
+
+        for nthreads in &[1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(*nthreads)
+                .build()
+                .unwrap();
+            // small cells stay sequential even when there is a pool
+            for nrec in &[0, PARALLEL_PUG_MIN_READS] {
+                let par = build_graph(&eq_map, *nrec, umi_len, Some(&pool), &log);
+                assert_eq!(par.node_count(), seq.node_count());
+                assert_eq!(par.edge_count(), seq.edge_count());
+                assert_eq!(sorted_components(&par), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mtx_row_writer_matches_sprs() {
+        let dir = std::env::temp_dir().join(format!("mtx_stream_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rows: Vec<(Vec<usize>, Vec<f32>)> = vec![
+            (vec![0, 3], vec![1.0, 2.5]),
+            (vec![], vec![]),
+            (vec![1, 2, 4], vec![0.333, 7.0, 1e-3]),
+        ];
+
+        let mut trimat = sprs::TriMatI::<f32, u32>::new((3, 5));
+        let mut ms = MtxRowWriter::new(dir.join("streamed.mtx.entries"), 3, 5).unwrap();
+        for (r, (cols, vals)) in rows.iter().enumerate() {
+            for (c, v) in cols.iter().zip(vals.iter()) {
+                trimat.add_triplet(r, *c, *v);
+            }
+            ms.add_row(r, cols, vals).unwrap();
+        }
+        ms.finish(dir.join("streamed.mtx")).unwrap();
+        sprs::io::write_matrix_market(dir.join("batched.mtx"), &trimat).unwrap();
+
+        let streamed = fs::read(dir.join("streamed.mtx")).unwrap();
+        let batched = fs::read(dir.join("batched.mtx")).unwrap();
+        assert_eq!(streamed, batched);
+        assert!(!dir.join("streamed.mtx.entries").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_variable_length_umis_are_not_collapsed() {
+        use libradicl::utils::{pack_barcode, tag_umi_length};
+        let umi = |seq: &[u8]| {
+            tag_umi_length(
+                pack_barcode(seq, seq.len() as u16).unwrap(),
+                seq.len() as u16,
+            )
+        };
+        // "ACGT" and "AACGT" pack to the same value when untagged
+        let a = (umi(b"ACGT"), 10u32);
+        let b = (umi(b"AACGT"), 1u32);
+        let c = (umi(b"ACGA"), 1u32);
+        assert!(matches!(
+            pugutils::pug_edge_type(&a, &b),
+            libradicl::schema::PugEdgeType::NoEdge
+        ));
+        assert!(matches!(
+            pugutils::pug_edge_type(&a, &c),
+            libradicl::schema::PugEdgeType::XToY
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Reading RAD files over HTTP(S) with range requests, so that a pass
+// over a file in object storage downloads only the bytes it reads.
+
+extern crate ureq;
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::RadReader;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The number of bytes fetched by each range request, unless otherwise given.
+pub const DEFAULT_BLOCK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Translate `s3://bucket/key` and `gs://bucket/key` URLs into the
+/// corresponding public HTTPS endpoints; any other URL is returned as is.
+/// Private objects can be read through a pre-signed HTTPS URL.
+pub fn resolve_url(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        format!("https://{}.s3.amazonaws.com/{}", bucket, key)
+    } else if let Some(rest) = url.strip_prefix("gs://") {
+        format!("https://storage.googleapis.com/{}", rest)
+    } else {
+        url.to_string()
+    }
+}
+
+// Parse the total length out of a Content-Range header (`bytes a-b/total`).
+fn content_range_total(header: &str) -> Option<u64> {
+    header.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+fn to_io(e: ureq::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// A `Read + Seek` over a file served over HTTP(S), fetched a block at a
+/// time with range requests as it is read.
+pub struct HttpRangeReader {
+    url: String,
+    len: u64,
+    pos: u64,
+    block_size: u64,
+    // the most recently fetched block, and its offset within the file
+    block: Vec<u8>,
+    block_start: u64,
+}
+
+impl HttpRangeReader {
+    pub fn open(url: &str) -> io::Result<HttpRangeReader> {
+        HttpRangeReader::with_block_size(url, DEFAULT_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(url: &str, block_size: u64) -> io::Result<HttpRangeReader> {
+        let mut r = HttpRangeReader {
+            url: resolve_url(url),
+            len: u64::MAX,
+            pos: 0,
+            block_size: block_size.max(1),
+            block: Vec::new(),
+            block_start: 0,
+        };
+        // the length of the file comes back with the first block
+        r.fetch(0)?;
+        Ok(r)
+    }
+
+    /// The length of the remote file, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // fetch the block beginning at `start`
+    fn fetch(&mut self, start: u64) -> io::Result<()> {
+        let end = start.saturating_add(self.block_size).min(self.len) - 1;
+        let resp = ureq::get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end))
+            .call()
+            .map_err(to_io)?;
+        if resp.status() != 206 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} does not support range requests", self.url),
+            ));
+        }
+        if self.len == u64::MAX {
+            self.len = resp
+                .header("Content-Range")
+                .and_then(content_range_total)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "missing or invalid Content-Range header",
+                    )
+                })?;
+        }
+        self.block.clear();
+        resp.into_reader().read_to_end(&mut self.block)?;
+        self.block_start = start;
+        Ok(())
+    }
+}
+
+impl Read for HttpRangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_end = self.block_start + self.block.len() as u64;
+        if self.pos < self.block_start || self.pos >= block_end {
+            self.fetch(self.pos)?;
+        }
+        let off = (self.pos - self.block_start) as usize;
+        let n = buf.len().min(self.block.len() - off);
+        buf[..n].copy_from_slice(&self.block[off..off + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpRangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => offset_by(self.len, d),
+            SeekFrom::Current(d) => offset_by(self.pos, d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset_by(base: u64, d: i64) -> Option<u64> {
+    if d >= 0 {
+        base.checked_add(d as u64)
+    } else {
+        base.checked_sub(d.unsigned_abs())
+    }
+}
+
+impl RadReader<HttpRangeReader> {
+    /// Open the RAD file at `url` (see `resolve_url`), reading it with
+    /// range requests as the header and chunks are requested.  With a
+    /// chunk index, `seek_to_cell` fetches only the chunk holding a cell.
+    pub fn open_url(url: &str) -> Result<RadReader<HttpRangeReader>, RadError> {
+        RadReader::new(HttpRangeReader::open(url)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_url() {
+        assert_eq!(
+            resolve_url("s3://my-bucket/runs/map.rad"),
+            "https://my-bucket.s3.amazonaws.com/runs/map.rad"
+        );
+        assert_eq!(
+            resolve_url("gs://my-bucket/map.rad"),
+            "https://storage.googleapis.com/my-bucket/map.rad"
+        );
+        assert_eq!(
+            resolve_url("https://example.org/map.rad"),
+            "https://example.org/map.rad"
+        );
+        assert_eq!(content_range_total("bytes 0-99/12345"), Some(12345));
+        assert_eq!(content_range_total("bytes 0-99/*"), None);
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::{
+    decode_int_type_tag, decode_type_tag, read_chunk, FileTags, RadHeader, RadReader, TagSection,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+fn tag_section_to_json(ts: &TagSection) -> serde_json::Value {
+    let tags: Vec<serde_json::Value> = ts
+        .tags
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "typeid": t.typeid,
+                "type": decode_type_tag(t.typeid).map(|rt| format!("{:?}", rt)),
+            })
+        })
+        .collect();
+    serde_json::Value::Array(tags)
+}
+
+/// Read the RAD file at `path` and write a JSON summary of it to `writer`.
+/// The summary contains the header fields, the file-level tag values, the
+/// descriptions of all tags, and aggregate statistics over the records in
+/// the file.  Since the list of reference names can be very long, it is only
+/// included if `include_ref_names` is true.
+pub fn summarize_to_json<W: Write>(
+    path: &Path,
+    writer: &mut W,
+    include_ref_names: bool,
+) -> Result<(), RadError> {
+    let mut br = BufReader::new(File::open(path)?);
+    let hdr = RadHeader::from_bytes_checked(&mut br)?;
+    let fl_tags = TagSection::from_bytes_checked(&mut br)?;
+    let rl_tags = TagSection::from_bytes_checked(&mut br)?;
+    let al_tags = TagSection::from_bytes_checked(&mut br)?;
+    let ft_vals = FileTags::from_bytes_with_section(&mut br, &fl_tags)?;
+
+    let mut bct = None;
+    let mut umit = None;
+    for rt in &rl_tags.tags {
+        if rt.name == "b" {
+            bct = Some(
+                decode_int_type_tag(rt.typeid).ok_or(RadError::UnsupportedTypeTag(rt.typeid))?,
+            );
+        } else if rt.name == "u" {
+            umit = Some(
+                decode_int_type_tag(rt.typeid).ok_or(RadError::UnsupportedTypeTag(rt.typeid))?,
+            );
+        }
+    }
+    let bct = bct.ok_or_else(|| RadError::MissingTag("b".to_string()))?;
+    let umit = umit.ok_or_else(|| RadError::MissingTag("u".to_string()))?;
+    ft_vals.validate(&bct, &umit)?;
+
+    let mut num_records = 0u64;
+    let mut num_alignments = 0u64;
+    let mut max_alignments = 0usize;
+    for _ in 0..hdr.num_chunks {
+        let c = read_chunk(&mut br, ft_vals.zstd_chunks, &bct, &umit)?;
+        for r in &c.reads {
+            num_records += 1;
+            num_alignments += r.refs.len() as u64;
+            max_alignments = max_alignments.max(r.refs.len());
+        }
+    }
+    let mean_alignments = if num_records > 0 {
+        num_alignments as f64 / num_records as f64
+    } else {
+        0.0
+    };
+
+    let mut header = json!({
+        "is_paired": hdr.is_paired != 0,
+        "ref_count": hdr.ref_count,
+        "num_chunks": hdr.num_chunks,
+    });
+    if include_ref_names {
+        header["ref_names"] = json!(hdr.ref_names);
+    }
+
+    let summary = json!({
+        "header": header,
+        "file_tags": {
+            "bclen": ft_vals.bclen,
+            "umilen": ft_vals.umilen,
+            "max_ambiguity": ft_vals.max_ambiguity,
+        },
+        "tags": {
+            "file_level": tag_section_to_json(&fl_tags),
+            "read_level": tag_section_to_json(&rl_tags),
+            "alignment_level": tag_section_to_json(&al_tags),
+        },
+        "stats": {
+            "num_records": num_records,
+            "num_alignments": num_alignments,
+            "mean_alignments_per_read": mean_alignments,
+            "max_alignments_per_read": max_alignments,
+        }
+    });
+
+    serde_json::to_writer_pretty(&mut *writer, &summary)?;
+    Ok(())
+}
+
+/// Statistics over the records of a RAD file, as computed by `rad_stats`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct RadSummary {
+    pub num_chunks: u64,
+    pub num_records: u64,
+    pub num_alignments: u64,
+    /// The number of records in each chunk, in file order.
+    pub records_per_chunk: Vec<u32>,
+    pub num_distinct_barcodes: u64,
+    /// The number of records with each number of alignments (the index).
+    pub multimapping_hist: Vec<u64>,
+    pub num_forward: u64,
+    pub num_reverse: u64,
+    /// The number of alignments to each reference, indexed by reference id.
+    pub ref_hits: Vec<u64>,
+}
+
+/// Compute a `RadSummary` of the chunks remaining in `reader`, in a single
+/// pass.  Only the set of distinct barcodes grows with the size of the
+/// input.
+pub fn rad_stats<R: Read>(reader: RadReader<R>) -> Result<RadSummary, RadError> {
+    let mut s = RadSummary {
+        ref_hits: vec![0; reader.header.ref_count as usize],
+        ..RadSummary::default()
+    };
+    let mut barcodes: HashSet<u64, ahash::RandomState> = HashSet::default();
+    for c in reader {
+        let c = c?;
+        s.num_chunks += 1;
+        s.records_per_chunk.push(c.nrec);
+        for r in &c.reads {
+            s.num_records += 1;
+            s.num_alignments += r.refs.len() as u64;
+            barcodes.insert(r.bc);
+            if s.multimapping_hist.len() <= r.refs.len() {
+                s.multimapping_hist.resize(r.refs.len() + 1, 0);
+            }
+            s.multimapping_hist[r.refs.len()] += 1;
+            for &t in &r.refs {
+                if s.ref_hits.len() <= t as usize {
+                    s.ref_hits.resize(t as usize + 1, 0);
+                }
+                s.ref_hits[t as usize] += 1;
+            }
+            let fw = r.dirs.iter().filter(|&&d| d).count() as u64;
+            s.num_forward += fw;
+            s.num_reverse += r.dirs.len() as u64 - fw;
+        }
+    }
+    s.num_distinct_barcodes = barcodes.len() as u64;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RadIntId, RadWriter, ReadRecord};
+    use std::io::Cursor;
+
+    // a file of two chunks holding three records over three references
+    fn test_file() -> Vec<u8> {
+        let hdr = RadHeader {
+            is_paired: 0,
+            ref_count: 3,
+            ref_names: vec!["t0".to_string(), "t1".to_string(), "t2".to_string()],
+            ref_lengths: vec![],
+            ref_digest: None,
+            num_chunks: 2,
+        };
+        let ft = FileTags {
+            bclen: 16,
+            umilen: 12,
+            max_ambiguity: None,
+            variable_umi: false,
+            ..Default::default()
+        };
+        let mut rw = RadWriter::new(Vec::new());
+        rw.write_header(&hdr, &ft, RadIntId::U32, RadIntId::U32)
+            .unwrap();
+        let recs: [&[(u64, Vec<u32>, Vec<bool>)]; 2] = [
+            &[(1, vec![0], vec![true]), (1, vec![0, 2], vec![true, false])],
+            &[(2, vec![2], vec![false])],
+        ];
+        for chunk in &recs {
+            rw.begin_chunk().unwrap();
+            for (bc, refs, dirs) in chunk.iter() {
+                rw.push_record(&ReadRecord {
+                    bc: *bc,
+                    umi: 0,
+                    dirs: dirs.clone(),
+                    mate_dirs: Vec::new(),
+                    refs: refs.clone(),
+                    tags: None,
+                })
+                .unwrap();
+            }
+            rw.finish_chunk().unwrap();
+        }
+        rw.finalize().unwrap()
+    }
+
+    #[test]
+    fn test_rad_stats() {
+        let rr = RadReader::new(Cursor::new(test_file())).unwrap();
+        let s = rad_stats(rr).unwrap();
+        assert_eq!(s.num_chunks, 2);
+        assert_eq!(s.num_records, 3);
+        assert_eq!(s.num_alignments, 4);
+        assert_eq!(s.records_per_chunk, vec![2, 1]);
+        assert_eq!(s.num_distinct_barcodes, 2);
+        assert_eq!(s.multimapping_hist, vec![0, 2, 1]);
+        assert_eq!((s.num_forward, s.num_reverse), (2, 2));
+        assert_eq!(s.ref_hits, vec![2, 0, 2]);
+        let v = serde_json::to_value(&s).unwrap();
+        assert_eq!(v["num_distinct_barcodes"], 2);
+    }
+
+    #[test]
+    fn test_summarize_to_json() {
+        let path = std::env::temp_dir().join(format!("summary_{}.rad", std::process::id()));
+        std::fs::write(&path, test_file()).unwrap();
+
+        let mut out = Vec::new();
+        summarize_to_json(&path, &mut out, false).unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(v["header"]["is_paired"], false);
+        assert_eq!(v["header"]["ref_count"], 3);
+        assert_eq!(v["header"]["num_chunks"], 2);
+        assert!(v["header"].get("ref_names").is_none());
+        assert_eq!(v["file_tags"]["bclen"], 16);
+        assert_eq!(v["file_tags"]["umilen"], 12);
+        assert_eq!(v["tags"]["read_level"][0]["name"], "b");
+        assert_eq!(v["tags"]["read_level"][1]["name"], "u");
+        assert_eq!(v["stats"]["num_records"], 3);
+        assert_eq!(v["stats"]["num_alignments"], 4);
+        assert_eq!(v["stats"]["max_alignments_per_read"], 2);
+        let mean = v["stats"]["mean_alignments_per_read"].as_f64().unwrap();
+        assert!((mean - 4.0 / 3.0).abs() < 1e-12);
+
+        let mut out = Vec::new();
+        summarize_to_json(&path, &mut out, true).unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(v["header"]["ref_names"], json!(["t0", "t1", "t2"]));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Parsing of tag values according to the tag descriptions of a RAD file,
+// for files carrying tags beyond the barcode, umi and reference id that
+// `ReadRecord` understands.
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::utils::{
+    MASK_LOWER_30_U32, MASK_LOWER_31_U32, MASK_MATE_ORI_U32, MASK_TOP_BIT_U32,
+};
+use self::libradicl::{
+    check_limit_io, decode_type_tag, prealloc, write_str_bin, Chunk, RadIntId, RadType, ReadRecord,
+    TagSection, MAX_ALIGNMENTS_PER_RECORD, MAX_RECORDS_PER_CHUNK,
+};
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// A single tag value, of one of the RAD types.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    String(String),
+}
+
+impl TagValue {
+    /// Read a value of type `t` from `reader`.
+    pub fn read_from<R: Read>(reader: &mut R, t: RadType) -> std::io::Result<TagValue> {
+        let mut buf = [0u8; 16];
+        if let Some(n) = t.fixed_size() {
+            reader.read_exact(&mut buf[..n])?;
+        }
+        let b4 = [buf[0], buf[1], buf[2], buf[3]];
+        let b8: [u8; 8] = buf[..8].try_into().unwrap();
+        Ok(match t {
+            RadType::Bool => TagValue::Bool(buf[0] != 0),
+            RadType::U8 => TagValue::U8(buf[0]),
+            RadType::U16 => TagValue::U16(u16::from_le_bytes([buf[0], buf[1]])),
+            RadType::U32 => TagValue::U32(u32::from_le_bytes(b4)),
+            RadType::U64 => TagValue::U64(u64::from_le_bytes(b8)),
+            RadType::U128 => TagValue::U128(u128::from_le_bytes(buf)),
+            RadType::F32 => TagValue::F32(f32::from_le_bytes(b4)),
+            RadType::F64 => TagValue::F64(f64::from_le_bytes(b8)),
+            RadType::String => {
+                reader.read_exact(&mut buf[..2])?;
+                let mut bytes = vec![0u8; u16::from_le_bytes([buf[0], buf[1]]) as usize];
+                reader.read_exact(&mut bytes)?;
+                TagValue::String(String::from_utf8(bytes).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        RadError::InvalidUtf8 {
+                            context: "string tag value".to_string(),
+                            bytes: e.into_bytes(),
+                        },
+                    )
+                })?)
+            }
+        })
+    }
+
+    /// Write the value in the form read by `read_from`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            TagValue::Bool(v) => writer.write_all(&[*v as u8]),
+            TagValue::U8(v) => writer.write_all(&[*v]),
+            TagValue::U16(v) => writer.write_all(&v.to_le_bytes()),
+            TagValue::U32(v) => writer.write_all(&v.to_le_bytes()),
+            TagValue::U64(v) => writer.write_all(&v.to_le_bytes()),
+            TagValue::U128(v) => writer.write_all(&v.to_le_bytes()),
+            TagValue::F32(v) => writer.write_all(&v.to_le_bytes()),
+            TagValue::F64(v) => writer.write_all(&v.to_le_bytes()),
+            TagValue::String(v) => write_str_bin(v, &RadIntId::U16, writer),
+        }
+    }
+
+    pub fn rad_type(&self) -> RadType {
+        match self {
+            TagValue::Bool(_) => RadType::Bool,
+            TagValue::U8(_) => RadType::U8,
+            TagValue::U16(_) => RadType::U16,
+            TagValue::U32(_) => RadType::U32,
+            TagValue::U64(_) => RadType::U64,
+            TagValue::U128(_) => RadType::U128,
+            TagValue::F32(_) => RadType::F32,
+            TagValue::F64(_) => RadType::F64,
+            TagValue::String(_) => RadType::String,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            TagValue::String(v) => Some(v.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The value as a `u64`, if it is of an integer type and fits.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            TagValue::U8(v) => Some(v as u64),
+            TagValue::U16(v) => Some(v as u64),
+            TagValue::U32(v) => Some(v as u64),
+            TagValue::U64(v) => Some(v),
+            TagValue::U128(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as an `f64`, if it is of a numeric type.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            TagValue::F32(v) => Some(*v as f64),
+            TagValue::F64(v) => Some(*v),
+            TagValue::U128(v) => Some(*v as f64),
+            TagValue::Bool(_) | TagValue::String(_) => None,
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+}
+
+/// The names and types of the tags of one `TagSection`, used to parse
+/// their values.
+#[derive(Debug, Clone)]
+pub struct TagSchema {
+    names: Arc<[String]>,
+    types: Vec<RadType>,
+}
+
+impl TagSchema {
+    /// Returns `UnsupportedTypeTag` if a tag of `section` has an unknown
+    /// type id.
+    pub fn new(section: &TagSection) -> Result<TagSchema, RadError> {
+        let types = section
+            .tags
+            .iter()
+            .map(|t| decode_type_tag(t.typeid).ok_or(RadError::UnsupportedTypeTag(t.typeid)))
+            .collect::<Result<Vec<RadType>, RadError>>()?;
+        Ok(TagSchema {
+            names: section.tags.iter().map(|t| t.name.clone()).collect(),
+            types,
+        })
+    }
+
+    /// Read one value of each tag, in order.
+    pub fn read_values<R: Read>(&self, reader: &mut R) -> std::io::Result<TagMap> {
+        let mut values = Vec::with_capacity(self.types.len());
+        for t in &self.types {
+            values.push(TagValue::read_from(reader, *t)?);
+        }
+        Ok(TagMap {
+            names: self.names.clone(),
+            values,
+        })
+    }
+}
+
+/// The values of the tags of one `TagSection`, accessible by name.  The
+/// names are shared by every map read with the same `TagSchema`.
+#[derive(Debug, Clone)]
+pub struct TagMap {
+    names: Arc<[String]>,
+    values: Vec<TagValue>,
+}
+
+impl TagMap {
+    pub fn get(&self, name: &str) -> Option<&TagValue> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| &self.values[i])
+    }
+
+    /// The `(name, value)` pairs, in the order of the tag section.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TagValue)> {
+        self.names
+            .iter()
+            .map(|n| n.as_str())
+            .zip(self.values.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// The values of every read-level tag of a record and, for each of its
+/// alignments, of every alignment-level tag, as kept in `ReadRecord::tags`
+/// for records read with a `RecordSchema`.
+#[derive(Debug, Clone)]
+pub struct RecordTags {
+    pub read: TagMap,
+    /// Parallel to the `refs` of the record.
+    pub alns: Vec<TagMap>,
+}
+
+// The value of the integer tag `name` of `tags`, or an `InvalidData` error
+// if it is absent or not of an integer type.
+fn required_u64(tags: &TagMap, name: &str) -> std::io::Result<u64> {
+    tags.get(name).and_then(|v| v.as_u64()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            RadError::MissingTag(name.to_string()),
+        )
+    })
+}
+
+/// Parses records whose read-level and alignment-level tags are given by
+/// the corresponding tag sections of a RAD file.
+#[derive(Debug, Clone)]
+pub struct RecordSchema {
+    pub read: TagSchema,
+    pub aln: TagSchema,
+    /// Whether the records are those of a paired-end file, whose alignments
+    /// also hold the orientation of the mate (see `AlnLayout`).
+    pub paired: bool,
+}
+
+impl RecordSchema {
+    pub fn new(read_tags: &TagSection, aln_tags: &TagSection) -> Result<RecordSchema, RadError> {
+        Ok(RecordSchema {
+            read: TagSchema::new(read_tags)?,
+            aln: TagSchema::new(aln_tags)?,
+            paired: false,
+        })
+    }
+
+    /// Read a record, taking its barcode ("b"), umi ("u") and alignments
+    /// ("compressed_ori_refid") from the corresponding tags, and keeping
+    /// the values of every tag in its `tags`.  Returns an `InvalidData`
+    /// error if any of these tags is absent or not of an integer type.
+    pub fn read_record<R: Read>(&self, reader: &mut R) -> std::io::Result<ReadRecord> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        let na = u32::from_le_bytes(buf);
+        check_limit_io(
+            "alignment count",
+            na as u64,
+            MAX_ALIGNMENTS_PER_RECORD as u64,
+        )?;
+        let read = self.read.read_values(reader)?;
+        let mut rec = ReadRecord {
+            bc: required_u64(&read, "b")?,
+            umi: required_u64(&read, "u")?,
+            dirs: Vec::with_capacity(prealloc(na as u64)),
+            mate_dirs: Vec::new(),
+            refs: Vec::with_capacity(prealloc(na as u64)),
+            tags: None,
+        };
+        let mut alns = Vec::with_capacity(prealloc(na as u64));
+        for _ in 0..na {
+            let a = self.aln.read_values(reader)?;
+            let v = required_u64(&a, "compressed_ori_refid")? as u32;
+            rec.dirs.push((v & MASK_LOWER_31_U32) != 0);
+            if self.paired {
+                rec.mate_dirs.push((v & MASK_MATE_ORI_U32) != 0);
+                rec.refs.push(v & MASK_LOWER_30_U32);
+            } else {
+                rec.refs.push(v & MASK_TOP_BIT_U32);
+            }
+            alns.push(a);
+        }
+        rec.tags = Some(Box::new(RecordTags { read, alns }));
+        Ok(rec)
+    }
+
+    /// Read a chunk of records, each read with `read_record`.
+    pub fn read_chunk<R: Read>(&self, reader: &mut R) -> std::io::Result<Chunk> {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf)?;
+        let nbytes = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let nrec = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        check_limit_io("record count", nrec as u64, MAX_RECORDS_PER_CHUNK as u64)?;
+        let mut reads = Vec::with_capacity(prealloc(nrec as u64));
+        for _ in 0..nrec {
+            reads.push(self.read_record(reader)?);
+        }
+        Ok(Chunk {
+            nbytes,
+            nrec,
+            reads,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TagDesc;
+    use std::io::Cursor;
+
+    fn section(tags: &[(&str, u8)]) -> TagSection {
+        TagSection {
+            tags: tags
+                .iter()
+                .map(|(n, t)| TagDesc {
+                    name: n.to_string(),
+                    typeid: *t,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_read_tagged_chunk() {
+        // barcode (u32), umi (u16), a mapping score (f32) and a flag per read;
+        // a reference id and fragment length (u16) per alignment
+        let schema = RecordSchema::new(
+            &section(&[("b", 3), ("u", 2), ("score", 5), ("dup", 0)]),
+            &section(&[("compressed_ori_refid", 3), ("frag_len", 2)]),
+        )
+        .unwrap();
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&2u32.to_le_bytes());
+        body.extend_from_slice(&7u32.to_le_bytes());
+        body.extend_from_slice(&9u16.to_le_bytes());
+        body.extend_from_slice(&0.5f32.to_le_bytes());
+        body.push(1);
+        for (r, fl) in &[(3u32 | MASK_LOWER_31_U32, 250u16), (5, 300)] {
+            body.extend_from_slice(&r.to_le_bytes());
+            body.extend_from_slice(&fl.to_le_bytes());
+        }
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&((body.len() + 8) as u32).to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&body);
+
+        let c = schema.read_chunk(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!((c.nbytes as usize, c.nrec), (buf.len(), 1));
+        let rec = &c.reads[0];
+        assert_eq!((rec.bc, rec.umi), (7, 9));
+        assert_eq!(rec.refs, vec![3, 5]);
+        assert_eq!(rec.dirs, vec![true, false]);
+        let tags = rec.tags.as_ref().unwrap();
+        assert_eq!(tags.read.get("score"), Some(&TagValue::F32(0.5)));
+        assert_eq!(tags.read.get("dup"), Some(&TagValue::Bool(true)));
+        assert_eq!(tags.read.get("missing"), None);
+        let fl: Vec<u64> = tags
+            .alns
+            .iter()
+            .map(|a| a.get("frag_len").unwrap().as_u64().unwrap())
+            .collect();
+        assert_eq!(fl, vec![250, 300]);
+
+        // records without a barcode can't be read as a `ReadRecord`
+        let no_bc = RecordSchema::new(
+            &section(&[("u", 2), ("score", 5), ("dup", 0)]),
+            &section(&[("compressed_ori_refid", 3), ("frag_len", 2)]),
+        )
+        .unwrap();
+        let e = no_bc.read_chunk(&mut Cursor::new(&buf)).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        assert!(matches!(
+            TagSchema::new(&section(&[("x", 10)])),
+            Err(RadError::UnsupportedTypeTag(10))
+        ));
+    }
+
+    #[test]
+    fn test_string_tag_values() {
+        let vals = [
+            TagValue::String("sample_1".to_string()),
+            TagValue::U16(12),
+            TagValue::String(String::new()),
+            TagValue::F64(0.25),
+        ];
+        let mut buf = Vec::new();
+        for v in &vals {
+            v.write_to(&mut buf).unwrap();
+        }
+        let sec = section(&[("sample", 8), ("ulen", 2), ("empty", 8), ("frac", 6)]);
+        let m = TagSchema::new(&sec)
+            .unwrap()
+            .read_values(&mut Cursor::new(&buf))
+            .unwrap();
+        assert_eq!(m.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>(), vals);
+        assert_eq!(m.get("sample").unwrap().as_str(), Some("sample_1"));
+        assert_eq!(m.get("sample").unwrap().as_u64(), None);
+        assert!(vals.iter().all(|v| crate::decode_type_tag(
+            crate::encode_type_tag(v.rad_type()).unwrap()
+        ) == Some(v.rad_type())));
+    }
+}
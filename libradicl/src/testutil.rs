@@ -0,0 +1,168 @@
+/*
+ * Copyright (c) 2020-2021 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+// Generation of synthetic RAD files for tests and benchmarks.  The
+// random number generator is implemented here (rather than taken from
+// `rand`) so that the output for a given seed can never change with
+// the version of a dependency or the platform.
+
+use crate as libradicl;
+
+use self::libradicl::error::RadError;
+use self::libradicl::{FileTags, RadHeader, RadIntId, RadWriter, ReadRecord};
+use std::io::Write;
+
+/// The shape of a synthetic RAD file.  Ranges are inclusive, and values
+/// are drawn uniformly from them.
+#[derive(Debug, Clone)]
+pub struct SynthParams {
+    pub num_refs: u32,
+    pub num_chunks: u64,
+    pub records_per_chunk: (u32, u32),
+    pub alignments_per_record: (u32, u32),
+    pub bclen: u16,
+    pub umilen: u16,
+}
+
+impl Default for SynthParams {
+    fn default() -> Self {
+        SynthParams {
+            num_refs: 100,
+            num_chunks: 10,
+            records_per_chunk: (1, 1000),
+            alignments_per_record: (1, 4),
+            bclen: 16,
+            umilen: 12,
+        }
+    }
+}
+
+/// The splitmix64 generator.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn in_range(&mut self, (lo, hi): (u32, u32)) -> u32 {
+        assert!(lo <= hi, "invalid range ({}, {})", lo, hi);
+        lo + (self.next_u64() % ((hi - lo) as u64 + 1)) as u32
+    }
+
+    fn kmer(&mut self, len: u16) -> u64 {
+        if len >= 32 {
+            self.next_u64()
+        } else {
+            self.next_u64() & ((1u64 << (2 * len)) - 1)
+        }
+    }
+}
+
+fn int_type_for_len(len: u16) -> RadIntId {
+    match 2 * len as usize {
+        0..=8 => RadIntId::U8,
+        9..=16 => RadIntId::U16,
+        17..=32 => RadIntId::U32,
+        _ => RadIntId::U64,
+    }
+}
+
+/// Write a valid RAD file described by `params` to `writer`, using the
+/// random seed `seed`.  The same `params` and `seed` always produce the
+/// same bytes.  References are named `ref0`, `ref1`, ..., barcodes and
+/// umis are random kmers of the requested lengths (stored in the smallest
+/// integer type that holds them), and each alignment is to a random
+/// reference in a random orientation.
+pub fn generate_rad<W: Write>(writer: W, params: SynthParams, seed: u64) -> Result<W, RadError> {
+    let mut rng = SplitMix64(seed);
+    let hdr = RadHeader {
+        is_paired: 0,
+        ref_count: params.num_refs as u64,
+        ref_names: (0..params.num_refs).map(|i| format!("ref{}", i)).collect(),
+        ref_lengths: vec![],
+        ref_digest: None,
+        num_chunks: params.num_chunks,
+    };
+    let file_tags = FileTags {
+        bclen: params.bclen,
+        umilen: params.umilen,
+        max_ambiguity: None,
+        variable_umi: false,
+        zstd_chunks: false,
+        extra: Vec::new(),
+    };
+
+    let mut rw = RadWriter::new(writer);
+    rw.write_header(
+        &hdr,
+        &file_tags,
+        int_type_for_len(params.bclen),
+        int_type_for_len(params.umilen),
+    )?;
+
+    let mut rec = ReadRecord::default();
+    for _ in 0..params.num_chunks {
+        rw.begin_chunk()?;
+        for _ in 0..rng.in_range(params.records_per_chunk) {
+            rec.bc = rng.kmer(params.bclen);
+            rec.umi = rng.kmer(params.umilen);
+            rec.refs.clear();
+            rec.dirs.clear();
+            for _ in 0..rng.in_range(params.alignments_per_record) {
+                let r = rng.next_u64();
+                rec.refs.push((r % params.num_refs.max(1) as u64) as u32);
+                rec.dirs.push((r >> 63) == 1);
+            }
+            rw.push_record(&rec)?;
+        }
+        rw.finish_chunk()?;
+    }
+    rw.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chunk, TagSection};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_generate_rad_is_deterministic() {
+        let params = SynthParams {
+            num_chunks: 5,
+            records_per_chunk: (0, 50),
+            ..SynthParams::default()
+        };
+        let a = generate_rad(Vec::new(), params.clone(), 42).unwrap();
+        let b = generate_rad(Vec::new(), params.clone(), 42).unwrap();
+        let c = generate_rad(Vec::new(), params, 43).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        let mut reader = Cursor::new(&a);
+        let hdr = RadHeader::from_bytes(&mut reader);
+        assert_eq!(hdr.ref_count, 100);
+        for _ in 0..3 {
+            TagSection::from_bytes(&mut reader);
+        }
+        let ft = FileTags::from_bytes(&mut reader);
+        assert_eq!((ft.bclen, ft.umilen), (16, 12));
+        for _ in 0..hdr.num_chunks {
+            let c = Chunk::from_bytes_checked(&mut reader, &RadIntId::U32, &RadIntId::U32).unwrap();
+            assert!(c.nrec <= 50);
+            assert!(c.reads.iter().all(|r| r.refs.iter().all(|t| *t < 100)));
+        }
+        assert_eq!(reader.position() as usize, a.len());
+    }
+}
@@ -11,9 +11,9 @@ extern crate ahash;
 
 use crate as libradicl;
 
+use self::libradicl::error::PackError;
 use self::libradicl::schema::IndexedEqList;
 use bstr::io::BufReadExt;
-use needletail::bitkmer::*;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
@@ -21,6 +21,10 @@ use std::io::BufReader;
 
 pub(super) const MASK_TOP_BIT_U32: u32 = 0x7FFFFFFF;
 pub(super) const MASK_LOWER_31_U32: u32 = 0x80000000;
+// in paired-end records, the orientation bit of the second read of the
+// pair, and the bits left for the reference id
+pub(super) const MASK_MATE_ORI_U32: u32 = 0x40000000;
+pub(super) const MASK_LOWER_30_U32: u32 = 0x3FFFFFFF;
 pub const SPLICE_MASK_U32: u32 = 0xFFFFFFFE;
 
 #[allow(dead_code)]
@@ -82,6 +86,140 @@ pub(super) fn count_diff_2_bit_packed(a: u64, b: u64) -> usize {
     two_bit_diffs.count_ones() as usize
 }
 
+const NUCL_CHARS: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+/// Decode the first `len` bases of the 2-bit packed k-mer `val` into
+/// an ACGT string.  The layout is the one produced by needletail's
+/// `BitNuclKmer` (A=0, C=1, G=2, T=3, with the first base in the most
+/// significant occupied bits), which is how barcodes and UMIs are packed.
+pub fn decode_kmer(val: u64, len: u16) -> String {
+    assert!(len <= 32, "a u64 can hold at most 32 bases");
+    (0..len)
+        .rev()
+        .map(|i| NUCL_CHARS[((val >> (2 * i)) & 0x3) as usize] as char)
+        .collect()
+}
+
+/// As `decode_kmer`, but for k-mers of up to 64 bases packed into a u128.
+pub fn decode_kmer_u128(val: u128, len: u16) -> String {
+    assert!(len <= 64, "a u128 can hold at most 64 bases");
+    (0..len)
+        .rev()
+        .map(|i| NUCL_CHARS[((val >> (2 * i)) & 0x3) as usize] as char)
+        .collect()
+}
+
+/// Pack the bases of `seq` 2 bits per base, in the same layout as
+/// `decode_kmer` expects, along with a mask having both bits set at the
+/// positions of any `N` bases (which are packed as `A`).  Returns `None` if
+/// `seq` is longer than 32 bases or contains anything other than ACGTN.
+pub fn pack_kmer_with_ns(seq: &[u8]) -> Option<(u64, u64)> {
+    if seq.len() > 32 {
+        return None;
+    }
+    let mut val = 0u64;
+    let mut n_mask = 0u64;
+    for b in seq {
+        let (code, is_n) = match b.to_ascii_uppercase() {
+            b'A' => (0, false),
+            b'C' => (1, false),
+            b'G' => (2, false),
+            b'T' => (3, false),
+            b'N' => (0, true),
+            _ => return None,
+        };
+        val = (val << 2) | code;
+        n_mask = (n_mask << 2) | if is_n { 0x3 } else { 0x0 };
+    }
+    Some((val, n_mask))
+}
+
+/// Pack the barcode `seq`, which must be exactly `len` bases of ACGT
+/// (in either case), 2 bits per base in the layout `decode_kmer` expects.
+/// Unlike `pack_kmer_with_ns`, `N` is rejected like any other invalid base.
+pub fn pack_barcode(seq: &[u8], len: u16) -> Result<u64, PackError> {
+    if len > 32 {
+        return Err(PackError::WrongLength {
+            expected: len,
+            found: seq.len(),
+        });
+    }
+    pack_barcode_u128(seq, len).map(|v| v as u64)
+}
+
+/// As `pack_barcode`, but for barcodes of up to 64 bases (e.g. the
+/// composite barcodes of combinatorial indexing protocols), packed into
+/// a u128 in the layout `decode_kmer_u128` expects.
+pub fn pack_barcode_u128(seq: &[u8], len: u16) -> Result<u128, PackError> {
+    if seq.len() != len as usize || len > 64 {
+        return Err(PackError::WrongLength {
+            expected: len,
+            found: seq.len(),
+        });
+    }
+    let mut val = 0u128;
+    for (pos, b) in seq.iter().enumerate() {
+        let code = match b.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return Err(PackError::InvalidBase { byte: *b, pos }),
+        };
+        val = (val << 2) | code;
+    }
+    Ok(val)
+}
+
+/// The longest UMI that can be tagged with its length by `tag_umi_length`.
+pub const MAX_VARIABLE_UMI_LEN: u16 = 26;
+
+/// Store `len`, the number of bases of the packed UMI `umi`, in the top 12
+/// bits of a u64 (twice over, 6 bits each), so that UMIs of different
+/// lengths never compare equal and always differ in at least two 2-bit
+/// positions (as counted by `count_diff_2_bit_packed`), while UMIs of the
+/// same length differ exactly where the untagged UMIs do.  Hence UMIs
+/// tagged this way can be deduplicated as if they were all of one length.
+pub fn tag_umi_length(umi: u64, len: u16) -> u64 {
+    assert!(
+        len <= MAX_VARIABLE_UMI_LEN,
+        "a variable length umi can have at most {} bases",
+        MAX_VARIABLE_UMI_LEN
+    );
+    let len = len as u64;
+    umi | (len << 52) | (len << 58)
+}
+
+/// The inverse of `tag_umi_length`, returning the packed UMI and its length.
+pub fn untag_umi_length(val: u64) -> (u64, u16) {
+    (val & ((1u64 << 52) - 1), (val >> 58) as u16)
+}
+
+/// Reverse complement the `len` base, 2-bit packed k-mer `val`.
+/// Complementing a base in this encoding amounts to flipping both of its bits.
+pub fn reverse_complement_packed(val: u64, len: u16) -> u64 {
+    assert!(len <= 32, "a u64 can hold at most 32 bases");
+    let mut v = val;
+    let mut rc = 0u64;
+    for _ in 0..len {
+        rc = (rc << 2) | (0x3 ^ (v & 0x3));
+        v >>= 2;
+    }
+    rc
+}
+
+/// The number of bases at which the 2-bit packed k-mers `a` and `b` differ.
+pub fn hamming_distance_packed(a: u64, b: u64) -> u32 {
+    let x = a ^ b;
+    ((x | (x >> 1)) & 0x5555_5555_5555_5555).count_ones()
+}
+
+/// As `hamming_distance_packed`, for k-mers of up to 64 bases.
+pub fn hamming_distance_packed_u128(a: u128, b: u128) -> u32 {
+    let x = a ^ b;
+    ((x | (x >> 1)) & 0x5555_5555_5555_5555_5555_5555_5555_5555).count_ones()
+}
+
 fn get_bit_mask(nt_index: usize, fill_with: u64) -> u64 {
     let mut mask: u64 = fill_with;
     mask <<= 2 * (nt_index - 1);
@@ -243,9 +381,7 @@ pub fn read_filter_list(
     // Read the file line by line using the lines() iterator from std::io::BufRead.
     reader
         .for_byte_line(|line| {
-            let mut bnk = BitNuclKmer::new(line, bclen as u8, false);
-            let (_, k, _) = bnk.next().expect("can't extract kmer");
-            fset.insert(k.0);
+            fset.insert(pack_barcode(line, bclen).expect("invalid barcode in filter list"));
             Ok(true)
         })
         .unwrap();
@@ -733,6 +869,7 @@ pub(super) fn extract_usa_eqmap(
 mod tests {
     use self::libradicl::utils::*;
     use crate as libradicl;
+    use needletail::bitkmer::BitNuclKmer;
     use std::collections::HashSet;
 
     #[test]
@@ -792,6 +929,96 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pack_barcode() {
+        let seq = b"ACGTTGCAGGCATTAC";
+        let mut bnk = BitNuclKmer::new(&seq[..], 16, false);
+        let (_, k, _) = bnk.next().expect("can't extract kmer");
+        assert_eq!(pack_barcode(seq, 16), Ok(k.0));
+        assert_eq!(pack_barcode(b"acgttgcaggcattac", 16), Ok(k.0));
+        assert_eq!(
+            decode_kmer(pack_barcode(seq, 16).unwrap(), 16),
+            "ACGTTGCAGGCATTAC"
+        );
+
+        assert_eq!(
+            pack_barcode(b"ACGT", 5),
+            Err(PackError::WrongLength {
+                expected: 5,
+                found: 4
+            })
+        );
+        assert_eq!(
+            pack_barcode(b"ACNT", 4),
+            Err(PackError::InvalidBase { byte: b'N', pos: 2 })
+        );
+    }
+
+    #[test]
+    fn test_tag_umi_length() {
+        let short = tag_umi_length(pack_barcode(b"ACGTAC", 6).unwrap(), 6);
+        let long = tag_umi_length(pack_barcode(b"AACGTAC", 7).unwrap(), 7);
+        let near = tag_umi_length(pack_barcode(b"ACGTAA", 6).unwrap(), 6);
+        assert_eq!(decode_kmer(untag_umi_length(long).0, 7), "AACGTAC");
+        assert_eq!(untag_umi_length(long).1, 7);
+        // the untagged values are equal, but the tagged ones differ
+        assert_ne!(short, long);
+        assert!(count_diff_2_bit_packed(short, long) >= 2);
+        assert_eq!(count_diff_2_bit_packed(short, near), 1);
+        for a in 0..=MAX_VARIABLE_UMI_LEN {
+            for b in 0..a {
+                assert!(count_diff_2_bit_packed(tag_umi_length(0, a), tag_umi_length(0, b)) >= 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pack_barcode_u128() {
+        // a 48 base composite barcode
+        let seq = b"ACGTTGCAGGCATTACTTGACCAGTGCAAGTCATCGGATCAAGCTTAG";
+        let v = pack_barcode_u128(seq, 48).unwrap();
+        assert_eq!(decode_kmer_u128(v, 48).as_bytes(), &seq[..]);
+        assert!(matches!(
+            pack_barcode(seq, 48),
+            Err(PackError::WrongLength { .. })
+        ));
+        // short barcodes pack identically either way
+        assert_eq!(
+            pack_barcode_u128(&seq[..16], 16).unwrap(),
+            pack_barcode(&seq[..16], 16).unwrap() as u128
+        );
+
+        let mut other = seq.to_vec();
+        other[0] = b'T';
+        other[47] = b'C';
+        let w = pack_barcode_u128(&other, 48).unwrap();
+        assert_eq!(hamming_distance_packed_u128(v, w), 2);
+    }
+
+    #[test]
+    fn test_decode_kmer() {
+        let seq = b"ACGTTGCAGGCATTACCGTAGCTAGCATCGA";
+        for &len in &[8u16, 16, 31] {
+            let mut bnk = BitNuclKmer::new(&seq[..], len as u8, false);
+            let (_, k, _) = bnk.next().expect("can't extract kmer");
+            let expected = std::str::from_utf8(&seq[..len as usize]).unwrap();
+            assert_eq!(decode_kmer(k.0, len), expected);
+            assert_eq!(decode_kmer_u128(k.0 as u128, len), expected);
+
+            let rc: String = expected
+                .chars()
+                .rev()
+                .map(|c| match c {
+                    'A' => 'T',
+                    'C' => 'G',
+                    'G' => 'C',
+                    _ => 'A',
+                })
+                .collect();
+            assert_eq!(decode_kmer(reverse_complement_packed(k.0, len), len), rc);
+        }
+    }
+
     #[test]
     fn test_version_info() {
         let vi = InternalVersionInfo::from_str("1.2.3");
@@ -800,4 +1027,11 @@ mod tests {
             InternalVersionInfo{1, 2, 3}
         );
     }
+    #[test]
+    fn test_hamming_distance_packed() {
+        // ACGT vs ACGT, AGGT and TCGA
+        assert_eq!(hamming_distance_packed(0b00011011, 0b00011011), 0);
+        assert_eq!(hamming_distance_packed(0b00011011, 0b00101011), 1);
+        assert_eq!(hamming_distance_packed(0b00011011, 0b11011000), 2);
+    }
 }
@@ -0,0 +1,87 @@
+// Checks that one-pass collation keeps to its memory budget.  This lives
+// in its own test binary because it counts every allocation made through
+// the global allocator.
+
+use libradicl::schema::TempCellInfo;
+use libradicl::{collate_temporary_bucket_onepass, AlnLayout, RadIntId};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            let now = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(now, Ordering::SeqCst);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_onepass_peak_allocation_within_budget() {
+    // 4000 cells of 50 records of 20 bytes each, about 4MB in all
+    let mut bucket = Vec::new();
+    let nrec = 200_000u32;
+    for i in 0..nrec {
+        bucket.extend_from_slice(&2u32.to_le_bytes());
+        bucket.extend_from_slice(&(i % 4000).to_le_bytes());
+        bucket.extend_from_slice(&i.to_le_bytes());
+        bucket.extend_from_slice(&(i % 3).to_le_bytes());
+        bucket.extend_from_slice(&(0x8000_0000u32 | (i % 5)).to_le_bytes());
+    }
+    let budget = 256 * 1024;
+    let spill_path =
+        std::env::temp_dir().join(format!("collate_memory_test_{}.tmp", std::process::id()));
+
+    // the output is discarded, so that it isn't counted
+    let owriter = Mutex::new(std::io::sink());
+    let mut cmap: HashMap<u64, TempCellInfo, ahash::RandomState> =
+        HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+
+    let before = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let ncells = collate_temporary_bucket_onepass(
+        &mut Cursor::new(&bucket),
+        &RadIntId::U32,
+        &RadIntId::U32,
+        nrec,
+        &owriter,
+        false,
+        &mut cmap,
+        None,
+        &AlnLayout::default(),
+        budget,
+        &spill_path,
+        None,
+    );
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+
+    assert_eq!(ncells, 4000);
+    assert!(!spill_path.exists());
+    // the records held at once stay within the budget; the rest is the
+    // per-cell bookkeeping and fixed-size read and write buffers
+    assert!(
+        peak < 2 * budget + 512 * 1024,
+        "peak allocation of {} bytes for a budget of {}",
+        peak,
+        budget
+    );
+    assert!(peak < bucket.len() / 2);
+}
@@ -83,6 +83,24 @@ fn main() {
         )
         .arg(Arg::from("-o, --output=<rad-file> 'output plain-text-file file'").required(false));
 
+    let dump_app = App::new("dump")
+        .about("Dump the header and records of a RAD file as TSV or JSON lines")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-r, --rad=<rad-file> 'input RAD file'"))
+        .arg(
+            Arg::from("-f, --format=[format] 'the output format'")
+                .possible_values(&["tsv", "jsonl"])
+                .default_value("tsv"),
+        )
+        .arg(
+            Arg::from("-d, --decode 'write barcodes and umis as ACGT strings'")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(Arg::from("-n, --head=[head] 'write at most this many records'").required(false))
+        .arg(Arg::from("-o, --output=[output] 'output file (stdout if absent)'").required(false));
+
     let gen_app = App::new("generate-permit-list")
         .about("Generate a permit list of barcodes from a RAD file")
         .version(version)
@@ -104,6 +122,10 @@ fn main() {
             "-f, --force-cells=<force-cells>  'select the top-k most-frequent barcodes, based on read count, as valid (true)'"
         ).conflicts_with_all(&["expect-cells", "valid-bc", "knee-distance", "unfiltered-pl"])
         )
+        .arg(Arg::from(
+            "-n, --force-top-n=<force-top-n>  'select exactly the n most-frequent barcodes, based on read count, breaking ties by barcode'"
+        ).conflicts_with_all(&["force-cells", "expect-cells", "valid-bc", "knee-distance", "unfiltered-pl"])
+        )
         .arg(
             Arg::from(
                 "-b, --valid-bc=<valid-bc> 'uses true barcode collected from a provided file'",
@@ -121,7 +143,8 @@ fn main() {
             Arg::from("-m, --min-reads=<min-reads> 'minimum read count threshold; only used with --unfiltered-pl'")
                 .default_value("10")
                 .takes_value(true)
-                .required(true));
+                .required(true))
+        .arg(Arg::from("-t, --threads 'number of threads used to decode the input'").default_value(&max_num_collate_threads));
     //.arg(Arg::from("-v, --velocity-mode 'flag for velocity mode'").takes_value(false).required(false));
 
     let collate_app = App::new("collate")
@@ -130,10 +153,12 @@ fn main() {
     .author(crate_authors)
     .arg(Arg::from("-i, --input-dir=<input-dir> 'input directory made by generate-permit-list'"))
     .arg(Arg::from("-r, --rad-dir=<rad-file> 'the directory containing the RAD file to be collated'"))
+    .arg(Arg::from("--stdin 'read the RAD file to be collated from stdin rather than from the --rad-dir, which must still hold the unmapped barcode counts'").takes_value(false).required(false))
     .arg(Arg::from("-t, --threads 'number of threads to use for processing'").default_value(&max_num_collate_threads))
     .arg(Arg::from("-c, --compress 'compress the output collated RAD file'").takes_value(false).required(false))
     .arg(Arg::from("-m, --max-records=[max-records] 'the maximum number of read records to keep in memory at once'")
-         .default_value("30000000"));
+         .default_value("30000000"))
+    .arg(Arg::from("-s, --min-score=[min-score] 'drop alignments whose score is below this value (requires the score alignment tag)'"));
     //.arg(Arg::from("-e, --expected-ori=[expected-ori] 'the expected orientation of alignments'")
     //     .default_value("fw"));
 
@@ -150,6 +175,7 @@ fn main() {
     .arg(Arg::from("--init-uniform 'flag for uniform sampling'").requires("num-bootstraps").takes_value(false).required(false))
     .arg(Arg::from("--summary-stat 'flag for storing only summary statistics'").requires("num-bootstraps").takes_value(false).required(false))
     .arg(Arg::from("--use-mtx 'flag for writing output matrix in matrix market instead of EDS'").takes_value(false).required(false))
+    .arg(Arg::from("--stream-mtx 'write each cell of the matrix market output as it is quantified, rather than holding the whole matrix in memory'").requires("use-mtx").takes_value(false).required(false))
     .arg(Arg::from("--quant-subset=<sfile> 'file containing list of barcodes to quantify, those not in this list will be ignored").required(false))
     .arg(Arg::from("-r, --resolution 'the resolution strategy by which molecules will be counted'")
         .possible_values(&["full", "trivial", "cr-like", "cr-like-em", "parsimony", "parsimony-em"])
@@ -158,7 +184,11 @@ fn main() {
         .possible_values(&["prefer-ambig", "winner-take-all"])
         .default_value("winner-take-all")
         .setting(ArgSettings::Hidden))
-    .arg(Arg::from("--small-thresh 'cells with fewer than these many reads will be resolved using a custom approach'").default_value("10").setting(ArgSettings::Hidden));
+    .arg(Arg::from("--small-thresh 'cells with fewer than these many reads will be resolved using a custom approach'").default_value("10").setting(ArgSettings::Hidden))
+    .arg(Arg::from("--em-max-iter 'the maximum number of EM rounds run for each cell'").default_value("100"))
+    .arg(Arg::from("--em-abs-tol 'the EM has converged once no (non-negligible) gene abundance changes by more than this between rounds'").default_value("0.01"))
+    .arg(Arg::from("--em-rel-tol=[em-rel-tol] 'also require the total change in gene abundances between rounds, relative to the total abundance, to be at most this'").required(false))
+    .arg(Arg::from("--pug-threads 'number of threads used to build the UMI graph of each large cell with the full and parsimony resolution strategies'").default_value("1"));
 
     let infer_app = App::new("infer")
     .about("Perform inference on equivalence class count data")
@@ -184,6 +214,7 @@ fn main() {
         .subcommand(infer_app)
         .subcommand(convert_app)
         .subcommand(view_app)
+        .subcommand(dump_app)
         .get_matches();
 
     let decorator = slog_term::TermDecorator::new().build();
@@ -280,6 +311,10 @@ fn main() {
             Err(_) => None,
         };
 
+        if let Ok(v) = t.value_of_t("force-top-n") {
+            fmeth = CellFilterMethod::ForceTopN(v);
+        }
+
         let _valid_bc = match t.value_of_t::<String>("valid-bc") {
             Ok(v) => {
                 fmeth = CellFilterMethod::ExplicitList(v.clone());
@@ -306,6 +341,7 @@ fn main() {
 
         // velo_mode --- currently, on this branch, it is always false
         let velo_mode = false; //t.is_present("velocity-mode");
+        let num_threads = t.value_of_t("threads").unwrap();
 
         let nc = generate_permit_list(
             input_dir,
@@ -315,6 +351,7 @@ fn main() {
             VERSION,
             velo_mode,
             &cmdline,
+            num_threads,
             &log,
         )
         .unwrap();
@@ -343,6 +380,47 @@ fn main() {
         libradicl::convert::view(rad_file, print_header, out_file, &log)
     }
 
+    // dump the header and (some of the) records of a rad file as text
+    if let Some(ref t) = opts.subcommand_matches("dump") {
+        let rad_file: String = t.value_of_t("rad").unwrap();
+        let format = match t.value_of("format").unwrap() {
+            "jsonl" => libradicl::convert::DumpFormat::JsonLines,
+            _ => libradicl::convert::DumpFormat::Tsv,
+        };
+        let head: Option<u64> = match t.value_of("head").map(|h| h.parse()) {
+            Some(Ok(h)) => Some(h),
+            Some(Err(e)) => {
+                crit!(log, "invalid value for --head : {}", e);
+                std::process::exit(1);
+            }
+            None => None,
+        };
+        let rr = match std::fs::File::open(&rad_file)
+            .map_err(libradicl::error::RadError::from)
+            .and_then(|f| libradicl::RadReader::new(std::io::BufReader::new(f)))
+        {
+            Ok(rr) => rr,
+            Err(e) => {
+                crit!(log, "could not read {} : {}", rad_file, e);
+                std::process::exit(1);
+            }
+        };
+        let mut out: Box<dyn std::io::Write> = match t.value_of("output") {
+            Some(o) => Box::new(std::io::BufWriter::new(std::fs::File::create(o).unwrap())),
+            None => Box::new(std::io::BufWriter::new(std::io::stdout())),
+        };
+        match libradicl::convert::dump(rr, &mut out, format, t.is_present("decode"), head) {
+            Ok(_) => {}
+            // e.g. piped into head
+            Err(libradicl::error::RadError::Io(e))
+                if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            Err(e) => {
+                crit!(log, "{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     // collate a rad file to group together all records corresponding
     // to the same corrected barcode.
     if let Some(ref t) = opts.subcommand_matches("collate") {
@@ -351,16 +429,36 @@ fn main() {
         let num_threads = t.value_of_t("threads").unwrap();
         let compress_out = t.is_present("compress");
         let max_records: u32 = t.value_of_t("max-records").unwrap();
-        libradicl::collate::collate(
-            input_dir,
-            rad_dir,
-            num_threads,
-            max_records,
-            compress_out,
-            &cmdline,
-            &VERSION,
-            &log,
-        )
+        let min_score: Option<u32> = t.value_of("min-score").map(|s| {
+            s.parse()
+                .expect("the minimum score must be a non-negative integer")
+        });
+        if t.is_present("stdin") {
+            libradicl::collate::collate_from_reader(
+                std::io::stdin(),
+                input_dir,
+                rad_dir,
+                num_threads,
+                max_records,
+                compress_out,
+                min_score,
+                &cmdline,
+                &VERSION,
+                &log,
+            )
+        } else {
+            libradicl::collate::collate(
+                input_dir,
+                rad_dir,
+                num_threads,
+                max_records,
+                compress_out,
+                min_score,
+                &cmdline,
+                &VERSION,
+                &log,
+            )
+        }
         .expect("could not collate.");
     }
 
@@ -372,6 +470,7 @@ fn main() {
         let summary_stat = t.is_present("summary-stat");
         let dump_eq = t.is_present("dump-eqclasses");
         let use_mtx = t.is_present("use-mtx");
+        let stream_mtx = t.is_present("stream-mtx");
         let input_dir: String = t.value_of_t("input-dir").unwrap();
         let output_dir = t.value_of_t("output-dir").unwrap();
         let tg_map = t.value_of_t("tg-map").unwrap();
@@ -379,6 +478,31 @@ fn main() {
         let sa_model: SplicedAmbiguityModel = t.value_of_t("sa-model").unwrap();
         let small_thresh = t.value_of_t("small-thresh").unwrap();
         let filter_list = t.value_of("quant-subset");
+        let pug_threads = t.value_of_t("pug-threads").unwrap();
+
+        let mut em_params = libradicl::em::EmParams::default();
+        match t.value_of_t("em-max-iter") {
+            Ok(v) => em_params.max_iter = v,
+            Err(e) => {
+                crit!(log, "invalid value for --em-max-iter : {}", e);
+                std::process::exit(1);
+            }
+        }
+        match t.value_of_t("em-abs-tol") {
+            Ok(v) => em_params.abs_tol = v,
+            Err(e) => {
+                crit!(log, "invalid value for --em-abs-tol : {}", e);
+                std::process::exit(1);
+            }
+        }
+        match t.value_of("em-rel-tol").map(|v| v.parse()) {
+            Some(Ok(v)) => em_params.rel_tol = v,
+            Some(Err(e)) => {
+                crit!(log, "invalid value for --em-rel-tol : {}", e);
+                std::process::exit(1);
+            }
+            None => {}
+        }
 
         if dump_eq && (resolution == ResolutionStrategy::Trivial) {
             crit!(
@@ -464,9 +588,12 @@ fn main() {
                     summary_stat,
                     dump_eq,
                     use_mtx,
+                    stream_mtx,
                     resolution,
                     sa_model,
                     small_thresh,
+                    em_params,
+                    pug_threads,
                     filter_list,
                     &cmdline,
                     &log,
@@ -522,6 +649,7 @@ fn main() {
             num_threads,
             filter_list,
             output_dir,
+            std::sync::Arc::new(libradicl::em::UniformPrior),
             &log,
         )
         .expect("could not perform inference from equivalence class counts.");